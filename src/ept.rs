@@ -24,4 +24,10 @@ pub struct GuestPageWalkInfo {
     pub is_smap_on: bool,
     /// Guest page table Supervisor mode execution protection
     pub is_smep_on: bool,
+    /// PDPTEs of PAE paging loaded by the processor, used instead of those in memory
+    pub pdptes: Option<[u64; 4]>,
+    /// CR4.PKE, protection keys for user-mode pages in IA-32e mode
+    pub pke: bool,
+    /// PKRU, the access rights of each protection key if CR4.PKE is set
+    pub pkru: u32,
 }
@@ -18,7 +18,16 @@ cfg_if::cfg_if! {
     if #[cfg(feature = "vmx")] {
         mod vmx;
         use vmx as vender;
-        pub use vmx::{VmxExitInfo, VmxExitReason, VmxInterruptInfo, VmxIoExitInfo};
+        pub use vmx::{
+            EPT_SPP, EPT_SUPPRESS_VE, EPT_USER_EXECUTE, DebugEvent, DirtyBitmap, DirtyLog,
+            EptMemoryType, EptViolation, EptpConfig, ExceptionExit, ExceptionMerge, GuestMemory,
+            GuestPagingMode, HwBreakpoint, HwBreakpointKind, HyperVConfig, HypercallAbi, Ipi,
+            IpiDelivery, IpiDeliveryMode, IpiRouter, IpiShorthand, KvmPvConfig, MmuMode, PageFault,
+            PreemptionTimerConfig, SegmentAccess, SegmentFault, SegmentRegister, SppEvent,
+            VcpuDebugControl, VeInfo, VmxActivityState, VmxExitInfo, VmxExitReason, VmxHaltReason,
+            VmxInterruptInfo, VmxInterruptionType, VmxIoExitInfo, spp_leaf_entry, spp_table_entry,
+            spp_writable_subpages, suppress_ve_non_present,
+        };
 
         pub use vender::VmxArchVCpu;
         pub use vender::VmxArchPerCpuState;
@@ -9,6 +9,8 @@ pub enum Msr {
 
     IA32_PAT = 0x277,
 
+    IA32_TSC_DEADLINE = 0x6e0,
+
     IA32_VMX_BASIC = 0x480,
     IA32_VMX_PINBASED_CTLS = 0x481,
     IA32_VMX_PROCBASED_CTLS = 0x482,
@@ -25,6 +27,7 @@ pub enum Msr {
     IA32_VMX_TRUE_PROCBASED_CTLS = 0x48e,
     IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
     IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
+    IA32_VMX_VMFUNC = 0x491,
 
     IA32_XSS = 0xda0,
 
@@ -0,0 +1,442 @@
+use x86_64::registers::control::{Cr0Flags, Cr4Flags, EferFlags};
+
+/// Paging mode of the guest. (SDM Vol. 3A, Section 4.1.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestPagingMode {
+    /// Real-address mode, i.e. CR0.PE = 0.
+    Real,
+    /// Protected mode without paging.
+    Protected,
+    /// 32-bit paging.
+    Paging32,
+    /// PAE paging.
+    Pae,
+    /// 4-level paging in IA-32e mode.
+    Level4,
+    /// 5-level paging in IA-32e mode.
+    Level5,
+}
+
+impl GuestPagingMode {
+    /// Whether the mode is IA-32e mode.
+    pub fn is_ia32e(&self) -> bool {
+        matches!(self, Self::Level4 | Self::Level5)
+    }
+}
+
+/// Control registers and IA32_EFER of the guest, as seen by the guest.
+///
+/// Writes are checked as the processor does. A write that causes #GP(0) returns
+/// `None`, the registers are then left unchanged. (SDM Vol. 3A, Section 2.5 and
+/// Section 10.8.5)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GuestControlRegs {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl GuestControlRegs {
+    /// Bits 63:52 of CR3 are reserved, bit 63 is the no-flush hint if CR4.PCIDE = 1.
+    const CR3_RESERVED: u64 = 0xfff0_0000_0000_0000;
+    const CR3_NO_FLUSH: u64 = 1 << 63;
+    /// CR3 bits 11:0 are the PCID if CR4.PCIDE = 1.
+    const CR3_PCID: u64 = 0xfff;
+
+    fn cr0_flags(&self) -> Cr0Flags {
+        Cr0Flags::from_bits_truncate(self.cr0)
+    }
+
+    fn cr4_flags(&self) -> Cr4Flags {
+        Cr4Flags::from_bits_truncate(self.cr4)
+    }
+
+    fn efer_flags(&self) -> EferFlags {
+        EferFlags::from_bits_truncate(self.efer)
+    }
+
+    /// Whether IA-32e mode is active, i.e. EFER.LMA = 1.
+    pub fn is_long_mode(&self) -> bool {
+        self.efer_flags().contains(EferFlags::LONG_MODE_ACTIVE)
+    }
+
+    /// Whether paging is enabled, i.e. CR0.PG = 1.
+    pub fn is_paging(&self) -> bool {
+        self.cr0_flags().contains(Cr0Flags::PAGING)
+    }
+
+    /// Current paging mode, determined by CR0.PE, CR0.PG, CR4.PAE, CR4.LA57 and EFER.LMA.
+    pub fn paging_mode(&self) -> GuestPagingMode {
+        let cr0 = self.cr0_flags();
+        let cr4 = self.cr4_flags();
+        if !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE) {
+            GuestPagingMode::Real
+        } else if !cr0.contains(Cr0Flags::PAGING) {
+            GuestPagingMode::Protected
+        } else if self.is_long_mode() {
+            if cr4.contains(Cr4Flags::L5_PAGING) {
+                GuestPagingMode::Level5
+            } else {
+                GuestPagingMode::Level4
+            }
+        } else if cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION) {
+            GuestPagingMode::Pae
+        } else {
+            GuestPagingMode::Paging32
+        }
+    }
+
+    /// Whether a write of the registers from `old` loads the PDPTEs of PAE paging,
+    /// i.e. PAE paging is used after it, and it changes CR3, CR0.CD, CR0.NW,
+    /// CR0.PG, CR4.PAE, CR4.PGE, CR4.PSE or CR4.SMEP. (SDM Vol. 3A, Section 4.4.1)
+    pub fn loads_pdptes(&self, old: &Self) -> bool {
+        let cr0_mask =
+            (Cr0Flags::CACHE_DISABLE | Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::PAGING).bits();
+        let cr4_mask = (Cr4Flags::PHYSICAL_ADDRESS_EXTENSION
+            | Cr4Flags::PAGE_GLOBAL
+            | Cr4Flags::PAGE_SIZE_EXTENSION
+            | Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)
+            .bits();
+        self.paging_mode() == GuestPagingMode::Pae
+            && (self.cr3 != old.cr3
+                || (self.cr0 ^ old.cr0) & cr0_mask != 0
+                || (self.cr4 ^ old.cr4) & cr4_mask != 0)
+    }
+
+    /// The physical address of the PDPT of PAE paging, in CR3 bits 31:5.
+    pub fn pdpt_addr(&self) -> u64 {
+        self.cr3 & 0xffff_ffe0
+    }
+
+    /// Update EFER.LMA, which is set iff both EFER.LME and CR0.PG are set.
+    fn update_lma(mut self) -> Self {
+        let mut efer = self.efer_flags();
+        let lma = self.is_paging() && efer.contains(EferFlags::LONG_MODE_ENABLE);
+        efer.set(EferFlags::LONG_MODE_ACTIVE, lma);
+        self.efer = efer.bits() | (self.efer & !EferFlags::all().bits());
+        self
+    }
+
+    /// Write CR0 by MOV to CR0.
+    pub fn write_cr0(self, val: u64) -> Option<Self> {
+        // ET is hardcoded to 1.
+        let val = val | Cr0Flags::EXTENSION_TYPE.bits();
+        let new = Cr0Flags::from_bits(val)?;
+        if new.contains(Cr0Flags::NOT_WRITE_THROUGH) && !new.contains(Cr0Flags::CACHE_DISABLE) {
+            return None;
+        }
+        if new.contains(Cr0Flags::PAGING) && !new.contains(Cr0Flags::PROTECTED_MODE_ENABLE) {
+            return None;
+        }
+
+        let cr4 = self.cr4_flags();
+        let enabling_paging = !self.is_paging() && new.contains(Cr0Flags::PAGING);
+        let disabling_paging = self.is_paging() && !new.contains(Cr0Flags::PAGING);
+        if enabling_paging
+            && self.efer_flags().contains(EferFlags::LONG_MODE_ENABLE)
+            && !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+        {
+            // IA-32e mode requires PAE paging.
+            return None;
+        }
+        if disabling_paging && cr4.contains(Cr4Flags::PCID) {
+            return None;
+        }
+
+        Some(Self { cr0: val, ..self }.update_lma())
+    }
+
+    /// Write CR3 by MOV to CR3.
+    pub fn write_cr3(self, val: u64) -> Option<Self> {
+        let val = if self.cr4_flags().contains(Cr4Flags::PCID) {
+            val & !Self::CR3_NO_FLUSH
+        } else {
+            val
+        };
+        if self.is_long_mode() && val & Self::CR3_RESERVED != 0 {
+            return None;
+        }
+        Some(Self { cr3: val, ..self })
+    }
+
+    /// Write CR4 by MOV to CR4.
+    pub fn write_cr4(self, val: u64) -> Option<Self> {
+        let old = self.cr4_flags();
+        let new = Cr4Flags::from_bits(val)?;
+        if self.is_long_mode() {
+            // PAE can not be cleared and LA57 can not be changed in IA-32e mode.
+            if !new.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+                || new.contains(Cr4Flags::L5_PAGING) != old.contains(Cr4Flags::L5_PAGING)
+            {
+                return None;
+            }
+        }
+        if !old.contains(Cr4Flags::PCID)
+            && new.contains(Cr4Flags::PCID)
+            && (!self.is_long_mode() || self.cr3 & Self::CR3_PCID != 0)
+        {
+            // PCIDs can only be enabled in IA-32e mode with PCID 000H.
+            return None;
+        }
+        Some(Self { cr4: val, ..self })
+    }
+
+    /// Write IA32_EFER by WRMSR.
+    ///
+    /// EFER.LMA is read-only, it follows CR0.PG and EFER.LME.
+    pub fn write_efer(self, val: u64) -> Option<Self> {
+        let new = EferFlags::from_bits(val)?;
+        let old = self.efer_flags();
+        if self.is_paging()
+            && new.contains(EferFlags::LONG_MODE_ENABLE)
+                != old.contains(EferFlags::LONG_MODE_ENABLE)
+        {
+            // EFER.LME can not be changed while paging is enabled.
+            return None;
+        }
+        let efer = val & !EferFlags::LONG_MODE_ACTIVE.bits();
+        Some(Self { efer, ..self }.update_lma())
+    }
+
+    /// Clear CR0.TS by CLTS.
+    pub fn clts(self) -> Self {
+        Self {
+            cr0: self.cr0 & !Cr0Flags::TASK_SWITCHED.bits(),
+            ..self
+        }
+    }
+
+    /// Load the machine status word, i.e. CR0 bits 3:0, by LMSW.
+    ///
+    /// LMSW can set CR0.PE, but can not clear it.
+    pub fn lmsw(self, msw: u16) -> Option<Self> {
+        let pe = Cr0Flags::PROTECTED_MODE_ENABLE.bits();
+        let val = (self.cr0 & !0xe) | (msw as u64 & 0xf) | (self.cr0 & pe);
+        self.write_cr0(val)
+    }
+}
+
+/// Whether the PDPTEs of PAE paging can be loaded, i.e. no present PDPTE sets
+/// reserved bits, with physical addresses of `phys_addr_bits` bits. Otherwise
+/// the instruction loading them causes #GP(0). (SDM Vol. 3A, Section 4.4.1)
+pub fn pdptes_valid(pdptes: &[u64; 4], phys_addr_bits: u8) -> bool {
+    // Bits 2:1, 8:5 and 63:MAXPHYADDR are reserved.
+    let reserved = 0x1e6 | !((1u64 << phys_addr_bits) - 1);
+    pdptes
+        .iter()
+        .all(|&pdpte| pdpte & 1 == 0 || pdpte & reserved == 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    const PE: u64 = Cr0Flags::PROTECTED_MODE_ENABLE.bits();
+    const TS: u64 = Cr0Flags::TASK_SWITCHED.bits();
+    const ET: u64 = Cr0Flags::EXTENSION_TYPE.bits();
+    const NW: u64 = Cr0Flags::NOT_WRITE_THROUGH.bits();
+    const CD: u64 = Cr0Flags::CACHE_DISABLE.bits();
+    const PG: u64 = Cr0Flags::PAGING.bits();
+    const PAE: u64 = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+    const LA57: u64 = Cr4Flags::L5_PAGING.bits();
+    const PCIDE: u64 = Cr4Flags::PCID.bits();
+    const LME: u64 = EferFlags::LONG_MODE_ENABLE.bits();
+    const LMA: u64 = EferFlags::LONG_MODE_ACTIVE.bits();
+
+    fn real_mode() -> GuestControlRegs {
+        GuestControlRegs {
+            cr0: CD | NW | ET,
+            ..Default::default()
+        }
+    }
+
+    fn long_mode() -> GuestControlRegs {
+        GuestControlRegs {
+            cr0: PG | PE | ET,
+            cr3: 0x1000,
+            cr4: PAE,
+            efer: LME | LMA,
+        }
+    }
+
+    #[test]
+    fn test_real_to_protected_to_paging() {
+        let regs = real_mode().write_cr0(PE).unwrap();
+        assert_eq!(regs.cr0, PE | ET);
+        let regs = regs.write_cr3(0x2000).unwrap().write_cr0(PG | PE).unwrap();
+        assert!(regs.is_paging());
+        assert!(!regs.is_long_mode());
+        let regs = regs.write_cr0(PE).unwrap().write_cr4(PAE).unwrap();
+        assert!(regs.write_cr0(PG | PE).unwrap().is_paging());
+    }
+
+    #[test]
+    fn test_invalid_cr0() {
+        // PG without PE.
+        assert_eq!(real_mode().write_cr0(PG), None);
+        // NW without CD.
+        assert_eq!(real_mode().write_cr0(NW | PE), None);
+        // Reserved bits.
+        assert_eq!(real_mode().write_cr0(1 << 32), None);
+        assert_eq!(real_mode().write_cr0(1 << 6), None);
+    }
+
+    #[test]
+    fn test_enter_and_leave_long_mode() {
+        let regs = GuestControlRegs {
+            efer: LME,
+            ..real_mode().write_cr0(PE).unwrap()
+        };
+        assert!(!regs.is_long_mode());
+        // IA-32e mode requires PAE.
+        assert_eq!(regs.write_cr0(PG | PE), None);
+
+        let regs = regs.write_cr4(PAE).unwrap().write_cr0(PG | PE).unwrap();
+        assert!(regs.is_long_mode());
+        assert_eq!(regs.efer, LME | LMA);
+
+        // Disabling paging leaves IA-32e mode.
+        let regs = regs.write_cr0(PE).unwrap();
+        assert!(!regs.is_long_mode());
+        assert_eq!(regs.efer, LME);
+    }
+
+    #[test]
+    fn test_long_mode_restrictions() {
+        let regs = long_mode();
+        // PAE can not be cleared, LA57 can not be changed.
+        assert_eq!(regs.write_cr4(0), None);
+        assert_eq!(regs.write_cr4(PAE | LA57), None);
+        // LME can not be changed while paging is enabled.
+        assert_eq!(regs.write_efer(0), None);
+        // LMA is read-only.
+        assert_eq!(regs.write_efer(LME).unwrap(), regs);
+        assert_eq!(regs.write_efer(LME | LMA).unwrap(), regs);
+        assert_eq!(
+            regs.write_cr0(PE)
+                .unwrap()
+                .write_efer(LME | LMA)
+                .unwrap()
+                .efer,
+            LME
+        );
+        // Reserved CR3 bits.
+        assert_eq!(regs.write_cr3(1 << 60), None);
+    }
+
+    #[test]
+    fn test_paging_mode_transitions() {
+        use GuestPagingMode::*;
+
+        let transitions: [fn(GuestControlRegs) -> Option<GuestControlRegs>; 8] = [
+            // Boot: real -> protected -> 32-bit paging -> PAE -> 4-level paging.
+            |r| r.write_cr0(PE),
+            |r| r.write_cr0(PG | PE),
+            |r| r.write_cr0(PE)?.write_cr4(PAE),
+            |r| r.write_cr0(PG | PE),
+            |r| r.write_cr0(PE)?.write_efer(LME)?.write_cr0(PG | PE),
+            // Kexec: back to protected mode without paging, then to 5-level paging.
+            |r| r.write_cr0(PE),
+            |r| r.write_cr4(PAE | LA57)?.write_cr0(PG | PE),
+            // Reboot: back to real mode.
+            |r| r.write_cr0(PE)?.write_efer(0)?.write_cr4(0)?.write_cr0(0),
+        ];
+
+        let mut regs = real_mode();
+        let mut modes = vec![regs.paging_mode()];
+        for transition in transitions {
+            regs = transition(regs).unwrap();
+            modes.push(regs.paging_mode());
+        }
+
+        assert_eq!(
+            modes,
+            [
+                Real, Protected, Paging32, Protected, Pae, Level4, Protected, Level5, Real
+            ]
+        );
+        assert!(!regs.is_long_mode());
+        assert_eq!(regs.efer, 0);
+    }
+
+    #[test]
+    fn test_pcide() {
+        // PCIDE can only be set in IA-32e mode.
+        let regs = real_mode().write_cr0(PE).unwrap();
+        assert_eq!(regs.write_cr4(PCIDE), None);
+
+        // CR3[11:0] must be 0 to set PCIDE.
+        let regs = long_mode().write_cr3(0x1005).unwrap();
+        assert_eq!(regs.write_cr4(PAE | PCIDE), None);
+        let regs = regs
+            .write_cr3(0x1000)
+            .unwrap()
+            .write_cr4(PAE | PCIDE)
+            .unwrap();
+
+        // The no-flush hint is not stored in CR3.
+        assert_eq!(regs.write_cr3(1 << 63 | 0x2003).unwrap().cr3, 0x2003);
+        // Paging can not be disabled while PCIDE is set.
+        assert_eq!(regs.write_cr0(PE), None);
+    }
+
+    #[test]
+    fn test_clts_lmsw() {
+        let regs = real_mode().write_cr0(PE | TS).unwrap();
+        assert_eq!(regs.clts().cr0, PE | ET);
+
+        // LMSW sets PE, but does not clear it.
+        let regs = real_mode().lmsw(0x1).unwrap();
+        assert_eq!(regs.cr0 & PE, PE);
+        let regs = regs.lmsw(0x8).unwrap();
+        assert_eq!(regs.cr0 & (PE | TS), PE | TS);
+        // Only bits 3:0 are loaded.
+        assert_eq!(regs.lmsw(0xfff0).unwrap().cr0 & 0xf, PE);
+    }
+
+    #[test]
+    fn test_pae_pdptes() {
+        const PGE: u64 = Cr4Flags::PAGE_GLOBAL.bits();
+        let protected = real_mode()
+            .write_cr0(PE)
+            .unwrap()
+            .write_cr3(0x1fe8)
+            .unwrap();
+        assert_eq!(protected.pdpt_addr(), 0x1fe0);
+
+        // Enabling PAE paging loads the PDPTEs, enabling 32-bit paging does not.
+        let pae = protected.write_cr4(PAE).unwrap();
+        assert!(!pae.loads_pdptes(&protected));
+        let paging = pae.write_cr0(PG | PE).unwrap();
+        assert_eq!(paging.paging_mode(), GuestPagingMode::Pae);
+        assert!(paging.loads_pdptes(&pae));
+        assert!(
+            !protected
+                .write_cr0(PG | PE)
+                .unwrap()
+                .loads_pdptes(&protected)
+        );
+
+        // So do changes of PGE and CR3 with PAE paging, but not of other bits.
+        assert!(paging.write_cr4(PAE | PGE).unwrap().loads_pdptes(&paging));
+        assert!(paging.write_cr3(0x3000).unwrap().loads_pdptes(&paging));
+        assert!(
+            !paging
+                .write_cr0(PG | PE | TS)
+                .unwrap()
+                .loads_pdptes(&paging)
+        );
+        // Entering IA-32e mode does not use PDPTEs.
+        let long = pae.write_efer(LME).unwrap().write_cr0(PG | PE).unwrap();
+        assert!(!long.loads_pdptes(&pae));
+
+        // Reserved bits of present PDPTEs.
+        assert!(pdptes_valid(&[0x2001, 0x3001, 0, 0x2], 36));
+        assert!(!pdptes_valid(&[0x2001, 0x3003, 0, 0], 36));
+        assert!(!pdptes_valid(&[0x10_0000_0001, 0, 0, 0], 36));
+        assert!(pdptes_valid(&[0x10_0000_0001, 0, 0, 0], 40));
+        assert!(!pdptes_valid(&[1 << 63 | 1, 0, 0, 0], 52));
+    }
+}
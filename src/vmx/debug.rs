@@ -0,0 +1,350 @@
+use x86::debugregs::{self, Dr6, Dr7};
+
+use super::vmcs::controls::PrimaryControls;
+
+/// DR6 bits that are always 1. (SDM Vol. 3B, Section 18.2.3)
+pub const DR6_FIXED_1: u64 = 0xffff_0ff0;
+/// DR6 bits that report debug conditions: B0-B3, BD, BS and BT.
+const DR6_VOLATILE: u64 = 0xe00f;
+/// DR7 bits that are always 1. (SDM Vol. 3B, Section 18.2.4)
+pub const DR7_FIXED_1: u64 = 0x400;
+/// DR7 bits that are reserved and must be 0.
+const DR7_RESERVED: u64 = 0xffff_ffff_0000_d000;
+/// DR7 bits that enable breakpoints: L0-L3 and G0-G3.
+pub const DR7_BREAKPOINT_ENABLE: u64 = 0xff;
+
+/// Keep the fixed bits of a value to be written into DR6.
+pub fn normalize_dr6(val: u64) -> u64 {
+    (val & DR6_VOLATILE) | DR6_FIXED_1
+}
+
+/// Keep the fixed and reserved bits of a value to be written into DR7.
+pub fn normalize_dr7(val: u64) -> u64 {
+    (val & !DR7_RESERVED) | DR7_FIXED_1
+}
+
+/// Debug registers which are not part of the VMCS guest state, i.e. DR0-DR3 and DR6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugRegs {
+    /// Breakpoint addresses in DR0-DR3.
+    pub dr: [u64; 4],
+    /// Debug status in DR6.
+    pub dr6: u64,
+}
+
+impl DebugRegs {
+    /// Create [`DebugRegs`] in their state after power-up or reset.
+    pub const fn new() -> Self {
+        Self {
+            dr: [0; 4],
+            dr6: DR6_FIXED_1,
+        }
+    }
+
+    /// Read DR0-DR3 and DR6 of the current processor.
+    pub fn save() -> Self {
+        unsafe {
+            Self {
+                dr: [
+                    debugregs::dr0() as _,
+                    debugregs::dr1() as _,
+                    debugregs::dr2() as _,
+                    debugregs::dr3() as _,
+                ],
+                dr6: debugregs::dr6().bits() as u64 | DR6_FIXED_1,
+            }
+        }
+    }
+
+    /// Write DR0-DR3 and DR6 of the current processor.
+    ///
+    /// Breakpoints should be disabled in DR7 first, to avoid breakpoints at the
+    /// new addresses being hit before DR7 is reloaded.
+    pub fn load(&self) {
+        unsafe {
+            debugregs::dr0_write(self.dr[0] as _);
+            debugregs::dr1_write(self.dr[1] as _);
+            debugregs::dr2_write(self.dr[2] as _);
+            debugregs::dr3_write(self.dr[3] as _);
+            debugregs::dr6_write(Dr6::from_bits_truncate(self.dr6 as _));
+        }
+    }
+}
+
+/// Read DR7 of the current processor.
+pub fn read_dr7() -> u64 {
+    unsafe { debugregs::dr7().0 as _ }
+}
+
+/// Write DR7 of the current processor.
+pub fn write_dr7(val: u64) {
+    unsafe { debugregs::dr7_write(Dr7(val as _)) }
+}
+
+/// Condition of a hardware breakpoint, encoded as the R/W field in DR7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakpointKind {
+    /// Break on instruction execution.
+    Execute = 0b00,
+    /// Break on data writes.
+    Write = 0b01,
+    /// Break on data reads or writes.
+    ReadWrite = 0b11,
+}
+
+/// A hardware breakpoint or watchpoint set by the VMM, which is invisible to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HwBreakpoint {
+    /// The guest linear address.
+    pub addr: u64,
+    /// The breakpoint condition.
+    pub kind: HwBreakpointKind,
+    /// The length of the watched data in bytes, 1, 2, 4 or 8. Must be 1 for
+    /// instruction breakpoints.
+    pub len: u8,
+}
+
+impl HwBreakpoint {
+    /// The LEN field in DR7.
+    fn len_bits(&self) -> u64 {
+        match self.len {
+            2 => 0b01,
+            8 => 0b10,
+            4 => 0b11,
+            _ => 0b00,
+        }
+    }
+}
+
+/// A debug event reported to the VMM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugEvent {
+    /// The guest has executed one instruction in single-step mode.
+    SingleStep,
+    /// The guest has executed INT3.
+    SoftwareBreakpoint,
+    /// A hardware breakpoint set by the VMM has been hit, with its index.
+    HardwareBreakpoint(usize),
+    /// The guest has raised #DB for its own reasons, with the debug conditions
+    /// in the DR6 format.
+    DebugException(u64),
+}
+
+/// A VM exit that may be caused by guest debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DebugExit {
+    /// VM exit due to the monitor trap flag.
+    MonitorTrapFlag,
+    /// VM exit due to #DB, with the exit qualification in the DR6 format.
+    DebugException(u64),
+    /// VM exit due to #BP.
+    Breakpoint,
+}
+
+/// The VMCS fields involved in guest debugging.
+///
+/// This is not an in-memory copy of the whole VMCS, only of the few fields that
+/// [`VcpuDebugControl::apply`] updates, so that the debug logic can be tested
+/// without a VMCS. The vCPU reads them from and writes them back to the current VMCS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DebugVmcsFields {
+    /// Primary processor-based VM-execution controls.
+    pub primary_controls: u32,
+    /// Exception bitmap.
+    pub exception_bitmap: u32,
+    /// Guest DR7.
+    pub dr7: u64,
+}
+
+/// Guest debugging controlled by the VMM, e.g. by a gdbstub.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VcpuDebugControl {
+    /// Single-step the guest with the monitor trap flag.
+    pub single_step: bool,
+    /// Intercept #BP raised by INT3 in the guest.
+    pub intercept_breakpoint: bool,
+    /// Intercept #DB raised by the guest.
+    pub intercept_debug: bool,
+    /// Hardware breakpoints in DR0-DR3, which take over the debug registers from
+    /// the guest while any of them is set.
+    pub hw_breakpoints: [Option<HwBreakpoint>; 4],
+}
+
+impl VcpuDebugControl {
+    const DB_VECTOR: u32 = 1;
+    const BP_VECTOR: u32 = 3;
+    /// DR7.GE, recommended for exact data breakpoints.
+    const DR7_GE: u64 = 1 << 9;
+
+    /// Whether any hardware breakpoint is set.
+    pub fn uses_hw_breakpoints(&self) -> bool {
+        self.hw_breakpoints.iter().any(Option::is_some)
+    }
+
+    /// Addresses of the hardware breakpoints to be loaded into DR0-DR3.
+    pub fn breakpoint_addrs(&self) -> [u64; 4] {
+        self.hw_breakpoints.map(|bp| bp.map_or(0, |bp| bp.addr))
+    }
+
+    /// DR7 value enabling the hardware breakpoints.
+    pub fn dr7(&self) -> u64 {
+        let mut dr7 = DR7_FIXED_1;
+        for (i, bp) in self.hw_breakpoints.iter().enumerate() {
+            if let Some(bp) = bp {
+                dr7 |= 1 << (i * 2);
+                dr7 |= (bp.kind as u64 | bp.len_bits() << 2) << (16 + i * 4);
+                if bp.kind != HwBreakpointKind::Execute {
+                    dr7 |= Self::DR7_GE;
+                }
+            }
+        }
+        dr7
+    }
+
+    /// Update the VMCS fields for the debug control, `guest_dr7` is the DR7 seen by the guest.
+    ///
+    /// MOV DR is intercepted while hardware breakpoints are set, so that the guest
+    /// can not see or change them. Otherwise it is left to the lazy switching of
+    /// debug registers.
+    pub(crate) fn apply(&self, fields: &mut DebugVmcsFields, guest_dr7: u64) {
+        let hw = self.uses_hw_breakpoints();
+        let set = |val: &mut u32, bits: u32, enable: bool| {
+            if enable { *val |= bits } else { *val &= !bits }
+        };
+        set(
+            &mut fields.primary_controls,
+            PrimaryControls::MONITOR_TRAP_FLAG.bits(),
+            self.single_step,
+        );
+        if hw {
+            fields.primary_controls |= PrimaryControls::MOV_DR_EXITING.bits();
+        }
+        set(
+            &mut fields.exception_bitmap,
+            1 << Self::DB_VECTOR,
+            self.intercept_debug || hw,
+        );
+        set(
+            &mut fields.exception_bitmap,
+            1 << Self::BP_VECTOR,
+            self.intercept_breakpoint,
+        );
+        fields.dr7 = if hw { self.dr7() } else { guest_dr7 };
+    }
+
+    /// Decide whether a VM exit is a debug event to be reported to the VMM.
+    ///
+    /// Return `None` if it is not, and the exception should be reflected to the guest.
+    pub(crate) fn decode_exit(&self, exit: DebugExit) -> Option<DebugEvent> {
+        match exit {
+            DebugExit::MonitorTrapFlag => self.single_step.then_some(DebugEvent::SingleStep),
+            DebugExit::Breakpoint => self
+                .intercept_breakpoint
+                .then_some(DebugEvent::SoftwareBreakpoint),
+            DebugExit::DebugException(dr6) => {
+                let hit = (0..4).find(|&i| dr6 & (1 << i) != 0 && self.hw_breakpoints[i].is_some());
+                match hit {
+                    Some(i) => Some(DebugEvent::HardwareBreakpoint(i)),
+                    None => self
+                        .intercept_debug
+                        .then_some(DebugEvent::DebugException(dr6)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MTF: u32 = PrimaryControls::MONITOR_TRAP_FLAG.bits();
+    const MOV_DR: u32 = PrimaryControls::MOV_DR_EXITING.bits();
+
+    fn breakpoint(addr: u64, kind: HwBreakpointKind, len: u8) -> Option<HwBreakpoint> {
+        Some(HwBreakpoint { addr, kind, len })
+    }
+
+    #[test]
+    fn test_single_step() {
+        let mut control = VcpuDebugControl::default();
+        let mut fields = DebugVmcsFields {
+            primary_controls: MOV_DR,
+            exception_bitmap: 1 << 6,
+            dr7: 0,
+        };
+        control.single_step = true;
+        control.apply(&mut fields, 0x401);
+        assert_eq!(fields.primary_controls, MOV_DR | MTF);
+        assert_eq!(fields.exception_bitmap, 1 << 6);
+        assert_eq!(fields.dr7, 0x401);
+        assert_eq!(
+            control.decode_exit(DebugExit::MonitorTrapFlag),
+            Some(DebugEvent::SingleStep)
+        );
+
+        control.single_step = false;
+        control.apply(&mut fields, 0x401);
+        assert_eq!(fields.primary_controls, MOV_DR);
+        assert_eq!(control.decode_exit(DebugExit::MonitorTrapFlag), None);
+    }
+
+    #[test]
+    fn test_intercept_exceptions() {
+        let mut control = VcpuDebugControl {
+            intercept_breakpoint: true,
+            ..Default::default()
+        };
+        let mut fields = DebugVmcsFields::default();
+        control.apply(&mut fields, DR7_FIXED_1);
+        assert_eq!(fields.exception_bitmap, 1 << 3);
+        assert_eq!(
+            control.decode_exit(DebugExit::Breakpoint),
+            Some(DebugEvent::SoftwareBreakpoint)
+        );
+        // #DB is reflected to the guest if not intercepted.
+        assert_eq!(control.decode_exit(DebugExit::DebugException(0x4000)), None);
+
+        control.intercept_breakpoint = false;
+        control.intercept_debug = true;
+        control.apply(&mut fields, DR7_FIXED_1);
+        assert_eq!(fields.exception_bitmap, 1 << 1);
+        assert_eq!(control.decode_exit(DebugExit::Breakpoint), None);
+        assert_eq!(
+            control.decode_exit(DebugExit::DebugException(0x4000)),
+            Some(DebugEvent::DebugException(0x4000))
+        );
+    }
+
+    #[test]
+    fn test_hw_breakpoints() {
+        let mut control = VcpuDebugControl::default();
+        control.hw_breakpoints[0] = breakpoint(0xffff_8000_0000_1000, HwBreakpointKind::Execute, 1);
+        control.hw_breakpoints[2] = breakpoint(0x2000, HwBreakpointKind::Write, 8);
+        assert!(control.uses_hw_breakpoints());
+        assert_eq!(
+            control.breakpoint_addrs(),
+            [0xffff_8000_0000_1000, 0, 0x2000, 0]
+        );
+        // L0, L2, GE, R/W2 = 01, LEN2 = 10.
+        assert_eq!(control.dr7(), 0x0900_0611);
+
+        let mut fields = DebugVmcsFields::default();
+        control.apply(&mut fields, 0x403);
+        assert_eq!(fields.primary_controls, MOV_DR);
+        assert_eq!(fields.exception_bitmap, 1 << 1);
+        assert_eq!(fields.dr7, 0x0900_0611);
+
+        assert_eq!(
+            control.decode_exit(DebugExit::DebugException(0b100)),
+            Some(DebugEvent::HardwareBreakpoint(2))
+        );
+        // A condition on a breakpoint not set by the VMM belongs to the guest.
+        assert_eq!(control.decode_exit(DebugExit::DebugException(0b10)), None);
+
+        control.hw_breakpoints = [None; 4];
+        control.apply(&mut fields, 0x403);
+        assert_eq!(fields.exception_bitmap, 0);
+        assert_eq!(fields.dr7, 0x403);
+    }
+}
@@ -207,6 +207,22 @@ pub enum VmxExitReason {
 }
 }
 
+numeric_enum_macro::numeric_enum! {
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// The activity state of the guest logical processor. (SDM Vol. 3C, Section 24.4.2)
+pub enum VmxActivityState {
+    /// The logical processor is executing instructions normally.
+    Active = 0,
+    /// The logical processor is inactive because it executed the HLT instruction.
+    Hlt = 1,
+    /// The logical processor is inactive because it incurred a triple fault or some other serious error.
+    Shutdown = 2,
+    /// The logical processor is inactive because it is waiting for a startup-IPI (SIPI).
+    WaitForSipi = 3,
+}
+}
+
 numeric_enum_macro::numeric_enum! {
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
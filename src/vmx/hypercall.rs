@@ -0,0 +1,179 @@
+use crate::regs::GeneralRegisters;
+
+/// Calling convention of hypercalls issued by the guest with VMCALL.
+///
+/// Outside 64-bit mode, only the lower 32 bits of registers are used, and 64-bit
+/// Hyper-V values are passed in pairs of registers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallAbi {
+    /// Hypercalls are disabled, VMCALL raises #UD in the guest.
+    Disabled,
+    /// The ArceOS convention: the number in RAX, arguments in RDI, RSI, RDX, RCX,
+    /// R8 and R9, and the return value in RAX.
+    #[default]
+    ArceOs,
+    /// The KVM convention: the number in RAX, arguments in RBX, RCX, RDX and RSI,
+    /// and the return value in RAX.
+    Kvm,
+    /// The Hyper-V convention: the hypercall input value in RCX, the input and output
+    /// GPAs (or the input parameters of fast hypercalls) in RDX and R8, and the
+    /// hypercall result value in RAX. Outside 64-bit mode, they are in EDX:EAX,
+    /// EBX:ECX, EDI:ESI and EDX:EAX respectively.
+    ///
+    /// The hypercall number is the call code in the input value, and the arguments
+    /// are the input value, the input GPA and the output GPA.
+    HyperV,
+}
+
+/// Combine two 32-bit halves in a pair of registers.
+fn pair(high: u64, low: u64) -> u64 {
+    (high & 0xffff_ffff) << 32 | (low & 0xffff_ffff)
+}
+
+impl HypercallAbi {
+    /// Decode the hypercall number and arguments from guest registers.
+    ///
+    /// Return `None` if hypercalls are disabled.
+    pub fn decode(self, regs: &GeneralRegisters, long_mode: bool) -> Option<(u64, [u64; 6])> {
+        let reg = |val: u64| if long_mode { val } else { val & 0xffff_ffff };
+        match self {
+            Self::Disabled => None,
+            Self::ArceOs => Some((
+                reg(regs.rax),
+                [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8, regs.r9].map(reg),
+            )),
+            Self::Kvm => Some((
+                reg(regs.rax),
+                [regs.rbx, regs.rcx, regs.rdx, regs.rsi, 0, 0].map(reg),
+            )),
+            Self::HyperV => {
+                let (input, input_gpa, output_gpa) = if long_mode {
+                    (regs.rcx, regs.rdx, regs.r8)
+                } else {
+                    (
+                        pair(regs.rdx, regs.rax),
+                        pair(regs.rbx, regs.rcx),
+                        pair(regs.rdi, regs.rsi),
+                    )
+                };
+                Some((input & 0xffff, [input, input_gpa, output_gpa, 0, 0, 0]))
+            }
+        }
+    }
+
+    /// Write the return value of a hypercall into guest registers.
+    pub fn set_result(self, regs: &mut GeneralRegisters, long_mode: bool, ret: u64) {
+        if long_mode {
+            regs.rax = ret;
+        } else if self == Self::HyperV {
+            regs.rdx = ret >> 32;
+            regs.rax = ret & 0xffff_ffff;
+        } else {
+            regs.rax = ret & 0xffff_ffff;
+        }
+    }
+}
+
+/// A hypercall reported to the VMM, whose return value has not been set yet.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingHypercall {
+    /// The ABI used by the hypercall.
+    pub abi: HypercallAbi,
+    /// Whether the hypercall is issued in 64-bit mode.
+    pub long_mode: bool,
+    /// Whether the VMM completes the hypercall later, the vCPU does not run until then.
+    pub deferred: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn regs() -> GeneralRegisters {
+        let mut regs = GeneralRegisters::default();
+        for (i, val) in [
+            (0, 0xaaaa_aaaa_0000_0001),
+            (1, 0xcccc_cccc_0000_0002),
+            (2, 0xdddd_dddd_0000_0003),
+            (3, 0xbbbb_bbbb_0000_0004),
+            (6, 0x5555_5555_0000_0005),
+            (7, 0x7777_7777_0000_0006),
+            (8, 0x8888_8888_0000_0007),
+            (9, 0x9999_9999_0000_0008),
+        ] {
+            regs.set_reg_of_index(i, val);
+        }
+        regs
+    }
+
+    #[test]
+    fn test_arceos_and_kvm() {
+        let regs = regs();
+        assert_eq!(HypercallAbi::Disabled.decode(&regs, true), None);
+        assert_eq!(
+            HypercallAbi::ArceOs.decode(&regs, true),
+            Some((
+                0xaaaa_aaaa_0000_0001,
+                [
+                    0x7777_7777_0000_0006,
+                    0x5555_5555_0000_0005,
+                    0xdddd_dddd_0000_0003,
+                    0xcccc_cccc_0000_0002,
+                    0x8888_8888_0000_0007,
+                    0x9999_9999_0000_0008
+                ]
+            ))
+        );
+        // Arguments are truncated outside 64-bit mode.
+        assert_eq!(
+            HypercallAbi::ArceOs.decode(&regs, false),
+            Some((1, [6, 5, 3, 2, 7, 8]))
+        );
+        assert_eq!(
+            HypercallAbi::Kvm.decode(&regs, false),
+            Some((1, [4, 2, 3, 5, 0, 0]))
+        );
+    }
+
+    #[test]
+    fn test_hyperv() {
+        let mut regs = regs();
+        let (nr, args) = HypercallAbi::HyperV.decode(&regs, true).unwrap();
+        assert_eq!(nr, 2);
+        assert_eq!(
+            args[..3],
+            [
+                0xcccc_cccc_0000_0002,
+                0xdddd_dddd_0000_0003,
+                0x8888_8888_0000_0007
+            ]
+        );
+
+        // EDX:EAX, EBX:ECX and EDI:ESI outside 64-bit mode.
+        let (nr, args) = HypercallAbi::HyperV.decode(&regs, false).unwrap();
+        assert_eq!(nr, 1);
+        assert_eq!(
+            args[..3],
+            [
+                0x0000_0003_0000_0001,
+                0x0000_0004_0000_0002,
+                0x0000_0006_0000_0005
+            ]
+        );
+
+        HypercallAbi::HyperV.set_result(&mut regs, false, 0x1234_5678_9abc_def0);
+        assert_eq!((regs.rdx, regs.rax), (0x1234_5678, 0x9abc_def0));
+        HypercallAbi::HyperV.set_result(&mut regs, true, 0x1234_5678_9abc_def0);
+        assert_eq!(regs.rax, 0x1234_5678_9abc_def0);
+    }
+
+    #[test]
+    fn test_result_truncation() {
+        let mut regs = regs();
+        HypercallAbi::Kvm.set_result(&mut regs, false, u64::MAX);
+        assert_eq!(regs.rax, 0xffff_ffff);
+        assert_eq!(regs.rdx, 0xdddd_dddd_0000_0003);
+        HypercallAbi::Kvm.set_result(&mut regs, true, u64::MAX);
+        assert_eq!(regs.rax, u64::MAX);
+    }
+}
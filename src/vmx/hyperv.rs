@@ -0,0 +1,610 @@
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use raw_cpuid::CpuIdResult;
+
+use super::memory::{self, GuestMemory};
+
+/// Identity of the guest operating system, required to enable the hypercall page.
+pub const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+/// Guest physical address of the hypercall page, and the enable and locked bits.
+pub const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+/// Index of the virtual processor, read-only.
+pub const HV_X64_MSR_VP_INDEX: u32 = 0x4000_0002;
+/// Partition reference time in 100ns units, read-only.
+pub const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+/// Guest physical address of the reference TSC page, and the enable bit.
+pub const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+/// TSC frequency in Hz, read-only.
+pub const HV_X64_MSR_TSC_FREQUENCY: u32 = 0x4000_0022;
+/// Local APIC timer frequency in Hz, read-only.
+pub const HV_X64_MSR_APIC_FREQUENCY: u32 = 0x4000_0023;
+/// SynIC control, with the enable bit.
+pub const HV_X64_MSR_SCONTROL: u32 = 0x4000_0080;
+/// SynIC version, read-only.
+pub const HV_X64_MSR_SVERSION: u32 = 0x4000_0081;
+/// Guest physical address of the SynIC event flags page, and the enable bit.
+pub const HV_X64_MSR_SIEFP: u32 = 0x4000_0082;
+/// Guest physical address of the SynIC message page, and the enable bit.
+pub const HV_X64_MSR_SIMP: u32 = 0x4000_0083;
+/// End of message, written by the guest after consuming a message.
+pub const HV_X64_MSR_EOM: u32 = 0x4000_0084;
+/// The first of 16 synthetic interrupt source MSRs.
+pub const HV_X64_MSR_SINT0: u32 = 0x4000_0090;
+/// Configuration of synthetic timer 0, followed by its count, and then the
+/// configurations and counts of the other 3 timers.
+pub const HV_X64_MSR_STIMER0_CONFIG: u32 = 0x4000_00b0;
+
+const SINT_COUNT: usize = 16;
+const STIMER_COUNT: usize = 4;
+
+/// The Hyper-V CPUID leaves, `0x4000_0000` reports the last one.
+pub const HV_CPUID_LEAVES: RangeInclusive<u32> = 0x4000_0000..=0x4000_000a;
+/// Interface signature "Hv#1" in CPUID leaf 0x4000_0001.
+const HV_INTERFACE_SIGNATURE: u32 = 0x3123_7648;
+
+// Partition privileges in CPUID leaf 0x4000_0003 EAX.
+const HV_MSR_TIME_REF_COUNT_AVAILABLE: u32 = 1 << 1;
+const HV_MSR_SYNIC_AVAILABLE: u32 = 1 << 2;
+const HV_MSR_SYNTIMER_AVAILABLE: u32 = 1 << 3;
+const HV_MSR_HYPERCALL_AVAILABLE: u32 = 1 << 5;
+const HV_MSR_VP_INDEX_AVAILABLE: u32 = 1 << 6;
+const HV_MSR_REFERENCE_TSC_AVAILABLE: u32 = 1 << 9;
+const HV_ACCESS_FREQUENCY_MSRS: u32 = 1 << 11;
+// Features in CPUID leaf 0x4000_0003 EDX.
+const HV_FEATURE_FREQUENCY_MSRS_AVAILABLE: u32 = 1 << 8;
+const HV_STIMER_DIRECT_MODE_AVAILABLE: u32 = 1 << 19;
+// Recommendations in CPUID leaf 0x4000_0004 EAX.
+const HV_X64_RELAXED_TIMING_RECOMMENDED: u32 = 1 << 5;
+
+/// Enable bit in the MSRs holding guest physical addresses, and in SCONTROL.
+const HV_MSR_ENABLED: u64 = 1;
+/// The hypercall MSR can not be changed any more.
+const HV_HYPERCALL_LOCKED: u64 = 1 << 1;
+/// The synthetic interrupt source is masked.
+const HV_SINT_MASKED: u64 = 1 << 16;
+
+// Synthetic timer configuration bits.
+const HV_STIMER_ENABLE: u64 = 1 << 0;
+const HV_STIMER_PERIODIC: u64 = 1 << 1;
+const HV_STIMER_AUTOENABLE: u64 = 1 << 3;
+const HV_STIMER_DIRECT_MODE: u64 = 1 << 12;
+
+/// Message slot size in the SynIC message page, one per SINT.
+const HV_MESSAGE_SIZE: usize = 256;
+const HVMSG_TIMER_EXPIRED: u32 = 0x8000_0010;
+/// Another message is pending on the SINT, in the message flags.
+const HV_MESSAGE_FLAG_PENDING: u8 = 1 << 0;
+
+/// The reference time counts in 100ns units.
+const REF_TIME_HZ: u64 = 10_000_000;
+
+/// `vmcall; ret`, the hypercall page for Intel processors.
+const HYPERCALL_CODE: [u8; 4] = [0x0f, 0x01, 0xc1, 0xc3];
+
+/// Configuration of the Hyper-V enlightenments, shared by all vCPUs of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HyperVConfig {
+    /// The TSC value at which the partition reference time is 0.
+    pub base_tsc: u64,
+    /// The number of vCPUs in the VM.
+    pub vcpu_num: u32,
+}
+
+/// Page-aligned guest physical address in an MSR value.
+fn page_gpa(val: u64) -> GuestPhysAddr {
+    GuestPhysAddr::from((val & !0xfff) as usize)
+}
+
+/// A synthetic timer.
+#[derive(Debug, Default, Clone, Copy)]
+struct SyntheticTimer {
+    config: u64,
+    count: u64,
+    /// Reference time of the next expiration, if the timer is running.
+    deadline: Option<u64>,
+    /// Reference time of an expiration that has not been delivered yet.
+    pending: Option<u64>,
+}
+
+impl SyntheticTimer {
+    fn start(&mut self, now: u64) {
+        self.deadline = if self.config & HV_STIMER_ENABLE == 0 || self.count == 0 {
+            None
+        } else if self.config & HV_STIMER_PERIODIC != 0 {
+            Some(now + self.count)
+        } else {
+            // The count is an absolute reference time for one-shot timers.
+            Some(self.count)
+        };
+    }
+
+    /// Record the expiration if the deadline has passed at reference time `now`.
+    fn expire(&mut self, now: u64) {
+        let Some(deadline) = self.deadline.filter(|&d| d <= now) else {
+            return;
+        };
+        self.pending = Some(deadline);
+        if self.config & HV_STIMER_PERIODIC != 0 {
+            // Skip the missed periods.
+            let periods = (now - deadline) / self.count + 1;
+            self.deadline = Some(deadline + periods * self.count);
+        } else {
+            self.deadline = None;
+            self.config &= !HV_STIMER_ENABLE;
+        }
+    }
+}
+
+/// Per-vCPU state of the Hyper-V enlightenments: the hypercall page, VP index,
+/// reference time, and the SynIC with synthetic timers. (Hyper-V TLFS)
+pub struct HyperV {
+    config: HyperVConfig,
+    vp_index: u32,
+    tsc_hz: u64,
+    guest_os_id: u64,
+    hypercall_msr: u64,
+    reference_tsc_msr: u64,
+    scontrol: u64,
+    siefp: u64,
+    simp: u64,
+    sint: [u64; SINT_COUNT],
+    stimer: [SyntheticTimer; STIMER_COUNT],
+}
+
+impl HyperV {
+    /// Signature in CPUID leaf 0x4000_0000.
+    pub const SIGNATURE: &[u8; 12] = b"Microsoft Hv";
+
+    /// Create the enlightenments for the vCPU `vp_index`, with the TSC frequency `tsc_hz`.
+    pub fn new(config: HyperVConfig, vp_index: u32, tsc_hz: u64) -> Self {
+        Self {
+            config,
+            vp_index,
+            tsc_hz,
+            guest_os_id: 0,
+            hypercall_msr: 0,
+            reference_tsc_msr: 0,
+            scontrol: 0,
+            siefp: 0,
+            simp: 0,
+            sint: [HV_SINT_MASKED; SINT_COUNT],
+            stimer: [SyntheticTimer::default(); STIMER_COUNT],
+        }
+    }
+
+    /// A CPUID leaf in [`HV_CPUID_LEAVES`].
+    pub fn cpuid(&self, leaf: u32) -> CpuIdResult {
+        let signature =
+            |i: usize| u32::from_le_bytes(Self::SIGNATURE[i * 4..i * 4 + 4].try_into().unwrap());
+        let (eax, ebx, ecx, edx) = match leaf {
+            0x4000_0000 => (
+                *HV_CPUID_LEAVES.end(),
+                signature(0),
+                signature(1),
+                signature(2),
+            ),
+            0x4000_0001 => (HV_INTERFACE_SIGNATURE, 0, 0, 0),
+            // Build number, and version 10.0.
+            0x4000_0002 => (0, 0x000a_0000, 0, 0),
+            0x4000_0003 => (
+                HV_MSR_TIME_REF_COUNT_AVAILABLE
+                    | HV_MSR_SYNIC_AVAILABLE
+                    | HV_MSR_SYNTIMER_AVAILABLE
+                    | HV_MSR_HYPERCALL_AVAILABLE
+                    | HV_MSR_VP_INDEX_AVAILABLE
+                    | HV_MSR_REFERENCE_TSC_AVAILABLE
+                    | HV_ACCESS_FREQUENCY_MSRS,
+                0,
+                0,
+                HV_FEATURE_FREQUENCY_MSRS_AVAILABLE | HV_STIMER_DIRECT_MODE_AVAILABLE,
+            ),
+            // Never notify the hypervisor about long spin waits.
+            0x4000_0004 => (HV_X64_RELAXED_TIMING_RECOMMENDED, u32::MAX, 0, 0),
+            0x4000_0005 => (self.config.vcpu_num, self.config.vcpu_num, 0, 0),
+            _ => (0, 0, 0, 0),
+        };
+        CpuIdResult { eax, ebx, ecx, edx }
+    }
+
+    /// Whether `msr` belongs to the enlightenments.
+    pub fn is_hyperv_msr(msr: u32) -> bool {
+        matches!(
+            msr,
+            HV_X64_MSR_GUEST_OS_ID..=HV_X64_MSR_VP_INDEX
+                | HV_X64_MSR_TIME_REF_COUNT..=HV_X64_MSR_APIC_FREQUENCY
+                | HV_X64_MSR_SCONTROL..=HV_X64_MSR_EOM
+        ) || Self::sint_index(msr).is_some()
+            || Self::stimer_index(msr).is_some()
+    }
+
+    fn sint_index(msr: u32) -> Option<usize> {
+        let index = msr.wrapping_sub(HV_X64_MSR_SINT0) as usize;
+        (index < SINT_COUNT).then_some(index)
+    }
+
+    /// The timer index, and whether `msr` is its count rather than its configuration.
+    fn stimer_index(msr: u32) -> Option<(usize, bool)> {
+        let index = msr.wrapping_sub(HV_X64_MSR_STIMER0_CONFIG) as usize;
+        (index < STIMER_COUNT * 2).then_some((index / 2, index % 2 == 1))
+    }
+
+    /// The partition reference time in 100ns units at TSC value `now`.
+    pub fn reference_time(&self, now: u64) -> u64 {
+        (now.wrapping_sub(self.config.base_tsc) as u128 * REF_TIME_HZ as u128 / self.tsc_hz as u128)
+            as u64
+    }
+
+    /// The TSC value at the partition reference time `time`.
+    fn reference_time_to_tsc(&self, time: u64) -> u64 {
+        let cycles = time as u128 * self.tsc_hz as u128 / REF_TIME_HZ as u128;
+        self.config.base_tsc.wrapping_add(cycles as u64)
+    }
+
+    /// Whether the guest has enabled the hypercall page, before which hypercalls
+    /// raise #UD.
+    pub fn hypercall_enabled(&self) -> bool {
+        self.hypercall_msr & HV_MSR_ENABLED != 0
+    }
+
+    /// Read an MSR of the enlightenments at TSC value `now`.
+    pub fn read_msr(&self, msr: u32, now: u64) -> AxResult<u64> {
+        match msr {
+            HV_X64_MSR_GUEST_OS_ID => Ok(self.guest_os_id),
+            HV_X64_MSR_HYPERCALL => Ok(self.hypercall_msr),
+            HV_X64_MSR_VP_INDEX => Ok(self.vp_index as u64),
+            HV_X64_MSR_TIME_REF_COUNT => Ok(self.reference_time(now)),
+            HV_X64_MSR_REFERENCE_TSC => Ok(self.reference_tsc_msr),
+            // The local APIC timer is clocked by the TSC.
+            HV_X64_MSR_TSC_FREQUENCY | HV_X64_MSR_APIC_FREQUENCY => Ok(self.tsc_hz),
+            HV_X64_MSR_SCONTROL => Ok(self.scontrol),
+            HV_X64_MSR_SVERSION => Ok(1),
+            HV_X64_MSR_SIEFP => Ok(self.siefp),
+            HV_X64_MSR_SIMP => Ok(self.simp),
+            HV_X64_MSR_EOM => Ok(0),
+            _ => {
+                if let Some(index) = Self::sint_index(msr) {
+                    Ok(self.sint[index])
+                } else if let Some((index, count)) = Self::stimer_index(msr) {
+                    let timer = &self.stimer[index];
+                    Ok(if count { timer.count } else { timer.config })
+                } else {
+                    ax_err!(InvalidInput, "not a Hyper-V MSR")
+                }
+            }
+        }
+    }
+
+    /// Write an MSR of the enlightenments at TSC value `now`, and update the
+    /// shared pages in guest memory.
+    ///
+    /// Return `InvalidInput` for read-only MSRs, which raise #GP in the guest.
+    pub fn write_msr(&mut self, msr: u32, val: u64, mem: &dyn GuestMemory, now: u64) -> AxResult {
+        match msr {
+            HV_X64_MSR_GUEST_OS_ID => {
+                self.guest_os_id = val;
+                // Clearing the guest OS ID disables the hypercall page.
+                if val == 0 {
+                    self.hypercall_msr &= !HV_MSR_ENABLED;
+                }
+                Ok(())
+            }
+            HV_X64_MSR_HYPERCALL => self.write_hypercall_msr(val, mem),
+            HV_X64_MSR_REFERENCE_TSC => {
+                self.reference_tsc_msr = val;
+                self.write_reference_tsc_page(mem)
+            }
+            HV_X64_MSR_SCONTROL => {
+                self.scontrol = val;
+                Ok(())
+            }
+            HV_X64_MSR_SIEFP => {
+                self.siefp = val;
+                Ok(())
+            }
+            HV_X64_MSR_SIMP => {
+                self.simp = val;
+                Ok(())
+            }
+            // Pending messages are delivered again by `poll_timers`.
+            HV_X64_MSR_EOM => Ok(()),
+            HV_X64_MSR_VP_INDEX
+            | HV_X64_MSR_TIME_REF_COUNT
+            | HV_X64_MSR_TSC_FREQUENCY
+            | HV_X64_MSR_APIC_FREQUENCY
+            | HV_X64_MSR_SVERSION => ax_err!(InvalidInput, "read-only Hyper-V MSR"),
+            _ => {
+                if let Some(index) = Self::sint_index(msr) {
+                    self.sint[index] = val;
+                    Ok(())
+                } else if let Some((index, count)) = Self::stimer_index(msr) {
+                    self.write_stimer(index, count, val, self.reference_time(now));
+                    Ok(())
+                } else {
+                    ax_err!(InvalidInput, "not a Hyper-V MSR")
+                }
+            }
+        }
+    }
+
+    fn write_hypercall_msr(&mut self, val: u64, mem: &dyn GuestMemory) -> AxResult {
+        if self.hypercall_msr & HV_HYPERCALL_LOCKED != 0 {
+            return Ok(());
+        }
+        // The hypercall page can not be enabled before the guest identifies itself.
+        self.hypercall_msr = if self.guest_os_id == 0 {
+            val & !HV_MSR_ENABLED
+        } else {
+            val
+        };
+        if self.hypercall_enabled() {
+            mem.write_phys(page_gpa(val), &HYPERCALL_CODE)?;
+        }
+        Ok(())
+    }
+
+    /// Write the reference TSC page, with which the guest computes the reference
+    /// time as `((tsc * scale) >> 64) + offset`.
+    fn write_reference_tsc_page(&self, mem: &dyn GuestMemory) -> AxResult {
+        if self.reference_tsc_msr & HV_MSR_ENABLED == 0 {
+            return Ok(());
+        }
+        let scale = (((REF_TIME_HZ as u128) << 64) / self.tsc_hz as u128) as u64;
+        let offset = ((self.config.base_tsc as u128 * scale as u128) >> 64) as i64;
+        let mut page = [0; 24];
+        // A non-zero sequence makes the page valid, the TSC is assumed to be
+        // invariant so it never changes.
+        page[0..4].copy_from_slice(&1u32.to_le_bytes());
+        page[8..16].copy_from_slice(&scale.to_le_bytes());
+        page[16..24].copy_from_slice(&(-offset).to_le_bytes());
+        mem.write_phys(page_gpa(self.reference_tsc_msr), &page)
+    }
+
+    fn write_stimer(&mut self, index: usize, count: bool, val: u64, now: u64) {
+        let timer = &mut self.stimer[index];
+        if count {
+            timer.count = val;
+            if val == 0 {
+                timer.config &= !HV_STIMER_ENABLE;
+            } else if timer.config & HV_STIMER_AUTOENABLE != 0 {
+                timer.config |= HV_STIMER_ENABLE;
+            }
+        } else {
+            timer.config = val;
+        }
+        timer.pending = None;
+        timer.start(now);
+    }
+
+    /// The TSC value of the earliest synthetic timer expiration.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.stimer
+            .iter()
+            .filter_map(|t| t.deadline)
+            .min()
+            .map(|time| self.reference_time_to_tsc(time))
+    }
+
+    /// Expire the synthetic timers at TSC value `now`, and deliver the
+    /// expirations, either directly or with messages in the SynIC message page.
+    ///
+    /// Return the vectors to inject. An expiration whose message slot is still
+    /// occupied is delivered by a later call, after the guest writes EOM.
+    pub fn poll_timers(&mut self, mem: &dyn GuestMemory, now: u64) -> AxResult<Vec<u8>> {
+        let now = self.reference_time(now);
+        let mut vectors = Vec::new();
+        for index in 0..STIMER_COUNT {
+            let mut timer = self.stimer[index];
+            timer.expire(now);
+            let delivered = match timer.pending {
+                Some(expiration) => {
+                    self.deliver_timer(index, &timer, expiration, now, mem, &mut vectors)?
+                }
+                None => false,
+            };
+            if delivered {
+                timer.pending = None;
+            }
+            self.stimer[index] = timer;
+        }
+        Ok(vectors)
+    }
+
+    /// Deliver an expiration of timer `index`, return whether it is done.
+    fn deliver_timer(
+        &self,
+        index: usize,
+        timer: &SyntheticTimer,
+        expiration: u64,
+        now: u64,
+        mem: &dyn GuestMemory,
+        vectors: &mut Vec<u8>,
+    ) -> AxResult<bool> {
+        if timer.config & HV_STIMER_DIRECT_MODE != 0 {
+            vectors.push((timer.config >> 4) as u8);
+            return Ok(true);
+        }
+        let sint = self.sint[(timer.config >> 16) as usize & 0xf];
+        if self.scontrol & HV_MSR_ENABLED == 0
+            || self.simp & HV_MSR_ENABLED == 0
+            || sint & HV_SINT_MASKED != 0
+        {
+            // The expiration is lost.
+            return Ok(true);
+        }
+
+        let slot = page_gpa(self.simp) + ((timer.config >> 16) as usize & 0xf) * HV_MESSAGE_SIZE;
+        if memory::read_u32(mem, slot)? != 0 {
+            // Ask the guest to write EOM after consuming the current message.
+            let mut flags = [0];
+            mem.read_phys(slot + 5, &mut flags)?;
+            mem.write_phys(slot + 5, &[flags[0] | HV_MESSAGE_FLAG_PENDING])?;
+            return Ok(false);
+        }
+        // Payload: timer index, reserved, expiration time and delivery time.
+        let mut message = [0; 40];
+        message[4] = 24;
+        message[16..20].copy_from_slice(&(index as u32).to_le_bytes());
+        message[24..32].copy_from_slice(&expiration.to_le_bytes());
+        message[32..40].copy_from_slice(&now.to_le_bytes());
+        mem.write_phys(slot + 4, &message[4..])?;
+        // The message type is written last, the guest treats it as valid then.
+        memory::write_u32(mem, slot, HVMSG_TIMER_EXPIRED)?;
+        vectors.push(sint as u8);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::memory::TestGuestMemory;
+
+    const TSC_HZ: u64 = 2_500_000_000;
+    const CONFIG: HyperVConfig = HyperVConfig {
+        base_tsc: 1_000_000,
+        vcpu_num: 4,
+    };
+
+    /// TSC value at reference time `time`.
+    fn tsc(time: u64) -> u64 {
+        CONFIG.base_tsc + time * (TSC_HZ / REF_TIME_HZ)
+    }
+
+    #[test]
+    fn test_cpuid_and_msrs() {
+        let hv = HyperV::new(CONFIG, 2, TSC_HZ);
+        let info = hv.cpuid(0x4000_0000);
+        assert_eq!(info.eax, 0x4000_000a);
+        let signature: Vec<u8> = [info.ebx, info.ecx, info.edx]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect();
+        assert_eq!(&signature, HyperV::SIGNATURE);
+        assert_eq!(hv.cpuid(0x4000_0001).eax, HV_INTERFACE_SIGNATURE);
+        assert_eq!(hv.cpuid(0x4000_0003).eax, 0xa6e);
+        assert_eq!(hv.cpuid(0x4000_0005).eax, 4);
+
+        assert!(HyperV::is_hyperv_msr(HV_X64_MSR_SINT0 + 15));
+        assert!(HyperV::is_hyperv_msr(HV_X64_MSR_STIMER0_CONFIG + 7));
+        assert!(!HyperV::is_hyperv_msr(HV_X64_MSR_STIMER0_CONFIG + 8));
+        assert!(!HyperV::is_hyperv_msr(0x4000_0003));
+
+        assert_eq!(hv.read_msr(HV_X64_MSR_VP_INDEX, 0).unwrap(), 2);
+        assert_eq!(hv.read_msr(HV_X64_MSR_TSC_FREQUENCY, 0).unwrap(), TSC_HZ);
+        assert_eq!(
+            hv.read_msr(HV_X64_MSR_TIME_REF_COUNT, tsc(12345)).unwrap(),
+            12345
+        );
+        let mem = TestGuestMemory::new(0x1000);
+        let mut hv = hv;
+        assert!(hv.write_msr(HV_X64_MSR_VP_INDEX, 0, &mem, 0).is_err());
+    }
+
+    #[test]
+    fn test_hypercall_page() {
+        let mem = TestGuestMemory::new(0x2000);
+        let mut hv = HyperV::new(CONFIG, 0, TSC_HZ);
+
+        // Ignored before the guest OS ID is set.
+        hv.write_msr(HV_X64_MSR_HYPERCALL, 0x1001, &mem, 0).unwrap();
+        assert!(!hv.hypercall_enabled());
+        assert_eq!(mem.u32_at(0x1000), 0);
+
+        hv.write_msr(HV_X64_MSR_GUEST_OS_ID, 0x8100_0a00_0000_0000, &mem, 0)
+            .unwrap();
+        hv.write_msr(HV_X64_MSR_HYPERCALL, 0x1003, &mem, 0).unwrap();
+        assert!(hv.hypercall_enabled());
+        assert_eq!(mem.u32_at(0x1000), u32::from_le_bytes(HYPERCALL_CODE));
+
+        // Locked.
+        hv.write_msr(HV_X64_MSR_HYPERCALL, 0, &mem, 0).unwrap();
+        assert_eq!(hv.read_msr(HV_X64_MSR_HYPERCALL, 0).unwrap(), 0x1003);
+    }
+
+    #[test]
+    fn test_reference_tsc_page() {
+        let mem = TestGuestMemory::new(0x2000);
+        let mut hv = HyperV::new(CONFIG, 0, TSC_HZ);
+        hv.write_msr(HV_X64_MSR_REFERENCE_TSC, 0x1001, &mem, 0)
+            .unwrap();
+        assert_eq!(mem.u32_at(0x1000), 1);
+        let scale = mem.u64_at(0x1008) as u128;
+        let offset = mem.u64_at(0x1010) as i64;
+        // The guest computes the same time as the host, up to rounding.
+        for time in [0, 1, 10_000_000, 123_456_789_012] {
+            let guest = (((tsc(time) as u128 * scale) >> 64) as i64 + offset) as u64;
+            assert!(guest.abs_diff(time) <= 1, "{guest} != {time}");
+        }
+    }
+
+    #[test]
+    fn test_direct_timer() {
+        let mem = TestGuestMemory::new(0x1000);
+        let mut hv = HyperV::new(CONFIG, 0, TSC_HZ);
+        // Periodic, direct mode with vector 0x40, auto-enabled by the count.
+        let config = HV_STIMER_PERIODIC | HV_STIMER_AUTOENABLE | HV_STIMER_DIRECT_MODE | 0x40 << 4;
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG, config, &mem, tsc(100))
+            .unwrap();
+        assert_eq!(hv.next_deadline(), None);
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG + 1, 1000, &mem, tsc(100))
+            .unwrap();
+        assert_eq!(hv.next_deadline(), Some(tsc(1100)));
+
+        assert!(hv.poll_timers(&mem, tsc(1099)).unwrap().is_empty());
+        assert_eq!(hv.poll_timers(&mem, tsc(3500)).unwrap(), [0x40]);
+        // Missed periods are skipped.
+        assert_eq!(hv.next_deadline(), Some(tsc(4100)));
+
+        // Count 0 stops the timer.
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG + 1, 0, &mem, tsc(3500))
+            .unwrap();
+        assert_eq!(hv.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_synic_timer_message() {
+        let mem = TestGuestMemory::new(0x2000);
+        let mut hv = HyperV::new(CONFIG, 0, TSC_HZ);
+        hv.write_msr(HV_X64_MSR_SCONTROL, 1, &mem, 0).unwrap();
+        hv.write_msr(HV_X64_MSR_SIMP, 0x1001, &mem, 0).unwrap();
+        hv.write_msr(HV_X64_MSR_SINT0 + 2, 0x50, &mem, 0).unwrap();
+        // One-shot timer 1 on SINT 2, expiring at reference time 500.
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG + 2, 2 << 16 | 1, &mem, 0)
+            .unwrap();
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG + 3, 500, &mem, 0)
+            .unwrap();
+
+        assert_eq!(hv.poll_timers(&mem, tsc(600)).unwrap(), [0x50]);
+        let slot = 0x1000 + 2 * HV_MESSAGE_SIZE;
+        assert_eq!(mem.u32_at(slot), HVMSG_TIMER_EXPIRED);
+        assert_eq!(mem.u32_at(slot + 4), 24);
+        assert_eq!(mem.u32_at(slot + 16), 1);
+        assert_eq!(mem.u64_at(slot + 24), 500);
+        assert_eq!(mem.u64_at(slot + 32), 600);
+        // One-shot timers are disabled after expiring.
+        assert_eq!(
+            hv.read_msr(HV_X64_MSR_STIMER0_CONFIG + 2, 0).unwrap() & HV_STIMER_ENABLE,
+            0
+        );
+
+        // The slot is occupied, the next expiration waits for EOM.
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG + 2, 2 << 16 | 1, &mem, tsc(600))
+            .unwrap();
+        hv.write_msr(HV_X64_MSR_STIMER0_CONFIG + 3, 700, &mem, tsc(600))
+            .unwrap();
+        assert!(hv.poll_timers(&mem, tsc(800)).unwrap().is_empty());
+        assert_eq!(
+            mem.u32_at(slot + 4) >> 8 & 0xff,
+            HV_MESSAGE_FLAG_PENDING as u32
+        );
+
+        memory::write_u32(&mem, slot.into(), 0).unwrap();
+        hv.write_msr(HV_X64_MSR_EOM, 0, &mem, tsc(900)).unwrap();
+        assert_eq!(hv.poll_timers(&mem, tsc(900)).unwrap(), [0x50]);
+        assert_eq!(mem.u64_at(slot + 24), 700);
+        assert_eq!(mem.u64_at(slot + 32), 900);
+    }
+}
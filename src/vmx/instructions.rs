@@ -23,8 +23,7 @@ fn vmx_capture_status() -> Result<()> {
 
 /// INVEPT type. (SDM Vol. 3C, Section 30.3)
 #[repr(u64)]
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InvEptType {
     /// The logical processor invalidates all mappings associated with bits
     /// 51:12 of the EPT pointer (EPTP) specified in the INVEPT descriptor.
@@ -48,3 +47,67 @@ pub unsafe fn invept(inv_type: InvEptType, eptp: u64) -> Result<()> {
     }
     vmx_capture_status()
 }
+
+/// Read Protection Key Rights for User Pages. (SDM Vol. 2B, RDPKRU)
+///
+/// CR4.PKE must be set, or it causes #UD.
+pub unsafe fn rdpkru() -> u32 {
+    let pkru: u32;
+    unsafe {
+        asm!("rdpkru", in("ecx") 0, out("eax") pkru, out("edx") _, options(nomem, nostack));
+    }
+    pkru
+}
+
+/// Write Protection Key Rights for User Pages. (SDM Vol. 2B, WRPKRU)
+///
+/// CR4.PKE must be set, or it causes #UD.
+pub unsafe fn wrpkru(pkru: u32) {
+    unsafe {
+        asm!("wrpkru", in("eax") pkru, in("ecx") 0, in("edx") 0, options(nostack));
+    }
+}
+
+/// Hand an NMI that caused a VM exit over to the host NMI handler, by `INT 2`.
+///
+/// NMIs are not blocked after such VM exits, as the NMI handler was not invoked.
+/// (SDM Vol. 3C, Section 33.2)
+pub unsafe fn int2() {
+    unsafe {
+        asm!("int 2");
+    }
+}
+
+/// INVVPID type. (SDM Vol. 3C, Section 30.3)
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum InvVpidType {
+    /// The logical processor invalidates mappings for the linear address and
+    /// VPID specified in the INVVPID descriptor.
+    IndividualAddress = 0,
+    /// The logical processor invalidates all mappings tagged with the VPID
+    /// specified in the INVVPID descriptor.
+    SingleContext = 1,
+    /// The logical processor invalidates all mappings tagged with all VPIDs
+    /// except VPID 0000H.
+    AllContext = 2,
+    /// The logical processor invalidates all mappings tagged with the VPID
+    /// specified in the INVVPID descriptor, except global translations.
+    SingleContextRetainingGlobals = 3,
+}
+
+/// Invalidate Translations Based on VPID. (SDM Vol. 3C, Section 30.3)
+///
+/// Invalidates mappings in the translation lookaside buffers (TLBs) and
+/// paging-structure caches based on virtual-processor identifier (VPID).
+/// Invalidation is based on the INVVPID type specified in the register operand
+/// and the INVVPID descriptor specified in the memory operand. `addr` is only
+/// used by [`InvVpidType::IndividualAddress`].
+pub unsafe fn invvpid(inv_type: InvVpidType, vpid: u16, addr: u64) -> Result<()> {
+    let invvpid_desc = [vpid as u64, addr];
+    unsafe {
+        asm!("invvpid {0}, [{1}]", in(reg) inv_type as u64, in(reg) &invvpid_desc);
+    }
+    vmx_capture_status()
+}
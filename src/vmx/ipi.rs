@@ -0,0 +1,328 @@
+use alloc::vec::Vec;
+use bit_field::BitField;
+
+numeric_enum_macro::numeric_enum! {
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Delivery mode of an IPI in the Interrupt Command Register. (SDM Vol. 3A, Section 11.6.1)
+pub enum IpiDeliveryMode {
+    /// Deliver the interrupt specified in the vector field to the target processors.
+    Fixed = 0,
+    /// Same as fixed mode, except that the interrupt is delivered to the processor
+    /// executing at the lowest priority among the target processors.
+    LowestPriority = 1,
+    /// Deliver an SMI interrupt to the target processors.
+    Smi = 2,
+    /// Deliver an NMI interrupt to the target processors.
+    Nmi = 4,
+    /// Deliver an INIT request to the target processors.
+    Init = 5,
+    /// Send a start-up IPI (SIPI) to the target processors.
+    StartUp = 6,
+}
+}
+
+numeric_enum_macro::numeric_enum! {
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Destination shorthand of an IPI in the Interrupt Command Register. (SDM Vol. 3A, Section 11.6.1)
+pub enum IpiShorthand {
+    /// The destination is specified in the destination field.
+    NoShorthand = 0,
+    /// The issuing processor is the one and only destination.
+    SelfOnly = 1,
+    /// The IPI is sent to all processors, including the issuing processor.
+    AllIncludingSelf = 2,
+    /// The IPI is sent to all processors, excluding the issuing processor.
+    AllExcludingSelf = 3,
+}
+}
+
+/// An IPI decoded from a write to the x2APIC Interrupt Command Register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipi {
+    /// The vector of the interrupt, or the start-up vector for SIPIs.
+    pub vector: u8,
+    /// The delivery mode.
+    pub delivery_mode: IpiDeliveryMode,
+    /// Whether the destination is a logical destination (otherwise a physical x2APIC ID).
+    pub logical: bool,
+    /// Whether the level is assert (otherwise de-assert).
+    pub level_assert: bool,
+    /// Whether the trigger mode is level (otherwise edge).
+    pub level_triggered: bool,
+    /// The destination shorthand.
+    pub shorthand: IpiShorthand,
+    /// The destination field.
+    pub destination: u32,
+}
+
+impl Ipi {
+    /// Decode a 64-bit value written to the x2APIC ICR (MSR 0x830).
+    ///
+    /// Return `None` if a reserved delivery mode is used.
+    pub fn from_icr(icr: u64) -> Option<Self> {
+        Some(Self {
+            vector: icr.get_bits(0..8) as u8,
+            delivery_mode: IpiDeliveryMode::try_from(icr.get_bits(8..11) as u8).ok()?,
+            logical: icr.get_bit(11),
+            level_assert: icr.get_bit(14),
+            level_triggered: icr.get_bit(15),
+            shorthand: IpiShorthand::try_from(icr.get_bits(18..20) as u8).unwrap(),
+            destination: icr.get_bits(32..64) as u32,
+        })
+    }
+}
+
+/// Hook provided by the VMM to deliver IPIs to other vCPUs.
+pub trait IpiDelivery {
+    /// Deliver `ipi` to the vCPU with `vcpu_id`.
+    ///
+    /// The implementation usually calls [`receive_ipi`] on the target vCPU, and
+    /// kicks it if it is running on another physical CPU.
+    ///
+    /// [`receive_ipi`]: crate::VmxArchVCpu::receive_ipi
+    fn deliver(&self, vcpu_id: usize, ipi: Ipi);
+}
+
+/// Destination resolution of IPIs among the vCPUs of a VM in x2APIC mode.
+///
+/// Physical destinations are matched against the x2APIC IDs of the vCPUs, and
+/// logical destinations are matched against the logical x2APIC IDs derived from
+/// them, in cluster mode. (SDM Vol. 3A, Section 11.12.10)
+#[derive(Debug, Clone)]
+pub struct IpiRouter {
+    /// x2APIC IDs of the vCPUs, indexed by vCPU ID.
+    apic_ids: Vec<u32>,
+}
+
+impl IpiRouter {
+    /// The destination value that addresses all processors.
+    pub const BROADCAST: u32 = 0xffff_ffff;
+
+    /// Create an [`IpiRouter`] for `vcpu_num` vCPUs, whose x2APIC IDs equal their vCPU IDs.
+    pub fn new(vcpu_num: usize) -> Self {
+        Self::with_apic_ids((0..vcpu_num as u32).collect())
+    }
+
+    /// Create an [`IpiRouter`] with the x2APIC IDs of the vCPUs, indexed by vCPU ID.
+    pub fn with_apic_ids(apic_ids: Vec<u32>) -> Self {
+        Self { apic_ids }
+    }
+
+    /// The logical x2APIC ID derived from an x2APIC ID, i.e. the cluster ID in
+    /// bits 31:16, and the position within the cluster in bits 15:0.
+    pub fn logical_id(apic_id: u32) -> u32 {
+        (apic_id >> 4) << 16 | 1 << (apic_id & 0xf)
+    }
+
+    fn is_destination(&self, apic_id: u32, ipi: &Ipi) -> bool {
+        if ipi.destination == Self::BROADCAST {
+            true
+        } else if ipi.logical {
+            let logical_id = Self::logical_id(apic_id);
+            ipi.destination >> 16 == logical_id >> 16 && ipi.destination & logical_id & 0xffff != 0
+        } else {
+            ipi.destination == apic_id
+        }
+    }
+
+    /// Resolve the vCPU IDs to which `ipi` sent by vCPU `source` is delivered.
+    ///
+    /// Task priorities of vCPUs are not tracked, so a lowest-priority IPI is
+    /// delivered to the first destination.
+    pub fn destinations(&self, source: usize, ipi: &Ipi) -> Vec<usize> {
+        let all = 0..self.apic_ids.len();
+        let mut targets: Vec<usize> = match ipi.shorthand {
+            IpiShorthand::NoShorthand => all
+                .filter(|&id| self.is_destination(self.apic_ids[id], ipi))
+                .collect(),
+            IpiShorthand::SelfOnly => [source].into(),
+            IpiShorthand::AllIncludingSelf => all.collect(),
+            IpiShorthand::AllExcludingSelf => all.filter(|&id| id != source).collect(),
+        };
+        if ipi.delivery_mode == IpiDeliveryMode::LowestPriority {
+            targets.truncate(1);
+        }
+        targets
+    }
+
+    /// Deliver `ipi` sent by vCPU `source` to each destination by `delivery`.
+    pub fn route(&self, source: usize, ipi: &Ipi, delivery: &dyn IpiDelivery) {
+        for target in self.destinations(source, ipi) {
+            delivery.deliver(target, *ipi);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<(usize, Ipi)>>);
+
+    impl IpiDelivery for Recorder {
+        fn deliver(&self, vcpu_id: usize, ipi: Ipi) {
+            self.0.borrow_mut().push((vcpu_id, ipi));
+        }
+    }
+
+    fn icr(
+        vector: u8,
+        mode: IpiDeliveryMode,
+        logical: bool,
+        shorthand: IpiShorthand,
+        dest: u32,
+    ) -> u64 {
+        vector as u64
+            | (mode as u64) << 8
+            | (logical as u64) << 11
+            | 1 << 14
+            | (shorthand as u64) << 18
+            | (dest as u64) << 32
+    }
+
+    fn targets(router: &IpiRouter, source: usize, icr: u64) -> Vec<usize> {
+        router.destinations(source, &Ipi::from_icr(icr).unwrap())
+    }
+
+    #[test]
+    fn test_decode_icr() {
+        let ipi = Ipi::from_icr(0x0000_0003_0000_c5a0).unwrap();
+        assert_eq!(ipi.vector, 0xa0);
+        assert_eq!(ipi.delivery_mode, IpiDeliveryMode::Init);
+        assert!(!ipi.logical);
+        assert!(ipi.level_assert);
+        assert!(ipi.level_triggered);
+        assert_eq!(ipi.shorthand, IpiShorthand::NoShorthand);
+        assert_eq!(ipi.destination, 3);
+
+        // Delivery modes 3 and 7 are reserved.
+        assert_eq!(Ipi::from_icr(3 << 8), None);
+        assert_eq!(Ipi::from_icr(7 << 8), None);
+    }
+
+    #[test]
+    fn test_physical_destination() {
+        use IpiDeliveryMode::Fixed;
+        use IpiShorthand::NoShorthand;
+
+        let router = IpiRouter::with_apic_ids(vec![0, 2, 4, 6]);
+        assert_eq!(
+            targets(&router, 0, icr(0x30, Fixed, false, NoShorthand, 4)),
+            [2]
+        );
+        assert_eq!(
+            targets(&router, 0, icr(0x30, Fixed, false, NoShorthand, 1)),
+            []
+        );
+        assert_eq!(
+            targets(
+                &router,
+                0,
+                icr(0x30, Fixed, false, NoShorthand, IpiRouter::BROADCAST)
+            ),
+            [0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_cluster_destination() {
+        use IpiDeliveryMode::Fixed;
+        use IpiShorthand::NoShorthand;
+
+        // Two clusters: vCPUs 0..16 in cluster 0, vCPUs 16..20 in cluster 1.
+        let router = IpiRouter::new(20);
+        assert_eq!(IpiRouter::logical_id(17), 0x0001_0002);
+        assert_eq!(
+            targets(&router, 0, icr(0x30, Fixed, true, NoShorthand, 0x0000_8005)),
+            [0, 2, 15]
+        );
+        assert_eq!(
+            targets(&router, 0, icr(0x30, Fixed, true, NoShorthand, 0x0001_0003)),
+            [16, 17]
+        );
+        // Cluster 2 has no processor.
+        assert_eq!(
+            targets(&router, 0, icr(0x30, Fixed, true, NoShorthand, 0x0002_ffff)),
+            []
+        );
+        assert_eq!(
+            targets(
+                &router,
+                0,
+                icr(0x30, Fixed, true, NoShorthand, IpiRouter::BROADCAST)
+            )
+            .len(),
+            20
+        );
+    }
+
+    #[test]
+    fn test_shorthands() {
+        use IpiDeliveryMode::Fixed;
+        use IpiShorthand::*;
+
+        let router = IpiRouter::new(4);
+        // The destination field is ignored with a shorthand.
+        assert_eq!(
+            targets(&router, 2, icr(0x30, Fixed, false, SelfOnly, 0)),
+            [2]
+        );
+        assert_eq!(
+            targets(&router, 2, icr(0x30, Fixed, false, AllIncludingSelf, 0)),
+            [0, 1, 2, 3]
+        );
+        assert_eq!(
+            targets(&router, 2, icr(0x30, Fixed, false, AllExcludingSelf, 0)),
+            [0, 1, 3]
+        );
+    }
+
+    #[test]
+    fn test_lowest_priority() {
+        let router = IpiRouter::new(4);
+        let icr = icr(
+            0x30,
+            IpiDeliveryMode::LowestPriority,
+            true,
+            IpiShorthand::NoShorthand,
+            0b1110,
+        );
+        assert_eq!(targets(&router, 0, icr), [1]);
+    }
+
+    #[test]
+    fn test_route_init_sipi() {
+        use IpiDeliveryMode::{Init, StartUp};
+        use IpiShorthand::AllExcludingSelf;
+
+        let router = IpiRouter::new(3);
+        let recorder = Recorder::default();
+        for icr in [
+            icr(0, Init, false, AllExcludingSelf, 0),
+            icr(0x9f, StartUp, false, AllExcludingSelf, 0),
+        ] {
+            router.route(0, &Ipi::from_icr(icr).unwrap(), &recorder);
+        }
+
+        let delivered: Vec<_> = recorder
+            .0
+            .borrow()
+            .iter()
+            .map(|(id, ipi)| (*id, ipi.delivery_mode, ipi.vector))
+            .collect();
+        assert_eq!(
+            delivered,
+            [
+                (1, Init, 0),
+                (2, Init, 0),
+                (1, StartUp, 0x9f),
+                (2, StartUp, 0x9f)
+            ]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use raw_cpuid::CpuIdResult;
+
+use super::memory::{self, GuestMemory};
+
+/// Wall clock at guest system time 0, in `pvclock_wall_clock`.
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+/// Guest physical address of `pvclock_vcpu_time_info`, and the enable bit.
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+/// Guest physical address of `kvm_steal_time`, and the enable bit.
+pub const MSR_KVM_STEAL_TIME: u32 = 0x4b56_4d03;
+/// Guest physical address of the PV EOI flag, and the enable bit.
+pub const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+
+/// Send IPIs to up to 128 vCPUs in one hypercall.
+pub const KVM_HC_SEND_IPI: u64 = 10;
+
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_STEAL_TIME: u32 = 1 << 5;
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+const KVM_FEATURE_PV_SEND_IPI: u32 = 1 << 11;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+/// Enable bit in the MSRs holding guest physical addresses.
+const KVM_MSR_ENABLED: u64 = 1;
+/// The TSC is synchronized across vCPUs, in `pvclock_vcpu_time_info.flags`.
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+/// The vCPU has been preempted, in `kvm_steal_time.preempted`.
+const KVM_VCPU_PREEMPTED: u8 = 1 << 0;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Configuration of the KVM paravirtual interface, shared by all vCPUs of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvmPvConfig {
+    /// The TSC value at which the guest system time is 0.
+    pub base_tsc: u64,
+    /// The wall-clock time at guest system time 0, in nanoseconds since the UNIX epoch.
+    pub boot_wall_clock_ns: u64,
+}
+
+/// Convert TSC cycles to nanoseconds as the guest does with `pvclock_vcpu_time_info`.
+pub fn pvclock_scale(delta: u64, mul: u32, shift: i8) -> u64 {
+    let delta = if shift < 0 {
+        delta >> -shift
+    } else {
+        delta << shift
+    };
+    ((delta as u128 * mul as u128) >> 32) as u64
+}
+
+/// Compute `tsc_to_system_mul` and `tsc_shift` for the TSC frequency `tsc_hz`.
+fn pvclock_time_scale(tsc_hz: u64) -> (u32, i8) {
+    let mut shift = 0;
+    let mut tps64 = tsc_hz;
+    let mut scaled64 = NANOS_PER_SEC;
+    while tps64 > scaled64 * 2 || tps64 >> 32 != 0 {
+        tps64 >>= 1;
+        shift -= 1;
+    }
+    let mut tps32 = tps64 as u32;
+    while tps32 as u64 <= scaled64 || scaled64 >> 32 != 0 {
+        if scaled64 >> 32 != 0 || tps32 & 0x8000_0000 != 0 {
+            scaled64 >>= 1;
+        } else {
+            tps32 <<= 1;
+        }
+        shift += 1;
+    }
+    (((scaled64 << 32) / tps32 as u64) as u32, shift)
+}
+
+/// Per-vCPU state of the KVM paravirtual interface: kvmclock, steal time and
+/// PV EOI. (Linux `Documentation/virt/kvm/x86/msr.rst`)
+pub struct KvmPv {
+    config: KvmPvConfig,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    system_time_msr: u64,
+    wall_clock_msr: u64,
+    steal_time_msr: u64,
+    pv_eoi_msr: u64,
+    /// Version of `pvclock_vcpu_time_info`, odd while it is being updated.
+    system_time_version: u32,
+    /// Total steal time in nanoseconds.
+    steal_ns: u64,
+    /// Version of `kvm_steal_time`, odd while it is being updated.
+    steal_version: u32,
+    /// Whether the PV EOI flag has been set for the last injected interrupt.
+    pv_eoi_pending: bool,
+}
+
+impl KvmPv {
+    /// Signature in CPUID leaf 0x4000_0000.
+    pub const SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+
+    /// Create the interface for a vCPU, with the TSC frequency `tsc_hz`.
+    pub fn new(config: KvmPvConfig, tsc_hz: u64) -> Self {
+        let (tsc_to_system_mul, tsc_shift) = pvclock_time_scale(tsc_hz);
+        Self {
+            config,
+            tsc_to_system_mul,
+            tsc_shift,
+            system_time_msr: 0,
+            wall_clock_msr: 0,
+            steal_time_msr: 0,
+            pv_eoi_msr: 0,
+            system_time_version: 0,
+            steal_ns: 0,
+            steal_version: 0,
+            pv_eoi_pending: false,
+        }
+    }
+
+    /// CPUID leaves 0x4000_0000 and 0x4000_0001, or `None` for other leaves.
+    pub fn cpuid(leaf: u32) -> Option<CpuIdResult> {
+        let signature =
+            |i: usize| u32::from_le_bytes(Self::SIGNATURE[i * 4..i * 4 + 4].try_into().unwrap());
+        match leaf {
+            0x4000_0000 => Some(CpuIdResult {
+                eax: 0x4000_0001,
+                ebx: signature(0),
+                ecx: signature(1),
+                edx: signature(2),
+            }),
+            0x4000_0001 => Some(CpuIdResult {
+                eax: KVM_FEATURE_CLOCKSOURCE2
+                    | KVM_FEATURE_STEAL_TIME
+                    | KVM_FEATURE_PV_EOI
+                    | KVM_FEATURE_PV_SEND_IPI
+                    | KVM_FEATURE_CLOCKSOURCE_STABLE_BIT,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether `msr` belongs to the interface.
+    pub fn is_kvm_msr(msr: u32) -> bool {
+        matches!(
+            msr,
+            MSR_KVM_WALL_CLOCK_NEW
+                | MSR_KVM_SYSTEM_TIME_NEW
+                | MSR_KVM_STEAL_TIME
+                | MSR_KVM_PV_EOI_EN
+        )
+    }
+
+    /// Read an MSR of the interface.
+    pub fn read_msr(&self, msr: u32) -> AxResult<u64> {
+        match msr {
+            MSR_KVM_WALL_CLOCK_NEW => Ok(self.wall_clock_msr),
+            MSR_KVM_SYSTEM_TIME_NEW => Ok(self.system_time_msr),
+            MSR_KVM_STEAL_TIME => Ok(self.steal_time_msr),
+            MSR_KVM_PV_EOI_EN => Ok(self.pv_eoi_msr),
+            _ => ax_err!(InvalidInput, "not a KVM MSR"),
+        }
+    }
+
+    /// Write an MSR of the interface at TSC value `now`, and update the shared
+    /// structures in guest memory.
+    ///
+    /// Return `InvalidInput` for invalid values, which raise #GP in the guest.
+    pub fn write_msr(&mut self, msr: u32, val: u64, mem: &dyn GuestMemory, now: u64) -> AxResult {
+        match msr {
+            MSR_KVM_WALL_CLOCK_NEW => {
+                self.wall_clock_msr = val;
+                self.write_wall_clock(mem)
+            }
+            MSR_KVM_SYSTEM_TIME_NEW => {
+                self.system_time_msr = val;
+                self.system_time_version = 0;
+                self.update_system_time(mem, now)
+            }
+            MSR_KVM_STEAL_TIME => {
+                // Bits 5:1 are reserved, the structure is 64-byte aligned.
+                if val & 0x3e != 0 {
+                    return ax_err!(InvalidInput, "reserved bits set in MSR_KVM_STEAL_TIME");
+                }
+                self.steal_time_msr = val;
+                self.update_steal_time(mem, false)
+            }
+            MSR_KVM_PV_EOI_EN => {
+                if val & 0x6 != 0 {
+                    return ax_err!(InvalidInput, "unaligned address in MSR_KVM_PV_EOI_EN");
+                }
+                self.pv_eoi_msr = val;
+                self.pv_eoi_pending = false;
+                Ok(())
+            }
+            _ => ax_err!(InvalidInput, "not a KVM MSR"),
+        }
+    }
+
+    /// The guest system time in nanoseconds at TSC value `now`.
+    pub fn system_time(&self, now: u64) -> u64 {
+        pvclock_scale(
+            now.wrapping_sub(self.config.base_tsc),
+            self.tsc_to_system_mul,
+            self.tsc_shift,
+        )
+    }
+
+    /// Write `pvclock_wall_clock` at the address in `MSR_KVM_WALL_CLOCK_NEW`.
+    fn write_wall_clock(&mut self, mem: &dyn GuestMemory) -> AxResult {
+        let gpa = GuestPhysAddr::from(self.wall_clock_msr as usize);
+        let boot = self.config.boot_wall_clock_ns;
+        let version = memory::read_u32(mem, gpa)?.wrapping_add(1) | 1;
+        memory::write_u32(mem, gpa, version)?;
+        memory::write_u32(mem, gpa + 4, (boot / NANOS_PER_SEC) as u32)?;
+        memory::write_u32(mem, gpa + 8, (boot % NANOS_PER_SEC) as u32)?;
+        memory::write_u32(mem, gpa, version.wrapping_add(1))
+    }
+
+    /// Update `pvclock_vcpu_time_info` with the TSC value `now`, if it is enabled.
+    ///
+    /// It only needs to be updated when it is enabled, as the TSC is assumed to be
+    /// invariant and synchronized across processors.
+    pub fn update_system_time(&mut self, mem: &dyn GuestMemory, now: u64) -> AxResult {
+        if self.system_time_msr & KVM_MSR_ENABLED == 0 {
+            return Ok(());
+        }
+        let gpa = GuestPhysAddr::from((self.system_time_msr & !KVM_MSR_ENABLED) as usize);
+        let mut info = [0; 32];
+        info[8..16].copy_from_slice(&now.to_le_bytes());
+        info[16..24].copy_from_slice(&self.system_time(now).to_le_bytes());
+        info[24..28].copy_from_slice(&self.tsc_to_system_mul.to_le_bytes());
+        info[28] = self.tsc_shift as u8;
+        info[29] = PVCLOCK_TSC_STABLE_BIT;
+
+        // The version is odd while the structure is being updated.
+        self.system_time_version = self.system_time_version.wrapping_add(1);
+        memory::write_u32(mem, gpa, self.system_time_version)?;
+        mem.write_phys(gpa + 4, &info[4..])?;
+        self.system_time_version = self.system_time_version.wrapping_add(1);
+        memory::write_u32(mem, gpa, self.system_time_version)
+    }
+
+    /// Account `ns` nanoseconds of steal time, during which the vCPU was runnable
+    /// but not running.
+    pub fn add_steal_time(&mut self, ns: u64) {
+        self.steal_ns = self.steal_ns.wrapping_add(ns);
+    }
+
+    /// Update `kvm_steal_time`, if it is enabled, with the total steal time and
+    /// whether the vCPU is preempted.
+    pub fn update_steal_time(&mut self, mem: &dyn GuestMemory, preempted: bool) -> AxResult {
+        if self.steal_time_msr & KVM_MSR_ENABLED == 0 {
+            return Ok(());
+        }
+        let gpa = GuestPhysAddr::from((self.steal_time_msr & !0x3f) as usize);
+        self.steal_version = self.steal_version.wrapping_add(1);
+        memory::write_u32(mem, gpa + 8, self.steal_version)?;
+        mem.write_phys(gpa, &self.steal_ns.to_le_bytes())?;
+        mem.write_phys(gpa + 16, &[if preempted { KVM_VCPU_PREEMPTED } else { 0 }])?;
+        self.steal_version = self.steal_version.wrapping_add(1);
+        memory::write_u32(mem, gpa + 8, self.steal_version)
+    }
+
+    /// Update the PV EOI flag when injecting an interrupt.
+    ///
+    /// The flag is set only if `no_eoi_needed`, so that the guest can clear it
+    /// instead of writing the EOI. For other interrupts, e.g. level-triggered ones,
+    /// it is cleared, and the guest writes the EOI as usual.
+    ///
+    /// Return whether the guest has acknowledged the interrupt injected last
+    /// time by clearing the flag.
+    pub fn inject_pv_eoi(&mut self, mem: &dyn GuestMemory, no_eoi_needed: bool) -> AxResult<bool> {
+        if self.pv_eoi_msr & KVM_MSR_ENABLED == 0 {
+            return Ok(false);
+        }
+        let gpa = GuestPhysAddr::from((self.pv_eoi_msr & !KVM_MSR_ENABLED) as usize);
+        let acked = self.pv_eoi_pending && memory::read_u32(mem, gpa)? & 1 == 0;
+        memory::write_u32(mem, gpa, no_eoi_needed as u32)?;
+        self.pv_eoi_pending = no_eoi_needed;
+        Ok(acked)
+    }
+}
+
+/// x2APIC IDs of the destinations of a `KVM_HC_SEND_IPI` hypercall, given the
+/// 128-bit destination bitmap and the x2APIC ID of its bit 0.
+pub fn send_ipi_destinations(
+    bitmap_low: u64,
+    bitmap_high: u64,
+    min: u32,
+) -> impl Iterator<Item = u32> {
+    let bitmap = (bitmap_high as u128) << 64 | bitmap_low as u128;
+    (0..128u32)
+        .filter(move |i| bitmap & (1 << i) != 0)
+        .map(move |i| min.wrapping_add(i))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::memory::TestGuestMemory;
+    use alloc::vec::Vec;
+
+    const TSC_HZ: u64 = 2_500_000_000;
+    const CONFIG: KvmPvConfig = KvmPvConfig {
+        base_tsc: 1_000_000,
+        boot_wall_clock_ns: 1_700_000_000_123_456_789,
+    };
+
+    #[test]
+    fn test_time_scale() {
+        for hz in [1_000_000_000, TSC_HZ, 3_333_333_333, 100_000_000] {
+            let (mul, shift) = pvclock_time_scale(hz);
+            // One second of TSC cycles is one second of system time.
+            let ns = pvclock_scale(hz, mul, shift);
+            assert!(ns.abs_diff(NANOS_PER_SEC) <= 1, "{hz} Hz: {ns} ns");
+        }
+    }
+
+    #[test]
+    fn test_cpuid() {
+        let info = KvmPv::cpuid(0x4000_0000).unwrap();
+        let signature: Vec<u8> = [info.ebx, info.ecx, info.edx]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .collect();
+        assert_eq!(&signature, KvmPv::SIGNATURE);
+        assert_eq!(KvmPv::cpuid(0x4000_0001).unwrap().eax, 0x0100_0868);
+        assert!(KvmPv::cpuid(0x4000_0002).is_none());
+    }
+
+    #[test]
+    fn test_kvmclock() {
+        let mem = TestGuestMemory::new(0x1000);
+        let mut pv = KvmPv::new(CONFIG, TSC_HZ);
+
+        pv.write_msr(MSR_KVM_WALL_CLOCK_NEW, 0x100, &mem, 0)
+            .unwrap();
+        assert_eq!(mem.u32_at(0x100), 2);
+        assert_eq!(mem.u32_at(0x104), 1_700_000_000);
+        assert_eq!(mem.u32_at(0x108), 123_456_789);
+
+        // Disabled, nothing is written.
+        pv.write_msr(MSR_KVM_SYSTEM_TIME_NEW, 0x200, &mem, 0)
+            .unwrap();
+        assert_eq!(mem.u32_at(0x200), 0);
+
+        let now = CONFIG.base_tsc + 2 * TSC_HZ;
+        pv.write_msr(MSR_KVM_SYSTEM_TIME_NEW, 0x201, &mem, now)
+            .unwrap();
+        assert_eq!(pv.read_msr(MSR_KVM_SYSTEM_TIME_NEW).unwrap(), 0x201);
+        assert_eq!(mem.u32_at(0x200), 2);
+        assert_eq!(mem.u64_at(0x208), now);
+        assert!(mem.u64_at(0x210).abs_diff(2 * NANOS_PER_SEC) <= 2);
+        let mul = mem.u32_at(0x218);
+        let shift = (mem.u32_at(0x21c) & 0xff) as u8 as i8;
+        let flags = (mem.u32_at(0x21c) >> 8) & 0xff;
+        assert_eq!(flags, PVCLOCK_TSC_STABLE_BIT as u32);
+        // The guest computes the same time as the host, up to rounding.
+        let later = now + TSC_HZ / 2;
+        let guest_time = mem.u64_at(0x210) + pvclock_scale(later - now, mul, shift);
+        assert!(guest_time.abs_diff(pv.system_time(later)) <= 1);
+
+        pv.update_system_time(&mem, later).unwrap();
+        assert_eq!(mem.u32_at(0x200), 4);
+    }
+
+    #[test]
+    fn test_steal_time() {
+        let mem = TestGuestMemory::new(0x1000);
+        let mut pv = KvmPv::new(CONFIG, TSC_HZ);
+        assert!(pv.write_msr(MSR_KVM_STEAL_TIME, 0x302, &mem, 0).is_err());
+
+        pv.add_steal_time(1000);
+        pv.write_msr(MSR_KVM_STEAL_TIME, 0x301, &mem, 0).unwrap();
+        assert_eq!(mem.u64_at(0x300), 1000);
+        assert_eq!(mem.u32_at(0x308), 2);
+
+        pv.add_steal_time(500);
+        pv.update_steal_time(&mem, true).unwrap();
+        assert_eq!(mem.u64_at(0x300), 1500);
+        assert_eq!(mem.u32_at(0x308), 4);
+        assert_eq!(mem.u32_at(0x310) & 0xff, KVM_VCPU_PREEMPTED as u32);
+
+        pv.update_steal_time(&mem, false).unwrap();
+        assert_eq!(mem.u32_at(0x310) & 0xff, 0);
+    }
+
+    #[test]
+    fn test_pv_eoi() {
+        let mem = TestGuestMemory::new(0x1000);
+        let mut pv = KvmPv::new(CONFIG, TSC_HZ);
+        assert!(pv.write_msr(MSR_KVM_PV_EOI_EN, 0x403, &mem, 0).is_err());
+        assert!(!pv.inject_pv_eoi(&mem, true).unwrap());
+        assert_eq!(mem.u32_at(0x400), 0);
+
+        pv.write_msr(MSR_KVM_PV_EOI_EN, 0x401, &mem, 0).unwrap();
+        assert!(!pv.inject_pv_eoi(&mem, true).unwrap());
+        assert_eq!(mem.u32_at(0x400), 1);
+        // The guest has not acknowledged it yet.
+        assert!(!pv.inject_pv_eoi(&mem, true).unwrap());
+        // The guest clears the flag instead of writing EOI.
+        memory::write_u32(&mem, 0x400.into(), 0).unwrap();
+        assert!(pv.inject_pv_eoi(&mem, true).unwrap());
+    }
+
+    #[test]
+    fn test_pv_eoi_level_triggered() {
+        let mem = TestGuestMemory::new(0x1000);
+        let mut pv = KvmPv::new(CONFIG, TSC_HZ);
+        pv.write_msr(MSR_KVM_PV_EOI_EN, 0x401, &mem, 0).unwrap();
+        // The flag is not set for a level-triggered interrupt, which needs an EOI.
+        assert!(!pv.inject_pv_eoi(&mem, false).unwrap());
+        assert_eq!(mem.u32_at(0x400), 0);
+        // Nor is a clear flag taken as an acknowledgement.
+        assert!(!pv.inject_pv_eoi(&mem, false).unwrap());
+
+        // A flag left set for an edge-triggered interrupt is cleared.
+        pv.inject_pv_eoi(&mem, true).unwrap();
+        assert_eq!(mem.u32_at(0x400), 1);
+        assert!(!pv.inject_pv_eoi(&mem, false).unwrap());
+        assert_eq!(mem.u32_at(0x400), 0);
+    }
+
+    #[test]
+    fn test_send_ipi_destinations() {
+        let dests: Vec<u32> = send_ipi_destinations(0b1011, 1 << 63, 8).collect();
+        assert_eq!(dests, [8, 9, 11, 135]);
+    }
+}
@@ -0,0 +1,127 @@
+use axaddrspace::GuestPhysAddr;
+use axerrno::AxResult;
+
+/// Access to guest physical memory, provided by the VMM.
+///
+/// Used by paravirtual interfaces that share data structures with the guest.
+pub trait GuestMemory {
+    /// Read `buf.len()` bytes from guest physical address `gpa`.
+    fn read_phys(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult;
+    /// Write `buf` to guest physical address `gpa`.
+    fn write_phys(&self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult;
+    /// Atomically replace the little-endian value of `size` (4 or 8) bytes at the
+    /// naturally aligned guest physical address `gpa` with `new`, if it is still
+    /// `current`. Return whether the value is replaced.
+    ///
+    /// Used to update guest paging-structure entries the guest may change
+    /// concurrently.
+    fn compare_exchange_phys(
+        &self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        current: u64,
+        new: u64,
+    ) -> AxResult<bool>;
+}
+
+/// Read a little-endian `u32` from guest physical address `gpa`.
+pub fn read_u32(mem: &dyn GuestMemory, gpa: GuestPhysAddr) -> AxResult<u32> {
+    let mut buf = [0; 4];
+    mem.read_phys(gpa, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Write a little-endian `u32` to guest physical address `gpa`.
+pub fn write_u32(mem: &dyn GuestMemory, gpa: GuestPhysAddr, val: u32) -> AxResult {
+    mem.write_phys(gpa, &val.to_le_bytes())
+}
+
+/// Host frames allocated from the heap, with physical addresses equal to virtual
+/// ones, for tests.
+#[cfg(test)]
+pub struct TestMmHal;
+
+#[cfg(test)]
+impl axaddrspace::AxMmHal for TestMmHal {
+    fn alloc_frame() -> Option<axaddrspace::HostPhysAddr> {
+        let layout = core::alloc::Layout::from_size_align(0x1000, 0x1000).unwrap();
+        let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        Some(axaddrspace::HostPhysAddr::from(ptr as usize))
+    }
+
+    fn dealloc_frame(paddr: axaddrspace::HostPhysAddr) {
+        let layout = core::alloc::Layout::from_size_align(0x1000, 0x1000).unwrap();
+        unsafe { alloc::alloc::dealloc(paddr.as_usize() as *mut u8, layout) }
+    }
+
+    fn phys_to_virt(paddr: axaddrspace::HostPhysAddr) -> axaddrspace::HostVirtAddr {
+        axaddrspace::HostVirtAddr::from(paddr.as_usize())
+    }
+
+    fn virt_to_phys(vaddr: axaddrspace::HostVirtAddr) -> axaddrspace::HostPhysAddr {
+        axaddrspace::HostPhysAddr::from(vaddr.as_usize())
+    }
+}
+
+/// Guest RAM in a host buffer starting at guest physical address 0, for tests.
+#[cfg(test)]
+pub struct TestGuestMemory(core::cell::RefCell<alloc::vec::Vec<u8>>);
+
+#[cfg(test)]
+impl TestGuestMemory {
+    /// Create zeroed guest RAM of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self(core::cell::RefCell::new(alloc::vec![0; size]))
+    }
+
+    fn range(&self, gpa: GuestPhysAddr, len: usize) -> AxResult<core::ops::Range<usize>> {
+        let start = gpa.as_usize();
+        if start + len > self.0.borrow().len() {
+            return axerrno::ax_err!(InvalidInput, "guest physical address out of range");
+        }
+        Ok(start..start + len)
+    }
+
+    /// Read a little-endian `u64` at `gpa`.
+    pub fn u64_at(&self, gpa: usize) -> u64 {
+        let mut buf = [0; 8];
+        self.read_phys(gpa.into(), &mut buf).unwrap();
+        u64::from_le_bytes(buf)
+    }
+
+    /// Read a little-endian `u32` at `gpa`.
+    pub fn u32_at(&self, gpa: usize) -> u32 {
+        read_u32(self, gpa.into()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl GuestMemory for TestGuestMemory {
+    fn read_phys(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        let range = self.range(gpa, buf.len())?;
+        buf.copy_from_slice(&self.0.borrow()[range]);
+        Ok(())
+    }
+
+    fn write_phys(&self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        let range = self.range(gpa, buf.len())?;
+        self.0.borrow_mut()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn compare_exchange_phys(
+        &self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        current: u64,
+        new: u64,
+    ) -> AxResult<bool> {
+        let range = self.range(gpa, size)?;
+        let mut mem = self.0.borrow_mut();
+        if mem[range.clone()] != current.to_le_bytes()[..size] {
+            return Ok(false);
+        }
+        mem[range].copy_from_slice(&new.to_le_bytes()[..size]);
+        Ok(true)
+    }
+}
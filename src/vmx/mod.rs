@@ -1,17 +1,53 @@
+mod cr;
+mod debug;
 mod definitions;
+mod hypercall;
+mod hyperv;
 mod instructions;
+mod ipi;
+mod kvm;
+mod memory;
+mod paging;
 mod percpu;
+mod pml;
+mod realmode;
+mod segment;
+mod shadow;
+mod spp;
 mod structs;
+mod timer;
 mod vcpu;
+mod ve;
 mod vmcs;
+mod vmfunc;
+mod vpid;
 
 use self::structs::VmxBasic;
 use axerrno::ax_err_type;
 
-pub use self::definitions::VmxExitReason;
+pub use self::cr::GuestPagingMode;
+pub use self::debug::{DebugEvent, HwBreakpoint, HwBreakpointKind, VcpuDebugControl};
+pub use self::definitions::{VmxActivityState, VmxExitReason, VmxInterruptionType};
+pub use self::hypercall::HypercallAbi;
+pub use self::hyperv::HyperVConfig;
+pub use self::ipi::{Ipi, IpiDelivery, IpiDeliveryMode, IpiRouter, IpiShorthand};
+pub use self::kvm::KvmPvConfig;
+pub use self::memory::GuestMemory;
+pub use self::paging::PageFault;
 pub use self::percpu::VmxPerCpuState as VmxArchPerCpuState;
-pub use self::vcpu::VmxVcpu as VmxArchVCpu;
-pub use self::vmcs::{VmxExitInfo, VmxInterruptInfo, VmxIoExitInfo};
+pub use self::pml::{DirtyBitmap, DirtyLog};
+pub use self::segment::{SegmentAccess, SegmentFault, SegmentRegister};
+pub use self::shadow::MmuMode;
+pub use self::spp::{
+    EPT_SPP, EPT_USER_EXECUTE, SppEvent, spp_leaf_entry, spp_table_entry, spp_writable_subpages,
+};
+pub use self::structs::{EptMemoryType, EptpConfig};
+pub use self::timer::PreemptionTimerConfig;
+pub use self::vcpu::{VmxHaltReason, VmxVcpu as VmxArchVCpu};
+pub use self::ve::{EPT_SUPPRESS_VE, VeInfo, suppress_ve_non_present};
+pub use self::vmcs::{
+    EptViolation, ExceptionExit, ExceptionMerge, VmxExitInfo, VmxInterruptInfo, VmxIoExitInfo,
+};
 
 /// Return if current platform support virtualization extension.
 pub fn has_hardware_support() -> bool {
@@ -0,0 +1,608 @@
+use alloc::vec::Vec;
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr};
+use axerrno::AxResult;
+use bit_field::BitField;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+use super::memory::GuestMemory;
+use crate::GuestPageWalkInfo;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_HUGE_PAGE: u64 = 1 << 7;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+/// Physical address bits 51:12 of 64-bit paging-structure entries.
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The maximum number of paging-structure levels.
+const MAX_LEVELS: usize = 5;
+
+/// A page fault to be injected into the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    /// The linear address loaded into CR2.
+    pub addr: GuestVirtAddr,
+    /// The error code pushed on the stack.
+    pub error_code: PageFaultErrorCode,
+}
+
+/// The translation of a guest linear address by the guest page tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestTranslation {
+    /// The guest physical address.
+    pub paddr: GuestPhysAddr,
+    /// The size of the page mapping the address.
+    pub page_size: usize,
+    /// Whether the page is writable, by all paging-structure entries.
+    pub writable: bool,
+    /// Whether the page is accessible in user mode, by all paging-structure entries.
+    pub user: bool,
+    /// Whether instructions can be fetched from the page.
+    pub executable: bool,
+    /// Whether the dirty flag of the page is set.
+    pub dirty: bool,
+    tables: [GuestPhysAddr; MAX_LEVELS],
+    table_count: usize,
+}
+
+impl GuestTranslation {
+    /// Addresses without paging are translated to the same physical addresses.
+    fn identity(vaddr: GuestVirtAddr) -> Self {
+        Self {
+            paddr: GuestPhysAddr::from(vaddr.as_usize()),
+            page_size: 1 << 12,
+            writable: true,
+            user: true,
+            executable: true,
+            dirty: true,
+            tables: [GuestPhysAddr::from(0); MAX_LEVELS],
+            table_count: 0,
+        }
+    }
+
+    /// The guest physical addresses of the paging structures used, from the
+    /// top level down.
+    pub fn tables(&self) -> &[GuestPhysAddr] {
+        &self.tables[..self.table_count]
+    }
+}
+
+/// Translate the guest linear address `vaddr` by the guest page tables, for the
+/// access described by `info`. (SDM Vol. 3A, Section 4.3 - 4.6)
+///
+/// The accessed flags of the paging-structure entries used, and the dirty flag of
+/// the page for writes, are set as the processor does. Return the page fault to be
+/// injected if the translation fails, or an error if the guest page tables can
+/// not be accessed.
+pub fn translate(
+    mem: &dyn GuestMemory,
+    info: &GuestPageWalkInfo,
+    vaddr: GuestVirtAddr,
+) -> AxResult<Result<GuestTranslation, PageFault>> {
+    if info.level == 0 {
+        return Ok(Ok(GuestTranslation::identity(vaddr)));
+    }
+    // Walk again if the guest changes a paging-structure entry before its
+    // accessed or dirty flags are set.
+    loop {
+        if let Some(result) = walk(mem, info, vaddr)? {
+            return Ok(result);
+        }
+    }
+}
+
+/// Walk the guest page tables once for [`translate`], or return `None` if a
+/// paging-structure entry is changed during the walk.
+fn walk(
+    mem: &dyn GuestMemory,
+    info: &GuestPageWalkInfo,
+    vaddr: GuestVirtAddr,
+) -> AxResult<Option<Result<GuestTranslation, PageFault>>> {
+    let va = vaddr.as_usize() as u64;
+    // 32-bit paging uses 4-byte entries, PAE and 4-level paging use 8-byte entries.
+    let entry_size = if info.width == 10 { 4 } else { 8 };
+    let pae = info.level == 3;
+    let mut table = match info.level {
+        2 => info.top_entry as u64 & 0xffff_f000,
+        3 => info.top_entry as u64 & 0xffff_ffe0,
+        _ => info.top_entry as u64 & PTE_ADDR_MASK,
+    };
+
+    let fault = |present: bool, reserved: bool, protection_key: bool| {
+        let mut error_code = PageFaultErrorCode::empty();
+        error_code.set(PageFaultErrorCode::PROTECTION_VIOLATION, present);
+        error_code.set(PageFaultErrorCode::CAUSED_BY_WRITE, info.is_write_access);
+        error_code.set(PageFaultErrorCode::USER_MODE, info.is_user_mode_access);
+        error_code.set(PageFaultErrorCode::MALFORMED_TABLE, reserved);
+        error_code.set(PageFaultErrorCode::PROTECTION_KEY, protection_key);
+        error_code.set(
+            PageFaultErrorCode::INSTRUCTION_FETCH,
+            info.is_inst_fetch && ((info.nxe && entry_size == 8) || info.is_smep_on),
+        );
+        Ok(Some(Err(PageFault {
+            addr: vaddr,
+            error_code,
+        })))
+    };
+
+    let mut translation = GuestTranslation::identity(vaddr);
+    let mut entries = [(GuestPhysAddr::from(0), 0u64); MAX_LEVELS];
+    let mut level = info.level;
+    loop {
+        let shift = 12 + info.width as usize * (level - 1);
+        let index = (va >> shift) & ((1 << info.width) - 1);
+        let entry_addr = GuestPhysAddr::from((table + index * entry_size) as usize);
+        let entry = match info.pdptes {
+            // The PDPTEs of PAE paging are loaded with CR3, not on each walk.
+            Some(pdptes) if pae && level == 3 => pdptes[index as usize % 4],
+            _ => read_entry(mem, entry_addr, entry_size)?,
+        };
+        translation.tables[translation.table_count] = GuestPhysAddr::from(table as usize);
+        entries[translation.table_count] = (entry_addr, entry);
+        translation.table_count += 1;
+
+        if entry & PTE_PRESENT == 0 {
+            return fault(false, false, false);
+        }
+        // PDPTEs of PAE paging do not control access rights.
+        let is_pdpte = pae && level == 3;
+        if entry_size == 8 && !info.nxe && entry & PTE_NO_EXECUTE != 0 {
+            return fault(true, true, false);
+        }
+        if !is_pdpte {
+            translation.writable &= entry & PTE_WRITABLE != 0;
+            translation.user &= entry & PTE_USER != 0;
+            translation.executable &= entry & PTE_NO_EXECUTE == 0;
+        }
+
+        // Bit 7 of PTEs is the PAT bit, and it is ignored in PDEs of 32-bit paging
+        // without CR4.PSE.
+        let huge_page = level > 1 && entry & PTE_HUGE_PAGE != 0;
+        let huge_page_reserved = match info.level {
+            2 => false,
+            3 => level == 3,
+            _ => level >= 4,
+        };
+        if huge_page && huge_page_reserved {
+            return fault(true, true, false);
+        }
+        if level == 1 || (huge_page && (info.level != 2 || info.pse)) {
+            translation.page_size = 1 << shift;
+            translation.dirty = entry & PTE_DIRTY != 0;
+            let page_base = if entry_size == 4 && level == 2 {
+                // 4-MByte page of 32-bit paging, physical address bits 39:32 are in
+                // bits 20:13 of the PDE.
+                (entry & 0xffc0_0000) | (entry.get_bits(13..21) << 32)
+            } else {
+                entry & PTE_ADDR_MASK & !(translation.page_size as u64 - 1)
+            };
+            let offset = va & (translation.page_size as u64 - 1);
+            translation.paddr = GuestPhysAddr::from((page_base | offset) as usize);
+            break;
+        }
+        table = if entry_size == 4 {
+            entry & 0xffff_f000
+        } else {
+            entry & PTE_ADDR_MASK
+        };
+        level -= 1;
+    }
+
+    // Check access rights. (SDM Vol. 3A, Section 4.6)
+    let allowed = if info.is_user_mode_access {
+        translation.user
+            && (!info.is_write_access || translation.writable)
+            && (!info.is_inst_fetch || translation.executable)
+    } else if info.is_inst_fetch {
+        translation.executable && !(translation.user && info.is_smep_on)
+    } else {
+        !(translation.user && info.is_smap_on)
+            && (!info.is_write_access || translation.writable || !info.wp)
+    };
+    if !allowed {
+        return fault(true, false, false);
+    }
+
+    // Check protection keys of user-mode pages for data accesses in IA-32e mode.
+    // (SDM Vol. 3A, Section 4.6.2)
+    let count = translation.table_count;
+    if info.pke && info.level >= 4 && translation.user && !info.is_inst_fetch {
+        let key = entries[count - 1].1.get_bits(59..63) as usize;
+        let access_disabled = info.pkru.get_bit(2 * key);
+        let write_disabled = info.pkru.get_bit(2 * key + 1)
+            && info.is_write_access
+            && (info.is_user_mode_access || info.wp);
+        if access_disabled || write_disabled {
+            return fault(true, false, true);
+        }
+    }
+
+    // Set the accessed flags, and the dirty flag of the page for writes.
+    for (i, &(entry_addr, entry)) in entries[..count].iter().enumerate() {
+        let mut new = entry | PTE_ACCESSED;
+        if i == count - 1 && info.is_write_access {
+            new |= PTE_DIRTY;
+            translation.dirty = true;
+        }
+        // PDPTEs of PAE paging have no accessed flags.
+        if new != entry
+            && !(pae && i == 0)
+            && !mem.compare_exchange_phys(entry_addr, entry_size as usize, entry, new)?
+        {
+            return Ok(None);
+        }
+    }
+    Ok(Some(Ok(translation)))
+}
+
+/// Translate the `len` bytes at the guest linear address `vaddr`, which may
+/// cross pages, for the access described by `info`.
+///
+/// Return the guest physical address and the length of the part in each page,
+/// in order, or the page fault of the first page which can not be accessed.
+pub fn translate_range(
+    mem: &dyn GuestMemory,
+    info: &GuestPageWalkInfo,
+    vaddr: GuestVirtAddr,
+    len: usize,
+) -> AxResult<Result<Vec<(GuestPhysAddr, usize)>, PageFault>> {
+    const PAGE_SIZE: usize = 1 << 12;
+
+    let mut ranges = Vec::new();
+    let mut addr = vaddr.as_usize();
+    let mut remaining = len;
+    while remaining > 0 {
+        let part = remaining.min(PAGE_SIZE - addr % PAGE_SIZE);
+        match translate(mem, info, GuestVirtAddr::from(addr))? {
+            Ok(translation) => ranges.push((translation.paddr, part)),
+            Err(fault) => return Ok(Err(fault)),
+        }
+        // The linear address wraps around at the top of the address space.
+        addr = addr.wrapping_add(part);
+        remaining -= part;
+    }
+    Ok(Ok(ranges))
+}
+
+fn read_entry(mem: &dyn GuestMemory, addr: GuestPhysAddr, size: u64) -> AxResult<u64> {
+    let mut buf = [0; 8];
+    mem.read_phys(addr, &mut buf[..size as usize])?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::vmx::memory::TestGuestMemory;
+
+    pub const P: u64 = PTE_PRESENT;
+    pub const W: u64 = PTE_WRITABLE;
+    pub const U: u64 = PTE_USER;
+    pub const D: u64 = PTE_DIRTY;
+    pub const PS: u64 = PTE_HUGE_PAGE;
+    pub const NX: u64 = PTE_NO_EXECUTE;
+
+    /// Paging information of 4-level paging with CR3 = `cr3`, for a supervisor read.
+    pub fn level4_info(cr3: usize) -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            top_entry: cr3,
+            level: 4,
+            width: 9,
+            is_user_mode_access: false,
+            is_write_access: false,
+            is_inst_fetch: false,
+            pse: true,
+            wp: true,
+            nxe: true,
+            is_smap_on: false,
+            is_smep_on: false,
+            pdptes: None,
+            pke: false,
+            pkru: 0,
+        }
+    }
+
+    /// Write an 8-byte entry at `table[index]`.
+    pub fn set_entry(mem: &TestGuestMemory, table: usize, index: usize, entry: u64) {
+        mem.write_phys((table + index * 8).into(), &entry.to_le_bytes())
+            .unwrap();
+    }
+
+    /// Guest tables of 4-level paging at 0x1000-0x4000, mapping the 4K page at
+    /// 0x40_0000 to 0x8000 and the 2M page at 0x60_0000 to 0x20_0000.
+    pub fn level4_tables() -> TestGuestMemory {
+        let mem = TestGuestMemory::new(0x40_0000);
+        set_entry(&mem, 0x1000, 0, 0x2000 | P | W | U);
+        set_entry(&mem, 0x2000, 0, 0x3000 | P | W | U);
+        set_entry(&mem, 0x3000, 2, 0x4000 | P | W | U);
+        set_entry(&mem, 0x3000, 3, 0x20_0000 | P | PS);
+        set_entry(&mem, 0x4000, 0, 0x8000 | P | W | U);
+        mem
+    }
+
+    #[test]
+    fn test_level4() {
+        let mem = level4_tables();
+        let info = level4_info(0x1000);
+        let tr = translate(&mem, &info, 0x40_0123.into()).unwrap().unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0x8123));
+        assert_eq!(tr.page_size, 0x1000);
+        assert!(tr.writable && tr.user && tr.executable && !tr.dirty);
+        assert_eq!(
+            tr.tables(),
+            [0x1000.into(), 0x2000.into(), 0x3000.into(), 0x4000.into()]
+        );
+        // Accessed flags are set, the dirty flag is not.
+        assert_eq!(mem.u64_at(0x1000), 0x2000 | P | W | U | PTE_ACCESSED);
+        assert_eq!(mem.u64_at(0x4000), 0x8000 | P | W | U | PTE_ACCESSED);
+
+        // 2M page, and the dirty flag is set by writes.
+        let write = GuestPageWalkInfo {
+            is_write_access: true,
+            wp: false,
+            ..level4_info(0x1000)
+        };
+        let tr = translate(&mem, &write, 0x6f_f000.into()).unwrap().unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0x2f_f000));
+        assert_eq!(tr.page_size, 0x20_0000);
+        assert!(!tr.writable && !tr.user && tr.dirty);
+        assert_eq!(mem.u64_at(0x3018) & PTE_DIRTY, PTE_DIRTY);
+
+        // Write to a read-only page with CR0.WP = 1.
+        let tr = translate(&mem, &level4_info(0x1000), 0x60_0000.into())
+            .unwrap()
+            .unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0x20_0000));
+        let write = GuestPageWalkInfo {
+            is_write_access: true,
+            ..level4_info(0x1000)
+        };
+        assert_eq!(
+            translate(&mem, &write, 0x60_0000.into()).unwrap(),
+            Err(PageFault {
+                addr: 0x60_0000.into(),
+                error_code: PageFaultErrorCode::PROTECTION_VIOLATION
+                    | PageFaultErrorCode::CAUSED_BY_WRITE,
+            })
+        );
+    }
+
+    #[test]
+    fn test_faults() {
+        let mem = level4_tables();
+        // Not present.
+        let user_fetch = GuestPageWalkInfo {
+            is_user_mode_access: true,
+            is_inst_fetch: true,
+            ..level4_info(0x1000)
+        };
+        assert_eq!(
+            translate(&mem, &user_fetch, 0x80_0000.into())
+                .unwrap()
+                .unwrap_err()
+                .error_code,
+            PageFaultErrorCode::USER_MODE | PageFaultErrorCode::INSTRUCTION_FETCH
+        );
+        // User access to a supervisor page.
+        assert_eq!(
+            translate(&mem, &user_fetch, 0x60_0000.into())
+                .unwrap()
+                .unwrap_err()
+                .error_code,
+            PageFaultErrorCode::PROTECTION_VIOLATION
+                | PageFaultErrorCode::USER_MODE
+                | PageFaultErrorCode::INSTRUCTION_FETCH
+        );
+        // Supervisor fetch from a user page with SMEP, and from a no-execute page.
+        let smep_fetch = GuestPageWalkInfo {
+            is_inst_fetch: true,
+            is_smep_on: true,
+            ..level4_info(0x1000)
+        };
+        assert!(
+            translate(&mem, &smep_fetch, 0x40_0000.into())
+                .unwrap()
+                .is_err()
+        );
+        set_entry(&mem, 0x3000, 3, 0x20_0000 | P | PS | NX);
+        let fetch = GuestPageWalkInfo {
+            is_inst_fetch: true,
+            ..level4_info(0x1000)
+        };
+        assert!(translate(&mem, &fetch, 0x60_0000.into()).unwrap().is_err());
+        // XD is reserved without EFER.NXE.
+        let no_nxe = GuestPageWalkInfo {
+            nxe: false,
+            ..level4_info(0x1000)
+        };
+        assert_eq!(
+            translate(&mem, &no_nxe, 0x60_0000.into())
+                .unwrap()
+                .unwrap_err()
+                .error_code,
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::MALFORMED_TABLE
+        );
+    }
+
+    #[test]
+    fn test_level5() {
+        // The PML5 table at 0x5000 maps the 4-level tables with bit 48 set.
+        let mem = level4_tables();
+        set_entry(&mem, 0x5000, 1, 0x1000 | P | W | U);
+        let info = GuestPageWalkInfo {
+            level: 5,
+            ..level4_info(0x5000)
+        };
+        let tr = translate(&mem, &info, 0x1_0000_0040_0123.into())
+            .unwrap()
+            .unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0x8123));
+        assert_eq!(
+            tr.tables(),
+            [
+                0x5000.into(),
+                0x1000.into(),
+                0x2000.into(),
+                0x3000.into(),
+                0x4000.into()
+            ]
+        );
+        assert_eq!(mem.u64_at(0x5008), 0x1000 | P | W | U | PTE_ACCESSED);
+        assert!(translate(&mem, &info, 0x40_0000.into()).unwrap().is_err());
+
+        // Large pages are reserved in PML5Es.
+        set_entry(&mem, 0x5000, 2, P | PS);
+        assert_eq!(
+            translate(&mem, &info, 0x2_0000_0000_0000.into()).unwrap(),
+            Err(PageFault {
+                addr: 0x2_0000_0000_0000.into(),
+                error_code: PageFaultErrorCode::PROTECTION_VIOLATION
+                    | PageFaultErrorCode::MALFORMED_TABLE,
+            })
+        );
+    }
+
+    #[test]
+    fn test_32bit_and_pae() {
+        let mem = TestGuestMemory::new(0x40_0000);
+        // 32-bit paging: a 4K page at 0x40_1000 and a 4M page at 0x80_0000.
+        let pde = |index: usize, entry: u32| {
+            mem.write_phys((0x1000 + index * 4).into(), &entry.to_le_bytes())
+                .unwrap()
+        };
+        pde(1, 0x2000 | P as u32 | W as u32);
+        pde(2, 0x40_0000 | (P | PS) as u32);
+        mem.write_phys((0x2000 + 4).into(), &(0x9000 | P as u32).to_le_bytes())
+            .unwrap();
+        let info = GuestPageWalkInfo {
+            level: 2,
+            width: 10,
+            nxe: false,
+            ..level4_info(0x1000)
+        };
+        let tr = translate(&mem, &info, 0x40_1abc.into()).unwrap().unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0x9abc));
+        assert_eq!(mem.u32_at(0x1004), 0x2000 | (P | W | PTE_ACCESSED) as u32);
+        let tr = translate(&mem, &info, 0x81_2345.into()).unwrap().unwrap();
+        assert_eq!((tr.paddr, tr.page_size), (0x41_2345.into(), 0x40_0000));
+
+        // PAE paging: the PDPT at 0x3020.
+        set_entry(&mem, 0x3020, 1, 0x4000 | P);
+        set_entry(&mem, 0x4000, 0, 0x5000 | P | W);
+        set_entry(&mem, 0x5000, 1, 0xa000 | P | W | NX);
+        let info = GuestPageWalkInfo {
+            level: 3,
+            ..level4_info(0x3020)
+        };
+        let tr = translate(&mem, &info, 0x4000_1008.into()).unwrap().unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0xa008));
+        assert!(tr.writable && !tr.executable);
+        // PDPTEs have no accessed flags.
+        assert_eq!(mem.u64_at(0x3028), 0x4000 | P);
+
+        // PDPTEs loaded by the processor are used instead of those in memory.
+        let info = GuestPageWalkInfo {
+            pdptes: Some([0x4000 | P, 0, 0, 0]),
+            ..info
+        };
+        let tr = translate(&mem, &info, 0x1008.into()).unwrap().unwrap();
+        assert_eq!(tr.paddr, GuestPhysAddr::from(0xa008));
+        assert!(translate(&mem, &info, 0x4000_1008.into()).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_protection_keys() {
+        let mem = level4_tables();
+        // Key 1 disables writes, key 2 disables all data accesses.
+        set_entry(&mem, 0x4000, 1, 0x9000 | P | W | U | (1 << 59));
+        set_entry(&mem, 0x4000, 2, 0xa000 | P | W | U | (2 << 59));
+        let access = |write: bool, user: bool, fetch: bool| GuestPageWalkInfo {
+            is_write_access: write,
+            is_user_mode_access: user,
+            is_inst_fetch: fetch,
+            pke: true,
+            pkru: 0b01_1000,
+            ..level4_info(0x1000)
+        };
+        let pk_fault = |addr: usize, error_code| {
+            Err(PageFault {
+                addr: addr.into(),
+                error_code: PageFaultErrorCode::PROTECTION_VIOLATION
+                    | PageFaultErrorCode::PROTECTION_KEY
+                    | error_code,
+            })
+        };
+        assert!(
+            translate(&mem, &access(false, true, false), 0x40_1000.into())
+                .unwrap()
+                .is_ok()
+        );
+        assert_eq!(
+            translate(&mem, &access(true, true, false), 0x40_1000.into()).unwrap(),
+            pk_fault(
+                0x40_1000,
+                PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE
+            )
+        );
+        assert_eq!(
+            translate(&mem, &access(false, false, false), 0x40_2000.into()).unwrap(),
+            pk_fault(0x40_2000, PageFaultErrorCode::empty())
+        );
+        // Instruction fetches are not checked, and key 0 allows all accesses.
+        assert!(
+            translate(&mem, &access(false, true, true), 0x40_2000.into())
+                .unwrap()
+                .is_ok()
+        );
+        assert!(
+            translate(&mem, &access(true, true, false), 0x40_0000.into())
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_translate_range() {
+        let mem = level4_tables();
+        set_entry(&mem, 0x4000, 1, 0x9000 | P | W | U);
+        let write = GuestPageWalkInfo {
+            is_write_access: true,
+            ..level4_info(0x1000)
+        };
+        assert_eq!(
+            translate_range(&mem, &write, 0x40_0ffc.into(), 8).unwrap(),
+            Ok(alloc::vec![(0x8ffc.into(), 4), (0x9000.into(), 4)])
+        );
+        assert_eq!(mem.u64_at(0x4008), 0x9000 | P | W | U | PTE_ACCESSED | D);
+        assert_eq!(
+            translate_range(&mem, &write, 0x40_0000.into(), 0).unwrap(),
+            Ok(alloc::vec![])
+        );
+        // The second page is not present.
+        assert_eq!(
+            translate_range(&mem, &write, 0x40_1fff.into(), 2).unwrap(),
+            Err(PageFault {
+                addr: 0x40_2000.into(),
+                error_code: PageFaultErrorCode::CAUSED_BY_WRITE,
+            })
+        );
+
+        // A range at the top of the address space wraps around to address 0,
+        // which is not mapped.
+        set_entry(&mem, 0x1000, 511, 0x5000 | P | W);
+        set_entry(&mem, 0x5000, 511, 0x6000 | P | W);
+        set_entry(&mem, 0x6000, 511, 0x7000 | P | W);
+        set_entry(&mem, 0x7000, 511, 0xa000 | P | W);
+        assert_eq!(
+            translate_range(&mem, &write, usize::MAX.into(), 2).unwrap(),
+            Err(PageFault {
+                addr: 0.into(),
+                error_code: PageFaultErrorCode::CAUSED_BY_WRITE,
+            })
+        );
+        assert_eq!(mem.u64_at(0x7ff8), 0xa000 | P | W | PTE_ACCESSED | D);
+    }
+}
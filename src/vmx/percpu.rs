@@ -50,6 +50,8 @@ impl<H: AxVCpuHal> AxArchPerCpu for VmxPerCpuState<H> {
 
         // Enable XSAVE/XRSTOR.
         super::vcpu::XState::enable_xsave();
+        // Enable protection keys, so that PKRU can be switched between the host and guests.
+        super::vcpu::XState::enable_pku();
 
         // Enable VMXON, if required.
         let ctrl = FeatureControl::read();
@@ -0,0 +1,155 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr, PhysFrame};
+use axerrno::AxResult;
+
+/// The number of GPAs in the page-modification log. (SDM Vol. 3C, Section 29.3.6)
+pub const PML_ENTRIES: usize = 512;
+/// The PML index of an empty log, entries are logged from the last one down.
+pub const PML_INDEX_EMPTY: u16 = PML_ENTRIES as u16 - 1;
+
+const PAGE_SHIFT: usize = 12;
+
+/// Receiver of the guest pages dirtied while page-modification logging is enabled,
+/// provided by the VMM.
+pub trait DirtyLog {
+    /// Record that the 4K guest page at `gpa` has been written.
+    fn log_dirty(&self, gpa: GuestPhysAddr);
+}
+
+/// A bitmap of dirty 4K pages in a range of guest physical memory, which can be
+/// shared by the vCPUs of a VM.
+pub struct DirtyBitmap {
+    base: GuestPhysAddr,
+    bits: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    /// Create a clean bitmap for `size` bytes of guest physical memory from `base`.
+    pub fn new(base: GuestPhysAddr, size: usize) -> Self {
+        let pages = size.div_ceil(1 << PAGE_SHIFT);
+        Self {
+            base,
+            bits: (0..pages.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn page_index(&self, gpa: GuestPhysAddr) -> Option<usize> {
+        let page = gpa.as_usize().checked_sub(self.base.as_usize())? >> PAGE_SHIFT;
+        (page < self.bits.len() * 64).then_some(page)
+    }
+
+    /// Whether the page at `gpa` is dirty.
+    pub fn is_dirty(&self, gpa: GuestPhysAddr) -> bool {
+        self.page_index(gpa).is_some_and(|page| {
+            self.bits[page / 64].load(Ordering::Relaxed) & 1 << (page % 64) != 0
+        })
+    }
+
+    /// Take the addresses of the dirty pages, and make them clean.
+    pub fn take_dirty(&self) -> Vec<GuestPhysAddr> {
+        let mut dirty = Vec::new();
+        for (index, word) in self.bits.iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::AcqRel);
+            while bits != 0 {
+                let page = index * 64 + bits.trailing_zeros() as usize;
+                dirty.push(self.base + (page << PAGE_SHIFT));
+                bits &= bits - 1;
+            }
+        }
+        dirty
+    }
+}
+
+impl DirtyLog for DirtyBitmap {
+    fn log_dirty(&self, gpa: GuestPhysAddr) {
+        match self.page_index(gpa) {
+            Some(page) => {
+                self.bits[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+            }
+            None => warn!("Dirty page {:#x} out of the dirty bitmap", gpa),
+        }
+    }
+}
+
+/// Pass the GPAs logged in `entries` before the PML index `index` to `log`, and
+/// return the number of them.
+///
+/// The processor logs from the last entry down, and decrements the index after
+/// each one. The index wraps to 0xffff when the log is full.
+pub fn drain_entries(entries: &[u64; PML_ENTRIES], index: u16, log: &dyn DirtyLog) -> usize {
+    let first = if index as usize >= PML_ENTRIES {
+        0
+    } else {
+        index as usize + 1
+    };
+    for &entry in &entries[first..] {
+        log.log_dirty(GuestPhysAddr::from(
+            entry as usize & !((1 << PAGE_SHIFT) - 1),
+        ));
+    }
+    PML_ENTRIES - first
+}
+
+/// The 4K page-modification log referenced by the PML address in the VMCS, and
+/// the receiver of the logged pages.
+pub struct PmlBuffer<H: AxMmHal> {
+    frame: PhysFrame<H>,
+    log: Arc<dyn DirtyLog + Send + Sync>,
+}
+
+impl<H: AxMmHal> PmlBuffer<H> {
+    /// Allocate an empty log, whose pages are passed to `log`.
+    pub fn new(log: Arc<dyn DirtyLog + Send + Sync>) -> AxResult<Self> {
+        Ok(Self {
+            frame: PhysFrame::alloc_zero()?,
+            log,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frame.start_paddr()
+    }
+
+    /// Pass the GPAs logged before the PML index `index` to the receiver, and
+    /// return the number of them.
+    pub fn drain(&self, index: u16) -> usize {
+        let entries = unsafe { &*(self.frame.as_mut_ptr() as *const [u64; PML_ENTRIES]) };
+        drain_entries(entries, index, self.log.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_drain_entries() {
+        let bitmap = DirtyBitmap::new(0x10_0000.into(), 0x10_0000);
+        let mut entries = [0; PML_ENTRIES];
+        entries[511] = 0x10_0000;
+        entries[510] = 0x10_2abc;
+        entries[509] = 0x1f_f000;
+        entries[508] = 0xdead_0000; // Stale.
+
+        // Empty.
+        assert_eq!(drain_entries(&entries, PML_INDEX_EMPTY, &bitmap), 0);
+        assert!(bitmap.take_dirty().is_empty());
+
+        assert_eq!(drain_entries(&entries, 508, &bitmap), 3);
+        assert!(bitmap.is_dirty(0x10_2000.into()));
+        assert!(!bitmap.is_dirty(0x10_1000.into()));
+        assert_eq!(
+            bitmap.take_dirty(),
+            [0x10_0000.into(), 0x10_2000.into(), 0x1f_f000.into()]
+        );
+        assert!(!bitmap.is_dirty(0x10_2000.into()));
+
+        // Full, the index has wrapped.
+        entries.fill(0x18_0000);
+        assert_eq!(drain_entries(&entries, 0xffff, &bitmap), PML_ENTRIES);
+        assert_eq!(bitmap.take_dirty(), [0x18_0000.into()]);
+    }
+}
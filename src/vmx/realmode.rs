@@ -0,0 +1,556 @@
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use super::memory::GuestMemory;
+use super::segment::{Segment, SegmentRegister};
+use crate::regs::GeneralRegisters;
+
+/// Indexes of segment registers in [`Vm86Regs::segs`].
+pub const CS: usize = SegmentRegister::Cs as usize;
+const SS: usize = SegmentRegister::Ss as usize;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_IOPL: u64 = 0b11 << 12;
+const RFLAGS_VM: u64 = 1 << 17;
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// Access rights of all segments in virtual-8086 mode: present, DPL 3,
+/// read/write data, accessed. (SDM Vol. 3C, Section 27.3.1.2)
+const VM86_SEGMENT_AR: u32 = 0xf3;
+/// Access rights of real-mode segments loaded before entering protected mode:
+/// present, DPL 0, execute/read code or read/write data, accessed.
+const CODE_SEGMENT_AR: u32 = 0x9b;
+const DATA_SEGMENT_AR: u32 = 0x93;
+/// Present, system, 32-bit busy TSS.
+const TSS_BUSY_AR: u32 = 0x8b;
+
+/// The linear address of the TSS used in virtual-8086 mode. It is above the
+/// highest linear address of virtual-8086 mode (0x10_ffef), so the guest can
+/// not reach it.
+pub const VM86_TSS_BASE: usize = 0xffff_d000;
+/// The TSS spans 3 pages: the 104-byte 32-bit TSS, the interrupt redirection
+/// bitmap, and the I/O permission bitmap of all ports. (SDM Vol. 3A, Section 20.3)
+pub const VM86_TSS_PAGES: usize = 3;
+const TSS_IO_MAP_BASE: usize = 102;
+const TSS_REDIRECTION_BITMAP: usize = 104;
+const TSS_IO_BITMAP: usize = TSS_REDIRECTION_BITMAP + 32;
+/// The size of the TSS, including the trailing 0xff byte of the I/O bitmap.
+const VM86_TSS_SIZE: usize = TSS_IO_BITMAP + 0x10000 / 8 + 1;
+
+/// The maximum length of an instruction.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Initialize the TSS of virtual-8086 mode. With CR4.VME set, clear bits of the
+/// interrupt redirection bitmap let `INT n` go through the real-mode IVT of the
+/// guest, and clear bits of the I/O bitmap leave port I/O to the I/O exiting
+/// controls.
+pub fn init_vm86_tss(tss: &mut [u8; VM86_TSS_PAGES * 0x1000]) {
+    tss.fill(0);
+    tss[TSS_IO_MAP_BASE..TSS_IO_MAP_BASE + 2]
+        .copy_from_slice(&(TSS_IO_BITMAP as u16).to_le_bytes());
+    tss[VM86_TSS_SIZE - 1] = 0xff;
+}
+
+/// The base of CS after reset, which is kept as the base of its selector.
+const RESET_CS_BASE: u64 = 0xffff_0000;
+
+/// Whether a real-mode segment can not be emulated in virtual-8086 mode: an
+/// "unreal" segment left by protected mode with a limit above 64 KiB, or with a
+/// base that no selector produces, except the base of CS after reset.
+fn is_unreal_segment(seg: &Segment, index: usize) -> bool {
+    let base_kept = seg.base == (seg.selector as u64) << 4
+        || (seg.base < 0x10_0000 && seg.base & 0xf == 0)
+        || (index == CS && seg.base == RESET_CS_BASE);
+    seg.limit > 0xffff || !base_kept
+}
+
+/// The segment in virtual-8086 mode, where the base is always the selector
+/// shifted left by 4.
+///
+/// A real-mode base which no selector produces can not be kept. A base below
+/// 1 MiB is kept by choosing the selector from it. The 0xffff_0000 of CS after
+/// reset is replaced by the base of its selector, as the firmware is expected to
+/// be aliased below 1 MiB on PCs. Other segments are rejected by [`Vm86::enter`].
+fn vm86_segment(seg: &Segment) -> Segment {
+    let selector = if seg.base < 0x10_0000 && seg.base & 0xf == 0 {
+        (seg.base >> 4) as u16
+    } else {
+        seg.selector
+    };
+    Segment {
+        selector,
+        base: (selector as u64) << 4,
+        limit: 0xffff,
+        access_rights: VM86_SEGMENT_AR,
+    }
+}
+
+/// The real-mode segment of a guest entering protected mode, as it is cached
+/// until the guest loads the segment register. CS and SS must have RPL 0, as
+/// their DPL, and DPL of other segments is their RPL. (SDM Vol. 3C, Section 27.3.1.2)
+fn protected_segment(seg: &Segment, index: usize) -> Segment {
+    let selector = match index {
+        CS | SS => seg.selector & !0b11,
+        _ => seg.selector,
+    };
+    let access_rights = if index == CS {
+        CODE_SEGMENT_AR
+    } else {
+        DATA_SEGMENT_AR
+    };
+    Segment {
+        selector,
+        base: seg.base,
+        limit: 0xffff,
+        access_rights: access_rights | ((selector as u32 & 0b11) << 5),
+    }
+}
+
+/// Guest registers changed by virtual-8086 mode and by delivering real-mode
+/// interrupts, an in-memory copy of their VMCS fields.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Vm86Regs {
+    /// ES, CS, SS, DS, FS and GS.
+    pub segs: [Segment; 6],
+    pub tr: Segment,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+/// Guest state hidden while a real-mode guest runs in virtual-8086 mode.
+///
+/// Without unrestricted guest support, the processor can not run real-mode code
+/// in VMX non-root operation. Real-mode code then runs in virtual-8086 mode with
+/// IOPL 3, on identity-mapped shadow page tables. Privileged instructions cause
+/// #GP and are emulated, and interrupts are delivered through the IVT by the
+/// vCPU, see [`decode`] and [`deliver_interrupt`]. The guest runs natively once it
+/// enters protected mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vm86 {
+    tr: Segment,
+    iopl: u64,
+}
+
+impl Vm86 {
+    /// Switch the real-mode guest in `regs` into virtual-8086 mode, with the TSS
+    /// at [`VM86_TSS_BASE`].
+    ///
+    /// Return [`Unsupported`](axerrno::AxError::Unsupported) if any segment is
+    /// an "unreal" one, which virtual-8086 mode can not emulate.
+    pub fn enter(regs: &mut Vm86Regs) -> AxResult<Self> {
+        if let Some(index) = (0..regs.segs.len()).find(|&i| is_unreal_segment(&regs.segs[i], i)) {
+            warn!(
+                "Unreal segment in virtual-8086 mode: {:#x?}",
+                regs.segs[index]
+            );
+            return ax_err!(
+                Unsupported,
+                "unreal mode is not supported without unrestricted guest"
+            );
+        }
+        let vm86 = Self {
+            tr: regs.tr,
+            iopl: regs.rflags & RFLAGS_IOPL,
+        };
+        for seg in regs.segs.iter_mut() {
+            *seg = vm86_segment(seg);
+        }
+        regs.tr = Segment {
+            selector: 0,
+            base: VM86_TSS_BASE as u64,
+            limit: VM86_TSS_SIZE as u32 - 1,
+            access_rights: TSS_BUSY_AR,
+        };
+        regs.rflags |= RFLAGS_VM | RFLAGS_IOPL;
+        Ok(vm86)
+    }
+
+    /// Switch the guest in `regs` back from virtual-8086 mode, when it enters
+    /// protected mode.
+    pub fn leave(self, regs: &mut Vm86Regs) {
+        for (index, seg) in regs.segs.iter_mut().enumerate() {
+            *seg = protected_segment(seg, index);
+        }
+        regs.tr = self.tr;
+        regs.rflags = (regs.rflags & !(RFLAGS_VM | RFLAGS_IOPL)) | self.iopl;
+    }
+}
+
+/// The guest physical address of `offset` in segment `seg`. Paging is disabled
+/// in real mode, linear addresses are guest physical addresses.
+pub fn segment_addr(seg: &Segment, offset: u16) -> GuestPhysAddr {
+    GuestPhysAddr::from((seg.base + offset as u64) as usize)
+}
+
+/// Deliver interrupt `vector` to the guest in `regs` as in real-address mode:
+/// push FLAGS, CS and IP, clear IF, TF and AC, and jump to the handler in the
+/// IVT at `ivt_base`. (SDM Vol. 3A, Section 21.1.4)
+pub fn deliver_interrupt(
+    mem: &dyn GuestMemory,
+    regs: &mut Vm86Regs,
+    ivt_base: u64,
+    vector: u8,
+) -> AxResult {
+    let mut entry = [0; 4];
+    mem.read_phys(
+        GuestPhysAddr::from((ivt_base + vector as u64 * 4) as usize),
+        &mut entry,
+    )?;
+
+    let mut sp = regs.rsp as u16;
+    let cs = regs.segs[CS].selector;
+    for word in [regs.rflags as u16, cs, regs.rip as u16] {
+        sp = sp.wrapping_sub(2);
+        mem.write_phys(segment_addr(&regs.segs[SS], sp), &word.to_le_bytes())?;
+    }
+    regs.rsp = (regs.rsp & !0xffff) | sp as u64;
+    regs.rflags &= !(RFLAGS_IF | RFLAGS_TF | RFLAGS_AC);
+
+    let selector = u16::from_le_bytes([entry[2], entry[3]]);
+    regs.segs[CS].selector = selector;
+    regs.segs[CS].base = (selector as u64) << 4;
+    regs.rip = u16::from_le_bytes([entry[0], entry[1]]) as u64;
+    Ok(())
+}
+
+/// An operand of a real-mode instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// A general-purpose register, by its index.
+    Register(u8),
+    /// A memory operand at `offset` in segment `segment`.
+    Memory {
+        segment: SegmentRegister,
+        offset: u16,
+    },
+}
+
+/// A privileged instruction, which causes #GP in virtual-8086 mode but is
+/// allowed in real-address mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RealModeInstruction {
+    Hlt,
+    Clts,
+    /// INVD or WBINVD, which need no emulation.
+    CacheFlush,
+    /// LGDT, with a 24-bit base unless `operand32`.
+    Lgdt {
+        operand: Operand,
+        operand32: bool,
+    },
+    /// LIDT, with a 24-bit base unless `operand32`.
+    Lidt {
+        operand: Operand,
+        operand32: bool,
+    },
+    Lmsw(Operand),
+    MovToCr {
+        cr: u8,
+        gpr: u8,
+    },
+    MovFromCr {
+        cr: u8,
+        gpr: u8,
+    },
+}
+
+/// Decode the 16-bit ModR/M addressing form at the start of `bytes`. Return the
+/// operand, and the length of the ModR/M byte and the displacement.
+fn decode_modrm(
+    bytes: &[u8],
+    regs: &GeneralRegisters,
+    segment_override: Option<SegmentRegister>,
+) -> Option<(Operand, u8, usize)> {
+    let modrm = *bytes.first()?;
+    let (mode, reg, rm) = (modrm >> 6, (modrm >> 3) & 0b111, modrm & 0b111);
+    if mode == 0b11 {
+        return Some((Operand::Register(rm), reg, 1));
+    }
+    let (disp, len) = match mode {
+        0b00 if rm == 0b110 => (u16::from_le_bytes([*bytes.get(1)?, *bytes.get(2)?]), 3),
+        0b00 => (0, 1),
+        0b01 => (*bytes.get(1)? as i8 as u16, 2),
+        _ => (u16::from_le_bytes([*bytes.get(1)?, *bytes.get(2)?]), 3),
+    };
+    let (bx, bp, si, di) = (
+        regs.rbx as u16,
+        regs.rbp as u16,
+        regs.rsi as u16,
+        regs.rdi as u16,
+    );
+    let (base, segment) = match rm {
+        0b000 => (bx.wrapping_add(si), SegmentRegister::Ds),
+        0b001 => (bx.wrapping_add(di), SegmentRegister::Ds),
+        0b010 => (bp.wrapping_add(si), SegmentRegister::Ss),
+        0b011 => (bp.wrapping_add(di), SegmentRegister::Ss),
+        0b100 => (si, SegmentRegister::Ds),
+        0b101 => (di, SegmentRegister::Ds),
+        0b110 if mode == 0b00 => (0, SegmentRegister::Ds),
+        0b110 => (bp, SegmentRegister::Ss),
+        _ => (bx, SegmentRegister::Ds),
+    };
+    let operand = Operand::Memory {
+        segment: segment_override.unwrap_or(segment),
+        offset: base.wrapping_add(disp),
+    };
+    Some((operand, reg, len))
+}
+
+/// Decode the privileged real-mode instruction at the start of `bytes`, with
+/// 16-bit addressing and general-purpose registers `regs`. Return the
+/// instruction and its length, or `None` if it is not one of them.
+pub fn decode(bytes: &[u8], regs: &GeneralRegisters) -> Option<(RealModeInstruction, usize)> {
+    let mut segment_override = None;
+    let mut operand32 = false;
+    let mut len = 0;
+    let opcode = loop {
+        let byte = *bytes.get(len)?;
+        len += 1;
+        match byte {
+            0x26 => segment_override = Some(SegmentRegister::Es),
+            0x2e => segment_override = Some(SegmentRegister::Cs),
+            0x36 => segment_override = Some(SegmentRegister::Ss),
+            0x3e => segment_override = Some(SegmentRegister::Ds),
+            0x64 => segment_override = Some(SegmentRegister::Fs),
+            0x65 => segment_override = Some(SegmentRegister::Gs),
+            0x66 => operand32 = true,
+            // 32-bit addressing is not supported.
+            0x67 => return None,
+            byte => break byte,
+        }
+    };
+
+    if opcode == 0xf4 {
+        return Some((RealModeInstruction::Hlt, len));
+    }
+    if opcode != 0x0f {
+        return None;
+    }
+    let opcode = *bytes.get(len)?;
+    len += 1;
+    let insn = match opcode {
+        0x06 => RealModeInstruction::Clts,
+        0x08 | 0x09 => RealModeInstruction::CacheFlush,
+        0x01 => {
+            let (operand, reg, modrm_len) = decode_modrm(&bytes[len..], regs, segment_override)?;
+            len += modrm_len;
+            match (reg, operand) {
+                (2, Operand::Memory { .. }) => RealModeInstruction::Lgdt { operand, operand32 },
+                (3, Operand::Memory { .. }) => RealModeInstruction::Lidt { operand, operand32 },
+                (6, _) => RealModeInstruction::Lmsw(operand),
+                _ => return None,
+            }
+        }
+        // MOV to/from control registers always use a register operand.
+        0x20 | 0x22 => {
+            let modrm = *bytes.get(len)?;
+            len += 1;
+            let (cr, gpr) = ((modrm >> 3) & 0b111, modrm & 0b111);
+            if opcode == 0x20 {
+                RealModeInstruction::MovFromCr { cr, gpr }
+            } else {
+                RealModeInstruction::MovToCr { cr, gpr }
+            }
+        }
+        _ => return None,
+    };
+    Some((insn, len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::memory::TestGuestMemory;
+
+    const DS: usize = SegmentRegister::Ds as usize;
+    const ES: usize = SegmentRegister::Es as usize;
+    const FS: usize = SegmentRegister::Fs as usize;
+
+    fn real_mode_regs() -> Vm86Regs {
+        let data = Segment {
+            selector: 0,
+            base: 0,
+            limit: 0xffff,
+            access_rights: DATA_SEGMENT_AR,
+        };
+        let mut regs = Vm86Regs {
+            segs: [data; 6],
+            tr: Segment {
+                access_rights: TSS_BUSY_AR,
+                limit: 0xffff,
+                ..Default::default()
+            },
+            rip: 0xfff0,
+            rsp: 0,
+            rflags: 0x2 | RFLAGS_IF,
+        };
+        // After reset.
+        regs.segs[CS] = Segment {
+            selector: 0xf000,
+            base: 0xffff_0000,
+            access_rights: CODE_SEGMENT_AR,
+            ..data
+        };
+        regs.segs[DS] = Segment {
+            selector: 0x1234,
+            base: 0x12340,
+            ..data
+        };
+        regs
+    }
+
+    #[test]
+    fn test_vm86_transitions() {
+        let mut regs = real_mode_regs();
+        let real = regs;
+        let vm86 = Vm86::enter(&mut regs).unwrap();
+        for seg in regs.segs {
+            assert_eq!(seg.base, (seg.selector as u64) << 4);
+            assert_eq!(seg.limit, 0xffff);
+            assert_eq!(seg.access_rights, VM86_SEGMENT_AR);
+        }
+        assert_eq!(regs.segs[CS].base, 0xf_0000);
+        assert_eq!(
+            regs.segs[DS],
+            Segment {
+                access_rights: VM86_SEGMENT_AR,
+                ..real.segs[DS]
+            }
+        );
+        assert_eq!(regs.tr.base, VM86_TSS_BASE as u64);
+        assert_eq!(regs.rflags, real.rflags | RFLAGS_VM | RFLAGS_IOPL);
+
+        // The guest loads SS with RPL 3, then enters protected mode.
+        regs.segs[SS].selector = 0x2003;
+        regs.segs[SS].base = 0x20030;
+        vm86.leave(&mut regs);
+        assert_eq!(regs.rflags, real.rflags);
+        assert_eq!(regs.tr, real.tr);
+        assert_eq!(regs.segs[CS].access_rights, CODE_SEGMENT_AR);
+        assert_eq!(regs.segs[SS].selector, 0x2000);
+        assert_eq!(regs.segs[SS].base, 0x20030);
+        assert_eq!(regs.segs[SS].access_rights, DATA_SEGMENT_AR);
+        assert_eq!(regs.segs[DS], real.segs[DS]);
+    }
+
+    #[test]
+    fn test_unreal_segments() {
+        // A 4G limit left by protected mode.
+        let mut regs = real_mode_regs();
+        regs.segs[DS].limit = 0xffff_ffff;
+        let unreal = regs;
+        assert!(Vm86::enter(&mut regs).is_err());
+        assert_eq!(regs, unreal);
+
+        // A base above 1 MiB, only allowed for CS after reset.
+        let mut regs = real_mode_regs();
+        regs.segs[ES].base = 0x20_0000;
+        assert!(Vm86::enter(&mut regs).is_err());
+        let mut regs = real_mode_regs();
+        regs.segs[SS].base = RESET_CS_BASE;
+        assert!(Vm86::enter(&mut regs).is_err());
+
+        // A base below 1 MiB that no selector produces.
+        let mut regs = real_mode_regs();
+        regs.segs[FS].base = 0x1_2345;
+        assert!(Vm86::enter(&mut regs).is_err());
+    }
+
+    #[test]
+    fn test_deliver_interrupt() {
+        let mem = TestGuestMemory::new(0x2_0000);
+        let mut regs = real_mode_regs();
+        Vm86::enter(&mut regs).unwrap();
+        regs.segs[SS] = Segment {
+            selector: 0x1000,
+            base: 0x1_0000,
+            ..regs.segs[SS]
+        };
+        regs.rsp = 0x100;
+        // INT 8 handler at 0xc000:0x1234.
+        mem.write_phys(GuestPhysAddr::from(0x20), &[0x34, 0x12, 0x00, 0xc0])
+            .unwrap();
+
+        let flags = regs.rflags;
+        deliver_interrupt(&mem, &mut regs, 0, 8).unwrap();
+        assert_eq!(regs.segs[CS].selector, 0xc000);
+        assert_eq!(regs.segs[CS].base, 0xc_0000);
+        assert_eq!(regs.rip, 0x1234);
+        assert_eq!(regs.rsp, 0xfa);
+        assert_eq!(regs.rflags, flags & !RFLAGS_IF);
+        // IP, CS and FLAGS from the top of the stack.
+        assert_eq!(mem.u32_at(0x1_00fa), 0xf000_fff0);
+        assert_eq!(mem.u32_at(0x1_00fe) as u16, flags as u16);
+
+        // The IVT and the stack outside guest memory fail, leaving the registers as is.
+        let saved = regs;
+        assert!(deliver_interrupt(&mem, &mut regs, 0x2_0000, 8).is_err());
+        regs.segs[SS].base = 0x2_0000;
+        assert!(deliver_interrupt(&mem, &mut regs, 0, 8).is_err());
+        regs.segs[SS].base = saved.segs[SS].base;
+        assert_eq!(regs, saved);
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut regs = GeneralRegisters::default();
+        regs.rbx = 0x100;
+        regs.rsi = 0x20;
+        regs.rbp = 0x8000;
+        let mem = |segment, offset| Operand::Memory { segment, offset };
+        // hlt
+        assert_eq!(decode(&[0xf4], &regs), Some((RealModeInstruction::Hlt, 1)));
+        // mov cr0, eax
+        assert_eq!(
+            decode(&[0x0f, 0x22, 0xc0], &regs),
+            Some((RealModeInstruction::MovToCr { cr: 0, gpr: 0 }, 3))
+        );
+        // mov ebx, cr4
+        assert_eq!(
+            decode(&[0x0f, 0x20, 0xe3], &regs),
+            Some((RealModeInstruction::MovFromCr { cr: 4, gpr: 3 }, 3))
+        );
+        // lgdt [bx+si+0x10]
+        assert_eq!(
+            decode(&[0x0f, 0x01, 0x50, 0x10], &regs),
+            Some((
+                RealModeInstruction::Lgdt {
+                    operand: mem(SegmentRegister::Ds, 0x130),
+                    operand32: false
+                },
+                4
+            ))
+        );
+        // o32 lidt cs:[0x1234]
+        assert_eq!(
+            decode(&[0x2e, 0x66, 0x0f, 0x01, 0x1e, 0x34, 0x12], &regs),
+            Some((
+                RealModeInstruction::Lidt {
+                    operand: mem(SegmentRegister::Cs, 0x1234),
+                    operand32: true
+                },
+                7
+            ))
+        );
+        // lmsw [bp-2], lmsw ax
+        assert_eq!(
+            decode(&[0x0f, 0x01, 0x76, 0xfe], &regs),
+            Some((
+                RealModeInstruction::Lmsw(mem(SegmentRegister::Ss, 0x7ffe)),
+                4
+            ))
+        );
+        assert_eq!(
+            decode(&[0x0f, 0x01, 0xf0], &regs),
+            Some((RealModeInstruction::Lmsw(Operand::Register(0)), 3))
+        );
+        // lgdt with a register operand, 32-bit addressing, and other instructions.
+        assert_eq!(decode(&[0x0f, 0x01, 0xd0], &regs), None);
+        assert_eq!(decode(&[0x67, 0x0f, 0x01, 0x10], &regs), None);
+        assert_eq!(decode(&[0x0f, 0x30], &regs), None);
+        assert_eq!(decode(&[0x90], &regs), None);
+        assert_eq!(decode(&[0x66; MAX_INSTRUCTION_LEN], &regs), None);
+    }
+}
@@ -0,0 +1,267 @@
+use super::vcpu::VmCpuMode;
+
+/// A segment register, by its encoding in instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentRegister {
+    Es = 0,
+    Cs = 1,
+    Ss = 2,
+    Ds = 3,
+    Fs = 4,
+    Gs = 5,
+}
+
+impl SegmentRegister {
+    /// Decode from its encoding in instructions and exit information.
+    pub fn from_index(index: u8) -> Option<Self> {
+        Some(match index {
+            0 => Self::Es,
+            1 => Self::Cs,
+            2 => Self::Ss,
+            3 => Self::Ds,
+            4 => Self::Fs,
+            5 => Self::Gs,
+            _ => return None,
+        })
+    }
+}
+
+/// The kind of an access through a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A fault of an access through a segment, to be injected with error code 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentFault {
+    /// #GP(0).
+    GeneralProtection,
+    /// #SS(0), for accesses through SS.
+    StackFault,
+}
+
+impl SegmentFault {
+    fn of(seg: SegmentRegister) -> Self {
+        if seg == SegmentRegister::Ss {
+            Self::StackFault
+        } else {
+            Self::GeneralProtection
+        }
+    }
+
+    /// The exception vector.
+    pub fn vector(&self) -> u8 {
+        match self {
+            Self::GeneralProtection => x86::irq::GENERAL_PROTECTION_FAULT_VECTOR,
+            Self::StackFault => x86::irq::STACK_SEGEMENT_FAULT_VECTOR,
+        }
+    }
+}
+
+/// Segment type bits in access rights. (SDM Vol. 3A, Section 3.4.5.1)
+const AR_TYPE_CODE: u32 = 1 << 3;
+/// Expand-down for data segments, conforming for code segments.
+const AR_TYPE_EXPAND_DOWN: u32 = 1 << 2;
+/// Writable for data segments, readable for code segments.
+const AR_TYPE_READ_WRITE: u32 = 1 << 1;
+/// The D/B flag, which sets the upper bound of expand-down segments to 4G - 1.
+const AR_DB: u32 = 1 << 14;
+/// The segment is unusable, e.g. loaded with a null selector. (SDM Vol. 3C, Section 25.4.1)
+const AR_UNUSABLE: u32 = 1 << 16;
+
+/// A segment register of the guest, as in the VMCS, with the limit in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub selector: u16,
+    pub base: u64,
+    pub limit: u32,
+    pub access_rights: u32,
+}
+
+/// Whether `addr` is a canonical linear address, of 57 bits with five-level
+/// paging or 48 bits otherwise.
+fn is_canonical(addr: u64, la57: bool) -> bool {
+    let shift = if la57 { 7 } else { 16 };
+    ((addr << shift) as i64 >> shift) as u64 == addr
+}
+
+/// The linear address of `size` bytes at `offset` in segment `seg_reg` holding
+/// `seg`, accessed by `access` in CPU mode `mode`, or the fault of the access.
+/// `la57` is CR4.LA57. (SDM Vol. 3A, Section 3.4 and Section 5.3 to 5.5)
+///
+/// - In 64-bit mode, only FS and GS have a base, and both ends of the access must
+///   be canonical, for 57-bit linear addresses if `la57` is set. Limits and
+///   access rights are not checked.
+/// - In real-address and virtual-8086 mode, only the limit is checked.
+/// - In protected and compatibility mode, the segment must be usable and allow
+///   the access, and the access must be within the limit, above it for
+///   expand-down data segments. Linear addresses wrap around at 4G.
+pub fn segmented_to_linear(
+    seg_reg: SegmentRegister,
+    seg: &Segment,
+    offset: u64,
+    size: usize,
+    access: SegmentAccess,
+    mode: &VmCpuMode,
+    la57: bool,
+) -> Result<u64, SegmentFault> {
+    let fault = SegmentFault::of(seg_reg);
+    let last = offset.checked_add(size.max(1) as u64 - 1).ok_or(fault)?;
+
+    if *mode == VmCpuMode::Mode64 {
+        let base = match seg_reg {
+            SegmentRegister::Fs | SegmentRegister::Gs => seg.base,
+            _ => 0,
+        };
+        let linear = base.wrapping_add(offset);
+        let linear_last = base.wrapping_add(last);
+        if !is_canonical(linear, la57) || !is_canonical(linear_last, la57) {
+            return Err(fault);
+        }
+        return Ok(linear);
+    }
+
+    if *mode != VmCpuMode::Real {
+        if seg.access_rights & AR_UNUSABLE != 0 {
+            return Err(fault);
+        }
+        let seg_type = seg.access_rights & 0xf;
+        let is_code = seg_type & AR_TYPE_CODE != 0;
+        let read_write = seg_type & AR_TYPE_READ_WRITE != 0;
+        let allowed = match access {
+            SegmentAccess::Execute => is_code,
+            SegmentAccess::Read => !is_code || read_write,
+            SegmentAccess::Write => !is_code && read_write,
+        };
+        if !allowed {
+            return Err(fault);
+        }
+        if !is_code && seg_type & AR_TYPE_EXPAND_DOWN != 0 {
+            let upper = if seg.access_rights & AR_DB != 0 {
+                u32::MAX
+            } else {
+                u16::MAX as u32
+            };
+            if offset <= seg.limit as u64 || last > upper as u64 {
+                return Err(fault);
+            }
+            return Ok(seg.base.wrapping_add(offset) & 0xffff_ffff);
+        }
+    }
+    if last > seg.limit as u64 {
+        return Err(fault);
+    }
+    Ok(seg.base.wrapping_add(offset) & 0xffff_ffff)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use SegmentAccess::*;
+    use SegmentRegister::*;
+
+    fn segment(base: u64, limit: u32, access_rights: u32) -> Segment {
+        Segment {
+            selector: 0x10,
+            base,
+            limit,
+            access_rights,
+        }
+    }
+
+    #[test]
+    fn test_protected_mode() {
+        let mode = VmCpuMode::Protected;
+        let data = segment(0x1000, 0xfff, 0xc093);
+        let to_linear = |seg_reg, seg: &Segment, offset, size, access| {
+            segmented_to_linear(seg_reg, seg, offset, size, access, &mode, false)
+        };
+        assert_eq!(to_linear(Ds, &data, 0xffc, 4, Write), Ok(0x1ffc));
+        assert_eq!(
+            to_linear(Ds, &data, 0xffd, 4, Read),
+            Err(SegmentFault::GeneralProtection)
+        );
+        assert_eq!(
+            to_linear(Ss, &data, 0x1000, 1, Read),
+            Err(SegmentFault::StackFault)
+        );
+        // Wrap around at 4G.
+        let high = segment(0xffff_f000, 0xffff_ffff, 0xc093);
+        assert_eq!(to_linear(Es, &high, 0x2000, 2, Read), Ok(0x1000));
+
+        // Execute-only and execute/read code segments.
+        let code = segment(0, 0xffff_ffff, 0xc099);
+        assert_eq!(to_linear(Cs, &code, 0x1234, 1, Execute), Ok(0x1234));
+        assert!(to_linear(Cs, &code, 0x1234, 1, Read).is_err());
+        let code = segment(0, 0xffff_ffff, 0xc09b);
+        assert_eq!(to_linear(Ds, &code, 0x1234, 1, Read), Ok(0x1234));
+        assert!(to_linear(Ds, &code, 0x1234, 1, Write).is_err());
+        assert!(to_linear(Ds, &data, 0, 1, Execute).is_err());
+        // Read-only data, and unusable segments.
+        assert!(to_linear(Ds, &segment(0, 0xffff, 0x91), 0, 1, Write).is_err());
+        assert!(to_linear(Fs, &segment(0, 0xffff, 0x1_0093), 0, 1, Read).is_err());
+
+        // 16-bit and 32-bit expand-down stacks, valid above the limit.
+        let stack16 = segment(0x1_0000, 0xfff, 0x97);
+        assert_eq!(to_linear(Ss, &stack16, 0xfffe, 2, Write), Ok(0x1_fffe));
+        assert_eq!(
+            to_linear(Ss, &stack16, 0xffff, 2, Write),
+            Err(SegmentFault::StackFault)
+        );
+        assert!(to_linear(Ss, &stack16, 0xfff, 2, Write).is_err());
+        let stack32 = segment(0, 0xfff, 0xc097);
+        assert_eq!(to_linear(Ss, &stack32, 0x1_0000, 4, Write), Ok(0x1_0000));
+        assert!(to_linear(Ss, &stack32, 0xffff_fffe, 4, Write).is_err());
+
+        // Compatibility mode follows the same rules.
+        assert!(
+            segmented_to_linear(Ds, &data, 0x1000, 1, Read, &VmCpuMode::Compatibility, false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_real_and_64bit_mode() {
+        // Only the limit is checked in real mode, including big "unreal" limits.
+        let real = segment(0x1_2340, 0xffff, 0x93);
+        let to_real = |seg: &Segment, offset, size, access| {
+            segmented_to_linear(Ds, seg, offset, size, access, &VmCpuMode::Real, false)
+        };
+        assert_eq!(to_real(&real, 0xfffe, 2, Execute), Ok(0x2_233e));
+        assert!(to_real(&real, 0xffff, 2, Read).is_err());
+        let unreal = segment(0, 0xffff_ffff, 0x93);
+        assert_eq!(to_real(&unreal, 0x10_0000, 4, Write), Ok(0x10_0000));
+
+        // Only FS and GS have a base in 64-bit mode, and limits are not checked.
+        let mode = VmCpuMode::Mode64;
+        let fs = segment(0xffff_8000_0000_0000, 0, 0x1_0000);
+        assert_eq!(
+            segmented_to_linear(Fs, &fs, 0x10, 8, Write, &mode, false),
+            Ok(0xffff_8000_0000_0010)
+        );
+        assert_eq!(
+            segmented_to_linear(Ds, &fs, 0x10, 8, Read, &mode, false),
+            Ok(0x10)
+        );
+        // Non-canonical addresses, at either end of the access.
+        assert_eq!(
+            segmented_to_linear(Ss, &fs, 0x8000_0000_0000, 8, Read, &mode, false),
+            Err(SegmentFault::StackFault)
+        );
+        assert_eq!(
+            segmented_to_linear(Ds, &fs, 0x7fff_ffff_fffc, 8, Read, &mode, false),
+            Err(SegmentFault::GeneralProtection)
+        );
+        // 57-bit linear addresses with five-level paging.
+        assert_eq!(
+            segmented_to_linear(Ds, &fs, 0x8000_0000_0000, 8, Read, &mode, true),
+            Ok(0x8000_0000_0000)
+        );
+        assert!(segmented_to_linear(Ds, &fs, 0x100_0000_0000_0000, 8, Read, &mode, true).is_err());
+        let gs = segment(0xfe00_0000_0000_0000, 0, 0x1_0000);
+        assert!(segmented_to_linear(Gs, &gs, 0, 8, Read, &mode, true).is_err());
+    }
+}
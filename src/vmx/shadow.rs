@@ -0,0 +1,722 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::marker::PhantomData;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, PhysFrame};
+use axerrno::{AxResult, ax_err};
+
+use super::memory::GuestMemory;
+use super::paging::{self, PageFault};
+use super::structs::VmxEptVpidCap;
+use super::vmcs::{self, controls::SecondaryControls};
+use crate::GuestPageWalkInfo;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_HUGE_PAGE: u64 = 1 << 7;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Software-available bit 9 of non-leaf shadow entries, set if 4K shadow pages
+/// below it are split from a large guest page.
+const SHADOW_LARGE_PAGE: u64 = 1 << 9;
+
+const EPT_ACCESS_MASK: u64 = 0x7;
+const EPT_WRITE: u64 = 1 << 1;
+
+const PAGE_SHIFT: usize = 12;
+const ENTRIES: usize = 512;
+
+/// How guest physical memory is virtualized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmuMode {
+    /// The processor translates guest physical addresses by the EPT, and the
+    /// guest runs on its own page tables as an unrestricted guest.
+    Ept,
+    /// The processor runs the guest on shadow page tables, built by the vCPU from
+    /// the guest page tables and the EPT walked in software. The guest always runs
+    /// with paging and protection enabled, and real-mode code runs in
+    /// virtual-8086 mode.
+    Shadow,
+}
+
+impl MmuMode {
+    /// EPT if the processor supports 4-level EPT and unrestricted guests, shadow
+    /// paging otherwise.
+    pub fn detect() -> Self {
+        let bits = SecondaryControls::ENABLE_EPT | SecondaryControls::UNRESTRICTED_GUEST;
+        if vmcs::secondary_control_allowed(bits.bits()) && VmxEptVpidCap::read().ept_walk_length_4 {
+            Self::Ept
+        } else {
+            Self::Shadow
+        }
+    }
+}
+
+/// Translation of guest physical addresses to host physical addresses.
+pub trait GuestPhysMap {
+    /// The host physical address of `gpa`, and whether the guest may write it, or
+    /// `None` if it is not backed by host memory, e.g. emulated MMIO.
+    fn translate(&self, gpa: GuestPhysAddr) -> Option<(HostPhysAddr, bool)>;
+}
+
+/// Guest physical memory mapped by an EPT, walked in software while the
+/// processor does not use it.
+pub struct EptMap<H: AxMmHal> {
+    root: HostPhysAddr,
+    levels: usize,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: AxMmHal> EptMap<H> {
+    pub fn new(root: HostPhysAddr, levels: usize) -> Self {
+        Self {
+            root,
+            levels,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Run `f` on the host memory of each page in `len` bytes from `gpa`.
+    fn for_each_page(
+        &self,
+        gpa: GuestPhysAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, core::ops::Range<usize>),
+    ) -> AxResult {
+        let mut done = 0;
+        while done < len {
+            let addr = gpa + done;
+            let chunk = (len - done).min((1 << PAGE_SHIFT) - (addr.as_usize() & 0xfff));
+            let Some((hpa, _)) = self.translate(addr) else {
+                return ax_err!(InvalidInput, "guest physical address is not mapped");
+            };
+            f(
+                H::phys_to_virt(hpa).as_usize() as *mut u8,
+                done..done + chunk,
+            );
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl<H: AxMmHal> GuestPhysMap for EptMap<H> {
+    fn translate(&self, gpa: GuestPhysAddr) -> Option<(HostPhysAddr, bool)> {
+        let gpa = gpa.as_usize() as u64;
+        let mut table = self.root.as_usize() as u64;
+        for level in (1..=self.levels).rev() {
+            let shift = PAGE_SHIFT + 9 * (level - 1);
+            let index = (gpa >> shift) as usize % ENTRIES;
+            let entry_paddr = HostPhysAddr::from(table as usize + index * 8);
+            let entry = unsafe { *(H::phys_to_virt(entry_paddr).as_usize() as *const u64) };
+            if entry & EPT_ACCESS_MASK == 0 {
+                return None;
+            }
+            if level == 1 || (level <= 3 && entry & PTE_HUGE_PAGE != 0) {
+                let offset_mask = (1u64 << shift) - 1;
+                let hpa = (entry & PTE_ADDR_MASK & !offset_mask) | (gpa & offset_mask);
+                return Some((HostPhysAddr::from(hpa as usize), entry & EPT_WRITE != 0));
+            }
+            table = entry & PTE_ADDR_MASK;
+        }
+        None
+    }
+}
+
+impl<H: AxMmHal> GuestMemory for EptMap<H> {
+    fn read_phys(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        self.for_each_page(gpa, buf.len(), |src, range| unsafe {
+            core::ptr::copy_nonoverlapping(src, buf[range.clone()].as_mut_ptr(), range.len())
+        })
+    }
+
+    fn write_phys(&self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        self.for_each_page(gpa, buf.len(), |dst, range| unsafe {
+            core::ptr::copy_nonoverlapping(buf[range.clone()].as_ptr(), dst, range.len())
+        })
+    }
+
+    fn compare_exchange_phys(
+        &self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        current: u64,
+        new: u64,
+    ) -> AxResult<bool> {
+        use core::sync::atomic::{AtomicU32, AtomicU64, Ordering::SeqCst};
+
+        if !matches!(size, 4 | 8) || gpa.as_usize() % size != 0 {
+            return ax_err!(InvalidInput, "unaligned guest physical address");
+        }
+        let Some((hpa, _)) = self.translate(gpa) else {
+            return ax_err!(InvalidInput, "guest physical address is not mapped");
+        };
+        let ptr = H::phys_to_virt(hpa).as_usize();
+        // The naturally aligned value lies in one mapped page of guest RAM.
+        let replaced = unsafe {
+            if size == 4 {
+                AtomicU32::from_ptr(ptr as *mut u32)
+                    .compare_exchange(current as u32, new as u32, SeqCst, SeqCst)
+                    .is_ok()
+            } else {
+                AtomicU64::from_ptr(ptr as *mut u64)
+                    .compare_exchange(current, new, SeqCst, SeqCst)
+                    .is_ok()
+            }
+        };
+        Ok(replaced)
+    }
+}
+
+/// Guest memory used by the walk of the guest page tables on a shadow page fault.
+///
+/// Paging-structure entries at guest physical addresses which are not mapped by
+/// `map`, or written but not writable, are recorded in `unmapped`, as the
+/// processor would cause an EPT violation on such accesses.
+struct WalkMemory<'a> {
+    mem: &'a dyn GuestMemory,
+    map: &'a dyn GuestPhysMap,
+    unmapped: Cell<Option<GuestPhysAddr>>,
+}
+
+impl WalkMemory<'_> {
+    /// Check that the entry at `gpa`, which does not cross pages, is accessible.
+    fn check(&self, gpa: GuestPhysAddr, write: bool) -> AxResult {
+        match self.map.translate(gpa) {
+            Some((_, writable)) if writable || !write => Ok(()),
+            _ => {
+                self.unmapped.set(Some(gpa));
+                ax_err!(BadAddress, "guest paging structure is not mapped")
+            }
+        }
+    }
+}
+
+impl GuestMemory for WalkMemory<'_> {
+    fn read_phys(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        self.check(gpa, false)?;
+        self.mem.read_phys(gpa, buf)
+    }
+
+    fn write_phys(&self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        self.check(gpa, true)?;
+        self.mem.write_phys(gpa, buf)
+    }
+
+    fn compare_exchange_phys(
+        &self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        current: u64,
+        new: u64,
+    ) -> AxResult<bool> {
+        self.check(gpa, true)?;
+        self.mem.compare_exchange_phys(gpa, size, current, new)
+    }
+}
+
+/// Storage of shadow paging structures.
+pub trait ShadowTables {
+    /// Allocate a zeroed table.
+    fn alloc(&mut self) -> AxResult<HostPhysAddr>;
+    /// The entries of the table at `paddr`.
+    fn entries(&mut self, paddr: HostPhysAddr) -> &mut [u64; ENTRIES];
+    /// Free all tables.
+    fn clear(&mut self);
+}
+
+/// Shadow paging structures in host frames.
+pub struct FrameTables<H: AxMmHal> {
+    frames: Vec<PhysFrame<H>>,
+}
+
+impl<H: AxMmHal> FrameTables<H> {
+    pub fn new() -> Self {
+        Self { frames: Vec::new() }
+    }
+}
+
+impl<H: AxMmHal> ShadowTables for FrameTables<H> {
+    fn alloc(&mut self) -> AxResult<HostPhysAddr> {
+        let frame = PhysFrame::alloc_zero()?;
+        let paddr = frame.start_paddr();
+        self.frames.push(frame);
+        Ok(paddr)
+    }
+
+    fn entries(&mut self, paddr: HostPhysAddr) -> &mut [u64; ENTRIES] {
+        unsafe { &mut *(H::phys_to_virt(paddr).as_usize() as *mut [u64; ENTRIES]) }
+    }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+/// The result of handling a page fault of a guest running on shadow page tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowFault {
+    /// The shadow page tables are filled, and the access can be retried. The guest
+    /// TLB must be flushed first if `flush_tlb`.
+    Fixed { flush_tlb: bool },
+    /// The page fault is caused by the guest page tables, and is to be injected.
+    Guest(PageFault),
+    /// The guest physical address is not backed by host memory.
+    Unmapped(GuestPhysAddr),
+}
+
+/// Shadow page tables, which map guest linear addresses to host physical
+/// addresses as the guest page tables and the EPT together.
+///
+/// Shadow entries are filled on page faults at 4K granularity. Guest frames used
+/// as guest paging structures are mapped read-only, and a write to one of them
+/// drops all shadow entries. The frame is then left writable until the next
+/// [`flush`](Self::flush), as the guest must flush its TLB by loading CR3 or by
+/// INVLPG after changing its page tables anyway.
+pub struct ShadowMmu<T: ShadowTables> {
+    tables: T,
+    root: HostPhysAddr,
+    /// 3 for PAE paging, 4 for 4-level paging, or 5 for 5-level paging.
+    levels: usize,
+    /// Frames of guest paging structures, mapped read-only.
+    protected: BTreeSet<usize>,
+    /// Frames of guest paging structures written by the guest since the last flush.
+    unsynced: BTreeSet<usize>,
+    /// Writable shadow leaf entries of each frame.
+    writable: BTreeMap<usize, Vec<(HostPhysAddr, usize)>>,
+    /// Linear addresses and leaf entries of pages mapped for the processor
+    /// itself, which are kept when shadow entries are dropped.
+    fixed: Vec<(u64, u64)>,
+}
+
+impl<T: ShadowTables> ShadowMmu<T> {
+    /// Create empty shadow page tables of `levels` levels, 3 for PAE paging, 4 for
+    /// 4-level paging or 5 for 5-level paging.
+    pub fn new(mut tables: T, levels: usize) -> AxResult<Self> {
+        let root = Self::alloc_root(&mut tables, levels)?;
+        Ok(Self {
+            tables,
+            root,
+            levels,
+            protected: BTreeSet::new(),
+            unsynced: BTreeSet::new(),
+            writable: BTreeMap::new(),
+            fixed: Vec::new(),
+        })
+    }
+
+    fn alloc_root(tables: &mut T, levels: usize) -> AxResult<HostPhysAddr> {
+        let root = tables.alloc()?;
+        // CR3 only holds 32 bits of the PDPT address with PAE paging.
+        if levels == 3 && root.as_usize() >= 1 << 32 {
+            return ax_err!(NoMemory, "PAE shadow page tables must be below 4 GiB");
+        }
+        Ok(root)
+    }
+
+    /// The root table to be loaded into CR3.
+    pub fn root(&self) -> HostPhysAddr {
+        self.root
+    }
+
+    /// Drop all shadow entries, and switch to `levels` levels. Called when the guest
+    /// loads CR3 or changes its paging mode.
+    pub fn flush(&mut self, levels: usize) -> AxResult {
+        self.levels = levels;
+        self.unsynced.clear();
+        self.zap()
+    }
+
+    /// Drop all shadow entries, and stop protecting guest paging structures.
+    fn zap(&mut self) -> AxResult {
+        self.tables.clear();
+        self.protected.clear();
+        self.writable.clear();
+        self.root = Self::alloc_root(&mut self.tables, self.levels)?;
+        for i in 0..self.fixed.len() {
+            let (va, leaf) = self.fixed[i];
+            self.map_page(va, leaf, 1 << PAGE_SHIFT)?;
+        }
+        Ok(())
+    }
+
+    /// Map 4K pages at their linear addresses for the processor itself, e.g. the
+    /// TSS of virtual-8086 mode, instead of those mapped earlier. They are only
+    /// accessible in supervisor mode, and kept until replaced.
+    ///
+    /// The root may change, it must be loaded into CR3 again.
+    pub fn set_fixed_mappings(&mut self, pages: &[(GuestVirtAddr, HostPhysAddr)]) -> AxResult {
+        self.fixed = pages
+            .iter()
+            .map(|(vaddr, paddr)| {
+                let leaf = (paddr.as_usize() as u64 & PTE_ADDR_MASK) | PTE_PRESENT | PTE_WRITABLE;
+                (vaddr.as_usize() as u64, leaf)
+            })
+            .collect();
+        self.zap()
+    }
+
+    /// Install the 4K shadow leaf entry of `va`, derived from a guest page of
+    /// `page_size`. Return the table and the index of the entry.
+    fn map_page(
+        &mut self,
+        va: u64,
+        leaf: u64,
+        page_size: usize,
+    ) -> AxResult<(HostPhysAddr, usize)> {
+        let mut table = self.root;
+        for level in (2..=self.levels).rev() {
+            let index = self.index(va, level);
+            let mut entry = self.tables.entries(table)[index];
+            if entry & PTE_PRESENT == 0 {
+                let next = self.tables.alloc()?;
+                entry = next.as_usize() as u64 | PTE_PRESENT;
+                // PDPTEs of PAE paging do not control access rights.
+                if !(self.levels == 3 && level == 3) {
+                    entry |= PTE_WRITABLE | PTE_USER;
+                }
+            }
+            if page_size >= 1 << (PAGE_SHIFT + 9 * (level - 1)) {
+                entry |= SHADOW_LARGE_PAGE;
+            }
+            self.tables.entries(table)[index] = entry;
+            table = HostPhysAddr::from((entry & PTE_ADDR_MASK) as usize);
+        }
+        let index = self.index(va, 1);
+        self.tables.entries(table)[index] = leaf;
+        Ok((table, index))
+    }
+
+    /// Note a write of the vCPU to guest physical memory at `paddr`, e.g. by
+    /// instruction emulation. Return whether all shadow entries are dropped, as
+    /// `paddr` is in a guest paging structure. The root must then be loaded into
+    /// CR3 again.
+    pub fn write_guest_phys(&mut self, paddr: GuestPhysAddr) -> AxResult<bool> {
+        if !self.protected.contains(&(paddr.as_usize() >> PAGE_SHIFT)) {
+            return Ok(false);
+        }
+        self.zap()?;
+        Ok(true)
+    }
+
+    /// The index of `vaddr` in a table of `level`.
+    fn index(&self, vaddr: u64, level: usize) -> usize {
+        let index = (vaddr >> (PAGE_SHIFT + 9 * (level - 1))) as usize % ENTRIES;
+        // The PDPT of PAE paging has 4 entries.
+        if self.levels == 3 && level == 3 {
+            index % 4
+        } else {
+            index
+        }
+    }
+
+    /// Drop the shadow entries of the guest page containing `vaddr`, for INVLPG.
+    pub fn invalidate(&mut self, vaddr: GuestVirtAddr) {
+        let va = vaddr.as_usize() as u64;
+        let mut table = self.root;
+        for level in (1..=self.levels).rev() {
+            let index = self.index(va, level);
+            let entry = self.tables.entries(table)[index];
+            if entry & PTE_PRESENT == 0 {
+                return;
+            }
+            if level == 1 || entry & SHADOW_LARGE_PAGE != 0 {
+                self.tables.entries(table)[index] = 0;
+                // A 4M guest page of 32-bit paging spans two shadow PDEs.
+                if level == 2 && self.levels == 3 {
+                    let buddy = index ^ 1;
+                    if self.tables.entries(table)[buddy] & SHADOW_LARGE_PAGE != 0 {
+                        self.tables.entries(table)[buddy] = 0;
+                    }
+                }
+                return;
+            }
+            table = HostPhysAddr::from((entry & PTE_ADDR_MASK) as usize);
+        }
+    }
+
+    /// Handle a page fault of the guest at `vaddr`, with the guest paging state and
+    /// the faulting access in `info`.
+    ///
+    /// The guest page tables are read and updated through `mem`, and guest physical
+    /// addresses are translated by `map`. Guest paging structures not mapped by
+    /// `map` are reported as [`ShadowFault::Unmapped`].
+    pub fn handle_page_fault(
+        &mut self,
+        mem: &dyn GuestMemory,
+        map: &dyn GuestPhysMap,
+        info: &GuestPageWalkInfo,
+        vaddr: GuestVirtAddr,
+    ) -> AxResult<ShadowFault> {
+        let walk = WalkMemory {
+            mem,
+            map,
+            unmapped: Cell::new(None),
+        };
+        let translation = match paging::translate(&walk, info, vaddr) {
+            Ok(Ok(translation)) => translation,
+            Ok(Err(fault)) => return Ok(ShadowFault::Guest(fault)),
+            Err(err) => {
+                return match walk.unmapped.get() {
+                    Some(gpa) => Ok(ShadowFault::Unmapped(gpa)),
+                    None => Err(err),
+                };
+            }
+        };
+        let Some((hpa, map_writable)) = map.translate(translation.paddr) else {
+            return Ok(ShadowFault::Unmapped(translation.paddr));
+        };
+        let frame = translation.paddr.as_usize() >> PAGE_SHIFT;
+
+        let mut flush_tlb = false;
+        if info.is_write_access && self.protected.contains(&frame) {
+            // Shadow entries derived from the written paging structure can not be
+            // found, drop all of them.
+            trace!("Guest writes its page table at {:#x}", translation.paddr);
+            self.zap()?;
+            self.unsynced.insert(frame);
+            flush_tlb = true;
+        }
+        for table in translation.tables() {
+            let table = table.as_usize() >> PAGE_SHIFT;
+            if self.unsynced.contains(&table) || !self.protected.insert(table) {
+                continue;
+            }
+            for (leaf_table, index) in self.writable.remove(&table).unwrap_or_default() {
+                self.tables.entries(leaf_table)[index] &= !PTE_WRITABLE;
+                flush_tlb = true;
+            }
+        }
+
+        // A supervisor write to a read-only page is allowed with CR0.WP = 0. The
+        // page is then writable but not accessible in user mode.
+        let supervisor_write =
+            info.is_write_access && !info.is_user_mode_access && !translation.writable;
+        // Pages which are not dirty yet are mapped read-only, to set the dirty flag
+        // on the first write.
+        let writable = (translation.writable || supervisor_write)
+            && translation.dirty
+            && map_writable
+            && !self.protected.contains(&frame);
+        let mut leaf = (hpa.as_usize() as u64 & PTE_ADDR_MASK) | PTE_PRESENT;
+        if writable {
+            leaf |= PTE_WRITABLE;
+        }
+        if translation.user && !supervisor_write {
+            leaf |= PTE_USER;
+        }
+        if !translation.executable && info.nxe {
+            leaf |= PTE_NO_EXECUTE;
+        }
+
+        let (table, index) = self.map_page(vaddr.as_usize() as u64, leaf, translation.page_size)?;
+        if writable {
+            self.writable.entry(frame).or_default().push((table, index));
+        }
+        Ok(ShadowFault::Fixed { flush_tlb })
+    }
+
+    /// The shadow leaf entry of `vaddr`.
+    #[cfg(test)]
+    fn leaf(&mut self, vaddr: usize) -> Option<u64> {
+        let mut table = self.root;
+        for level in (2..=self.levels).rev() {
+            let index = self.index(vaddr as u64, level);
+            let entry = self.tables.entries(table)[index];
+            if entry & PTE_PRESENT == 0 {
+                return None;
+            }
+            table = HostPhysAddr::from((entry & PTE_ADDR_MASK) as usize);
+        }
+        let index = self.index(vaddr as u64, 1);
+        Some(self.tables.entries(table)[index]).filter(|&entry| entry != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::memory::TestGuestMemory;
+    use crate::vmx::paging::test::{D, P, PS, U, W, level4_info, level4_tables, set_entry};
+    use alloc::boxed::Box;
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    /// Tables on the heap, the table at index `i` is at address `(i + 1) << 12`.
+    struct TestTables(Vec<Box<[u64; ENTRIES]>>);
+
+    impl ShadowTables for TestTables {
+        fn alloc(&mut self) -> AxResult<HostPhysAddr> {
+            self.0.push(Box::new([0; ENTRIES]));
+            Ok(HostPhysAddr::from(self.0.len() << PAGE_SHIFT))
+        }
+
+        fn entries(&mut self, paddr: HostPhysAddr) -> &mut [u64; ENTRIES] {
+            &mut self.0[(paddr.as_usize() >> PAGE_SHIFT) - 1]
+        }
+
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    /// Guest RAM below 4M at host physical address 0x1_0000_0000, read-only above 2M.
+    struct TestMap;
+
+    const HOST_BASE: u64 = 0x1_0000_0000;
+
+    impl GuestPhysMap for TestMap {
+        fn translate(&self, gpa: GuestPhysAddr) -> Option<(HostPhysAddr, bool)> {
+            let gpa = gpa.as_usize();
+            (gpa < 0x40_0000).then(|| {
+                let hpa = HostPhysAddr::from(HOST_BASE as usize + gpa);
+                (hpa, gpa < 0x20_0000)
+            })
+        }
+    }
+
+    fn access(write: bool) -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            is_write_access: write,
+            ..level4_info(0x1000)
+        }
+    }
+
+    fn fault(
+        mmu: &mut ShadowMmu<TestTables>,
+        mem: &TestGuestMemory,
+        vaddr: usize,
+        write: bool,
+    ) -> ShadowFault {
+        mmu.handle_page_fault(mem, &TestMap, &access(write), vaddr.into())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fill() {
+        let mem = level4_tables();
+        let mut mmu = ShadowMmu::new(TestTables(Vec::new()), 4).unwrap();
+
+        // Read-only until the first write sets the dirty flag.
+        assert_eq!(
+            fault(&mut mmu, &mem, 0x40_0123, false),
+            ShadowFault::Fixed { flush_tlb: false }
+        );
+        assert_eq!(mmu.leaf(0x40_0000), Some((HOST_BASE + 0x8000) | P | U));
+        assert_eq!(
+            fault(&mut mmu, &mem, 0x40_0123, true),
+            ShadowFault::Fixed { flush_tlb: false }
+        );
+        assert_eq!(mmu.leaf(0x40_0000), Some((HOST_BASE + 0x8000) | P | W | U));
+
+        // 4K pages of a 2M guest page, which is read-only.
+        fault(&mut mmu, &mem, 0x7f_f000, false);
+        assert_eq!(mmu.leaf(0x7f_f000), Some((HOST_BASE + 0x3f_f000) | P));
+        mmu.invalidate(0x60_0000.into());
+        assert_eq!(mmu.leaf(0x7f_f000), None);
+        assert!(mmu.leaf(0x40_0000).is_some());
+        mmu.invalidate(0x40_0000.into());
+        assert_eq!(mmu.leaf(0x40_0000), None);
+
+        // Faults of the guest page tables, and unmapped guest physical addresses.
+        let not_present = fault(&mut mmu, &mem, 0x80_0000, false);
+        assert_eq!(
+            not_present,
+            ShadowFault::Guest(PageFault {
+                addr: 0x80_0000.into(),
+                error_code: PageFaultErrorCode::empty(),
+            })
+        );
+        set_entry(&mem, 0x3000, 4, 0x40_0000 | P | PS);
+        assert_eq!(
+            fault(&mut mmu, &mem, 0x80_0000, false),
+            ShadowFault::Unmapped(0x40_0000.into())
+        );
+
+        // The shadow page tables are empty after a flush.
+        mmu.flush(3).unwrap();
+        assert_eq!(mmu.leaf(0x7f_f000), None);
+    }
+
+    #[test]
+    fn test_unmapped_tables() {
+        let mem = level4_tables();
+        let mut mmu = ShadowMmu::new(TestTables(Vec::new()), 4).unwrap();
+
+        // CR3 outside the guest RAM.
+        let info = level4_info(0x80_0000);
+        assert_eq!(
+            mmu.handle_page_fault(&mem, &TestMap, &info, 0x40_0000.into())
+                .unwrap(),
+            ShadowFault::Unmapped(0x80_0000.into())
+        );
+
+        // A PDE pointing to a page table outside the guest RAM.
+        set_entry(&mem, 0x3000, 5, 0x50_0000 | P | W | U);
+        assert_eq!(
+            fault(&mut mmu, &mem, 0xa0_1000, false),
+            ShadowFault::Unmapped(0x50_0008.into())
+        );
+        // A read-only page table, of which the accessed flag can not be set.
+        set_entry(&mem, 0x3000, 6, 0x20_0000 | P | W | U);
+        set_entry(&mem, 0x20_0000, 0, 0x8000 | P | W | U);
+        assert_eq!(
+            fault(&mut mmu, &mem, 0xc0_0000, false),
+            ShadowFault::Unmapped(0x20_0000.into())
+        );
+    }
+
+    #[test]
+    fn test_write_protection() {
+        let mem = level4_tables();
+        // 0x40_1000 maps the guest page table at 0x4000, 0x40_2000 maps 0x5000.
+        set_entry(&mem, 0x4000, 1, 0x4000 | P | W | D);
+        set_entry(&mem, 0x4000, 2, 0x5000 | P | W);
+        let mut mmu = ShadowMmu::new(TestTables(Vec::new()), 4).unwrap();
+
+        fault(&mut mmu, &mem, 0x40_2000, true);
+        assert_eq!(mmu.leaf(0x40_2000), Some((HOST_BASE + 0x5000) | P | W));
+        assert!(!mmu.write_guest_phys(0x5008.into()).unwrap());
+        // The page table is read-only even if the guest maps it writable and dirty.
+        fault(&mut mmu, &mem, 0x40_1000, false);
+        assert_eq!(mmu.leaf(0x40_1000), Some((HOST_BASE + 0x4000) | P));
+        // Writing it drops all shadow entries, and leaves it writable until the next flush.
+        assert_eq!(
+            fault(&mut mmu, &mem, 0x40_1000, true),
+            ShadowFault::Fixed { flush_tlb: true }
+        );
+        assert_eq!(mmu.leaf(0x40_2000), None);
+        assert_eq!(mmu.leaf(0x40_1000), Some((HOST_BASE + 0x4000) | P | W));
+
+        // 0x5000 becomes a page table, and its writable mapping is write-protected.
+        fault(&mut mmu, &mem, 0x40_2000, true);
+        set_entry(&mem, 0x3000, 4, 0x5000 | P | W);
+        set_entry(&mem, 0x5000, 0, 0x9000 | P | W);
+        assert_eq!(
+            fault(&mut mmu, &mem, 0x80_0000, false),
+            ShadowFault::Fixed { flush_tlb: true }
+        );
+        assert_eq!(mmu.leaf(0x40_2000), Some((HOST_BASE + 0x5000) | P));
+
+        // The page table at 0x4000 is protected again after a flush.
+        mmu.flush(4).unwrap();
+        fault(&mut mmu, &mem, 0x40_1000, true);
+        assert_eq!(mmu.leaf(0x40_1000), Some((HOST_BASE + 0x4000) | P));
+        // Writes of the vCPU to the page table drop all shadow entries as well.
+        assert!(mmu.write_guest_phys(0x4008.into()).unwrap());
+        assert_eq!(mmu.leaf(0x40_1000), None);
+
+        // Pages mapped for the processor are kept when all shadow entries are dropped.
+        let tss = HostPhysAddr::from(0x7000);
+        mmu.set_fixed_mappings(&[(0xffff_d000.into(), tss)])
+            .unwrap();
+        fault(&mut mmu, &mem, 0x40_1000, true);
+        assert_eq!(
+            fault(&mut mmu, &mem, 0x40_1000, true),
+            ShadowFault::Fixed { flush_tlb: true }
+        );
+        assert_eq!(mmu.leaf(0x40_1000), Some((HOST_BASE + 0x4000) | P | W));
+        assert_eq!(mmu.leaf(0xffff_d000), Some(0x7000 | P | W));
+    }
+}
@@ -0,0 +1,79 @@
+use axaddrspace::{GuestPhysAddr, HostPhysAddr};
+use bit_field::BitField;
+
+/// Execute access for user-mode linear addresses, bit 10 of EPT paging-structure
+/// entries, with mode-based execute control. Bit 2 then only allows supervisor-mode
+/// linear addresses to be executed.
+pub const EPT_USER_EXECUTE: u64 = 1 << 10;
+/// Sub-page write permissions, bit 61 of EPT entries mapping 4K pages. Writes to
+/// a read-only page with this bit set are checked against the SPP table.
+pub const EPT_SPP: u64 = 1 << 61;
+
+/// The number of 128-byte sub-pages in a 4K page, each with a write permission.
+pub const SPP_SUBPAGES: usize = 32;
+/// The valid bit in non-leaf SPP table entries.
+const SPPT_ENTRY_VALID: u64 = 1 << 0;
+
+/// A non-leaf SPP table entry referencing the next-level table at `paddr`.
+pub fn spp_table_entry(paddr: HostPhysAddr) -> u64 {
+    (paddr.as_usize() as u64 & !0xfff) | SPPT_ENTRY_VALID
+}
+
+/// A leaf SPP table entry for a 4K page, where bit `i` of `writable` allows
+/// writes to the sub-page `i`. The write permission of sub-page `i` is in bit
+/// `2 * i`, odd bits are reserved.
+pub fn spp_leaf_entry(writable: u32) -> u64 {
+    (0..SPP_SUBPAGES)
+        .filter(|&i| writable.get_bit(i))
+        .fold(0, |entry, i| entry | 1 << (2 * i))
+}
+
+/// The writable sub-pages of a leaf SPP table entry, the inverse of [`spp_leaf_entry`].
+pub fn spp_writable_subpages(entry: u64) -> u32 {
+    (0..SPP_SUBPAGES)
+        .filter(|&i| entry.get_bit(2 * i))
+        .fold(0, |writable, i| writable | 1 << i)
+}
+
+/// A VM exit caused by the SPP table. (SDM Vol. 3C, Section 28.2.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SppEvent {
+    /// The guest-physical address of the write.
+    pub guest_phys_addr: GuestPhysAddr,
+    /// An SPP table entry is misconfigured, rather than missing for the address.
+    pub misconfig: bool,
+}
+
+impl SppEvent {
+    /// Decode from the exit qualification and the guest-physical address.
+    pub fn from_raw(qualification: u64, guest_phys_addr: u64) -> Self {
+        Self {
+            guest_phys_addr: GuestPhysAddr::from(guest_phys_addr as usize),
+            misconfig: qualification.get_bit(11),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spp_entries() {
+        assert_eq!(spp_leaf_entry(0), 0);
+        assert_eq!(spp_leaf_entry(0b1011), 0b0100_0101);
+        assert_eq!(spp_leaf_entry(u32::MAX), 0x5555_5555_5555_5555);
+        for writable in [0, 1 << 31, 0x8000_0001, 0x1234_5678, u32::MAX] {
+            assert_eq!(spp_writable_subpages(spp_leaf_entry(writable)), writable);
+        }
+        assert_eq!(spp_table_entry(HostPhysAddr::from(0x12_3456)), 0x12_3001);
+    }
+
+    #[test]
+    fn test_spp_event() {
+        let miss = SppEvent::from_raw(0, 0x1000);
+        assert_eq!(miss.guest_phys_addr, GuestPhysAddr::from(0x1000));
+        assert!(!miss.misconfig);
+        assert!(SppEvent::from_raw(1 << 11, 0x1000).misconfig);
+    }
+}
@@ -4,8 +4,9 @@ use bitflags::bitflags;
 use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
 
 use axaddrspace::{AxMmHal, HostPhysAddr, PhysFrame};
-use axerrno::AxResult;
+use axerrno::{AxResult, ax_err};
 
+use super::instructions::{InvEptType, InvVpidType};
 use crate::msr::{Msr, MsrReadWrite};
 
 /// VMCS/VMXON region in 4K size. (SDM Vol. 3C, Section 24.2)
@@ -202,6 +203,117 @@ impl VmxBasic {
     }
 }
 
+/// Reporting Register of Miscellaneous VMX Capabilities. (SDM Vol. 3D, Appendix A.6)
+#[derive(Debug)]
+pub struct VmxMisc {
+    /// The VMX-preemption timer counts down by 1 every time bit X in the TSC
+    /// changes, where X is reported in this field.
+    pub preemption_timer_rate: u8,
+    /// The HLT activity state is supported.
+    pub activity_hlt: bool,
+}
+
+impl MsrReadWrite for VmxMisc {
+    const MSR: Msr = Msr::IA32_VMX_MISC;
+}
+
+impl VmxMisc {
+    /// Read the current IA32_VMX_MISC flags.
+    pub fn read() -> Self {
+        let msr = Self::read_raw();
+        Self {
+            preemption_timer_rate: msr.get_bits(0..5) as u8,
+            activity_hlt: msr.get_bit(6),
+        }
+    }
+}
+
+/// Reporting Register of VPID and EPT Capabilities. (SDM Vol. 3D, Appendix A.10)
+#[derive(Debug, Default, Clone)]
+pub struct VmxEptVpidCap {
+    /// A page-walk length of 4 is supported.
+    pub ept_walk_length_4: bool,
+    /// A page-walk length of 5 is supported.
+    pub ept_walk_length_5: bool,
+    /// The EPT paging-structure memory type can be uncacheable (UC).
+    pub ept_uc: bool,
+    /// The EPT paging-structure memory type can be write-back (WB).
+    pub ept_wb: bool,
+    /// The INVEPT instruction is supported.
+    pub invept: bool,
+    /// Accessed and dirty flags for EPT are supported.
+    pub ept_accessed_dirty: bool,
+    /// The supervisor shadow-stack control is supported.
+    pub ept_supervisor_shadow_stack: bool,
+    /// The single-context INVEPT type is supported.
+    pub invept_single_context: bool,
+    /// The all-context INVEPT type is supported.
+    pub invept_all_context: bool,
+    /// The INVVPID instruction is supported.
+    pub invvpid: bool,
+    /// The single-context INVVPID type is supported.
+    pub invvpid_single_context: bool,
+    /// The all-context INVVPID type is supported.
+    pub invvpid_all_context: bool,
+}
+
+impl MsrReadWrite for VmxEptVpidCap {
+    const MSR: Msr = Msr::IA32_VMX_EPT_VPID_CAP;
+}
+
+impl VmxEptVpidCap {
+    /// Read the current IA32_VMX_EPT_VPID_CAP flags.
+    pub fn read() -> Self {
+        Self::from_raw(Self::read_raw())
+    }
+
+    /// Decode the raw value of IA32_VMX_EPT_VPID_CAP.
+    pub fn from_raw(msr: u64) -> Self {
+        Self {
+            ept_walk_length_4: msr.get_bit(6),
+            ept_walk_length_5: msr.get_bit(7),
+            ept_uc: msr.get_bit(8),
+            ept_wb: msr.get_bit(14),
+            invept: msr.get_bit(20),
+            ept_accessed_dirty: msr.get_bit(21),
+            ept_supervisor_shadow_stack: msr.get_bit(23),
+            invept_single_context: msr.get_bit(25),
+            invept_all_context: msr.get_bit(26),
+            invvpid: msr.get_bit(32),
+            invvpid_single_context: msr.get_bit(41),
+            invvpid_all_context: msr.get_bit(42),
+        }
+    }
+
+    /// The INVEPT type to flush all mappings of one EPTP, preferring the
+    /// single-context type, or `None` if INVEPT is not usable.
+    pub fn ept_flush_type(&self) -> Option<InvEptType> {
+        if !self.invept {
+            None
+        } else if self.invept_single_context {
+            Some(InvEptType::SingleContext)
+        } else if self.invept_all_context {
+            Some(InvEptType::Global)
+        } else {
+            None
+        }
+    }
+
+    /// The INVVPID type to flush all mappings of one VPID, preferring the
+    /// single-context type, or `None` if INVVPID is not usable.
+    pub fn vpid_flush_type(&self) -> Option<InvVpidType> {
+        if !self.invvpid {
+            None
+        } else if self.invvpid_single_context {
+            Some(InvVpidType::SingleContext)
+        } else if self.invvpid_all_context {
+            Some(InvVpidType::AllContext)
+        } else {
+            None
+        }
+    }
+}
+
 bitflags! {
     /// IA32_FEATURE_CONTROL flags.
     pub struct FeatureControlFlags: u64 {
@@ -254,15 +366,177 @@ bitflags! {
         const WALK_LENGTH_3 = 2 << 3;
         /// EPT page-walk length 4.
         const WALK_LENGTH_4 = 3 << 3;
+        /// EPT page-walk length 5.
+        const WALK_LENGTH_5 = 4 << 3;
         /// Setting this control to 1 enables accessed and dirty flags for EPT.
         const ENABLE_ACCESSED_DIRTY = 1 << 6;
+        /// Setting this control to 1 enables enforcement of access rights for
+        /// supervisor shadow-stack pages.
+        const SUPERVISOR_SHADOW_STACK = 1 << 7;
+    }
+}
+
+/// Memory type used to access the EPT paging structures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptMemoryType {
+    /// Uncacheable (UC).
+    Uncacheable,
+    /// Write-back (WB).
+    WriteBack,
+}
+
+/// Configuration of the EPT pointer, checked against the capabilities reported
+/// by IA32_VMX_EPT_VPID_CAP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptpConfig {
+    /// The number of EPT paging levels, 4 or 5. 5 levels are required by guest
+    /// physical addresses wider than 48 bits (256 TB).
+    pub levels: u8,
+    /// Memory type used to access the EPT paging structures.
+    pub memory_type: EptMemoryType,
+    /// Enable accessed and dirty flags in EPT entries.
+    pub accessed_dirty: bool,
+    /// Enforce access rights of supervisor shadow-stack pages.
+    pub supervisor_shadow_stack: bool,
+}
+
+impl Default for EptpConfig {
+    /// 4-level, write-back, with accessed and dirty flags.
+    fn default() -> Self {
+        Self {
+            levels: 4,
+            memory_type: EptMemoryType::WriteBack,
+            accessed_dirty: true,
+            supervisor_shadow_stack: false,
+        }
+    }
+}
+
+impl EptpConfig {
+    /// The preferred configuration supported by `cap` for guest physical
+    /// addresses of `guest_phys_bits` bits: write-back, with accessed and dirty
+    /// flags if possible.
+    pub fn supported(cap: &VmxEptVpidCap, guest_phys_bits: u8) -> AxResult<Self> {
+        let config = Self {
+            levels: if guest_phys_bits > 48 { 5 } else { 4 },
+            memory_type: if cap.ept_wb {
+                EptMemoryType::WriteBack
+            } else {
+                EptMemoryType::Uncacheable
+            },
+            accessed_dirty: cap.ept_accessed_dirty,
+            supervisor_shadow_stack: false,
+        };
+        config.validate(cap)?;
+        Ok(config)
+    }
+
+    /// Check the configuration against `cap`.
+    pub fn validate(&self, cap: &VmxEptVpidCap) -> AxResult {
+        match self.levels {
+            4 if !cap.ept_walk_length_4 => {
+                return ax_err!(Unsupported, "4-level EPT is not supported");
+            }
+            5 if !cap.ept_walk_length_5 => {
+                return ax_err!(Unsupported, "5-level EPT is not supported");
+            }
+            4 | 5 => {}
+            _ => return ax_err!(InvalidInput, "EPT must have 4 or 5 levels"),
+        }
+        match self.memory_type {
+            EptMemoryType::Uncacheable if !cap.ept_uc => {
+                return ax_err!(Unsupported, "uncacheable EPT structures are not supported");
+            }
+            EptMemoryType::WriteBack if !cap.ept_wb => {
+                return ax_err!(Unsupported, "write-back EPT structures are not supported");
+            }
+            _ => {}
+        }
+        if self.accessed_dirty && !cap.ept_accessed_dirty {
+            return ax_err!(
+                Unsupported,
+                "EPT accessed and dirty flags are not supported"
+            );
+        }
+        if self.supervisor_shadow_stack && !cap.ept_supervisor_shadow_stack {
+            return ax_err!(
+                Unsupported,
+                "EPT supervisor shadow-stack control is not supported"
+            );
+        }
+        if cap.ept_flush_type().is_none() {
+            return ax_err!(Unsupported, "INVEPT is not supported");
+        }
+        Ok(())
+    }
+
+    /// The EPT pointer to the root paging structure at `root_paddr`.
+    pub fn eptp(&self, root_paddr: HostPhysAddr) -> EPTPointer {
+        let aligned_addr = root_paddr.as_usize() & !(PAGE_SIZE - 1);
+        let mut eptp = EPTPointer::from_bits_retain(aligned_addr as u64);
+        eptp |= match self.memory_type {
+            EptMemoryType::Uncacheable => EPTPointer::MEM_TYPE_UC,
+            EptMemoryType::WriteBack => EPTPointer::MEM_TYPE_WB,
+        };
+        eptp |= if self.levels == 5 {
+            EPTPointer::WALK_LENGTH_5
+        } else {
+            EPTPointer::WALK_LENGTH_4
+        };
+        if self.accessed_dirty {
+            eptp |= EPTPointer::ENABLE_ACCESSED_DIRTY;
+        }
+        if self.supervisor_shadow_stack {
+            eptp |= EPTPointer::SUPERVISOR_SHADOW_STACK;
+        }
+        eptp
     }
 }
 
-impl EPTPointer {
-    pub fn from_table_phys(pml4_paddr: HostPhysAddr) -> Self {
-        let aligned_addr = pml4_paddr.as_usize() & !(PAGE_SIZE - 1);
-        let flags = Self::from_bits_retain(aligned_addr as u64);
-        flags | Self::MEM_TYPE_WB | Self::WALK_LENGTH_4 | Self::ENABLE_ACCESSED_DIRTY
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_eptp_config() {
+        // Walk length 4, WB, INVEPT with single-context, A/D.
+        let cap = VmxEptVpidCap::from_raw(1 << 6 | 1 << 14 | 1 << 20 | 1 << 21 | 1 << 25);
+        let config = EptpConfig::supported(&cap, 48).unwrap();
+        assert_eq!(config, EptpConfig::default());
+        assert_eq!(
+            config.eptp(HostPhysAddr::from(0x12_3456)).bits(),
+            0x12_3000 | 6 | 3 << 3 | 1 << 6
+        );
+        assert_eq!(cap.ept_flush_type(), Some(InvEptType::SingleContext));
+
+        // 5 levels for wider guest physical addresses.
+        assert!(EptpConfig::supported(&cap, 52).is_err());
+        let cap = VmxEptVpidCap::from_raw(1 << 6 | 1 << 7 | 1 << 8 | 1 << 20 | 1 << 26);
+        let config = EptpConfig::supported(&cap, 52).unwrap();
+        assert_eq!(config.levels, 5);
+        assert_eq!(config.memory_type, EptMemoryType::Uncacheable);
+        assert!(!config.accessed_dirty);
+        assert_eq!(
+            config.eptp(HostPhysAddr::from(0x1000)).bits(),
+            0x1000 | 4 << 3
+        );
+        assert_eq!(cap.ept_flush_type(), Some(InvEptType::Global));
+
+        let invalid = [
+            EptpConfig::default(),
+            EptpConfig {
+                levels: 3,
+                ..config
+            },
+            EptpConfig {
+                supervisor_shadow_stack: true,
+                ..config
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate(&cap).is_err(), "{config:?}");
+        }
+        // No INVEPT.
+        assert!(EptpConfig::supported(&VmxEptVpidCap::from_raw(1 << 6 | 1 << 14), 48).is_err());
     }
 }
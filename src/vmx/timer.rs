@@ -0,0 +1,484 @@
+use bit_field::BitField;
+use raw_cpuid::CpuId;
+
+use super::structs::VmxMisc;
+
+/// Fallback TSC frequency in MHz, used when it can not be determined by CPUID.
+const DEFAULT_TSC_FREQUENCY_MHZ: u64 = 3_000;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Configuration of the VMX-preemption timer when it is used as a scheduling quantum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreemptionTimerConfig {
+    /// Length of the scheduling quantum in nanoseconds.
+    pub quantum_ns: u64,
+    /// Whether the remaining timer value is saved on VM exit.
+    ///
+    /// If set, the quantum keeps counting down guest run time across VM exits
+    /// handled by the vCPU itself, and is only refilled after it has expired.
+    /// Otherwise the quantum is refilled on every VM entry.
+    pub save_on_exit: bool,
+}
+
+/// Per-vCPU state of the VMX-preemption timer.
+///
+/// The timer is programmed before every VM entry to fire at the earliest of the
+/// end of the scheduling quantum and the guest local APIC timer deadline. The
+/// quantum is kept in TSC cycles of guest run time, and the local APIC deadline
+/// in TSC values, both are converted to timer ticks on VM entry.
+pub struct PreemptionTimer {
+    config: Option<PreemptionTimerConfig>,
+    /// The quantum in TSC cycles.
+    quantum_tsc: u64,
+    /// Guest run time left in the current quantum, in TSC cycles.
+    quantum_left: Option<u64>,
+    /// The VMX-preemption timer rate reported in IA32_VMX_MISC.
+    rate: u8,
+    /// The value written into the VMCS before the last VM entry.
+    armed: Option<u32>,
+    /// Whether the VMX-preemption timer is activated in the VMCS.
+    active: bool,
+    /// Whether the VMCS controls need to be updated before the next VM entry.
+    controls_dirty: bool,
+    /// Whether the last VM exit is caused by the end of the quantum.
+    expired: bool,
+}
+
+impl PreemptionTimer {
+    /// Create a disabled [`PreemptionTimer`].
+    pub const fn new() -> Self {
+        Self {
+            config: None,
+            quantum_tsc: 0,
+            quantum_left: None,
+            rate: 0,
+            armed: None,
+            active: false,
+            controls_dirty: false,
+            expired: false,
+        }
+    }
+
+    /// Set a new quantum configuration, `None` disables the quantum.
+    pub fn configure(&mut self, config: Option<PreemptionTimerConfig>) {
+        self.configure_with(
+            config,
+            tsc_frequency_hz(),
+            VmxMisc::read().preemption_timer_rate,
+        );
+    }
+
+    fn configure_with(&mut self, config: Option<PreemptionTimerConfig>, tsc_hz: u64, rate: u8) {
+        self.quantum_tsc = config.map_or(0, |c| ns_to_tsc(c.quantum_ns, tsc_hz));
+        self.rate = rate;
+        self.config = config;
+        self.quantum_left = None;
+        self.controls_dirty = true;
+    }
+
+    /// Current quantum configuration of the timer.
+    pub fn config(&self) -> Option<PreemptionTimerConfig> {
+        self.config
+    }
+
+    /// Whether the remaining timer value is saved on VM exit.
+    pub fn save_on_exit(&self) -> bool {
+        self.config.is_some_and(|c| c.save_on_exit)
+    }
+
+    /// Whether the VMX-preemption timer is activated in the VMCS.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Record whether the VMX-preemption timer is activated in the VMCS.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    /// Take the "VMCS controls need updating" flag.
+    pub fn take_controls_dirty(&mut self) -> bool {
+        core::mem::take(&mut self.controls_dirty)
+    }
+
+    /// Whether the last VM exit is caused by the end of the quantum.
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    /// The value to be written into the VMCS before a VM entry at TSC value `now`,
+    /// or `None` if the timer is not needed.
+    ///
+    /// The quantum is refilled if it has been used up, or on every VM entry if
+    /// the timer value is not saved on VM exit.
+    pub fn next_value(&mut self, now: u64, lapic_deadline: Option<u64>) -> Option<u32> {
+        if self.config.is_some() && (self.quantum_left.is_none() || !self.save_on_exit()) {
+            self.quantum_left = Some(self.quantum_tsc);
+        }
+        let quantum = self.quantum_left.map(|left| tsc_to_ticks(left, self.rate));
+        let lapic = lapic_deadline.map(|d| tsc_to_ticks(d.saturating_sub(now), self.rate));
+        self.armed = match (quantum, lapic) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.armed
+    }
+
+    /// Account for the guest run time on a VM exit, and check whether the quantum
+    /// has been used up.
+    ///
+    /// `saved` is the timer value saved by the processor if the timer value is
+    /// saved on VM exit, and `timer_exit` is whether the VM exit is caused by the
+    /// timer. Without the saved value, the run time of other VM exits is unknown,
+    /// and the quantum is refilled on the next VM entry anyway.
+    pub fn account_exit(&mut self, saved: Option<u32>, timer_exit: bool) -> bool {
+        self.expired = false;
+        let (Some(armed), Some(left)) = (self.armed.take(), self.quantum_left) else {
+            return false;
+        };
+        let elapsed = if timer_exit {
+            armed
+        } else {
+            armed.saturating_sub(saved.unwrap_or(armed))
+        };
+        if elapsed >= tsc_to_ticks(left, self.rate) {
+            self.quantum_left = None;
+            self.expired = true;
+        } else {
+            self.quantum_left = Some(left.saturating_sub((elapsed as u64) << self.rate));
+        }
+        self.expired
+    }
+}
+
+/// Mode of the local APIC timer. (SDM Vol. 3A, Section 11.5.4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LapicTimerMode {
+    /// One-shot mode using a count-down value.
+    OneShot,
+    /// Periodic mode reloading a count-down value.
+    Periodic,
+    /// TSC-Deadline mode using absolute target value in IA32_TSC_DEADLINE MSR.
+    TscDeadline,
+}
+
+/// The guest local APIC timer.
+///
+/// Register accesses are forwarded to the emulated local APIC as usual, this
+/// only keeps track of when the timer fires, in guest TSC cycles. The timer is
+/// assumed to be clocked at the TSC frequency before the divider.
+pub struct GuestLapicTimer {
+    /// LVT Timer Register.
+    lvt: u32,
+    /// Divide Configuration Register.
+    divide_config: u32,
+    /// Initial Count Register.
+    initial_count: u32,
+    /// IA32_TSC_DEADLINE MSR.
+    tsc_deadline: u64,
+    /// The TSC value at which the timer fires next time.
+    deadline: Option<u64>,
+}
+
+impl GuestLapicTimer {
+    /// LVT timer is masked after reset.
+    const LVT_MASKED: u32 = 1 << 16;
+
+    /// Create a [`GuestLapicTimer`] in its reset state.
+    pub const fn new() -> Self {
+        Self {
+            lvt: Self::LVT_MASKED,
+            divide_config: 0,
+            initial_count: 0,
+            tsc_deadline: 0,
+            deadline: None,
+        }
+    }
+
+    /// Current timer mode.
+    pub fn mode(&self) -> LapicTimerMode {
+        match self.lvt.get_bits(17..19) {
+            0 => LapicTimerMode::OneShot,
+            1 => LapicTimerMode::Periodic,
+            _ => LapicTimerMode::TscDeadline,
+        }
+    }
+
+    /// The vector to be delivered when the timer fires.
+    pub fn vector(&self) -> u8 {
+        self.lvt.get_bits(0..8) as u8
+    }
+
+    /// Whether the timer interrupt is masked.
+    pub fn is_masked(&self) -> bool {
+        self.lvt.get_bit(16)
+    }
+
+    /// The TSC value at which the timer fires next time, if it is armed.
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    /// Divide value configured by the Divide Configuration Register.
+    fn divisor(&self) -> u64 {
+        let value = self.divide_config.get_bits(0..2) | (self.divide_config.get_bit(3) as u32) << 2;
+        if value == 0b111 { 1 } else { 2 << value }
+    }
+
+    /// Handle a write to the LVT Timer Register.
+    pub fn write_lvt(&mut self, value: u32) {
+        let old_mode = self.mode();
+        self.lvt = value;
+        if self.mode() != old_mode {
+            // Changing the timer mode disarms the timer.
+            self.initial_count = 0;
+            self.tsc_deadline = 0;
+            self.deadline = None;
+        }
+    }
+
+    /// Handle a write to the Divide Configuration Register.
+    pub fn write_divide_config(&mut self, value: u32) {
+        self.divide_config = value;
+    }
+
+    /// Handle a write to the Initial Count Register at TSC value `now`.
+    pub fn write_initial_count(&mut self, value: u32, now: u64) {
+        if self.mode() == LapicTimerMode::TscDeadline {
+            // Writes to the initial-count register are ignored in TSC-deadline mode.
+            return;
+        }
+        self.initial_count = value;
+        self.deadline = if value == 0 {
+            None
+        } else {
+            Some(now.saturating_add(self.period()))
+        };
+    }
+
+    /// Read the Current Count Register at TSC value `now`.
+    pub fn current_count(&self, now: u64) -> u32 {
+        match (self.mode(), self.deadline) {
+            (LapicTimerMode::TscDeadline, _) | (_, None) => 0,
+            (_, Some(deadline)) => (deadline.saturating_sub(now) / self.divisor()) as u32,
+        }
+    }
+
+    /// Handle a write to the IA32_TSC_DEADLINE MSR.
+    pub fn write_tsc_deadline(&mut self, value: u64) {
+        if self.mode() != LapicTimerMode::TscDeadline {
+            // Writes to the IA32_TSC_DEADLINE MSR are ignored in other modes.
+            return;
+        }
+        self.tsc_deadline = value;
+        self.deadline = if value == 0 { None } else { Some(value) };
+    }
+
+    /// Read the IA32_TSC_DEADLINE MSR.
+    pub fn read_tsc_deadline(&self) -> u64 {
+        if self.mode() == LapicTimerMode::TscDeadline {
+            self.tsc_deadline
+        } else {
+            0
+        }
+    }
+
+    /// The period of one-shot and periodic timers in TSC cycles.
+    fn period(&self) -> u64 {
+        self.initial_count as u64 * self.divisor()
+    }
+
+    /// Check whether the timer fires at TSC value `now`, rearm it if it is periodic.
+    ///
+    /// Return the vector to be injected, or `None` if the timer does not fire or
+    /// its interrupt is masked.
+    pub fn expire(&mut self, now: u64) -> Option<u8> {
+        let deadline = self.deadline.filter(|&d| now >= d)?;
+        match self.mode() {
+            LapicTimerMode::Periodic => {
+                // Skip the periods that have been missed entirely.
+                let period = self.period();
+                let missed = (now - deadline) / period;
+                self.deadline = Some(deadline + (missed + 1) * period);
+            }
+            LapicTimerMode::OneShot => self.deadline = None,
+            LapicTimerMode::TscDeadline => {
+                self.tsc_deadline = 0;
+                self.deadline = None;
+            }
+        }
+        if self.is_masked() {
+            None
+        } else {
+            Some(self.vector())
+        }
+    }
+}
+
+/// Convert `ns` nanoseconds to TSC cycles with the TSC frequency `tsc_hz`.
+pub fn ns_to_tsc(ns: u64, tsc_hz: u64) -> u64 {
+    (ns as u128 * tsc_hz as u128 / NANOS_PER_SEC).min(u64::MAX as u128) as u64
+}
+
+/// Convert TSC cycles to VMX-preemption timer ticks, given the timer rate
+/// reported in IA32_VMX_MISC.
+///
+/// The result is saturated to the range of the 32-bit timer and is never 0, so that
+/// a tiny interval does not turn into a VM exit loop without any guest progress.
+pub fn tsc_to_ticks(tsc: u64, rate: u8) -> u32 {
+    (tsc >> rate).clamp(1, u32::MAX as u64) as u32
+}
+
+/// Read the current TSC value.
+pub fn current_tsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// Get the TSC frequency of the current processor in Hz.
+pub fn tsc_frequency_hz() -> u64 {
+    let cpuid = CpuId::new();
+    if let Some(hz) = cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return hz;
+    }
+    match cpuid.get_processor_frequency_info() {
+        Some(info) if info.processor_base_frequency() != 0 => {
+            info.processor_base_frequency() as u64 * 1_000_000
+        }
+        _ => {
+            warn!(
+                "Failed to get TSC frequency by CPUID, default to {} MHz",
+                DEFAULT_TSC_FREQUENCY_MHZ
+            );
+            DEFAULT_TSC_FREQUENCY_MHZ * 1_000_000
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(ns_to_tsc(1_000_000, 2_000_000_000), 2_000_000);
+        assert_eq!(ns_to_tsc(u64::MAX, u64::MAX), u64::MAX);
+        // Ticks are never 0, and saturate to 32 bits.
+        assert_eq!(tsc_to_ticks(0, 5), 1);
+        assert_eq!(tsc_to_ticks(64, 5), 2);
+        assert_eq!(tsc_to_ticks(u64::MAX, 0), u32::MAX);
+    }
+
+    #[test]
+    fn test_quantum() {
+        let config = PreemptionTimerConfig {
+            quantum_ns: 1000,
+            save_on_exit: true,
+        };
+        let mut timer = PreemptionTimer::new();
+        assert_eq!(timer.next_value(0, None), None);
+        timer.configure_with(Some(config), 1_000_000_000, 0);
+        assert!(timer.take_controls_dirty());
+
+        // The quantum only counts guest run time, saved on VM exits.
+        assert_eq!(timer.next_value(0, None), Some(1000));
+        assert!(!timer.account_exit(Some(600), false));
+        // The local APIC timer fires first.
+        assert_eq!(timer.next_value(0, Some(100)), Some(100));
+        assert!(!timer.account_exit(Some(0), true));
+        assert_eq!(timer.next_value(0, None), Some(500));
+        assert!(timer.account_exit(Some(0), true));
+        assert!(timer.is_expired());
+        // A used up quantum is refilled.
+        assert_eq!(timer.next_value(0, Some(5000)), Some(1000));
+        assert!(!timer.account_exit(Some(900), false));
+        assert!(!timer.is_expired());
+
+        // Without saving, the quantum is refilled on every VM entry.
+        let config = PreemptionTimerConfig {
+            save_on_exit: false,
+            ..config
+        };
+        timer.configure_with(Some(config), 1_000_000_000, 1);
+        assert_eq!(timer.next_value(0, None), Some(500));
+        assert!(!timer.account_exit(None, false));
+        assert_eq!(timer.next_value(0, None), Some(500));
+        assert!(timer.account_exit(None, true));
+    }
+
+    #[test]
+    fn test_divide_config() {
+        let mut timer = GuestLapicTimer::new();
+        for (config, divisor) in [
+            (0b0000, 2),
+            (0b0001, 4),
+            (0b0010, 8),
+            (0b0011, 16),
+            (0b1000, 32),
+            (0b1001, 64),
+            (0b1010, 128),
+            (0b1011, 1),
+        ] {
+            timer.write_divide_config(config);
+            assert_eq!(timer.divisor(), divisor);
+        }
+    }
+
+    #[test]
+    fn test_one_shot_and_periodic() {
+        let mut timer = GuestLapicTimer::new();
+        // Masked after reset.
+        assert!(timer.is_masked());
+        timer.write_lvt(0x30);
+        timer.write_divide_config(0b0011);
+        timer.write_initial_count(100, 1000);
+        assert_eq!(timer.mode(), LapicTimerMode::OneShot);
+        assert_eq!(timer.deadline(), Some(2600));
+        assert_eq!(timer.current_count(1800), 50);
+        assert_eq!(timer.expire(2599), None);
+        assert_eq!(timer.expire(2600), Some(0x30));
+        assert_eq!(timer.deadline(), None);
+        assert_eq!(timer.current_count(2600), 0);
+
+        // Changing the mode disarms the timer.
+        timer.write_initial_count(100, 0);
+        timer.write_lvt((1 << 17) | 0x31);
+        assert_eq!(timer.mode(), LapicTimerMode::Periodic);
+        assert_eq!(timer.deadline(), None);
+
+        // Periodic timers skip the periods missed entirely.
+        timer.write_divide_config(0b1011);
+        timer.write_initial_count(10, 0);
+        assert_eq!(timer.expire(35), Some(0x31));
+        assert_eq!(timer.deadline(), Some(40));
+        // Masked interrupts are not delivered, but the timer keeps running.
+        timer.write_lvt((1 << 17) | (1 << 16) | 0x31);
+        assert_eq!(timer.expire(40), None);
+        assert_eq!(timer.deadline(), Some(50));
+        // Writing 0 stops the timer.
+        timer.write_initial_count(0, 45);
+        assert_eq!(timer.deadline(), None);
+    }
+
+    #[test]
+    fn test_tsc_deadline() {
+        let mut timer = GuestLapicTimer::new();
+        // IA32_TSC_DEADLINE is ignored in other modes.
+        timer.write_lvt(0x32);
+        timer.write_tsc_deadline(5000);
+        assert_eq!(timer.read_tsc_deadline(), 0);
+
+        timer.write_lvt((2 << 17) | 0x32);
+        assert_eq!(timer.mode(), LapicTimerMode::TscDeadline);
+        // The initial count is ignored in TSC-deadline mode.
+        timer.write_initial_count(100, 0);
+        assert_eq!(timer.deadline(), None);
+        timer.write_tsc_deadline(5000);
+        assert_eq!(timer.read_tsc_deadline(), 5000);
+        assert_eq!(timer.current_count(0), 0);
+        assert_eq!(timer.expire(4999), None);
+        assert_eq!(timer.expire(5000), Some(0x32));
+        assert_eq!(timer.read_tsc_deadline(), 0);
+        assert_eq!(timer.deadline(), None);
+    }
+}
@@ -1,10 +1,11 @@
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
 use bit_field::BitField;
 use core::{
     arch::naked_asm,
     fmt::{Debug, Formatter, Result},
     mem::size_of,
 };
+use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
 use raw_cpuid::CpuId;
 use x86::{
     bits64::vmx,
@@ -13,29 +14,50 @@ use x86::{
     segmentation::SegmentSelector,
 };
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags, EferFlags};
+use x86_64::structures::idt::PageFaultErrorCode;
 use x86_vlapic::EmulatedLocalApic;
 
 use axaddrspace::{
-    GuestPhysAddr, GuestVirtAddr, HostPhysAddr, NestedPageFaultInfo,
+    GuestPhysAddr, GuestVirtAddr, HostPhysAddr, NestedPageFaultInfo, PhysFrame,
     device::{AccessWidth, Port, SysRegAddr, SysRegAddrRange},
 };
 use axdevice_base::BaseDeviceOps;
-use axerrno::{AxResult, ax_err, ax_err_type};
+use axerrno::{AxError, AxResult, ax_err, ax_err_type};
 use axvcpu::{AxArchVCpu, AxVCpuExitReason, AxVCpuHal};
 use axvisor_api::vmm::{VCpuId, VMId};
+use page_table_entry::MappingFlags;
 
 use super::VmxExitInfo;
 use super::as_axerr;
-use super::definitions::VmxExitReason;
-use super::structs::{IOBitmap, MsrBitmap, VmxRegion};
+use super::cr::{self, GuestControlRegs, GuestPagingMode};
+use super::debug::{self, DebugEvent, DebugExit, DebugRegs, DebugVmcsFields, VcpuDebugControl};
+use super::definitions::{VmxActivityState, VmxExitReason, VmxInterruptionType};
+use super::hypercall::{HypercallAbi, PendingHypercall};
+use super::hyperv::{self, HyperV, HyperVConfig};
+use super::instructions::{InvVpidType, int2, invvpid, rdpkru, wrpkru};
+use super::ipi::{Ipi, IpiDelivery, IpiDeliveryMode, IpiRouter};
+use super::kvm::{self, KvmPv, KvmPvConfig};
+use super::memory::GuestMemory;
+use super::paging::{self, PageFault};
+use super::pml::{DirtyLog, PML_INDEX_EMPTY, PmlBuffer};
+use super::realmode::{
+    self, MAX_INSTRUCTION_LEN, Operand, RealModeInstruction, VM86_TSS_BASE, VM86_TSS_PAGES, Vm86,
+};
+use super::segment::{self, SegmentAccess, SegmentFault, SegmentRegister};
+use super::shadow::{EptMap, FrameTables, GuestPhysMap, MmuMode, ShadowFault, ShadowMmu};
+use super::spp::SppEvent;
+use super::structs::{EptpConfig, IOBitmap, MsrBitmap, VmxEptVpidCap, VmxMisc, VmxRegion};
+use super::timer::{self, GuestLapicTimer, PreemptionTimer, PreemptionTimerConfig};
+use super::ve::{self, VeInfo, VeInfoArea};
 use super::vmcs::{
-    self, ApicAccessExitType, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16,
-    VmcsGuest32, VmcsGuest64, VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
+    self, ApicAccessExitType, EptViolation, ExceptionExit, ExceptionMerge, VmcsControl16,
+    VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64,
+    VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
 };
+use super::vmfunc::{self, EPTP_LIST_ENTRIES, EptpList, PRIMARY_EPT_VIEW};
+use super::vpid::{self, Vpid};
 use crate::{ept::GuestPageWalkInfo, msr::Msr, regs::GeneralRegisters, vmx::vcpu};
 
-const VMX_PREEMPTION_TIMER_SET_VALUE: u32 = 1_000_000;
-
 const QEMU_EXIT_PORT: u16 = 0x604;
 const QEMU_EXIT_MAGIC: u64 = 0x2000;
 
@@ -44,9 +66,53 @@ pub struct XState {
     guest_xcr0: u64,
     host_xss: u64,
     guest_xss: u64,
+    host_pkru: u32,
+    guest_pkru: u32,
 
     xsave_available: bool,
     xsaves_available: bool,
+    pku_available: bool,
+}
+
+/// Why [`AxVCpuExitReason::Halt`] is returned by `run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxHaltReason {
+    /// The guest executed HLT, and no pending event can wake it up yet.
+    Hlt,
+    /// The vCPU waits for a SIPI, see [`VmxVcpu::send_sipi`].
+    WaitForSipi,
+    /// A hypercall deferred by [`VmxVcpu::defer_hypercall`] is not completed by
+    /// [`VmxVcpu::complete_deferred_hypercall`] yet.
+    DeferredHypercall,
+    /// The VM exit can not be handled, the guest can not continue.
+    UnsupportedExit,
+}
+
+/// An element of a string I/O instruction, INS or OUTS, emulated by the vCPU.
+#[derive(Debug, Clone, Copy)]
+struct StringIo {
+    /// The guest linear address of the memory operand.
+    gva: GuestVirtAddr,
+    /// The size of the element.
+    width: AccessWidth,
+    /// The access to the memory operand, with [`MappingFlags::USER`] at CPL 3.
+    access: MappingFlags,
+    is_in: bool,
+    is_repeat: bool,
+    /// The mask of the address size, applied to rSI, rDI and rCX.
+    address_mask: u64,
+    instruction_length: u8,
+}
+
+/// Why the memory operand of an instruction emulated in virtual-8086 mode can
+/// not be read.
+#[derive(Debug, Clone, Copy)]
+enum Vm86OperandFault {
+    /// The access exceeds the segment limit, the fault is delivered through the IVT.
+    Segment(SegmentFault),
+    /// The guest physical address is not backed by guest memory, reported as a
+    /// nested page fault before the instruction is retried.
+    Unmapped(GuestPhysAddr),
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -87,8 +153,12 @@ impl XState {
             guest_xcr0: xcr0,
             host_xss: xss,
             guest_xss: xss,
+            host_pkru: 0,
+            // PKRU is cleared on reset.
+            guest_pkru: 0,
             xsave_available,
             xsaves_available,
+            pku_available: Cr4::read().contains(Cr4Flags::PROTECTION_KEY_USER),
         }
     }
 
@@ -99,6 +169,20 @@ impl XState {
         }
     }
 
+    /// Enable protection keys for user-mode pages, including RDPKRU and WRPKRU.
+    pub fn enable_pku() {
+        if Self::pku_available() {
+            unsafe { Cr4::write(Cr4::read() | Cr4Flags::PROTECTION_KEY_USER) };
+        }
+    }
+
+    /// Check if protection keys for user-mode pages are available on the current CPU.
+    pub fn pku_available() -> bool {
+        CpuId::new()
+            .get_extended_feature_info()
+            .is_some_and(|f| f.has_pku())
+    }
+
     /// Check if XSAVE is available on the current CPU.
     pub fn xsave_available() -> bool {
         let cpuid = CpuId::new();
@@ -117,9 +201,15 @@ impl XState {
             .unwrap_or(false)
     }
 
-    /// Save the current host XCR0 and IA32_XSS values and load the guest values.
+    /// Save the current host XCR0, IA32_XSS and PKRU values and load the guest values.
     pub fn switch_to_guest(&mut self) {
         unsafe {
+            if self.pku_available {
+                self.host_pkru = rdpkru();
+                if self.guest_pkru != self.host_pkru {
+                    wrpkru(self.guest_pkru);
+                }
+            }
             if self.xsave_available {
                 self.host_xcr0 = xcr0_read().bits();
                 xcr0_write(Xcr0::from_bits_unchecked(self.guest_xcr0));
@@ -132,9 +222,15 @@ impl XState {
         }
     }
 
-    /// Save the current guest XCR0 and IA32_XSS values and load the host values.
+    /// Save the current guest XCR0, IA32_XSS and PKRU values and load the host values.
     pub fn switch_to_host(&mut self) {
         unsafe {
+            if self.pku_available {
+                self.guest_pkru = rdpkru();
+                if self.guest_pkru != self.host_pkru {
+                    wrpkru(self.host_pkru);
+                }
+            }
             if self.xsave_available {
                 self.guest_xcr0 = xcr0_read().bits();
                 xcr0_write(Xcr0::from_bits_unchecked(self.host_xcr0));
@@ -166,10 +262,34 @@ pub struct VmxVcpu<H: AxVCpuHal> {
     // VCpu states and configurations
     /// Whether the VMCS has been launched. Used to determine whether to `vmx_launch` or `vmx_resume`.
     launched: bool,
+    /// Whether the vCPU has ever entered the guest. Unlike `launched`, it is not
+    /// reset when the vCPU is unbound.
+    has_run: bool,
     /// The guest entry point.
     entry: Option<GuestPhysAddr>,
     /// The EPT root address.
     ept_root: Option<HostPhysAddr>,
+    /// Configuration of the EPT pointer.
+    eptp_config: EptpConfig,
+    /// Whether the EPT pointer is updated before the next VM entry.
+    eptp_update_pending: bool,
+    /// How guest physical memory is virtualized.
+    mmu_mode: MmuMode,
+    /// Whether five-level paging is exposed to the guest.
+    la57: bool,
+    /// The shadow page tables, in shadow paging mode.
+    shadow_mmu: Option<ShadowMmu<FrameTables<H::MmHal>>>,
+    /// The guest CR3, while the processor uses the shadow page tables.
+    shadow_cr3: u64,
+    /// The access to unmapped guest physical memory reported by the last VM exit,
+    /// in shadow paging mode.
+    shadow_fault: Option<NestedPageFaultInfo>,
+    /// The hidden guest state, while a real-mode guest runs in virtual-8086 mode
+    /// with shadow paging.
+    vm86: Option<Vm86>,
+    /// The TSS of virtual-8086 mode, mapped at [`VM86_TSS_BASE`] in the shadow
+    /// page tables.
+    vm86_tss: Vec<PhysFrame<H::MmHal>>,
     // /// Whether this VCPU is a host VCpu. Used in type 1.5 hypervisor.
     // is_host: bool, temporary removed because we don't care about type 1.5 now
 
@@ -180,16 +300,87 @@ pub struct VmxVcpu<H: AxVCpuHal> {
     io_bitmap: IOBitmap<H::MmHal>,
     /// The MSR bitmap for the VMCS.
     msr_bitmap: MsrBitmap<H::MmHal>,
+    /// The VPID tagging guest TLB entries, and the INVVPID type to flush them.
+    vpid: Option<(Vpid, InvVpidType)>,
+    /// The physical CPU this vCPU was last bound to.
+    last_cpu: Option<u32>,
+    /// Whether guest TLB entries tagged with the VPID are flushed before the next VM entry.
+    vpid_flush_pending: bool,
+    /// Whether mappings derived from the EPT are flushed before the next VM entry.
+    ept_flush_pending: bool,
+    /// The page-modification log and the receiver of dirty pages, while PML is enabled.
+    dirty_log: Option<PmlBuffer<H::MmHal>>,
+    /// The EPT views the guest can switch between with VMFUNC, while EPTP switching
+    /// is enabled.
+    eptp_list: Option<EptpList<H::MmHal>>,
+    /// The virtualization-exception information area, while EPT violations may be
+    /// converted into #VE.
+    ve_info: Option<VeInfoArea<H::MmHal>>,
 
     // Interrupt-related fields
-    /// Pending events to be injected to the guest.
-    pending_events: VecDeque<(u8, Option<u32>)>,
+    /// Pending events to be injected to the guest: the vector, the error code, and
+    /// whether it is an edge-triggered interrupt that needs no EOI to its source.
+    pending_events: VecDeque<(u8, Option<u32>, bool)>,
     /// Emulated Local APIC.
     vlapic: EmulatedLocalApic,
+    /// Expiry tracking of the guest local APIC timer.
+    lapic_timer: GuestLapicTimer,
+    /// The ID of the VM this vCPU belongs to.
+    vm_id: VMId,
+    /// The ID of this vCPU in the VM.
+    vcpu_id: VCpuId,
+    /// Whether this vCPU is the bootstrap processor, which does not wait for SIPI after INIT.
+    is_bsp: bool,
+    /// IPI destination resolution and the VMM hook to deliver IPIs to other vCPUs.
+    ipi_router: Option<(IpiRouter, Arc<dyn IpiDelivery + Send + Sync>)>,
+    /// The value last written to the x2APIC ICR, while IPIs are sent by the IPI router.
+    icr: u64,
+    /// Whether an INIT signal is pending.
+    pending_init: bool,
+    /// The vector of the pending startup IPI (SIPI).
+    pending_sipi: Option<u8>,
+    /// Calling convention of hypercalls issued by VMCALL.
+    hypercall_abi: HypercallAbi,
+    /// The hypercall reported by the last VM exit, until its return value is set.
+    pending_hypercall: Option<PendingHypercall>,
+    /// The INS reported by the last VM exit, until the value read from the port is set.
+    pending_string_in: Option<StringIo>,
+    /// Why the last `run` returned [`AxVCpuExitReason::Halt`].
+    halt_reason: Option<VmxHaltReason>,
+    /// Access to guest physical memory provided by the VMM.
+    guest_memory: Option<Arc<dyn GuestMemory + Send + Sync>>,
+    /// The KVM-compatible paravirtual interface.
+    kvm_pv: Option<KvmPv>,
+    /// The Hyper-V enlightenments.
+    hyperv: Option<HyperV>,
 
     // Extra states
     /// The XState of the VCpu. Both host and guest.
     xstate: XState,
+    /// The VMX-preemption timer used as a scheduling quantum.
+    preemption_timer: PreemptionTimer,
+    /// Guest DR0-DR3 and DR6, while they are not loaded on the processor.
+    guest_debug_regs: DebugRegs,
+    /// Host DR0-DR3, DR6 and DR7, saved while guest debug registers are loaded.
+    host_debug_regs: Option<(DebugRegs, u64)>,
+    /// Whether guest MOV DR instructions pass through until the next VM exit.
+    dr_passthrough: bool,
+    /// Guest debugging controlled by the VMM.
+    debug_control: VcpuDebugControl,
+    /// Guest DR7, while DR7 in the VMCS enables the hardware breakpoints of the VMM.
+    guest_dr7: Option<u64>,
+    /// The debug event reported by the last VM exit.
+    debug_event: Option<DebugEvent>,
+    /// Exceptions intercepted by the VMM.
+    exception_bitmap: u32,
+    /// The exception reported by the last VM exit.
+    exception_exit: Option<ExceptionExit>,
+    /// Whether the guest is shut down by a triple fault, reported on the next return of `run`.
+    triple_fault: bool,
+    /// The SPP event reported by the last VM exit.
+    spp_event: Option<SppEvent>,
+    /// Guest CR2 to be loaded before the next VM entry, which is not part of the VMCS.
+    guest_cr2: Option<u64>,
 
     // Tracing-related fields
     #[cfg(feature = "tracing")]
@@ -201,19 +392,68 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     /// Create a new [`VmxVcpu`].
     pub fn new(vm_id: VMId, vcpu_id: VCpuId) -> AxResult<Self> {
         let vmcs_revision_id = super::read_vmcs_revision_id();
+        let mmu_mode = MmuMode::detect();
         let vcpu = Self {
             guest_regs: GeneralRegisters::default(),
             host_stack_top: 0,
             launched: false,
+            has_run: false,
             entry: None,
             ept_root: None,
+            eptp_config: match mmu_mode {
+                MmuMode::Ept => EptpConfig::supported(&VmxEptVpidCap::read(), 48)?,
+                // Only the number of levels is used, to walk the EPT in software.
+                MmuMode::Shadow => EptpConfig::default(),
+            },
+            eptp_update_pending: false,
+            mmu_mode,
+            la57: false,
+            shadow_mmu: None,
+            shadow_cr3: 0,
+            shadow_fault: None,
+            vm86: None,
+            vm86_tss: Vec::new(),
             // is_host: false,
             vmcs: VmxRegion::new(vmcs_revision_id, false)?,
             io_bitmap: IOBitmap::passthrough_all()?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
+            vpid: Self::alloc_vpid(),
+            last_cpu: None,
+            vpid_flush_pending: false,
+            ept_flush_pending: false,
+            dirty_log: None,
+            eptp_list: None,
+            ve_info: None,
             pending_events: VecDeque::with_capacity(8),
             vlapic: EmulatedLocalApic::new(vm_id, vcpu_id),
+            lapic_timer: GuestLapicTimer::new(),
+            vm_id,
+            vcpu_id,
+            is_bsp: vcpu_id == 0,
+            ipi_router: None,
+            icr: 0,
+            pending_init: false,
+            pending_sipi: None,
+            hypercall_abi: HypercallAbi::default(),
+            pending_hypercall: None,
+            pending_string_in: None,
+            halt_reason: None,
+            guest_memory: None,
+            kvm_pv: None,
+            hyperv: None,
             xstate: XState::new(),
+            preemption_timer: PreemptionTimer::new(),
+            guest_debug_regs: DebugRegs::new(),
+            host_debug_regs: None,
+            dr_passthrough: false,
+            debug_control: VcpuDebugControl::default(),
+            guest_dr7: None,
+            debug_event: None,
+            exception_bitmap: 1 << x86::irq::INVALID_OPCODE_VECTOR,
+            exception_exit: None,
+            triple_fault: false,
+            spp_event: None,
+            guest_cr2: None,
             #[cfg(feature = "tracing")]
             guest_regs_exiting: GeneralRegisters::default(),
         };
@@ -232,6 +472,681 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     //     get_current_vcpu::<Self>().unwrap().id()
     // }
 
+    /// Allocate a VPID if the processor supports VPIDs and INVVPID.
+    fn alloc_vpid() -> Option<(Vpid, InvVpidType)> {
+        use super::vmcs::controls::SecondaryControls;
+        let allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+        if allowed1 & SecondaryControls::ENABLE_VPID.bits() == 0 {
+            return None;
+        }
+        let inv_type = VmxEptVpidCap::read().vpid_flush_type()?;
+        let vpid = Vpid::alloc();
+        if vpid.is_none() {
+            warn!("VPIDs are used up, the guest TLB is flushed on every VM entry and exit");
+        }
+        Some((vpid?, inv_type))
+    }
+
+    /// The VPID of this [`VmxVcpu`], or `None` if VPIDs are not used.
+    pub fn vpid(&self) -> Option<u16> {
+        self.vpid.as_ref().map(|(vpid, _)| vpid.get())
+    }
+
+    /// Flush the guest TLB entries tagged with the VPID before the next VM entry.
+    ///
+    /// Without VPIDs, they are flushed on every VM entry and exit anyway.
+    pub fn flush_guest_tlb(&mut self) {
+        self.vpid_flush_pending = true;
+    }
+
+    /// Flush the mappings derived from the EPT before the next VM entry. It must be
+    /// called after mappings in the EPT are changed or removed.
+    pub fn flush_ept(&mut self) {
+        self.ept_flush_pending = true;
+    }
+
+    /// How guest physical memory is virtualized.
+    pub fn mmu_mode(&self) -> MmuMode {
+        self.mmu_mode
+    }
+
+    /// Select how guest physical memory is virtualized, instead of the mode
+    /// detected by [`MmuMode::detect`]. It must be called before
+    /// [`setup`](Self::setup).
+    ///
+    /// In shadow paging mode, the EPT root is still used to translate guest
+    /// physical addresses, but it is walked by the vCPU instead of the processor.
+    /// Accesses to guest physical addresses not mapped by the EPT are reported as
+    /// nested page faults.
+    pub fn set_mmu_mode(&mut self, mode: MmuMode) -> AxResult {
+        if self.has_run {
+            return ax_err!(
+                BadState,
+                "the MMU mode of a launched vCPU can not be changed"
+            );
+        }
+        if mode == MmuMode::Ept && MmuMode::detect() != MmuMode::Ept {
+            return ax_err!(Unsupported, "EPT with unrestricted guests is not supported");
+        }
+        self.mmu_mode = mode;
+        Ok(())
+    }
+
+    /// Whether the processor supports five-level paging (LA57) in VMX operation.
+    pub fn la57_supported() -> bool {
+        CpuId::new()
+            .get_extended_feature_info()
+            .is_some_and(|f| f.has_la57())
+            && Msr::IA32_VMX_CR4_FIXED1.read() & Cr4Flags::L5_PAGING.bits() != 0
+    }
+
+    /// Expose five-level paging (LA57) to the guest by CPUID, and allow it to set
+    /// CR4.LA57. It must be called before the vCPU is launched.
+    ///
+    /// Guest physical addresses wider than 48 bits also need a 5-level EPT, see
+    /// [`set_eptp_config`](Self::set_eptp_config).
+    pub fn set_la57(&mut self, enable: bool) -> AxResult {
+        if self.has_run {
+            return ax_err!(BadState, "LA57 of a launched vCPU can not be changed");
+        }
+        if enable && !Self::la57_supported() {
+            return ax_err!(Unsupported, "five-level paging is not supported");
+        }
+        self.la57 = enable;
+        Ok(())
+    }
+
+    /// The configuration of the EPT pointer.
+    pub fn eptp_config(&self) -> EptpConfig {
+        self.eptp_config
+    }
+
+    /// Set the configuration of the EPT pointer, after checking it against the
+    /// capabilities of the processor. It takes effect on the next VM entry.
+    ///
+    /// The EPT must have as many levels as the configuration. The EPT views registered
+    /// by [`set_ept_view`](Self::set_ept_view) use the new configuration as well, and
+    /// the number of levels can not change while any alternate view is registered.
+    pub fn set_eptp_config(&mut self, config: EptpConfig) -> AxResult {
+        config.validate(&VmxEptVpidCap::read())?;
+        if !config.accessed_dirty && self.dirty_log.is_some() {
+            return ax_err!(BadState, "PML requires EPT accessed and dirty flags");
+        }
+        // Alternate EPT views are rebuilt with the new configuration, but their
+        // paging structures are built by the VMM for the current number of levels.
+        let has_alternate_views = self
+            .eptp_list
+            .as_ref()
+            .is_some_and(|list| list.eptps().count() > 1);
+        if has_alternate_views && config.levels != self.eptp_config.levels {
+            return ax_err!(
+                BadState,
+                "can not change the EPT levels with alternate EPT views"
+            );
+        }
+        self.eptp_config = config;
+        self.eptp_update_pending = true;
+        Ok(())
+    }
+
+    /// Enable page-modification logging (PML): guest physical pages written by the
+    /// guest are passed to `log` when the log is full, or by
+    /// [`flush_dirty_log`](Self::flush_dirty_log).
+    ///
+    /// A page is only logged when the processor sets the dirty flag of its EPT entry,
+    /// so the VMM clears the dirty flags and calls [`flush_ept`](Self::flush_ept)
+    /// after collecting dirty pages, to track further writes. EPT accessed and dirty
+    /// flags must be enabled by the [`EptpConfig`].
+    pub fn enable_dirty_logging(&mut self, log: Arc<dyn DirtyLog + Send + Sync>) -> AxResult {
+        use super::vmcs::controls::SecondaryControls;
+        if !self.eptp_config.accessed_dirty {
+            return ax_err!(BadState, "PML requires EPT accessed and dirty flags");
+        }
+        let buffer = PmlBuffer::new(log)?;
+        VmcsControl64::PML_ADDR.write(buffer.phys_addr().as_usize() as _)?;
+        VmcsGuest16::PML_INDEX.write(PML_INDEX_EMPTY)?;
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?,
+            SecondaryControls::ENABLE_PML.bits(),
+            0,
+        )?;
+        self.dirty_log = Some(buffer);
+        Ok(())
+    }
+
+    /// Disable page-modification logging, after passing the logged pages to the
+    /// receiver.
+    pub fn disable_dirty_logging(&mut self) -> AxResult {
+        use super::vmcs::controls::SecondaryControls;
+        if self.dirty_log.is_none() {
+            return Ok(());
+        }
+        self.flush_dirty_log()?;
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?,
+            0,
+            SecondaryControls::ENABLE_PML.bits(),
+        )?;
+        self.dirty_log = None;
+        Ok(())
+    }
+
+    /// Pass the pages logged since the last flush to the receiver, and return the
+    /// number of them. Used for the final pass of live migration, after the vCPU
+    /// has stopped.
+    pub fn flush_dirty_log(&mut self) -> AxResult<usize> {
+        let Some(buffer) = &self.dirty_log else {
+            return Ok(0);
+        };
+        let count = buffer.drain(VmcsGuest16::PML_INDEX.read()?);
+        VmcsGuest16::PML_INDEX.write(PML_INDEX_EMPTY)?;
+        trace!("VmxVcpu {} flushed {} dirty pages", self.vcpu_id, count);
+        Ok(count)
+    }
+
+    /// Enable EPTP switching with VMFUNC, so that the guest can switch between EPT
+    /// views registered by [`set_ept_view`](Self::set_ept_view) without VM exits.
+    ///
+    /// The current EPT root is the primary view 0. Invalid switches raise #UD in
+    /// the guest.
+    pub fn enable_eptp_switching(&mut self) -> AxResult {
+        use super::vmcs::controls::SecondaryControls;
+        if self.eptp_list.is_some() {
+            return Ok(());
+        }
+        if !vmfunc::eptp_switching_supported() {
+            return ax_err!(Unsupported, "EPTP switching is not supported");
+        }
+        let mut list = EptpList::new()?;
+        list.set(PRIMARY_EPT_VIEW, Some(VmcsControl64::EPTP.read()?))?;
+        VmcsControl64::EPTP_LIST_ADDR.write(list.phys_addr().as_usize() as _)?;
+        VmcsControl64::VM_FUNCTION_CONTROLS
+            .write(VmcsControl64::VM_FUNCTION_CONTROLS.read()? | vmfunc::VMFUNC_EPTP_SWITCHING)?;
+        VmcsControl16::EPTP_INDEX.write(PRIMARY_EPT_VIEW)?;
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?,
+            SecondaryControls::ENABLE_VM_FUNCTIONS.bits(),
+            0,
+        )?;
+        self.eptp_list = Some(list);
+        Ok(())
+    }
+
+    /// Register the EPT root of the alternate view `index`, or remove the view with
+    /// `None`. The EPT pointer is built with the current [`EptpConfig`].
+    ///
+    /// The primary view 0 follows the EPT root of the vCPU, and the active view can
+    /// not be removed.
+    pub fn set_ept_view(&mut self, index: u16, ept_root: Option<HostPhysAddr>) -> AxResult {
+        let Some(list) = self.eptp_list.as_mut() else {
+            return ax_err!(BadState, "EPTP switching is not enabled");
+        };
+        if index == PRIMARY_EPT_VIEW || index as usize >= EPTP_LIST_ENTRIES {
+            return ax_err!(InvalidInput, "invalid alternate EPT view index");
+        }
+        let eptp = match ept_root {
+            Some(ept_root) => {
+                if self.ve_info.is_some() {
+                    // Keep EPT violations of the new view trapped, as those of the others.
+                    let levels = self.eptp_config.levels as usize;
+                    ve::suppress_ve_in_ept::<H::MmHal>(ept_root, levels);
+                }
+                let eptp = self.eptp_config.eptp(ept_root).bits();
+                // The guest may switch to it without a VM exit.
+                vmcs::invalidate_ept(eptp)?;
+                Some(eptp)
+            }
+            None if VmcsControl16::EPTP_INDEX.read()? == index => {
+                return ax_err!(BadState, "can not remove the active EPT view");
+            }
+            None => None,
+        };
+        list.set(index, eptp)
+    }
+
+    /// Switch the active EPT view to `index` from the host.
+    pub fn switch_ept_view(&mut self, index: u16) -> AxResult {
+        let Some(list) = self.eptp_list.as_ref() else {
+            return ax_err!(BadState, "EPTP switching is not enabled");
+        };
+        let Some(eptp) = list.get(index) else {
+            return ax_err!(InvalidInput, "EPT view is not registered");
+        };
+        VmcsControl64::EPTP.write(eptp)?;
+        VmcsControl16::EPTP_INDEX.write(index)
+    }
+
+    /// The index of the active EPT view, which the guest may have switched to.
+    pub fn active_ept_view(&self) -> AxResult<u16> {
+        if self.eptp_list.is_some() {
+            VmcsControl16::EPTP_INDEX.read()
+        } else {
+            Ok(PRIMARY_EPT_VIEW)
+        }
+    }
+
+    /// Convert EPT violations into virtualization exceptions (#VE) in the guest,
+    /// so that an in-guest agent can handle them without VM exits.
+    ///
+    /// An EPT violation causes a VM exit instead if the EPT entry causing it has
+    /// [`EPT_SUPPRESS_VE`](crate::EPT_SUPPRESS_VE) set, or a #VE delivered before
+    /// is not accepted yet. The bit is set in all non-present entries of the EPT
+    /// views by [`suppress_ve_in_ept_views`](Self::suppress_ve_in_ept_views), to keep
+    /// emulated MMIO regions trapped by the VMM.
+    pub fn enable_virtualization_exceptions(&mut self) -> AxResult {
+        use super::vmcs::controls::SecondaryControls;
+        if self.ve_info.is_some() {
+            return Ok(());
+        }
+        if self.mmu_mode != MmuMode::Ept {
+            return ax_err!(Unsupported, "#VE requires EPT");
+        }
+        self.suppress_ve_in_ept_views()?;
+        let area = VeInfoArea::new()?;
+        VmcsControl64::VIRT_EXCEPTION_INFO_ADDR.write(area.phys_addr().as_usize() as _)?;
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?,
+            SecondaryControls::EPT_VIOLATION_VE.bits(),
+            0,
+        )?;
+        self.ve_info = Some(area);
+        Ok(())
+    }
+
+    /// Set [`EPT_SUPPRESS_VE`](crate::EPT_SUPPRESS_VE) in all non-present entries of
+    /// the EPT views, so that EPT violations caused by them still cause VM exits.
+    ///
+    /// The VMM calls it again after adding paging structures to the EPT while #VE
+    /// is enabled, or marks them by [`suppress_ve_non_present`](crate::suppress_ve_non_present).
+    /// Return the number of entries newly marked.
+    pub fn suppress_ve_in_ept_views(&mut self) -> AxResult<usize> {
+        let Some(ept_root) = self.ept_root else {
+            return ax_err!(BadState, "EPT root is not set");
+        };
+        let levels = self.eptp_config.levels as usize;
+        let mut count = ve::suppress_ve_in_ept::<H::MmHal>(ept_root, levels);
+        if let Some(list) = self.eptp_list.as_ref() {
+            for eptp in list.eptps() {
+                count += ve::suppress_ve_in_ept::<H::MmHal>(vmfunc::eptp_root(eptp), levels);
+            }
+        }
+        Ok(count)
+    }
+
+    /// The virtualization-exception information of the last #VE, if #VE is enabled.
+    pub fn ve_info(&self) -> Option<VeInfo> {
+        self.ve_info.as_ref().map(|area| area.read())
+    }
+
+    /// Clear the virtualization-exception information, so that another #VE can be
+    /// delivered if the guest has not cleared the semaphore itself.
+    pub fn reset_ve_info(&mut self) {
+        if let Some(area) = self.ve_info.as_mut() {
+            area.reset();
+        }
+    }
+
+    /// Load the EPT pointer and execute the TLB flushes requested since the last
+    /// VM entry.
+    fn flush_pending_tlb(&mut self) -> AxResult {
+        // Shadow page tables are derived from the EPT, which is not used by the processor.
+        if self.shadow_mmu.is_some()
+            && (core::mem::take(&mut self.eptp_update_pending)
+                | core::mem::take(&mut self.ept_flush_pending))
+        {
+            self.flush_shadow_mmu()?;
+        }
+        let eptp_update = core::mem::take(&mut self.eptp_update_pending);
+        if let Some(ept_root) = self.ept_root.filter(|_| eptp_update) {
+            let eptp = self.eptp_config.eptp(ept_root);
+            match self.eptp_list.as_mut() {
+                Some(list) => {
+                    // The alternate views follow the EPTP configuration as well.
+                    list.set(PRIMARY_EPT_VIEW, Some(eptp.bits()))?;
+                    for eptp in list.rebuild(&self.eptp_config) {
+                        vmcs::invalidate_ept(eptp)?;
+                    }
+                    let active = VmcsControl16::EPTP_INDEX.read()?;
+                    VmcsControl64::EPTP.write(list.get(active).unwrap())?;
+                    vmcs::invalidate_ept(eptp.bits())?;
+                }
+                None => vmcs::set_ept_pointer(eptp)?,
+            }
+        }
+        if core::mem::take(&mut self.ept_flush_pending) {
+            match &self.eptp_list {
+                Some(list) => {
+                    for eptp in list.eptps() {
+                        vmcs::invalidate_ept(eptp)?;
+                    }
+                }
+                None => vmcs::invalidate_ept(VmcsControl64::EPTP.read()?)?,
+            }
+            // Combined mappings of all VPIDs are invalidated as well.
+            self.vpid_flush_pending = false;
+        }
+        let vpid_flush = core::mem::take(&mut self.vpid_flush_pending);
+        if let Some((vpid, inv_type)) = self.vpid.as_ref().filter(|_| vpid_flush) {
+            unsafe { invvpid(*inv_type, vpid.get(), 0).map_err(as_axerr)? };
+        }
+        Ok(())
+    }
+
+    /// Drop all shadow page table entries, and load the new shadow root.
+    ///
+    /// The shadow page tables follow the paging mode of the processor: 4-level or
+    /// 5-level paging in IA-32e mode, or PAE paging otherwise.
+    fn flush_shadow_mmu(&mut self) -> AxResult {
+        let long_mode = VmcsGuest64::IA32_EFER.read()? & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+        let la57 = VmcsGuestNW::CR4.read()? & Cr4Flags::L5_PAGING.bits() as usize != 0;
+        let levels = match (long_mode, la57) {
+            (true, true) => 5,
+            (true, false) => 4,
+            _ => 3,
+        };
+        if let Some(mmu) = self.shadow_mmu.as_mut() {
+            mmu.flush(levels)?;
+            VmcsGuestNW::CR3.write(mmu.root().as_usize())?;
+            self.flush_guest_tlb();
+        }
+        Ok(())
+    }
+
+    /// Fill the shadow page tables on a page fault of the guest, or pass it to the
+    /// guest if it is caused by the guest page tables.
+    ///
+    /// Return `None` if it is reported to the VMM, as a nested page fault or an
+    /// intercepted exception.
+    fn handle_shadow_page_fault(&mut self, exit: &ExceptionExit) -> Option<AxResult> {
+        let error_code = PageFaultErrorCode::from_bits_truncate(exit.err_code? as u64);
+        let vaddr = GuestVirtAddr::from(exit.cr2? as usize);
+        let info = GuestPageWalkInfo {
+            is_user_mode_access: error_code.contains(PageFaultErrorCode::USER_MODE),
+            is_write_access: error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            is_inst_fetch: error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+            ..self.get_ptw_info()
+        };
+        let map = EptMap::<H::MmHal>::new(self.ept_root?, self.eptp_config.levels as usize);
+        let mmu = self.shadow_mmu.as_mut()?;
+        let result = match mmu.handle_page_fault(&map, &map, &info, vaddr) {
+            Ok(result) => result,
+            Err(err) => return Some(Err(err)),
+        };
+        let root = mmu.root();
+        match result {
+            ShadowFault::Fixed { flush_tlb } => {
+                if flush_tlb {
+                    self.flush_guest_tlb();
+                }
+                Some(VmcsGuestNW::CR3.write(root.as_usize()))
+            }
+            ShadowFault::Guest(fault) => {
+                let exit = ExceptionExit {
+                    err_code: Some(fault.error_code.bits() as u32),
+                    cr2: Some(fault.addr.as_usize() as u64),
+                    ..*exit
+                };
+                if self.exception_bitmap & (1 << x86::irq::PAGE_FAULT_VECTOR) != 0 {
+                    self.exception_exit = Some(exit);
+                    return None;
+                }
+                Some(self.reflect_exception(&exit))
+            }
+            ShadowFault::Unmapped(gpa) => {
+                let access_flags = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                    MappingFlags::WRITE
+                } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                    MappingFlags::EXECUTE
+                } else {
+                    MappingFlags::READ
+                };
+                self.shadow_fault = Some(NestedPageFaultInfo {
+                    access_flags,
+                    fault_guest_paddr: gpa,
+                });
+                None
+            }
+        }
+    }
+
+    /// Guest memory accessed by the vCPU itself, e.g. in shadow paging mode, through
+    /// the EPT walked in software.
+    fn shadow_guest_memory(&self) -> AxResult<EptMap<H::MmHal>> {
+        match self.ept_root {
+            Some(root) => Ok(EptMap::new(root, self.eptp_config.levels as usize)),
+            None => ax_err!(BadState, "EPT root is not set"),
+        }
+    }
+
+    /// Run a real-mode guest in virtual-8086 mode with shadow paging, or switch
+    /// it back to native execution once it enters protected mode. See [`Vm86`].
+    fn update_vm86(&mut self) -> AxResult {
+        let real_mode = self.shadow_mmu.is_some()
+            && self.cr(0) as u64 & Cr0Flags::PROTECTED_MODE_ENABLE.bits() == 0;
+        if real_mode == self.vm86.is_some() {
+            return Ok(());
+        }
+        let mut regs = vmcs::vm86_regs()?;
+        let mut tss_pages = Vec::new();
+        if real_mode {
+            if self.vm86_tss.is_empty() {
+                let mut tss = alloc::vec![0; VM86_TSS_PAGES * PAGE_SIZE];
+                realmode::init_vm86_tss(tss.as_mut_slice().try_into().unwrap());
+                for page in tss.chunks(PAGE_SIZE) {
+                    let frame = PhysFrame::alloc()?;
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(page.as_ptr(), frame.as_mut_ptr(), PAGE_SIZE)
+                    };
+                    self.vm86_tss.push(frame);
+                }
+            }
+            tss_pages = self
+                .vm86_tss
+                .iter()
+                .enumerate()
+                .map(|(i, frame)| {
+                    let vaddr = GuestVirtAddr::from(VM86_TSS_BASE + i * PAGE_SIZE);
+                    (vaddr, frame.start_paddr())
+                })
+                .collect();
+            self.vm86 = Some(Vm86::enter(&mut regs)?);
+            debug!("Guest runs in virtual-8086 mode");
+        } else if let Some(vm86) = self.vm86.take() {
+            vm86.leave(&mut regs);
+            debug!("Guest leaves virtual-8086 mode");
+        }
+        vmcs::set_vm86_regs(&regs)?;
+        if let Some(mmu) = self.shadow_mmu.as_mut() {
+            mmu.set_fixed_mappings(&tss_pages)?;
+            VmcsGuestNW::CR3.write(mmu.root().as_usize())?;
+            self.flush_guest_tlb();
+        }
+        self.update_exception_bitmap()
+    }
+
+    /// Deliver interrupt `vector` through the IVT of the guest in virtual-8086 mode.
+    ///
+    /// If the IVT entry or the stack is not in guest memory, the guest is shut
+    /// down as on a triple fault.
+    fn deliver_real_mode_interrupt(&mut self, vector: u8) -> AxResult {
+        let mem = self.shadow_guest_memory()?;
+        let mut regs = vmcs::vm86_regs()?;
+        let ivt_base = VmcsGuestNW::IDTR_BASE.read()? as u64;
+        if let Err(err) = realmode::deliver_interrupt(&mem, &mut regs, ivt_base, vector) {
+            warn!(
+                "Failed to deliver interrupt {:#x} in virtual-8086 mode: {:?}",
+                vector, err
+            );
+            self.triple_fault = true;
+            return Ok(());
+        }
+        vmcs::set_vm86_regs(&regs)
+    }
+
+    /// Emulate a privileged instruction of the guest in virtual-8086 mode, which
+    /// caused #GP. Other causes of #GP are delivered through the IVT.
+    fn emulate_vm86_instruction(&mut self) -> AxResult {
+        let mem = self.shadow_guest_memory()?;
+        let regs = vmcs::vm86_regs()?;
+        let cs = &regs.segs[realmode::CS];
+        // The instruction may end at the end of guest memory.
+        let mut bytes = [0; MAX_INSTRUCTION_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let addr = realmode::segment_addr(cs, (regs.rip as u16).wrapping_add(i as u16));
+            if mem.read_phys(addr, core::slice::from_mut(byte)).is_err() {
+                break;
+            }
+        }
+        let Some((insn, len)) = realmode::decode(&bytes, &self.guest_regs) else {
+            debug!(
+                "#GP in virtual-8086 mode at {:#x}:{:#x}: {:02x?}",
+                cs.selector, regs.rip, bytes
+            );
+            return self.deliver_real_mode_interrupt(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR);
+        };
+        let old = self.guest_control_regs()?;
+        let new = match insn {
+            RealModeInstruction::Hlt => {
+                self.advance_rip(len as _)?;
+                if !self.has_deliverable_event() {
+                    vmcs::set_activity_state(VmxActivityState::Hlt)?;
+                }
+                return Ok(());
+            }
+            RealModeInstruction::CacheFlush => Some(old),
+            RealModeInstruction::Clts => Some(old.clts()),
+            RealModeInstruction::Lmsw(Operand::Register(gpr)) => {
+                old.lmsw(self.gpr_by_index(gpr) as u16)
+            }
+            RealModeInstruction::Lmsw(operand) => {
+                let mut buf = [0; 2];
+                if let Err(fault) = self.read_vm86_operand(&mem, operand, &mut buf)? {
+                    return self.handle_vm86_operand_fault(fault);
+                }
+                old.lmsw(u16::from_le_bytes(buf))
+            }
+            RealModeInstruction::Lgdt { operand, operand32 }
+            | RealModeInstruction::Lidt { operand, operand32 } => {
+                let mut buf = [0; 6];
+                if let Err(fault) = self.read_vm86_operand(&mem, operand, &mut buf)? {
+                    return self.handle_vm86_operand_fault(fault);
+                }
+                let limit = u16::from_le_bytes([buf[0], buf[1]]) as u32;
+                let mut base = u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]);
+                if !operand32 {
+                    base &= 0xff_ffff;
+                }
+                if matches!(insn, RealModeInstruction::Lgdt { .. }) {
+                    VmcsGuestNW::GDTR_BASE.write(base as _)?;
+                    VmcsGuest32::GDTR_LIMIT.write(limit)?;
+                } else {
+                    VmcsGuestNW::IDTR_BASE.write(base as _)?;
+                    VmcsGuest32::IDTR_LIMIT.write(limit)?;
+                }
+                Some(old)
+            }
+            RealModeInstruction::MovToCr { cr, gpr } => {
+                let val = self.gpr_by_index(gpr) & 0xffff_ffff;
+                match cr {
+                    0 => old.write_cr0(val),
+                    3 => old.write_cr3(val),
+                    4 => self.write_guest_cr4(&old, val),
+                    _ => None,
+                }
+            }
+            RealModeInstruction::MovFromCr { cr, gpr } => {
+                let val = match cr {
+                    0 => Some(old.cr0),
+                    3 => Some(old.cr3),
+                    4 => Some(old.cr4),
+                    _ => None,
+                };
+                val.map(|val| {
+                    self.set_gpr_by_index(gpr, val);
+                    old
+                })
+            }
+        };
+
+        match new {
+            // Leaves virtual-8086 mode if the guest enters protected mode.
+            Some(new) if self.set_guest_control_regs(&old, &new)? => self.advance_rip(len as _),
+            _ => self.deliver_real_mode_interrupt(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR),
+        }
+    }
+
+    /// Read the memory operand of an instruction of the guest in virtual-8086
+    /// mode, or return the fault of the access. Paging is disabled in real mode.
+    fn read_vm86_operand(
+        &self,
+        mem: &EptMap<H::MmHal>,
+        operand: Operand,
+        buf: &mut [u8],
+    ) -> AxResult<core::result::Result<(), Vm86OperandFault>> {
+        let Operand::Memory { segment, offset } = operand else {
+            return ax_err!(InvalidInput, "register operand");
+        };
+        let access = SegmentAccess::Read;
+        let linear = match self.segmented_to_linear(segment, offset as u64, buf.len(), access)? {
+            Ok(linear) => linear.as_usize(),
+            Err(fault) => return Ok(Err(Vm86OperandFault::Segment(fault))),
+        };
+        // The operand may cross a page boundary.
+        let last = linear + buf.len() - 1;
+        for addr in [linear, last & !(PAGE_SIZE - 1)] {
+            if addr >= linear && mem.translate(GuestPhysAddr::from(addr)).is_none() {
+                return Ok(Err(Vm86OperandFault::Unmapped(GuestPhysAddr::from(addr))));
+            }
+        }
+        mem.read_phys(GuestPhysAddr::from(linear), buf)?;
+        Ok(Ok(()))
+    }
+
+    /// Deliver the fault of a memory operand read by [`read_vm86_operand`], or
+    /// leave it to be reported as a nested page fault by `run`.
+    ///
+    /// [`read_vm86_operand`]: Self::read_vm86_operand
+    fn handle_vm86_operand_fault(&mut self, fault: Vm86OperandFault) -> AxResult {
+        match fault {
+            Vm86OperandFault::Segment(fault) => self.deliver_real_mode_interrupt(fault.vector()),
+            Vm86OperandFault::Unmapped(gpa) => {
+                self.shadow_fault = Some(NestedPageFaultInfo {
+                    access_flags: MappingFlags::READ,
+                    fault_guest_paddr: gpa,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Drop the shadow entries of the page invalidated by the guest with INVLPG.
+    fn handle_invlpg(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        let vaddr = vmcs::invlpg_address()?;
+        if let Some(mmu) = self.shadow_mmu.as_mut() {
+            mmu.invalidate(GuestVirtAddr::from(vaddr));
+        }
+        self.flush_guest_tlb();
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
+    /// Flush the guest TLB if this vCPU has migrated to the current physical CPU,
+    /// which may hold stale entries tagged with the VPID.
+    fn check_migration(&mut self) {
+        let cpu = vpid::current_cpu_id();
+        if self.last_cpu.replace(cpu) != Some(cpu) {
+            trace!("VmxVcpu {} migrated to CPU {}", self.vcpu_id, cpu);
+            self.flush_guest_tlb();
+        }
+    }
+
     /// Bind this [`VmxVcpu`] to current logical processor.
     pub fn bind_to_current_processor(&self) -> AxResult {
         debug!(
@@ -260,9 +1175,10 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
     /// Get CPU mode of the guest.
     pub fn get_cpu_mode(&self) -> VmCpuMode {
-        let ia32_efer = Msr::IA32_EFER.read();
+        let ia32_efer = VmcsGuest64::IA32_EFER.read().unwrap();
         let cs_access_right = VmcsGuest32::CS_ACCESS_RIGHTS.read().unwrap();
-        let cr0 = VmcsGuestNW::CR0.read().unwrap();
+        // CR0.PE is always set in shadow paging mode, the guest's view is used.
+        let cr0 = self.cr(0);
         if (ia32_efer & MSR_IA32_EFER_LMA_BIT) != 0 {
             if (cs_access_right & 0x2000) != 0 {
                 // CS.L = 1
@@ -279,10 +1195,21 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
     /// Run the guest. It returns when a vm-exit happens and returns the vm-exit if it cannot be handled by this [`VmxVcpu`] itself.
     pub fn inner_run(&mut self) -> Option<VmxExitInfo> {
+        self.fire_timers(timer::current_tsc());
         self.inject_pending_events().unwrap();
+        // The event can not be delivered through the IVT in virtual-8086 mode.
+        if self.triple_fault {
+            return None;
+        }
+        self.arm_preemption_timer().unwrap();
+        self.flush_pending_tlb().unwrap();
 
         // Run guest
         self.load_guest_xstate();
+        self.load_guest_debug_regs().unwrap();
+        if let Some(cr2) = self.guest_cr2.take() {
+            unsafe { x86::controlregs::cr2_write(cr2) };
+        }
 
         #[cfg(feature = "tracing")]
         {
@@ -304,6 +1231,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                 self.vmx_resume();
             } else {
                 self.launched = true;
+                self.has_run = true;
                 VmcsHostNW::RSP
                     .write(&self.host_stack_top as *const _ as usize)
                     .unwrap();
@@ -311,6 +1239,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                 self.vmx_launch();
             }
         }
+        self.load_host_debug_regs().unwrap();
         self.load_host_xstate();
 
         #[cfg(feature = "tracing")]
@@ -320,6 +1249,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
         // Handle vm-exits
         let exit_info = self.exit_info().unwrap();
+        self.account_preemption_timer(&exit_info).unwrap();
         // debug!("VM exit: {:#x?}", exit_info);
 
         match self.builtin_vmexit_handler(&exit_info) {
@@ -333,156 +1263,819 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                     );
                 }
 
-                None
-            }
-            None => Some(exit_info),
+                None
+            }
+            None => Some(exit_info),
+        }
+    }
+
+    /// Basic information about VM exits.
+    pub fn exit_info(&self) -> AxResult<vmcs::VmxExitInfo> {
+        vmcs::exit_info()
+    }
+
+    /// Raw information for VM Exits Due to Vectored Events, See SDM 25.9.2
+    pub fn raw_interrupt_exit_info(&self) -> AxResult<u32> {
+        vmcs::raw_interrupt_exit_info()
+    }
+
+    /// Information for VM exits due to external interrupts.
+    pub fn interrupt_exit_info(&self) -> AxResult<vmcs::VmxInterruptInfo> {
+        vmcs::interrupt_exit_info()
+    }
+
+    /// Information for VM exits due to I/O instructions.
+    pub fn io_exit_info(&self) -> AxResult<vmcs::VmxIoExitInfo> {
+        vmcs::io_exit_info()
+    }
+
+    /// Information for VM exits due to nested page table faults (EPT violation).
+    pub fn nested_page_fault_info(&self) -> AxResult<NestedPageFaultInfo> {
+        vmcs::ept_violation_info()
+    }
+
+    /// Detailed information for VM exits due to EPT violations, including the
+    /// access rights of the guest-physical address.
+    pub fn ept_violation(&self) -> AxResult<vmcs::EptViolation> {
+        vmcs::ept_violation()
+    }
+
+    /// Information for VM exits due to APIC access.
+    pub fn apic_access_exit_info(&self) -> AxResult<vmcs::ApicAccessExitInfo> {
+        vmcs::apic_access_exit_info()
+    }
+
+    /// Activity state of the guest.
+    pub fn activity_state(&self) -> AxResult<VmxActivityState> {
+        vmcs::activity_state()
+    }
+
+    /// Why the last call of `run` returned [`AxVCpuExitReason::Halt`], or `None`
+    /// if it returned another reason.
+    pub fn halt_reason(&self) -> Option<VmxHaltReason> {
+        self.halt_reason
+    }
+
+    /// Set activity state of the guest, e.g. when restoring a snapshot.
+    pub fn set_activity_state(&mut self, state: VmxActivityState) -> AxResult {
+        vmcs::set_activity_state(state)
+    }
+
+    /// Current paging mode of the guest.
+    pub fn paging_mode(&self) -> AxResult<GuestPagingMode> {
+        Ok(self.guest_control_regs()?.paging_mode())
+    }
+
+    /// Whether the guest is halted by the HLT instruction.
+    pub fn is_halted(&self) -> AxResult<bool> {
+        Ok(self.activity_state()? == VmxActivityState::Hlt)
+    }
+
+    /// Set whether this vCPU is the bootstrap processor (BSP) of the VM.
+    ///
+    /// The BSP resumes at the reset vector after INIT, while application processors
+    /// wait for a startup IPI. By default, vCPU 0 is the BSP.
+    pub fn set_bsp(&mut self, is_bsp: bool) {
+        self.is_bsp = is_bsp;
+    }
+
+    /// Whether this vCPU is the bootstrap processor (BSP) of the VM.
+    pub fn is_bsp(&self) -> bool {
+        self.is_bsp
+    }
+
+    /// Send an INIT signal to this vCPU, e.g. on behalf of an INIT IPI.
+    ///
+    /// The vCPU is reset to its INIT state before it runs next time. Except for
+    /// the bootstrap processor, it then waits for a startup IPI.
+    pub fn send_init(&mut self) {
+        self.pending_init = true;
+        self.pending_sipi = None;
+    }
+
+    /// Send a startup IPI (SIPI) with `vector` to this vCPU.
+    ///
+    /// If the vCPU is waiting for SIPI when it runs next time, it starts executing
+    /// at `vector << 12` in real mode, otherwise the SIPI is ignored.
+    pub fn send_sipi(&mut self, vector: u8) {
+        self.pending_sipi = Some(vector);
+    }
+
+    /// Route IPIs sent by the guest through x2APIC ICR writes with `router`, and
+    /// deliver those targeting other vCPUs by the VMM hook `delivery`.
+    ///
+    /// Without a router, ICR writes are handled by the emulated local APIC.
+    pub fn set_ipi_router(
+        &mut self,
+        router: IpiRouter,
+        delivery: Arc<dyn IpiDelivery + Send + Sync>,
+    ) {
+        self.ipi_router = Some((router, delivery));
+    }
+
+    /// Receive an IPI sent by another vCPU, or by this vCPU itself.
+    pub fn receive_ipi(&mut self, ipi: Ipi) {
+        match ipi.delivery_mode {
+            IpiDeliveryMode::Fixed | IpiDeliveryMode::LowestPriority => {
+                self.queue_edge_interrupt(ipi.vector)
+            }
+            IpiDeliveryMode::Nmi => self.queue_event(x86::irq::NONMASKABLE_INTERRUPT_VECTOR, None),
+            // INIT level de-assert has no effect.
+            IpiDeliveryMode::Init if ipi.level_assert => self.send_init(),
+            IpiDeliveryMode::Init => {}
+            IpiDeliveryMode::StartUp => self.send_sipi(ipi.vector),
+            IpiDeliveryMode::Smi => warn!("SMI IPI is not supported, ignored"),
+        }
+    }
+
+    /// Whether the guest is waiting for a startup IPI (SIPI).
+    pub fn is_waiting_for_sipi(&self) -> AxResult<bool> {
+        Ok(self.activity_state()? == VmxActivityState::WaitForSipi)
+    }
+
+    /// The TSC value at which the guest local APIC timer fires next time, if it is armed.
+    ///
+    /// A VMM can use this to decide how long a halted vCPU may sleep.
+    pub fn lapic_timer_deadline(&self) -> Option<u64> {
+        self.lapic_timer.deadline()
+    }
+
+    /// Guest general-purpose registers.
+    pub fn regs(&self) -> &GeneralRegisters {
+        &self.guest_regs
+    }
+
+    /// Mutable reference of guest general-purpose registers.
+    pub fn regs_mut(&mut self) -> &mut GeneralRegisters {
+        &mut self.guest_regs
+    }
+
+    /// Guest stack pointer. (`RSP`)
+    pub fn stack_pointer(&self) -> usize {
+        VmcsGuestNW::RSP.read().unwrap()
+    }
+
+    /// Set guest stack pointer. (`RSP`)
+    pub fn set_stack_pointer(&mut self, rsp: usize) {
+        VmcsGuestNW::RSP.write(rsp).unwrap()
+    }
+
+    /// Add the CS base to the offset `guest_rip`, giving a linear address outside
+    /// 64-bit mode, without checking the CS limit.
+    #[deprecated(note = "use `segmented_to_linear`, which checks limits and access rights")]
+    pub fn gla2gva(&self, guest_rip: GuestVirtAddr) -> GuestVirtAddr {
+        let cpu_mode = self.get_cpu_mode();
+        let seg_base = if cpu_mode == VmCpuMode::Mode64 {
+            0
+        } else {
+            VmcsGuestNW::CS_BASE.read().unwrap()
+        };
+        // debug!(
+        //     "seg_base: {:#x}, guest_rip: {:#x} cpu mode:{:?}",
+        //     seg_base, guest_rip, cpu_mode
+        // );
+        guest_rip + seg_base
+    }
+
+    /// The linear address of `size` bytes at `offset` in segment `seg`, accessed
+    /// by `access` in the current CPU mode.
+    ///
+    /// Return the fault to inject if the access violates the limit or the access
+    /// rights of the segment, or if the address is not canonical in 64-bit mode.
+    /// The fault is #SS(0) for SS, #GP(0) otherwise.
+    pub fn segmented_to_linear(
+        &self,
+        seg: SegmentRegister,
+        offset: u64,
+        size: usize,
+        access: SegmentAccess,
+    ) -> AxResult<core::result::Result<GuestVirtAddr, SegmentFault>> {
+        let segment = vmcs::guest_segment(seg)?;
+        let mode = self.get_cpu_mode();
+        let la57 = self.cr(4) & Cr4Flags::L5_PAGING.bits() as usize != 0;
+        Ok(
+            segment::segmented_to_linear(seg, &segment, offset, size, access, &mode, la57)
+                .map(|linear| GuestVirtAddr::from(linear as usize)),
+        )
+    }
+
+    /// Get Translate guest page table info, for a data read at the current
+    /// privilege level.
+    pub fn get_ptw_info(&self) -> GuestPageWalkInfo {
+        // SS.DPL is always the CPL of the guest.
+        let cpl = (VmcsGuest32::SS_ACCESS_RIGHTS.read().unwrap() >> 5) & 0x3;
+        let mut access = MappingFlags::READ;
+        access.set(MappingFlags::USER, cpl == 3);
+        self.get_ptw_info_for(access)
+    }
+
+    /// Get Translate guest page table info for the linear access which caused
+    /// the EPT violation `violation`, with the access kind from its exit
+    /// qualification, at the current privilege level.
+    pub fn get_ptw_info_for_ept_violation(&self, violation: &EptViolation) -> GuestPageWalkInfo {
+        let mut info = self.get_ptw_info();
+        info.is_write_access = violation.access_flags.contains(MappingFlags::WRITE);
+        info.is_inst_fetch = violation.access_flags.contains(MappingFlags::EXECUTE);
+        info
+    }
+
+    /// Get Translate guest page table info for an access of `access`.
+    ///
+    /// `access` contains [`MappingFlags::WRITE`] for writes and
+    /// [`MappingFlags::EXECUTE`] for instruction fetches, as
+    /// [`EptViolation::access_flags`], and [`MappingFlags::USER`] for user-mode
+    /// accesses. The top entry is the address of the top-level paging structure,
+    /// without the PCID and flag bits of CR3. PAE paging with EPT uses the PDPTEs
+    /// loaded by the processor, see [`guest_pdptes`](Self::guest_pdptes).
+    pub fn get_ptw_info_for(&self, access: MappingFlags) -> GuestPageWalkInfo {
+        let cr3 = self.cr(3);
+        let level = self.get_paging_level();
+        let top_entry = match level {
+            2 => cr3 & 0xffff_f000,
+            3 => cr3 & 0xffff_ffe0,
+            _ => cr3 & 0x000f_ffff_ffff_f000,
+        };
+        let is_write_access = access.contains(MappingFlags::WRITE);
+        let is_inst_fetch = access.contains(MappingFlags::EXECUTE);
+        let is_user_mode_access = access.contains(MappingFlags::USER);
+        let pdptes = if level == 3 {
+            self.guest_pdptes().unwrap()
+        } else {
+            None
+        };
+        let pke = self.cr(4) & Cr4Flags::PROTECTION_KEY_USER.bits() as usize != 0;
+        let pkru = if pke { self.xstate.guest_pkru } else { 0 };
+        let mut pse = true;
+        let mut nxe =
+            (VmcsGuest64::IA32_EFER.read().unwrap() & EferFlags::NO_EXECUTE_ENABLE.bits()) != 0;
+        let wp = (self.cr(0) & Cr0Flags::WRITE_PROTECT.bits() as usize) != 0;
+        let is_smap_on =
+            (self.cr(4) & Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits() as usize) != 0;
+        let is_smep_on =
+            (self.cr(4) & Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits() as usize) != 0;
+        let width: u32;
+        if level >= 3 {
+            width = 9;
+        } else if level == 2 {
+            width = 10;
+            pse = self.cr(4) & Cr4Flags::PAGE_SIZE_EXTENSION.bits() as usize != 0;
+            nxe = false;
+        } else {
+            width = 0;
+        }
+        GuestPageWalkInfo {
+            top_entry,
+            level,
+            width,
+            is_user_mode_access,
+            is_write_access,
+            is_inst_fetch,
+            pse,
+            wp,
+            nxe,
+            is_smap_on,
+            is_smep_on,
+            pdptes,
+            pke,
+            pkru,
+        }
+    }
+
+    /// The PDPTEs of PAE paging loaded by the processor, or by the vCPU on emulated
+    /// writes of control registers, or `None` if they are not used or not loaded
+    /// into the VMCS, e.g. with shadow paging.
+    pub fn guest_pdptes(&self) -> AxResult<Option<[u64; 4]>> {
+        if self.shadow_mmu.is_some() || self.get_paging_level() != 3 {
+            return Ok(None);
+        }
+        Ok(Some([
+            VmcsGuest64::PDPTE0.read()?,
+            VmcsGuest64::PDPTE1.read()?,
+            VmcsGuest64::PDPTE2.read()?,
+            VmcsGuest64::PDPTE3.read()?,
+        ]))
+    }
+
+    /// The guest memory provided by [`set_guest_memory`](Self::set_guest_memory).
+    fn guest_memory(&self) -> AxResult<Arc<dyn GuestMemory + Send + Sync>> {
+        self.guest_memory
+            .clone()
+            .ok_or_else(|| ax_err_type!(BadState, "guest memory is not set"))
+    }
+
+    /// Read `buf.len()` bytes at the guest linear address `gva`, through the
+    /// guest page tables and the guest memory provided by
+    /// [`set_guest_memory`](Self::set_guest_memory). The range may cross pages.
+    ///
+    /// `access` contains [`MappingFlags::EXECUTE`] for instruction fetches and
+    /// [`MappingFlags::USER`] for user-mode accesses. Accessed flags in the guest
+    /// page tables are set as by the processor.
+    ///
+    /// Return the page fault to inject, e.g. by
+    /// [`inject_page_fault`](Self::inject_page_fault), if the guest page tables
+    /// do not allow the access. Nothing is read then.
+    pub fn read_guest_virt(
+        &self,
+        gva: GuestVirtAddr,
+        buf: &mut [u8],
+        access: MappingFlags,
+    ) -> AxResult<core::result::Result<(), PageFault>> {
+        let mem = self.guest_memory()?;
+        let info = self.get_ptw_info_for(access - MappingFlags::WRITE);
+        let ranges = match paging::translate_range(&*mem, &info, gva, buf.len())? {
+            Ok(ranges) => ranges,
+            Err(fault) => return Ok(Err(fault)),
+        };
+        let mut offset = 0;
+        for (paddr, len) in ranges {
+            mem.read_phys(paddr, &mut buf[offset..offset + len])?;
+            offset += len;
+        }
+        Ok(Ok(()))
+    }
+
+    /// Write `buf` at the guest linear address `gva`, as
+    /// [`read_guest_virt`](Self::read_guest_virt) does for reads. Dirty flags in
+    /// the guest page tables are set as well.
+    ///
+    /// Return the page fault to inject if the guest page tables do not allow the
+    /// access. Nothing is written then.
+    pub fn write_guest_virt(
+        &mut self,
+        gva: GuestVirtAddr,
+        buf: &[u8],
+        access: MappingFlags,
+    ) -> AxResult<core::result::Result<(), PageFault>> {
+        let mem = self.guest_memory()?;
+        let info = self.get_ptw_info_for(access | MappingFlags::WRITE);
+        let ranges = match paging::translate_range(&*mem, &info, gva, buf.len())? {
+            Ok(ranges) => ranges,
+            Err(fault) => return Ok(Err(fault)),
+        };
+        let mut offset = 0;
+        let mut flush = false;
+        for (paddr, len) in ranges {
+            mem.write_phys(paddr, &buf[offset..offset + len])?;
+            offset += len;
+            // The write may change guest page tables shadowed by write protection.
+            if let Some(mmu) = self.shadow_mmu.as_mut() {
+                flush |= mmu.write_guest_phys(paddr)?;
+            }
+        }
+        if flush {
+            let root = self.shadow_mmu.as_ref().map(|mmu| mmu.root().as_usize());
+            if let Some(root) = root {
+                VmcsGuestNW::CR3.write(root)?;
+            }
+            self.flush_guest_tlb();
+        }
+        Ok(Ok(()))
+    }
+
+    /// Guest rip. (`RIP`)
+    pub fn rip(&self) -> usize {
+        VmcsGuestNW::RIP.read().unwrap()
+    }
+
+    /// Guest cs. (`cs`)
+    pub fn cs(&self) -> u16 {
+        VmcsGuest16::CS_SELECTOR.read().unwrap()
+    }
+
+    /// Advance guest `RIP` by `instr_len` bytes.
+    pub fn advance_rip(&mut self, instr_len: u8) -> AxResult {
+        VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)
+    }
+
+    /// Add a virtual interrupt or exception to the pending events list,
+    /// and try to inject it before later VM entries.
+    pub fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
+        self.pending_events.push_back((vector, err_code, false));
+    }
+
+    /// Queue an edge-triggered interrupt whose source needs no EOI, e.g. an IPI
+    /// or an MSI, and try to inject it before later VM entries.
+    ///
+    /// With PV EOI enabled, the guest may skip the EOI write for such interrupts.
+    /// Interrupts queued by [`queue_event`](Self::queue_event) always need an EOI.
+    pub fn queue_edge_interrupt(&mut self, vector: u8) {
+        self.pending_events.push_back((vector, None, true));
+    }
+
+    /// Queue the page fault returned by [`read_guest_virt`](Self::read_guest_virt)
+    /// or [`write_guest_virt`](Self::write_guest_virt), and load its address into
+    /// the guest CR2 before the next VM entry.
+    pub fn inject_page_fault(&mut self, fault: &PageFault) {
+        self.guest_cr2 = Some(fault.addr.as_usize() as u64);
+        self.queue_event(
+            x86::irq::PAGE_FAULT_VECTOR,
+            Some(fault.error_code.bits() as u32),
+        );
+    }
+
+    /// If enable, a VM exit occurs at the beginning of any instruction if
+    /// `RFLAGS.IF` = 1 and there are no other blocking of interrupts.
+    /// (see SDM, Vol. 3C, Section 24.4.2)
+    pub fn set_interrupt_window(&mut self, enable: bool) -> AxResult {
+        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::PrimaryControls::INTERRUPT_WINDOW_EXITING.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
+    /// If enable, a VM exit occurs on any MOV to or from debug registers.
+    fn set_mov_dr_exiting(&mut self, enable: bool) -> AxResult {
+        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::PrimaryControls::MOV_DR_EXITING.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
+    /// Read guest debug register DR0-DR3, DR6 or DR7.
+    pub fn debug_reg(&self, index: u8) -> AxResult<u64> {
+        match index {
+            0..=3 => Ok(self.guest_debug_regs.dr[index as usize]),
+            6 => Ok(self.guest_debug_regs.dr6),
+            7 => match self.guest_dr7 {
+                Some(dr7) => Ok(dr7),
+                None => Ok(VmcsGuestNW::DR7.read()? as u64),
+            },
+            _ => ax_err!(InvalidInput, "invalid debug register"),
+        }
+    }
+
+    /// Write guest debug register DR0-DR3, DR6 or DR7.
+    ///
+    /// Reserved bits of DR6 and DR7 are kept at their fixed values.
+    pub fn set_debug_reg(&mut self, index: u8, val: u64) -> AxResult {
+        match index {
+            0..=3 => self.guest_debug_regs.dr[index as usize] = val,
+            6 => self.guest_debug_regs.dr6 = debug::normalize_dr6(val),
+            7 => match self.guest_dr7 {
+                Some(_) => self.guest_dr7 = Some(debug::normalize_dr7(val)),
+                None => VmcsGuestNW::DR7.write(debug::normalize_dr7(val) as _)?,
+            },
+            _ => return ax_err!(InvalidInput, "invalid debug register"),
+        }
+        Ok(())
+    }
+
+    /// Current guest debugging controlled by the VMM.
+    pub fn debug_control(&self) -> &VcpuDebugControl {
+        &self.debug_control
+    }
+
+    /// Control guest debugging, e.g. on behalf of a gdbstub.
+    ///
+    /// Debug events are reported with [`AxVCpuExitReason::Nothing`], and can be
+    /// retrieved by [`take_debug_event`](Self::take_debug_event). Other debug
+    /// exceptions are reflected to the guest. Hardware breakpoints of the VMM
+    /// take over DR0-DR3 and DR7, while the guest keeps seeing its own values.
+    ///
+    /// Return `Unsupported` if the monitor trap flag is not supported for single-stepping.
+    pub fn set_debug_control(&mut self, control: VcpuDebugControl) -> AxResult {
+        let guest_dr7 = self.debug_reg(7)?;
+        let old = DebugVmcsFields {
+            primary_controls: VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?,
+            exception_bitmap: VmcsControl32::EXCEPTION_BITMAP.read()?,
+            dr7: VmcsGuestNW::DR7.read()? as u64,
+        };
+        let mut new = old;
+        control.apply(&mut new, guest_dr7);
+        vmcs::set_control(
+            VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+            old.primary_controls,
+            new.primary_controls & !old.primary_controls,
+            old.primary_controls & !new.primary_controls,
+        )?;
+        VmcsGuestNW::DR7.write(new.dr7 as _)?;
+        self.guest_dr7 = control.uses_hw_breakpoints().then_some(guest_dr7);
+        self.debug_control = control;
+        self.update_exception_bitmap()
+    }
+
+    /// Take the debug event reported by the last VM exit, if any.
+    pub fn take_debug_event(&mut self) -> Option<DebugEvent> {
+        self.debug_event.take()
+    }
+
+    /// Exceptions intercepted by the VMM, one bit for each vector.
+    pub fn exception_bitmap(&self) -> u32 {
+        self.exception_bitmap
+    }
+
+    /// Set the exceptions intercepted by the VMM, one bit for each vector.
+    ///
+    /// Intercepted exceptions are reported with [`AxVCpuExitReason::Nothing`], and
+    /// can be retrieved by [`take_exception_exit`](Self::take_exception_exit).
+    /// The VMM may reflect them into the guest by [`reflect_exception`](Self::reflect_exception).
+    pub fn set_exception_bitmap(&mut self, bitmap: u32) -> AxResult {
+        self.exception_bitmap = bitmap;
+        self.update_exception_bitmap()
+    }
+
+    /// Set the page-fault error-code mask and match.
+    ///
+    /// If #PF is intercepted, only page faults with `err_code & mask == match_` cause
+    /// VM exits. Otherwise, only page faults that do not satisfy it cause VM exits.
+    /// (SDM Vol. 3C, Section 26.2)
+    pub fn set_page_fault_filter(&mut self, mask: u32, match_: u32) -> AxResult {
+        if self.shadow_mmu.is_some() {
+            return ax_err!(
+                BadState,
+                "all page faults are intercepted for shadow paging"
+            );
         }
+        VmcsControl32::PAGE_FAULT_ERR_CODE_MASK.write(mask)?;
+        VmcsControl32::PAGE_FAULT_ERR_CODE_MATCH.write(match_)
     }
 
-    /// Basic information about VM exits.
-    pub fn exit_info(&self) -> AxResult<vmcs::VmxExitInfo> {
-        vmcs::exit_info()
+    /// Whether the processor supports mode-based execute control for EPT.
+    pub fn mode_based_execute_supported() -> bool {
+        use super::vmcs::controls::SecondaryControls;
+        vmcs::secondary_control_allowed(SecondaryControls::MODE_BASED_EPT.bits())
     }
 
-    /// Raw information for VM Exits Due to Vectored Events, See SDM 25.9.2
-    pub fn raw_interrupt_exit_info(&self) -> AxResult<u32> {
-        vmcs::raw_interrupt_exit_info()
+    /// Enable or disable mode-based execute control for EPT: bit 2 of EPT entries
+    /// allows supervisor-mode linear addresses to be executed, and
+    /// [`EPT_USER_EXECUTE`](crate::EPT_USER_EXECUTE) allows user-mode ones.
+    pub fn set_mode_based_execute(&mut self, enable: bool) -> AxResult {
+        use super::vmcs::controls::SecondaryControls;
+        let bits = SecondaryControls::MODE_BASED_EPT.bits();
+        let (set, clear) = if enable { (bits, 0) } else { (0, bits) };
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?,
+            set,
+            clear,
+        )?;
+        self.flush_ept();
+        Ok(())
     }
 
-    /// Information for VM exits due to external interrupts.
-    pub fn interrupt_exit_info(&self) -> AxResult<vmcs::VmxInterruptInfo> {
-        vmcs::interrupt_exit_info()
+    /// Whether the processor supports sub-page write permissions for EPT.
+    pub fn sub_page_write_supported() -> bool {
+        use super::vmcs::controls::SecondaryControls;
+        vmcs::secondary_control_allowed(SecondaryControls::SUB_PAGE_EPT.bits())
     }
 
-    /// Information for VM exits due to I/O instructions.
-    pub fn io_exit_info(&self) -> AxResult<vmcs::VmxIoExitInfo> {
-        vmcs::io_exit_info()
+    /// Install the root of the SPP table to enable sub-page write permissions, or
+    /// disable them with `None`.
+    ///
+    /// Writes to read-only 4K pages whose EPT entries have [`EPT_SPP`](crate::EPT_SPP)
+    /// set are allowed by the SPP table, in 128-byte sub-pages. A missing or
+    /// misconfigured SPP table entry causes a VM exit, reported by
+    /// [`take_spp_event`](Self::take_spp_event).
+    pub fn set_spp_table_root(&mut self, root: Option<HostPhysAddr>) -> AxResult {
+        use super::vmcs::controls::SecondaryControls;
+        let bits = SecondaryControls::SUB_PAGE_EPT.bits();
+        if root.is_some() && !Self::sub_page_write_supported() {
+            return ax_err!(Unsupported, "sub-page write permissions are not supported");
+        }
+        if let Some(root) = root {
+            VmcsControl64::SUBPAGE_PERM_TABLE_PTR.write(root.as_usize() as u64 & !0xfff)?;
+        }
+        let (set, clear) = if root.is_some() { (bits, 0) } else { (0, bits) };
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?,
+            set,
+            clear,
+        )?;
+        // Sub-page permissions are cached along with EPT mappings.
+        self.flush_ept();
+        Ok(())
     }
 
-    /// Information for VM exits due to nested page table faults (EPT violation).
-    pub fn nested_page_fault_info(&self) -> AxResult<NestedPageFaultInfo> {
-        vmcs::ept_violation_info()
+    /// Take the SPP event reported by the last VM exit, if any.
+    pub fn take_spp_event(&mut self) -> Option<SppEvent> {
+        self.spp_event.take()
     }
 
-    /// Information for VM exits due to APIC access.
-    pub fn apic_access_exit_info(&self) -> AxResult<vmcs::ApicAccessExitInfo> {
-        vmcs::apic_access_exit_info()
+    /// Take the exception reported by the last VM exit, if any.
+    pub fn take_exception_exit(&mut self) -> Option<ExceptionExit> {
+        self.exception_exit.take()
     }
 
-    /// Guest general-purpose registers.
-    pub fn regs(&self) -> &GeneralRegisters {
-        &self.guest_regs
+    /// Calling convention of hypercalls issued by VMCALL.
+    pub fn hypercall_abi(&self) -> HypercallAbi {
+        self.hypercall_abi
     }
 
-    /// Mutable reference of guest general-purpose registers.
-    pub fn regs_mut(&mut self) -> &mut GeneralRegisters {
-        &mut self.guest_regs
+    /// Set the calling convention of hypercalls issued by VMCALL.
+    ///
+    /// VMCALL raises #UD in the guest if hypercalls are disabled, or if it is
+    /// executed with CPL > 0.
+    pub fn set_hypercall_abi(&mut self, abi: HypercallAbi) {
+        self.hypercall_abi = abi;
     }
 
-    /// Guest stack pointer. (`RSP`)
-    pub fn stack_pointer(&self) -> usize {
-        VmcsGuestNW::RSP.read().unwrap()
+    /// Complete the hypercall reported by the last VM exit later, by
+    /// [`complete_deferred_hypercall`](Self::complete_deferred_hypercall).
+    ///
+    /// The vCPU does not run until then, [`AxVCpuExitReason::Halt`] is returned
+    /// instead, with [`VmxHaltReason::DeferredHypercall`] as the halt reason.
+    pub fn defer_hypercall(&mut self) -> AxResult {
+        match self.pending_hypercall.as_mut() {
+            Some(hypercall) if !hypercall.deferred => {
+                hypercall.deferred = true;
+                Ok(())
+            }
+            Some(_) => ax_err!(BadState, "hypercall is already deferred"),
+            None => ax_err!(BadState, "no hypercall to defer"),
+        }
     }
 
-    /// Set guest stack pointer. (`RSP`)
-    pub fn set_stack_pointer(&mut self, rsp: usize) {
-        VmcsGuestNW::RSP.write(rsp).unwrap()
+    /// Set the return value of the hypercall reported by the last VM exit.
+    ///
+    /// A deferred hypercall is completed by
+    /// [`complete_deferred_hypercall`](Self::complete_deferred_hypercall) instead.
+    pub fn complete_hypercall(&mut self, ret: u64) -> AxResult {
+        match self.pending_hypercall {
+            Some(hypercall) if !hypercall.deferred => self.finish_hypercall(hypercall, ret),
+            Some(_) => ax_err!(BadState, "hypercall is deferred"),
+            None => ax_err!(BadState, "no hypercall to complete"),
+        }
     }
 
-    /// Translate guest virtual addr to linear addr    
-    pub fn gla2gva(&self, guest_rip: GuestVirtAddr) -> GuestVirtAddr {
-        let cpu_mode = self.get_cpu_mode();
-        let seg_base = if cpu_mode == VmCpuMode::Mode64 {
-            0
-        } else {
-            VmcsGuestNW::CS_BASE.read().unwrap()
-        };
-        // debug!(
-        //     "seg_base: {:#x}, guest_rip: {:#x} cpu mode:{:?}",
-        //     seg_base, guest_rip, cpu_mode
-        // );
-        guest_rip + seg_base
+    /// Set the return value of the hypercall deferred by
+    /// [`defer_hypercall`](Self::defer_hypercall), so that the vCPU can run again.
+    pub fn complete_deferred_hypercall(&mut self, ret: u64) -> AxResult {
+        match self.pending_hypercall {
+            Some(hypercall) if hypercall.deferred => self.finish_hypercall(hypercall, ret),
+            _ => ax_err!(BadState, "no deferred hypercall to complete"),
+        }
     }
 
-    /// Get Translate guest page table info
-    pub fn get_ptw_info(&self) -> GuestPageWalkInfo {
-        let top_entry = VmcsGuestNW::CR3.read().unwrap();
-        let level = self.get_paging_level();
-        let is_write_access = false;
-        let is_inst_fetch = false;
-        let is_user_mode_access = ((VmcsGuest32::SS_ACCESS_RIGHTS.read().unwrap() >> 5) & 0x3) == 3;
-        let mut pse = true;
-        let mut nxe =
-            (VmcsGuest64::IA32_EFER.read().unwrap() & EferFlags::NO_EXECUTE_ENABLE.bits()) != 0;
-        let wp = (VmcsGuestNW::CR0.read().unwrap() & Cr0Flags::WRITE_PROTECT.bits() as usize) != 0;
-        let is_smap_on = (VmcsGuestNW::CR4.read().unwrap()
-            & Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits() as usize)
-            != 0;
-        let is_smep_on = (VmcsGuestNW::CR4.read().unwrap()
-            & Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits() as usize)
-            != 0;
-        let width: u32;
-        if level == 4 || level == 3 {
-            width = 9;
-        } else if level == 2 {
-            width = 10;
-            pse = VmcsGuestNW::CR4.read().unwrap() & Cr4Flags::PAGE_SIZE_EXTENSION.bits() as usize
-                != 0;
-            nxe = false;
-        } else {
-            width = 0;
+    fn finish_hypercall(&mut self, hypercall: PendingHypercall, ret: u64) -> AxResult {
+        hypercall
+            .abi
+            .set_result(&mut self.guest_regs, hypercall.long_mode, ret);
+        self.pending_hypercall = None;
+        Ok(())
+    }
+
+    /// Whether a hypercall has been deferred and is not completed yet.
+    pub fn has_deferred_hypercall(&self) -> bool {
+        self.pending_hypercall.is_some_and(|h| h.deferred)
+    }
+
+    /// Provide access to guest physical memory, required by paravirtual interfaces.
+    pub fn set_guest_memory(&mut self, mem: Arc<dyn GuestMemory + Send + Sync>) {
+        self.guest_memory = Some(mem);
+    }
+
+    /// Enable the KVM-compatible paravirtual interface, or disable it with `None`.
+    ///
+    /// The guest sees the KVM signature in CPUID, and may use kvmclock, steal time
+    /// and PV EOI. PV send-IPI is available if hypercalls use [`HypercallAbi::Kvm`]
+    /// and an IPI router is set. Guest memory must have been provided by
+    /// [`set_guest_memory`](Self::set_guest_memory).
+    pub fn set_kvm_pv(&mut self, config: Option<KvmPvConfig>) -> AxResult {
+        if config.is_some() && self.guest_memory.is_none() {
+            return ax_err!(BadState, "guest memory is required by KVM PV");
         }
-        GuestPageWalkInfo {
-            top_entry,
-            level,
-            width,
-            is_user_mode_access,
-            is_write_access,
-            is_inst_fetch,
-            pse,
-            wp,
-            nxe,
-            is_smap_on,
-            is_smep_on,
+        if config.is_some() && self.hyperv.is_some() {
+            return ax_err!(BadState, "KVM PV and Hyper-V enlightenments are exclusive");
         }
+        self.kvm_pv = config.map(|config| KvmPv::new(config, timer::tsc_frequency_hz()));
+        Ok(())
     }
 
-    /// Guest rip. (`RIP`)
-    pub fn rip(&self) -> usize {
-        VmcsGuestNW::RIP.read().unwrap()
+    /// Enable the Hyper-V enlightenments, or disable them with `None`.
+    ///
+    /// The guest sees the Hyper-V signature in CPUID, and may use the hypercall
+    /// page, the VP index, the reference time and the SynIC with synthetic timers.
+    /// The VP index is the vCPU ID. Hypercalls should use [`HypercallAbi::HyperV`],
+    /// and raise #UD until the guest enables the hypercall page. Guest memory must
+    /// have been provided by [`set_guest_memory`](Self::set_guest_memory).
+    pub fn set_hyperv(&mut self, config: Option<HyperVConfig>) -> AxResult {
+        if config.is_some() && self.guest_memory.is_none() {
+            return ax_err!(
+                BadState,
+                "guest memory is required by Hyper-V enlightenments"
+            );
+        }
+        if config.is_some() && self.kvm_pv.is_some() {
+            return ax_err!(BadState, "KVM PV and Hyper-V enlightenments are exclusive");
+        }
+        self.hyperv = config
+            .map(|config| HyperV::new(config, self.vcpu_id as u32, timer::tsc_frequency_hz()));
+        Ok(())
     }
 
-    /// Guest cs. (`cs`)
-    pub fn cs(&self) -> u16 {
-        VmcsGuest16::CS_SELECTOR.read().unwrap()
+    /// Account `ns` nanoseconds of steal time, during which the vCPU was runnable
+    /// but not running. It is reported to the guest on the next bind.
+    pub fn add_steal_time(&mut self, ns: u64) {
+        if let Some(kvm_pv) = self.kvm_pv.as_mut() {
+            kvm_pv.add_steal_time(ns);
+        }
     }
 
-    /// Advance guest `RIP` by `instr_len` bytes.
-    pub fn advance_rip(&mut self, instr_len: u8) -> AxResult {
-        VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)
+    /// Update the shared structures of the KVM paravirtual interface on bind and unbind.
+    fn update_kvm_pv(&mut self, preempted: bool) -> AxResult {
+        if let (Some(kvm_pv), Some(mem)) = (self.kvm_pv.as_mut(), self.guest_memory.as_ref()) {
+            if !preempted {
+                kvm_pv.update_system_time(mem.as_ref(), timer::current_tsc())?;
+            }
+            kvm_pv.update_steal_time(mem.as_ref(), preempted)?;
+        }
+        Ok(())
     }
 
-    /// Add a virtual interrupt or exception to the pending events list,
-    /// and try to inject it before later VM entries.
-    pub fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
-        self.pending_events.push_back((vector, err_code));
+    /// Reflect an exception that caused a VM exit into the guest, on the next VM entry.
+    ///
+    /// CR2 is loaded for #PF, and DR6 is updated for #DB, as if the exception had
+    /// been delivered to the guest directly.
+    ///
+    /// If the exception was raised while delivering another event, it is merged into
+    /// a double fault, or the other event is delivered after it. A triple fault shuts
+    /// down the guest, reported as [`AxVCpuExitReason::SystemDown`] by `run`.
+    pub fn reflect_exception(&mut self, exit: &ExceptionExit) -> AxResult {
+        if let Some(cr2) = exit.cr2 {
+            self.guest_cr2 = Some(cr2);
+        }
+        if let Some(conditions) = exit.dr6 {
+            // DR6 is not updated by VM exits due to #DB. (SDM Vol. 3C, Section 28.1)
+            let dr6 = (self.guest_debug_regs.dr6 & !0xf) | conditions;
+            self.guest_debug_regs.dr6 = debug::normalize_dr6(dr6);
+        }
+        if let Some(original) = vmcs::idt_vectoring_info()? {
+            match original.int_type {
+                VmxInterruptionType::HardException => match exit.merge_with(original.vector) {
+                    ExceptionMerge::DoubleFault => {
+                        return vmcs::inject_event(x86::irq::DOUBLE_FAULT_VECTOR, Some(0));
+                    }
+                    ExceptionMerge::TripleFault => {
+                        self.triple_fault = true;
+                        return Ok(());
+                    }
+                    ExceptionMerge::Serial => {}
+                },
+                // Interrupts and NMIs are not raised again, deliver them after the exception.
+                VmxInterruptionType::External | VmxInterruptionType::NMI => {
+                    self.pending_events
+                        .push_front((original.vector, original.err_code, false));
+                }
+                // Software interrupts and exceptions are raised again by the instruction.
+                _ => {}
+            }
+        }
+        vmcs::inject_interrupt_info(&exit.interrupt_info(), exit.instruction_length)
     }
 
-    /// If enable, a VM exit occurs at the beginning of any instruction if
-    /// `RFLAGS.IF` = 1 and there are no other blocking of interrupts.
-    /// (see SDM, Vol. 3C, Section 24.4.2)
-    pub fn set_interrupt_window(&mut self, enable: bool) -> AxResult {
-        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
-        let bits = vmcs::controls::PrimaryControls::INTERRUPT_WINDOW_EXITING.bits();
-        if enable {
-            ctrl |= bits
-        } else {
-            ctrl &= !bits
+    /// Enable the VMX-preemption timer as a scheduling quantum, or disable it with `None`.
+    ///
+    /// When enabled, the guest is forced to exit after running for `quantum_ns`
+    /// nanoseconds. The exit is reported to the VMM as
+    /// [`AxVCpuExitReason::Nothing`], with [`quantum_expired`](Self::quantum_expired)
+    /// returning `true`. The new configuration takes effect on the next VM entry.
+    pub fn set_preemption_timer(&mut self, config: Option<PreemptionTimerConfig>) -> AxResult {
+        use vmcs::controls::{ExitControls, PinbasedControls};
+
+        if let Some(config) = config {
+            let pin_allowed1 = (Msr::IA32_VMX_TRUE_PINBASED_CTLS.read() >> 32) as u32;
+            if pin_allowed1 & PinbasedControls::VMX_PREEMPTION_TIMER.bits() == 0 {
+                return ax_err!(Unsupported, "VMX-preemption timer is not supported");
+            }
+            let exit_allowed1 = (Msr::IA32_VMX_TRUE_EXIT_CTLS.read() >> 32) as u32;
+            if config.save_on_exit
+                && exit_allowed1 & ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits() == 0
+            {
+                return ax_err!(
+                    Unsupported,
+                    "saving VMX-preemption timer value is not supported"
+                );
+            }
         }
-        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        self.preemption_timer.configure(config);
         Ok(())
     }
 
+    /// Current configuration of the VMX-preemption timer.
+    pub fn preemption_timer(&self) -> Option<PreemptionTimerConfig> {
+        self.preemption_timer.config()
+    }
+
+    /// Whether the last VM exit is caused by the end of the scheduling quantum of
+    /// the VMX-preemption timer.
+    pub fn quantum_expired(&self) -> bool {
+        self.preemption_timer.is_expired()
+    }
+
     /// Set I/O intercept by modifying I/O bitmap.
     pub fn set_io_intercept_of_range(&mut self, port_base: u32, count: u32, intercept: bool) {
         self.io_bitmap
@@ -557,6 +2150,18 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         self.msr_bitmap
             .set_read_intercept(IA32_UMWAIT_CONTROL, true);
 
+        // Intercept IA32_TSC_DEADLINE MSR accesses, the guest local APIC timer
+        // is backed by the VMX-preemption timer.
+        self.msr_bitmap
+            .set_read_intercept(Msr::IA32_TSC_DEADLINE as u32, true);
+        self.msr_bitmap
+            .set_write_intercept(Msr::IA32_TSC_DEADLINE as u32, true);
+
+        // Intercept IA32_EFER writes to keep track of the paging mode, reads are
+        // served from the guest IA32_EFER in the VMCS.
+        self.msr_bitmap
+            .set_write_intercept(Msr::IA32_EFER as u32, true);
+
         // Intercept all x2APIC MSR accesses
         for msr in 0x800..=0x83f {
             self.msr_bitmap.set_read_intercept(msr, true);
@@ -647,7 +2252,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         VmcsGuestNW::IDTR_BASE.write(0)?;
         VmcsGuest32::IDTR_LIMIT.write(0xffff)?;
 
-        VmcsGuestNW::CR3.write(0)?;
+        self.set_cr(3, 0);
         VmcsGuestNW::DR7.write(0x400)?;
         VmcsGuestNW::RSP.write(0)?;
         VmcsGuestNW::RIP.write(entry.as_usize())?;
@@ -660,7 +2265,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         VmcsGuest32::INTERRUPTIBILITY_STATE.write(0)?;
         VmcsGuest32::ACTIVITY_STATE.write(0)?;
 
-        VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(VMX_PREEMPTION_TIMER_SET_VALUE)?;
+        VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(0)?;
 
         VmcsGuest64::LINK_PTR.write(u64::MAX)?; // SDM Vol. 3C, Section 24.4.2
         VmcsGuest64::IA32_DEBUGCTL.write(0)?;
@@ -669,6 +2274,55 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// Reset the guest to its state after INIT. (SDM Vol. 3A, Section 10.1.1, Table 10-1)
+    fn reset_to_init_state(&mut self) -> AxResult {
+        // Start from the real-mode state, then move to the reset vector at 0xffff_fff0.
+        self.vm86 = None;
+        self.setup_vmcs_guest(GuestPhysAddr::from(0xfff0))?;
+        VmcsGuest16::CS_SELECTOR.write(0xf000)?;
+        VmcsGuestNW::CS_BASE.write(0xffff_0000)?;
+        vmcs::update_efer(0)?;
+        self.flush_shadow_mmu()?;
+        self.update_vm86()?;
+
+        // EDX holds the processor signature, other general-purpose registers are cleared.
+        self.guest_regs = GeneralRegisters::default();
+        self.guest_regs.rdx = raw_cpuid::cpuid!(1).eax as u64;
+
+        self.pending_events.clear();
+        self.vlapic = EmulatedLocalApic::new(self.vm_id, self.vcpu_id);
+        self.icr = 0;
+        self.lapic_timer = GuestLapicTimer::new();
+        self.guest_debug_regs = DebugRegs::new();
+        self.guest_cr2 = None;
+        self.triple_fault = false;
+        if self.guest_dr7.is_some() {
+            self.guest_dr7 = Some(debug::DR7_FIXED_1);
+            VmcsGuestNW::DR7.write(self.debug_control.dr7() as _)?;
+        }
+        Ok(())
+    }
+
+    /// Handle pending INIT and SIPI signals before the vCPU runs.
+    fn handle_pending_init_sipi(&mut self) -> AxResult {
+        if core::mem::take(&mut self.pending_init) {
+            self.reset_to_init_state()?;
+            if !self.is_bsp {
+                self.set_activity_state(VmxActivityState::WaitForSipi)?;
+            }
+        }
+        if let Some(vector) = self.pending_sipi.take() {
+            // A SIPI is ignored if the processor is not waiting for it.
+            if self.is_waiting_for_sipi()? {
+                VmcsGuest16::CS_SELECTOR.write((vector as u16) << 8)?;
+                VmcsGuestNW::CS_BASE.write((vector as usize) << 12)?;
+                VmcsGuestNW::RIP.write(0)?;
+                self.set_activity_state(VmxActivityState::Active)?;
+            }
+        }
+        Ok(())
+    }
+
     fn setup_vmcs_control(&mut self, ept_root: HostPhysAddr, is_guest: bool) -> AxResult {
         // Intercept NMI and external interrupts.
         use super::vmcs::controls::*;
@@ -680,39 +2334,55 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             Msr::IA32_VMX_TRUE_PINBASED_CTLS,
             Msr::IA32_VMX_PINBASED_CTLS.read() as u32,
             (PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING).bits(),
-            // (PinCtrl::NMI_EXITING | PinCtrl::VMX_PREEMPTION_TIMER).bits(),
-            // PinCtrl::NMI_EXITING.bits(),
             0,
         )?;
+        // The VMX-preemption timer is enabled on demand, see `arm_preemption_timer`.
 
         // Intercept all I/O instructions, use MSR bitmaps, activate secondary controls,
-        // disable CR3 load/store interception.
+        // intercept CR8 accesses to emulate the TPR, disable CR3 load/store interception.
         use PrimaryControls as CpuCtrl;
+        let mut val =
+            CpuCtrl::USE_IO_BITMAPS | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS;
+        // Intercept HLT if the halted state can be kept in the VMCS.
+        if VmxMisc::read().activity_hlt {
+            val |= CpuCtrl::HLT_EXITING;
+        }
+        // Intercept MOV DR to switch debug registers lazily.
+        val |= CpuCtrl::MOV_DR_EXITING;
+        val |= CpuCtrl::CR8_LOAD_EXITING | CpuCtrl::CR8_STORE_EXITING;
+        let shadow_paging = self.mmu_mode == MmuMode::Shadow;
+        let mut clear = CpuCtrl::empty();
+        let cr3_exiting = CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING;
+        if shadow_paging {
+            // Intercept CR3 accesses and INVLPG to maintain the shadow page tables.
+            val |= cr3_exiting | CpuCtrl::INVLPG_EXITING;
+        } else {
+            clear |= cr3_exiting;
+        }
         vmcs::set_control(
             VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
             Msr::IA32_VMX_PROCBASED_CTLS.read() as u32,
-            (CpuCtrl::USE_IO_BITMAPS | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS)
-                .bits(),
-            (CpuCtrl::CR3_LOAD_EXITING
-                | CpuCtrl::CR3_STORE_EXITING
-                | CpuCtrl::CR8_LOAD_EXITING
-                | CpuCtrl::CR8_STORE_EXITING)
-                .bits(),
+            val.bits(),
+            clear.bits(),
         )?;
 
         // Enable EPT, RDTSCP, INVPCID, and unrestricted guest.
         use SecondaryControls as CpuCtrl2;
-        let mut val =
-            // CpuCtrl2::VIRTUALIZE_APIC | 
-            CpuCtrl2::ENABLE_EPT | CpuCtrl2::UNRESTRICTED_GUEST;
+        let mut val = if shadow_paging {
+            CpuCtrl2::empty()
+        } else {
+            // CpuCtrl2::VIRTUALIZE_APIC |
+            CpuCtrl2::ENABLE_EPT | CpuCtrl2::UNRESTRICTED_GUEST
+        };
         if let Some(features) = raw_cpuid.get_extended_processor_and_feature_identifiers() {
             if features.has_rdtscp() {
                 val |= CpuCtrl2::ENABLE_RDTSCP;
             }
         }
+        // INVPCID is not intercepted for shadow paging, and causes #UD.
         if let Some(features) = raw_cpuid.get_extended_feature_info() {
-            if features.has_invpcid() {
+            if features.has_invpcid() && !shadow_paging {
                 val |= CpuCtrl2::ENABLE_INVPCID;
             }
         }
@@ -721,6 +2391,11 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                 val |= CpuCtrl2::ENABLE_XSAVES_XRSTORS;
             }
         }
+        // Tag guest TLB entries with the VPID, instead of flushing them on every VM entry and exit.
+        if let Some((vpid, _)) = &self.vpid {
+            val |= CpuCtrl2::ENABLE_VPID;
+            VmcsControl16::VPID.write(vpid.get())?;
+        }
         vmcs::set_control(
             VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_PROCBASED_CTLS2,
@@ -729,7 +2404,8 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             0,
         )?;
 
-        // Switch to 64-bit host, acknowledge interrupt info, switch IA32_PAT/IA32_EFER on VM exit.
+        // Switch to 64-bit host, acknowledge interrupt info, switch IA32_PAT/IA32_EFER and
+        // save guest DR7/IA32_DEBUGCTL on VM exit.
         use ExitControls as ExitCtrl;
         vmcs::set_control(
             VmcsControl32::VMEXIT_CONTROLS,
@@ -740,12 +2416,14 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                 | ExitCtrl::SAVE_IA32_PAT
                 | ExitCtrl::LOAD_IA32_PAT
                 | ExitCtrl::SAVE_IA32_EFER
-                | ExitCtrl::LOAD_IA32_EFER)
+                | ExitCtrl::LOAD_IA32_EFER
+                | ExitCtrl::SAVE_DEBUG_CONTROLS)
                 .bits(),
             0,
         )?;
 
-        let mut val = EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER;
+        let mut val =
+            EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER | EntryCtrl::LOAD_DEBUG_CONTROLS;
 
         if !is_guest {
             // IA-32e mode guest
@@ -754,7 +2432,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             val |= EntryCtrl::IA32E_MODE_GUEST;
         }
 
-        // Load guest IA32_PAT/IA32_EFER on VM entry.
+        // Load guest IA32_PAT/IA32_EFER and DR7/IA32_DEBUGCTL on VM entry.
         use EntryControls as EntryCtrl;
         vmcs::set_control(
             VmcsControl32::VMENTRY_CONTROLS,
@@ -764,7 +2442,14 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             0,
         )?;
 
-        vmcs::set_ept_pointer(ept_root)?;
+        if shadow_paging {
+            self.shadow_mmu = Some(ShadowMmu::new(FrameTables::new(), 3)?);
+            self.flush_shadow_mmu()?;
+            self.update_vm86()?;
+        } else {
+            vmcs::set_ept_pointer(self.eptp_config.eptp(ept_root))?;
+        }
+        self.eptp_update_pending = false;
 
         // No MSR switches if hypervisor doesn't use and there is only one vCPU.
         VmcsControl32::VMEXIT_MSR_STORE_COUNT.write(0)?;
@@ -774,12 +2459,10 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         // VmcsControlNW::CR4_GUEST_HOST_MASK.write(0)?;
         VmcsControl32::CR3_TARGET_COUNT.write(0)?;
 
-        // Pass-through exceptions (except #UD(6)), don't use I/O bitmap, set MSR bitmaps.
-        let exception_bitmap: u32 = 1 << 6;
-
+        // Intercept exceptions for the VMM (only #UD(6) by default), set I/O and MSR bitmaps.
         self.setup_io_bitmap()?;
 
-        VmcsControl32::EXCEPTION_BITMAP.write(exception_bitmap)?;
+        self.update_exception_bitmap()?;
         VmcsControl64::IO_BITMAP_A_ADDR.write(self.io_bitmap.phys_addr().0.as_usize() as _)?;
         VmcsControl64::IO_BITMAP_B_ADDR.write(self.io_bitmap.phys_addr().1.as_usize() as _)?;
         VmcsControl64::MSR_BITMAPS_ADDR.write(self.msr_bitmap.phys_addr().as_usize() as _)?;
@@ -792,15 +2475,19 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
     fn get_paging_level(&self) -> usize {
         let mut level: u32 = 0; // non-paging
-        let cr0 = VmcsGuestNW::CR0.read().unwrap();
-        let cr4 = VmcsGuestNW::CR4.read().unwrap();
+        let cr0 = self.cr(0);
+        let cr4 = self.cr(4);
         let efer = VmcsGuest64::IA32_EFER.read().unwrap();
         // paging is enabled
         if cr0 & Cr0Flags::PAGING.bits() as usize != 0 {
             if cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() as usize != 0 {
                 // is long mode
                 if efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0 {
-                    level = 4;
+                    level = if cr4 & Cr4Flags::L5_PAGING.bits() as usize != 0 {
+                        5
+                    } else {
+                        4
+                    };
                 } else {
                     level = 3;
                 }
@@ -826,25 +2513,53 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                     // - NW and CD are kept off as they are not updated on VM exit and we
                     //   don't want them enabled for performance reasons while in root mode
                     // - PE and PG can be freely chosen (by the guest) because we demand
-                    //   unrestricted guest mode support anyway
+                    //   unrestricted guest mode support anyway, but writes to them are
+                    //   intercepted to check them and keep EFER.LMA up to date
                     // - ET is ignored
+                    // - With shadow paging, PE and PG are always set, and WP is set to
+                    //   write-protect shadow pages from supervisor writes
                     let must0 = Msr::IA32_VMX_CR0_FIXED1.read()
                         & !(Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE).bits();
-                    let must1 = Msr::IA32_VMX_CR0_FIXED0.read()
-                        & !(Cr0Flags::PAGING | Cr0Flags::PROTECTED_MODE_ENABLE).bits();
+                    let mut must1 = Msr::IA32_VMX_CR0_FIXED0.read();
+                    let mut intercepted =
+                        (Cr0Flags::PAGING | Cr0Flags::PROTECTED_MODE_ENABLE).bits();
+                    if self.mmu_mode == MmuMode::Shadow {
+                        must1 |= Cr0Flags::WRITE_PROTECT.bits();
+                        intercepted |= Cr0Flags::WRITE_PROTECT.bits();
+                    } else {
+                        must1 &= !(Cr0Flags::PAGING | Cr0Flags::PROTECTED_MODE_ENABLE).bits();
+                    }
                     VmcsGuestNW::CR0.write(((val & must0) | must1) as _)?;
                     VmcsControlNW::CR0_READ_SHADOW.write(val as _)?;
-                    VmcsControlNW::CR0_GUEST_HOST_MASK.write((must1 | !must0) as _)?;
+                    VmcsControlNW::CR0_GUEST_HOST_MASK
+                        .write((must1 | !must0 | intercepted) as _)?;
+                }
+                3 if self.shadow_mmu.is_some() => {
+                    self.shadow_cr3 = val;
+                    self.flush_shadow_mmu()?;
                 }
                 3 => VmcsGuestNW::CR3.write(val as _)?,
                 4 => {
                     // Retrieve/validate restrictions on CR4
                     let must0 = Msr::IA32_VMX_CR4_FIXED1.read();
-                    let must1 = Msr::IA32_VMX_CR4_FIXED0.read();
+                    let mut must1 = Msr::IA32_VMX_CR4_FIXED0.read();
+                    // Changes of paging modes are intercepted to be checked.
+                    let mut intercepted = (Cr4Flags::PHYSICAL_ADDRESS_EXTENSION
+                        | Cr4Flags::PCID
+                        | Cr4Flags::L5_PAGING)
+                        .bits();
+                    if self.mmu_mode == MmuMode::Shadow {
+                        // Shadow page tables use PAE paging outside IA-32e mode, and
+                        // are dropped when PSE or PGE changes.
+                        must1 |= Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits();
+                        intercepted |=
+                            (Cr4Flags::PAGE_SIZE_EXTENSION | Cr4Flags::PAGE_GLOBAL).bits();
+                    }
                     let val = val | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
                     VmcsGuestNW::CR4.write(((val & must0) | must1) as _)?;
                     VmcsControlNW::CR4_READ_SHADOW.write(val as _)?;
-                    VmcsControlNW::CR4_GUEST_HOST_MASK.write((must1 | !must0) as _)?;
+                    VmcsControlNW::CR4_GUEST_HOST_MASK
+                        .write((must1 | !must0 | intercepted) as _)?;
                 }
                 _ => unreachable!(),
             };
@@ -853,11 +2568,15 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         .expect("Failed to write guest control register")
     }
 
-    #[allow(dead_code)]
     fn cr(&self, cr_idx: usize) -> usize {
         (|| -> AxResult<usize> {
             Ok(match cr_idx {
-                0 => VmcsGuestNW::CR0.read()?,
+                0 => {
+                    let host_mask = VmcsControlNW::CR0_GUEST_HOST_MASK.read()?;
+                    (VmcsControlNW::CR0_READ_SHADOW.read()? & host_mask)
+                        | (VmcsGuestNW::CR0.read()? & !host_mask)
+                }
+                3 if self.shadow_mmu.is_some() => self.shadow_cr3 as usize,
                 3 => VmcsGuestNW::CR3.read()?,
                 4 => {
                     let host_mask = VmcsControlNW::CR4_GUEST_HOST_MASK.read()?;
@@ -869,6 +2588,100 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         })()
         .expect("Failed to read guest control register")
     }
+
+    /// Control registers and IA32_EFER of the guest.
+    fn guest_control_regs(&self) -> AxResult<GuestControlRegs> {
+        Ok(GuestControlRegs {
+            cr0: self.cr(0) as _,
+            cr3: self.cr(3) as _,
+            cr4: self.cr(4) as _,
+            efer: VmcsGuest64::IA32_EFER.read()?,
+        })
+    }
+
+    /// Write CR4 of the guest by MOV to CR4. Bits of features not exposed to the
+    /// guest are reserved.
+    fn write_guest_cr4(&self, regs: &GuestControlRegs, val: u64) -> Option<GuestControlRegs> {
+        if !self.la57 && val & Cr4Flags::L5_PAGING.bits() != 0 {
+            return None;
+        }
+        regs.write_cr4(val)
+    }
+
+    /// Load the PDPTEs of PAE paging from guest memory into the VMCS, as the
+    /// processor does on the emulated write of control registers from `old` to
+    /// `new`. The processor uses them on the next VM entry with EPT.
+    ///
+    /// Return `false` if a present PDPTE sets reserved bits, the write then causes
+    /// #GP(0). Shadow page tables read the PDPTEs from guest memory instead.
+    fn load_guest_pdptes(&self, old: &GuestControlRegs, new: &GuestControlRegs) -> AxResult<bool> {
+        if self.shadow_mmu.is_some() || !new.loads_pdptes(old) {
+            return Ok(true);
+        }
+        let mem = self.shadow_guest_memory()?;
+        let mut buf = [0; 32];
+        if mem
+            .read_phys(GuestPhysAddr::from(new.pdpt_addr() as usize), &mut buf)
+            .is_err()
+        {
+            // Physical addresses not backed by memory read as all ones on bare
+            // metal, which sets reserved bits.
+            buf = [0xff; 32];
+        }
+        let pdptes: [u64; 4] =
+            core::array::from_fn(|i| u64::from_le_bytes(buf[i * 8..i * 8 + 8].try_into().unwrap()));
+        let phys_addr_bits = CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map_or(36, |f| f.physical_address_bits());
+        if !cr::pdptes_valid(&pdptes, phys_addr_bits) {
+            return Ok(false);
+        }
+        VmcsGuest64::PDPTE0.write(pdptes[0])?;
+        VmcsGuest64::PDPTE1.write(pdptes[1])?;
+        VmcsGuest64::PDPTE2.write(pdptes[2])?;
+        VmcsGuest64::PDPTE3.write(pdptes[3])?;
+        Ok(true)
+    }
+
+    /// Write back the control registers and IA32_EFER of the guest which are changed.
+    ///
+    /// Return `false` if the PDPTEs of PAE paging can not be loaded, the write then
+    /// causes #GP(0) and nothing is changed.
+    fn set_guest_control_regs(
+        &mut self,
+        old: &GuestControlRegs,
+        new: &GuestControlRegs,
+    ) -> AxResult<bool> {
+        if !self.load_guest_pdptes(old, new)? {
+            return Ok(false);
+        }
+        let (old_mode, new_mode) = (old.paging_mode(), new.paging_mode());
+        if old_mode != new_mode {
+            debug!(
+                "Guest paging mode changed: {:?} -> {:?}",
+                old_mode, new_mode
+            );
+        }
+
+        if new.cr0 != old.cr0 {
+            self.set_cr(0, new.cr0);
+        }
+        if new.cr3 != old.cr3 {
+            self.set_cr(3, new.cr3);
+        }
+        if new.cr4 != old.cr4 {
+            self.set_cr(4, new.cr4);
+        }
+        if new.efer != old.efer || old_mode.is_ia32e() != new_mode.is_ia32e() {
+            vmcs::update_efer(new.efer)?;
+        }
+        // Shadow page tables depend on all of them.
+        if new != old {
+            self.flush_shadow_mmu()?;
+        }
+        self.update_vm86()?;
+        Ok(true)
+    }
 }
 
 /// Get ready then vmlaunch or vmresume.
@@ -941,24 +2754,162 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
             && block_state == 0
     }
 
+    /// Return [`AxVCpuExitReason::Halt`] from `run` for `reason`.
+    fn halt(&mut self, reason: VmxHaltReason) -> AxVCpuExitReason {
+        self.halt_reason = Some(reason);
+        AxVCpuExitReason::Halt
+    }
+
+    /// Whether any pending event can wake up a halted guest.
+    fn has_deliverable_event(&self) -> bool {
+        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
+        let interrupts_enabled =
+            rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0;
+        self.pending_events
+            .iter()
+            .any(|event| event.0 < 32 || interrupts_enabled)
+    }
+
     /// Try to inject a pending event before next VM entry.
     fn inject_pending_events(&mut self) -> AxResult {
-        if let Some(event) = self.pending_events.front() {
-            // trace!(
-            //     "pending event vector {:#x} allow_int {}",
-            //     event.0,
-            //     self.allow_interrupt()
-            // );
-            if event.0 < 32 || self.allow_interrupt() {
-                // if it's an exception, or an interrupt that is not blocked, inject it directly.
+        if vmcs::has_injected_event()? {
+            // An exception is being reflected, pending events are injected on a later
+            // VM entry, after an interrupt window.
+            if !self.pending_events.is_empty() {
+                self.set_interrupt_window(true)?;
+            }
+            return Ok(());
+        }
+        // Exceptions are not blocked by interrupts queued before them.
+        let allow_interrupt = self.allow_interrupt();
+        let index = self
+            .pending_events
+            .iter()
+            .position(|event| event.0 < 32 || allow_interrupt);
+        if let Some(index) = index {
+            let event = self.pending_events[index];
+            // if it's an exception, or an interrupt that is not blocked, inject it directly.
+            if self.vm86.is_some() {
+                self.deliver_real_mode_interrupt(event.0)?;
+            } else {
                 vmcs::inject_event(event.0, event.1)?;
-                self.pending_events.pop_front();
+            }
+            if event.0 >= 32 {
+                self.set_pv_eoi(event.2)?;
+            }
+            self.pending_events.remove(index);
+            // The injected event wakes up a halted guest.
+            if vmcs::activity_state()? == VmxActivityState::Hlt {
+                vmcs::set_activity_state(VmxActivityState::Active)?;
+            }
+        } else if !self.pending_events.is_empty() {
+            // interrupts are blocked, enable interrupt-window exiting.
+            self.set_interrupt_window(true)?;
+        }
+        Ok(())
+    }
+
+    /// Update the PV EOI flag for an injected interrupt, if PV EOI is enabled.
+    ///
+    /// The flag is only set if `no_eoi_needed`, i.e. the interrupt is edge-triggered
+    /// and its source does not wait for an EOI, so an EOI skipped by the guest needs
+    /// no further handling. Otherwise it is cleared, and the guest writes the EOI.
+    fn set_pv_eoi(&mut self, no_eoi_needed: bool) -> AxResult {
+        if let (Some(kvm_pv), Some(mem)) = (self.kvm_pv.as_mut(), self.guest_memory.as_ref()) {
+            let acked = kvm_pv.inject_pv_eoi(mem.as_ref(), no_eoi_needed)?;
+            trace!("PV EOI set, last one acknowledged: {}", acked);
+        }
+        Ok(())
+    }
+
+    /// Program the VMX-preemption timer before next VM entry, to expire at the
+    /// earliest of the end of the scheduling quantum and the guest local APIC
+    /// timer deadline.
+    fn arm_preemption_timer(&mut self) -> AxResult {
+        use vmcs::controls::{ExitControls, PinbasedControls};
+
+        let value = self
+            .preemption_timer
+            .next_value(timer::current_tsc(), self.timer_deadline());
+
+        let controls_dirty = self.preemption_timer.take_controls_dirty();
+        if controls_dirty || value.is_some() != self.preemption_timer.is_active() {
+            let (set, clear) = if value.is_some() {
+                (PinbasedControls::VMX_PREEMPTION_TIMER.bits(), 0)
             } else {
-                // interrupts are blocked, enable interrupt-window exiting.
-                self.set_interrupt_window(true)?;
+                (0, PinbasedControls::VMX_PREEMPTION_TIMER.bits())
+            };
+            vmcs::set_control(
+                VmcsControl32::PINBASED_EXEC_CONTROLS,
+                Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                VmcsControl32::PINBASED_EXEC_CONTROLS.read()?,
+                set,
+                clear,
+            )?;
+
+            let save = ExitControls::SAVE_VMX_PREEMPTION_TIMER.bits();
+            let (set, clear) = if value.is_some() && self.preemption_timer.save_on_exit() {
+                (save, 0)
+            } else {
+                (0, save)
+            };
+            vmcs::set_control(
+                VmcsControl32::VMEXIT_CONTROLS,
+                Msr::IA32_VMX_TRUE_EXIT_CTLS,
+                VmcsControl32::VMEXIT_CONTROLS.read()?,
+                set,
+                clear,
+            )?;
+            self.preemption_timer.set_active(value.is_some());
+        }
+
+        if let Some(value) = value {
+            VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(value)?;
+        }
+        Ok(())
+    }
+
+    /// Account for the guest run time in the scheduling quantum after a VM exit.
+    fn account_preemption_timer(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        let saved = if self.preemption_timer.is_active() && self.preemption_timer.save_on_exit() {
+            Some(VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.read()?)
+        } else {
+            None
+        };
+        let timer_exit = exit_info.exit_reason == VmxExitReason::PREEMPTION_TIMER;
+        self.preemption_timer.account_exit(saved, timer_exit);
+        Ok(())
+    }
+
+    /// The TSC deadline of the earliest guest timer, the local APIC timer or a
+    /// Hyper-V synthetic timer.
+    fn timer_deadline(&self) -> Option<u64> {
+        let stimer_deadline = self.hyperv.as_ref().and_then(|hv| hv.next_deadline());
+        match (self.lapic_timer.deadline(), stimer_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Queue the interrupts of the guest timers that have expired at TSC value `now`:
+    /// the local APIC timer and the Hyper-V synthetic timers.
+    fn fire_timers(&mut self, now: u64) {
+        if let Some(vector) = self.lapic_timer.expire(now) {
+            trace!("guest LAPIC timer fired, vector {:#x}", vector);
+            self.queue_edge_interrupt(vector);
+        }
+        let (Some(hv), Some(mem)) = (self.hyperv.as_mut(), self.guest_memory.as_ref()) else {
+            return;
+        };
+        match hv.poll_timers(mem.as_ref(), now) {
+            Ok(vectors) => {
+                for vector in vectors {
+                    trace!("guest synthetic timer fired, vector {:#x}", vector);
+                    self.queue_event(vector, None);
+                }
             }
+            Err(err) => warn!("Failed to deliver guest synthetic timers: {:?}", err),
         }
-        Ok(())
     }
 
     /// Handle vm-exits than can and should be handled by [`VmxVcpu`] itself.
@@ -973,10 +2924,48 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         // - cr access: just panic;
         match exit_info.exit_reason {
             VmxExitReason::INTERRUPT_WINDOW => Some(self.set_interrupt_window(false)),
-            VmxExitReason::PREEMPTION_TIMER => Some(self.handle_vmx_preemption_timer()),
+            VmxExitReason::PREEMPTION_TIMER => self.handle_vmx_preemption_timer(),
             VmxExitReason::XSETBV => Some(self.handle_xsetbv()),
-            VmxExitReason::CR_ACCESS => Some(self.handle_cr()),
+            VmxExitReason::HLT => self.handle_hlt(exit_info),
+            VmxExitReason::INIT => {
+                // An INIT signal to the host processor, not to the guest: guest INITs
+                // are sent by `send_init`. Ignore it rather than resetting the guest.
+                warn!(
+                    "VmxVcpu {} received a host INIT signal, ignored",
+                    self.vcpu_id
+                );
+                Some(Ok(()))
+            }
+            VmxExitReason::SIPI => Some(vmcs::sipi_vector().and_then(|vector| {
+                self.send_sipi(vector);
+                self.handle_pending_init_sipi()
+            })),
+            VmxExitReason::CR_ACCESS => match self.handle_cr(exit_info) {
+                // Unreal mode left by protected mode can not be emulated in virtual-8086 mode.
+                Err(AxError::Unsupported) => None,
+                res => Some(res),
+            },
+            VmxExitReason::INVLPG => Some(self.handle_invlpg(exit_info)),
+            VmxExitReason::DR_ACCESS => Some(self.handle_dr_access(exit_info)),
+            VmxExitReason::MONITOR_TRAP_FLAG => {
+                match self.debug_control.decode_exit(DebugExit::MonitorTrapFlag) {
+                    Some(event) => self.report_debug_event(event),
+                    // Single-stepping has been disabled by the VMM since the last VM entry.
+                    None => Some(Ok(())),
+                }
+            }
+            VmxExitReason::EXCEPTION_NMI => self.handle_exception(),
+            VmxExitReason::VMCALL => self.handle_vmcall(exit_info),
             VmxExitReason::CPUID => Some(self.handle_cpuid()),
+            VmxExitReason::PML_FULL => Some(self.flush_dirty_log().map(|_| ())),
+            VmxExitReason::VMFUNC => Some(self.handle_vmfunc()),
+            VmxExitReason::SPP_EVENT => match vmcs::spp_event() {
+                Ok(event) => {
+                    self.spp_event = Some(event);
+                    None
+                }
+                Err(err) => Some(Err(err)),
+            },
             msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
                 if {
                     let msr = self.regs().rcx as u32;
@@ -988,6 +2977,29 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                     self.regs().rcx as u32,
                 ))
             }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.kvm_pv.is_some() && KvmPv::is_kvm_msr(self.regs().rcx as u32) =>
+            {
+                Some(self.handle_kvm_msr_access(msr_rw == VmxExitReason::MSR_WRITE, exit_info))
+            }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.hyperv.is_some() && HyperV::is_hyperv_msr(self.regs().rcx as u32) =>
+            {
+                Some(self.handle_hyperv_msr_access(msr_rw == VmxExitReason::MSR_WRITE, exit_info))
+            }
+            msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
+                if self.regs().rcx as u32 == Msr::IA32_TSC_DEADLINE as u32 =>
+            {
+                Some(
+                    self.handle_tsc_deadline_msr_access(
+                        msr_rw == VmxExitReason::MSR_WRITE,
+                        exit_info,
+                    ),
+                )
+            }
+            VmxExitReason::MSR_WRITE if self.regs().rcx as u32 == Msr::IA32_EFER as u32 => {
+                Some(self.handle_efer_write(exit_info))
+            }
             VmxExitReason::APIC_ACCESS => Some(self.handle_apic_access(exit_info)),
             _ => None,
         }
@@ -1006,35 +3018,153 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
     fn handle_apic_msr_access(&mut self, write: bool, msr: u32) -> AxResult {
         const VMEXIT_INSTR_LEN_RDMSR_WRMSR: u8 = 2;
+        const X2APIC_MSR_ICR: u32 = 0x830;
 
         self.advance_rip(VMEXIT_INSTR_LEN_RDMSR_WRMSR)?;
 
-        let msr = msr as _;
         if write {
-            let value = self.read_edx_eax() as usize;
+            let value = self.read_edx_eax();
 
             trace!(
                 "handle_vlapic_msr_write: msr={:#x}, value={:#x}",
                 msr, value
             );
 
-            <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
-                &self.vlapic,
-                SysRegAddr::new(msr),
-                AccessWidth::Qword,
-                value,
-            )
+            if msr == X2APIC_MSR_ICR && self.ipi_router.is_some() {
+                self.icr = value;
+                self.handle_icr_write(value);
+                return Ok(());
+            }
+            self.write_lapic_register(msr, value)
         } else {
-            let value = <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_read(
+            // ICR writes bypass the emulated local APIC while routed, so read them back here.
+            let value = if msr == X2APIC_MSR_ICR && self.ipi_router.is_some() {
+                self.icr
+            } else {
+                self.read_lapic_register(msr)?
+            };
+            trace!("handle_vlapic_msr_read: msr={:#x}, value={:#x}", msr, value);
+            self.write_edx_eax(value);
+            Ok(())
+        }
+    }
+
+    /// Write a register of the emulated local APIC by its x2APIC MSR address,
+    /// keeping track of the guest local APIC timer.
+    fn write_lapic_register(&mut self, msr: u32, value: u64) -> AxResult {
+        const X2APIC_MSR_LVT_TIMER: u32 = 0x832;
+        const X2APIC_MSR_TIMER_INITIAL_COUNT: u32 = 0x838;
+        const X2APIC_MSR_TIMER_DIVIDE_CONFIG: u32 = 0x83e;
+
+        <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
+            &self.vlapic,
+            SysRegAddr::new(msr as _),
+            AccessWidth::Qword,
+            value as usize,
+        )?;
+
+        match msr {
+            X2APIC_MSR_LVT_TIMER => self.lapic_timer.write_lvt(value as u32),
+            X2APIC_MSR_TIMER_DIVIDE_CONFIG => self.lapic_timer.write_divide_config(value as u32),
+            X2APIC_MSR_TIMER_INITIAL_COUNT => self
+                .lapic_timer
+                .write_initial_count(value as u32, timer::current_tsc()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Read a register of the emulated local APIC by its x2APIC MSR address. The
+    /// current count of the timer comes from the guest local APIC timer.
+    fn read_lapic_register(&self, msr: u32) -> AxResult<u64> {
+        const X2APIC_MSR_TIMER_CURRENT_COUNT: u32 = 0x839;
+
+        if msr == X2APIC_MSR_TIMER_CURRENT_COUNT {
+            return Ok(self.lapic_timer.current_count(timer::current_tsc()) as u64);
+        }
+        Ok(
+            <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_read(
                 &self.vlapic,
-                SysRegAddr::new(msr),
+                SysRegAddr::new(msr as _),
                 AccessWidth::Qword,
-            )? as u64;
+            )? as u64,
+        )
+    }
 
-            trace!("handle_vlapic_msr_read: msr={:#x}, value={:#x}", msr, value);
+    /// The x2APIC MSR address of the 32-bit xAPIC register at `offset` in the
+    /// local APIC MMIO page. (SDM Vol. 3A, Section 11.12.1.2)
+    fn xapic_register_msr(offset: usize) -> AxResult<u32> {
+        const X2APIC_MSR_BASE: u32 = 0x800;
+        if offset % 16 != 0 || offset >= 0x400 {
+            return ax_err!(InvalidInput, "invalid xAPIC register offset");
+        }
+        Ok(X2APIC_MSR_BASE + (offset >> 4) as u32)
+    }
+
+    /// Handle a guest write to the xAPIC register at `offset` in the local APIC
+    /// MMIO page, which the VMM receives as an MMIO access.
+    ///
+    /// Like writes to x2APIC MSRs, it goes to the emulated local APIC, and writes
+    /// to the timer registers arm the guest local APIC timer.
+    pub fn handle_xapic_write(&mut self, offset: usize, value: u32) -> AxResult {
+        let msr = Self::xapic_register_msr(offset)?;
+        trace!(
+            "guest xAPIC write: offset={:#x}, value={:#x}",
+            offset, value
+        );
+        self.write_lapic_register(msr, value as u64)
+    }
+
+    /// Handle a guest read of the xAPIC register at `offset` in the local APIC
+    /// MMIO page, which the VMM receives as an MMIO access.
+    pub fn handle_xapic_read(&self, offset: usize) -> AxResult<u32> {
+        let msr = Self::xapic_register_msr(offset)?;
+        Ok(self.read_lapic_register(msr)? as u32)
+    }
+
+    /// Send the IPI written to the x2APIC ICR with the IPI router.
+    fn handle_icr_write(&mut self, icr: u64) {
+        let Some(ipi) = Ipi::from_icr(icr) else {
+            warn!("IPI with reserved delivery mode ignored: ICR={:#x}", icr);
+            return;
+        };
+        let (router, delivery) = self.ipi_router.as_ref().unwrap();
+        let targets = router.destinations(self.vcpu_id, &ipi);
+        let delivery = delivery.clone();
+        for target in targets {
+            if target == self.vcpu_id {
+                self.receive_ipi(ipi);
+            } else {
+                delivery.deliver(target, ipi);
+            }
+        }
+    }
 
+    fn handle_tsc_deadline_msr_access(&mut self, write: bool, exit_info: &VmxExitInfo) -> AxResult {
+        if write {
+            let value = self.read_edx_eax();
+            trace!("guest IA32_TSC_DEADLINE write: {:#x}", value);
+            self.lapic_timer.write_tsc_deadline(value);
+        } else {
+            let value = self.lapic_timer.read_tsc_deadline();
             self.write_edx_eax(value);
-            Ok(())
+        }
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
+    fn handle_efer_write(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        let value = self.read_edx_eax();
+        let regs = self.guest_control_regs()?;
+        match regs.write_efer(value) {
+            Some(new_regs) if self.set_guest_control_regs(&regs, &new_regs)? => {
+                trace!("guest IA32_EFER write: {:#x}", value);
+                self.advance_rip(exit_info.exit_instruction_length as _)
+            }
+            _ => {
+                trace!("guest IA32_EFER write causes #GP: {:#x}", value);
+                self.queue_event(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+                Ok(())
+            }
         }
     }
 
@@ -1059,51 +3189,271 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         self.advance_rip(exit_info.exit_instruction_length as _)
     }
 
-    fn handle_vmx_preemption_timer(&mut self) -> AxResult {
+    fn handle_vmx_preemption_timer(&mut self) -> Option<AxResult> {
         /*
         The VMX-preemption timer counts down at rate proportional to that of the timestamp counter (TSC).
         Specifically, the timer counts down by 1 every time bit X in the TSC changes due to a TSC increment.
         The value of X is in the range 0–31 and can be determined by consulting the VMX capability MSR IA32_VMX_MISC (see Appendix A.6).
          */
-        VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(VMX_PREEMPTION_TIMER_SET_VALUE)?;
-        Ok(())
+        self.fire_timers(timer::current_tsc());
+        if self.preemption_timer.is_expired() {
+            // The quantum is used up, let the VMM schedule.
+            None
+        } else {
+            Some(Ok(()))
+        }
     }
 
-    #[allow(clippy::single_match)]
-    fn handle_cr(&mut self) -> AxResult {
-        const VM_EXIT_INSTR_LEN_MV_TO_CR: u8 = 3;
+    /// Put the guest into the HLT activity state.
+    ///
+    /// Return `None` if the guest goes idle and the VMM should be notified, or the
+    /// result if the guest can be resumed at once because an event is deliverable.
+    fn handle_hlt(&mut self, exit_info: &VmxExitInfo) -> Option<AxResult> {
+        const BLOCKING_BY_STI_OR_MOV_SS: u32 = 0b11;
+
+        let res = (|| -> AxResult<bool> {
+            self.advance_rip(exit_info.exit_instruction_length as _)?;
+            // `STI; HLT` is the common idiom, the blocking by STI ends after HLT.
+            let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read()?;
+            VmcsGuest32::INTERRUPTIBILITY_STATE.write(block_state & !BLOCKING_BY_STI_OR_MOV_SS)?;
+
+            if self.has_deliverable_event() {
+                return Ok(false);
+            }
+            vmcs::set_activity_state(VmxActivityState::Hlt)?;
+            Ok(true)
+        })();
+
+        match res {
+            Ok(true) => None,
+            Ok(false) => Some(Ok(())),
+            Err(err) => Some(Err(err)),
+        }
+    }
 
-        let cr_access_info = vmcs::cr_access_info()?;
+    fn handle_cr(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        const X2APIC_MSR_TPR: u32 = 0x808;
 
+        let cr_access_info = vmcs::cr_access_info()?;
         let reg = cr_access_info.gpr;
         let cr = cr_access_info.cr_number;
+        let regs = self.guest_control_regs()?;
 
-        match cr_access_info.access_type {
+        let new_regs = match cr_access_info.access_type {
             /* move to cr */
             0 => {
-                let val = if reg == 4 {
-                    self.stack_pointer() as u64
-                } else {
-                    self.guest_regs.get_reg_of_index(reg)
-                };
-                if cr == 0 || cr == 4 {
-                    self.advance_rip(VM_EXIT_INSTR_LEN_MV_TO_CR)?;
-                    /* TODO: check for #GP reasons */
-                    self.set_cr(cr as usize, val);
-
-                    if cr == 0 && Cr0Flags::from_bits_truncate(val).contains(Cr0Flags::PAGING) {
-                        vmcs::update_efer()?;
+                let mut val = self.gpr_by_index(reg);
+                if self.get_cpu_mode() != VmCpuMode::Mode64 {
+                    val &= 0xffff_ffff;
+                }
+                match cr {
+                    0 => regs.write_cr0(val),
+                    3 => regs.write_cr3(val),
+                    4 => self.write_guest_cr4(&regs, val),
+                    // CR8 bits 3:0 are mapped to TPR bits 7:4, others are reserved.
+                    8 if val >> 4 == 0 => {
+                        <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
+                            &self.vlapic,
+                            SysRegAddr::new(X2APIC_MSR_TPR as _),
+                            AccessWidth::Qword,
+                            (val << 4) as usize,
+                        )?;
+                        Some(regs)
                     }
-                    return Ok(());
+                    8 => None,
+                    _ => return ax_err!(InvalidInput, "invalid control register"),
                 }
             }
-            _ => {}
+            /* move from cr */
+            1 => {
+                let val = match cr {
+                    0 => regs.cr0,
+                    3 => regs.cr3,
+                    4 => regs.cr4,
+                    8 => {
+                        let tpr =
+                            <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_read(
+                                &self.vlapic,
+                                SysRegAddr::new(X2APIC_MSR_TPR as _),
+                                AccessWidth::Qword,
+                            )?;
+                        (tpr as u64 >> 4) & 0xf
+                    }
+                    _ => return ax_err!(InvalidInput, "invalid control register"),
+                };
+                self.set_gpr_by_index(reg, val);
+                Some(regs)
+            }
+            /* clts */
+            2 => Some(regs.clts()),
+            /* lmsw */
+            _ => regs.lmsw(cr_access_info.lmsw_source_data),
         };
 
-        panic!(
-            "Guest's access to cr not allowed: {:#x?}, {:#x?}",
-            self, cr_access_info
-        );
+        // Paging can only be disabled in compatibility mode to leave IA-32e mode.
+        let new_regs = new_regs.filter(|new_regs| {
+            !(regs.is_long_mode()
+                && !new_regs.is_long_mode()
+                && self.get_cpu_mode() == VmCpuMode::Mode64)
+        });
+
+        match new_regs {
+            Some(new_regs) if self.set_guest_control_regs(&regs, &new_regs)? => {
+                self.advance_rip(exit_info.exit_instruction_length as _)
+            }
+            _ => {
+                trace!(
+                    "Guest control register access causes #GP: {:#x?}",
+                    cr_access_info
+                );
+                self.queue_event(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_dr_access(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        if self.debug_control.uses_hw_breakpoints() {
+            return self.emulate_mov_dr(exit_info);
+        }
+        // Let the guest access debug registers directly until the next VM exit.
+        // Guest debug registers are loaded before VM entry, and the MOV DR
+        // instruction is executed again.
+        self.dr_passthrough = true;
+        self.set_mov_dr_exiting(false)
+    }
+
+    /// Emulate MOV DR on the guest copies of debug registers, while the hardware
+    /// breakpoints of the VMM are loaded.
+    fn emulate_mov_dr(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        let dr_access_info = vmcs::dr_access_info()?;
+        // DR4 and DR5 are aliases of DR6 and DR7, as MOV DR with CR4.DE set
+        // causes #UD before the VM exit.
+        let dr = match dr_access_info.dr_number {
+            4 | 5 => dr_access_info.dr_number + 2,
+            dr => dr,
+        };
+        let mask = if self.get_cpu_mode() == VmCpuMode::Mode64 {
+            u64::MAX
+        } else {
+            0xffff_ffff
+        };
+        if dr_access_info.is_read {
+            let val = self.debug_reg(dr)?;
+            self.set_gpr_by_index(dr_access_info.gpr, val & mask);
+        } else {
+            self.set_debug_reg(dr, self.gpr_by_index(dr_access_info.gpr) & mask)?;
+        }
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
+    /// Handle a VM exit due to an exception.
+    ///
+    /// Return `None` if it is reported to the VMM as a debug event or an
+    /// intercepted exception, otherwise it is reflected into the guest.
+    fn handle_exception(&mut self) -> Option<AxResult> {
+        let exit = match vmcs::exception_exit_info() {
+            Ok(Some(exit)) => exit,
+            Ok(None) => return Some(self.handle_host_nmi()),
+            Err(err) => return Some(Err(err)),
+        };
+        if exit.vector == x86::irq::PAGE_FAULT_VECTOR && self.shadow_mmu.is_some() {
+            return self.handle_shadow_page_fault(&exit);
+        }
+        if exit.vector == x86::irq::GENERAL_PROTECTION_FAULT_VECTOR && self.vm86.is_some() {
+            return match self.emulate_vm86_instruction() {
+                // A memory operand not in guest memory is reported as a nested page fault.
+                Ok(()) if self.shadow_fault.is_some() => None,
+                res => Some(res),
+            };
+        }
+        let debug_exit = match exit.vector {
+            x86::irq::DEBUG_VECTOR => exit.dr6.map(DebugExit::DebugException),
+            x86::irq::BREAKPOINT_VECTOR => Some(DebugExit::Breakpoint),
+            _ => None,
+        };
+        if let Some(event) = debug_exit.and_then(|exit| self.debug_control.decode_exit(exit)) {
+            return self.report_debug_event(event);
+        }
+        if self.exception_bitmap & (1 << exit.vector) != 0 {
+            self.exception_exit = Some(exit);
+            return None;
+        }
+        if self.vm86.is_some() {
+            // Software exceptions, INT3 and INTO, return to the next instruction.
+            let res = if exit.int_type.is_soft() {
+                self.advance_rip(exit.instruction_length as _)
+            } else {
+                Ok(())
+            };
+            return Some(res.and_then(|_| self.deliver_real_mode_interrupt(exit.vector)));
+        }
+        Some(self.reflect_exception(&exit))
+    }
+
+    /// Handle a VM exit due to an NMI, which is delivered to the host rather than the guest.
+    fn handle_host_nmi(&mut self) -> AxResult {
+        let int_info = vmcs::interrupt_exit_info()?;
+        if !int_info.valid || int_info.int_type != VmxInterruptionType::NMI {
+            return ax_err!(BadState, "VM exit due to an unknown exception or NMI");
+        }
+        unsafe { int2() };
+        Ok(())
+    }
+
+    /// Report a debug event to the VMM, always return `None` unless an error occurs.
+    fn report_debug_event(&mut self, event: DebugEvent) -> Option<AxResult> {
+        if let DebugEvent::HardwareBreakpoint(index) = event {
+            let is_execute = self.debug_control.hw_breakpoints[index]
+                .is_some_and(|bp| bp.kind == debug::HwBreakpointKind::Execute);
+            // Set RFLAGS.RF, so that the instruction breakpoint is not hit again on resume.
+            if is_execute {
+                const RFLAGS_RF: usize = 1 << 16;
+                if let Err(err) = VmcsGuestNW::RFLAGS
+                    .read()
+                    .and_then(|rflags| VmcsGuestNW::RFLAGS.write(rflags | RFLAGS_RF))
+                {
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.debug_event = Some(event);
+        None
+    }
+
+    /// Write the exception bitmap, intercepting exceptions for both the VMM and
+    /// the debug control.
+    fn update_exception_bitmap(&self) -> AxResult {
+        let mut fields = DebugVmcsFields::default();
+        self.debug_control.apply(&mut fields, 0);
+        let mut bitmap = self.exception_bitmap | fields.exception_bitmap;
+        // Shadow page tables are filled on page faults.
+        if self.shadow_mmu.is_some() {
+            bitmap |= 1 << x86::irq::PAGE_FAULT_VECTOR;
+        }
+        // Exceptions in virtual-8086 mode are delivered through the real-mode IVT.
+        if self.vm86.is_some() {
+            bitmap = u32::MAX;
+        }
+        VmcsControl32::EXCEPTION_BITMAP.write(bitmap)
+    }
+
+    /// Read a general-purpose register by its index in exit qualifications.
+    fn gpr_by_index(&self, index: u8) -> u64 {
+        if index == 4 {
+            self.stack_pointer() as u64
+        } else {
+            self.guest_regs.get_reg_of_index(index)
+        }
+    }
+
+    /// Write a general-purpose register by its index in exit qualifications.
+    fn set_gpr_by_index(&mut self, index: u8, val: u64) {
+        if index == 4 {
+            self.set_stack_pointer(val as usize)
+        } else {
+            self.guest_regs.set_reg_of_index(index, val)
+        }
     }
 
     fn handle_cpuid(&mut self) -> AxResult {
@@ -1115,6 +3465,7 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         const LEAF_PROCESSOR_EXTENDED_STATE_ENUMERATION: u32 = 0xd;
         const EAX_FREQUENCY_INFO: u32 = 0x16;
         const LEAF_HYPERVISOR_INFO: u32 = 0x4000_0000;
+        const LEAF_ADDRESS_SIZES: u32 = 0x8000_0008;
         const LEAF_HYPERVISOR_FEATURE: u32 = 0x4000_0001;
         const VENDOR_STR: &[u8; 12] = b"RVMRVMRVMRVM";
         let vendor_regs = unsafe { &*(VENDOR_STR.as_ptr() as *const [u32; 3]) };
@@ -1130,6 +3481,10 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                 res.ecx &= !FEATURE_VMX;
                 res.ecx |= FEATURE_HYPERVISOR;
                 res.eax &= !FEATURE_MCE;
+                // PCIDs are not supported by the shadow page tables.
+                if self.mmu_mode == MmuMode::Shadow {
+                    res.ecx.set_bit(17, false);
+                }
                 res
             }
             // See SDM Table 3-8. Information Returned by CPUID Instruction (Contd.)
@@ -1139,7 +3494,13 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                     // Bit 05: WAITPKG.
                     res.ecx.set_bit(5, false); // clear waitpkg
                     // Bit 16: LA57. Supports 57-bit linear addresses and five-level paging if 1.
-                    res.ecx.set_bit(16, false); // clear LA57
+                    if !self.la57 {
+                        res.ecx.set_bit(16, false); // clear LA57
+                    }
+                    // Bit 10: INVPCID, which is not enabled with shadow paging.
+                    if self.mmu_mode == MmuMode::Shadow {
+                        res.ebx.set_bit(10, false);
+                    }
                 }
 
                 res
@@ -1151,6 +3512,12 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
 
                 res
             }
+            leaf if self.hyperv.is_some() && hyperv::HV_CPUID_LEAVES.contains(&leaf) => {
+                self.hyperv.as_ref().unwrap().cpuid(leaf)
+            }
+            leaf @ (LEAF_HYPERVISOR_INFO | LEAF_HYPERVISOR_FEATURE) if self.kvm_pv.is_some() => {
+                KvmPv::cpuid(leaf).unwrap()
+            }
             LEAF_HYPERVISOR_INFO => CpuIdResult {
                 eax: LEAF_HYPERVISOR_FEATURE,
                 ebx: vendor_regs[0],
@@ -1163,6 +3530,18 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
                 ecx: 0,
                 edx: 0,
             },
+            LEAF_ADDRESS_SIZES => {
+                let mut res = cpuid!(regs_clone.rax, regs_clone.rcx);
+                // Bits 07-00: physical address bits, limited to those translated
+                // by the EPT.
+                let ept_bits = if self.eptp_config.levels == 5 { 57 } else { 48 };
+                res.eax.set_bits(0..8, res.eax.get_bits(0..8).min(ept_bits));
+                // Bits 15-08: linear address bits.
+                if !self.la57 {
+                    res.eax.set_bits(8..16, res.eax.get_bits(8..16).min(48));
+                }
+                res
+            }
             EAX_FREQUENCY_INFO => {
                 /// Timer interrupt frequencyin Hz.
                 /// Todo: this should be the same as `axconfig::TIMER_FREQUENCY` defined in ArceOS's config file.
@@ -1195,6 +3574,245 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// Raise #UD for VMCALL if hypercalls are disabled or issued with CPL > 0.
+    ///
+    /// Return `None` if the hypercall is to be reported to the VMM.
+    fn handle_vmcall(&mut self, exit_info: &VmxExitInfo) -> Option<AxResult> {
+        let cpl = match VmcsGuest32::SS_ACCESS_RIGHTS.read() {
+            Ok(ss_access_rights) => ss_access_rights.get_bits(5..7),
+            Err(err) => return Some(Err(err)),
+        };
+        // Hyper-V hypercalls are only available through the hypercall page.
+        let hyperv_disabled = self.hypercall_abi == HypercallAbi::HyperV
+            && self
+                .hyperv
+                .as_ref()
+                .is_some_and(|hv| !hv.hypercall_enabled());
+        if self.hypercall_abi == HypercallAbi::Disabled || hyperv_disabled || cpl != 0 {
+            self.queue_event(x86::irq::INVALID_OPCODE_VECTOR, None);
+            return Some(Ok(()));
+        }
+        if self.hypercall_abi == HypercallAbi::Kvm
+            && self.kvm_pv.is_some()
+            && self.regs().rax == kvm::KVM_HC_SEND_IPI
+        {
+            return Some(self.handle_kvm_send_ipi(exit_info));
+        }
+        None
+    }
+
+    /// Emulate an element of INS or OUTS, with or without REP. The memory operand is
+    /// at ES:rDI for INS, and at rSI in the segment of the instruction for OUTS,
+    /// with the address size of the instruction.
+    ///
+    /// OUTS is reported as [`AxVCpuExitReason::IoWrite`] of the element read from
+    /// guest memory. INS is reported as [`AxVCpuExitReason::IoRead`], and the value
+    /// set by `set_return_value` is written to guest memory. With REP, the
+    /// instruction is executed again for the remaining elements, each reported by
+    /// a VM exit of its own. Segment and page faults are injected into the guest.
+    fn handle_string_io(
+        &mut self,
+        io_info: &vmcs::VmxIoExitInfo,
+        exit_info: &VmxExitInfo,
+    ) -> AxResult<AxVCpuExitReason> {
+        let string_info = vmcs::string_io_info(io_info.is_in)?;
+        let address_mask = string_info.address_mask();
+        let width = AccessWidth::try_from(io_info.access_size as usize)
+            .map_err(|_| ax_err_type!(BadState, "invalid I/O access size"))?;
+        if io_info.is_repeat && self.regs().rcx & address_mask == 0 {
+            self.advance_rip(exit_info.exit_instruction_length as _)?;
+            return Ok(AxVCpuExitReason::Nothing);
+        }
+        let (offset, seg_access) = if io_info.is_in {
+            (self.regs().rdi, SegmentAccess::Write)
+        } else {
+            (self.regs().rsi, SegmentAccess::Read)
+        };
+        let gva = match self.segmented_to_linear(
+            string_info.segment,
+            offset & address_mask,
+            width.size(),
+            seg_access,
+        )? {
+            Ok(gva) => gva,
+            Err(fault) => {
+                self.queue_event(fault.vector(), Some(0));
+                return Ok(AxVCpuExitReason::Nothing);
+            }
+        };
+        // SS.DPL is always the CPL of the guest.
+        let cpl = (VmcsGuest32::SS_ACCESS_RIGHTS.read()? >> 5) & 0x3;
+        let mut access = MappingFlags::empty();
+        access.set(MappingFlags::USER, cpl == 3);
+        let string_io = StringIo {
+            gva,
+            width,
+            access,
+            is_in: io_info.is_in,
+            is_repeat: io_info.is_repeat,
+            address_mask,
+            instruction_length: exit_info.exit_instruction_length as _,
+        };
+        let port = Port(io_info.port);
+
+        if io_info.is_in {
+            // Check the destination before reading the port, which may have side effects.
+            let mem = self.guest_memory()?;
+            let info = self.get_ptw_info_for(access | MappingFlags::WRITE);
+            if let Err(fault) = paging::translate_range(&*mem, &info, gva, width.size())? {
+                self.inject_page_fault(&fault);
+                return Ok(AxVCpuExitReason::Nothing);
+            }
+            self.pending_string_in = Some(string_io);
+            Ok(AxVCpuExitReason::IoRead { port, width })
+        } else {
+            let mut data = [0; 8];
+            if let Err(fault) = self.read_guest_virt(gva, &mut data[..width.size()], access)? {
+                self.inject_page_fault(&fault);
+                return Ok(AxVCpuExitReason::Nothing);
+            }
+            self.advance_string_io(&string_io)?;
+            Ok(AxVCpuExitReason::IoWrite {
+                port,
+                width,
+                data: u64::from_le_bytes(data),
+            })
+        }
+    }
+
+    /// Write the value read from the port by INS into guest memory.
+    fn complete_string_in(&mut self, string_io: &StringIo, val: u64) -> AxResult {
+        let bytes = val.to_le_bytes();
+        let buf = &bytes[..string_io.width.size()];
+        if let Err(fault) = self.write_guest_virt(string_io.gva, buf, string_io.access)? {
+            // The destination was checked before, but the guest page tables may
+            // have been changed by another vCPU since.
+            self.inject_page_fault(&fault);
+            return Ok(());
+        }
+        self.advance_string_io(string_io)
+    }
+
+    /// Move rSI or rDI to the next element by the direction flag and, with REP,
+    /// decrement rCX. The instruction completes unless REP has elements left.
+    fn advance_string_io(&mut self, string_io: &StringIo) -> AxResult {
+        const RFLAGS_DF: usize = 1 << 10;
+        let mask = string_io.address_mask;
+        // Only the bits of the address size change, 32-bit results are zero-extended.
+        let update = |reg: u64, delta: u64| {
+            let val = reg.wrapping_add(delta) & mask;
+            if mask == 0xffff {
+                (reg & !mask) | val
+            } else {
+                val
+            }
+        };
+        let step = string_io.width.size() as u64;
+        let delta = if VmcsGuestNW::RFLAGS.read()? & RFLAGS_DF != 0 {
+            step.wrapping_neg()
+        } else {
+            step
+        };
+        let regs = self.regs_mut();
+        if string_io.is_in {
+            regs.rdi = update(regs.rdi, delta);
+        } else {
+            regs.rsi = update(regs.rsi, delta);
+        }
+        if string_io.is_repeat {
+            regs.rcx = update(regs.rcx, u64::MAX);
+            if regs.rcx & mask != 0 {
+                return Ok(());
+            }
+        }
+        self.advance_rip(string_io.instruction_length)
+    }
+
+    /// Handle the `KVM_HC_SEND_IPI` hypercall, return the number of IPIs sent.
+    fn handle_kvm_send_ipi(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        let long_mode = self.get_cpu_mode() == VmCpuMode::Mode64;
+        let (_, [low, high, min, icr, ..]) = HypercallAbi::Kvm
+            .decode(&self.guest_regs, long_mode)
+            .unwrap();
+        // The bitmap covers 128 x2APIC IDs in 64-bit mode, and 64 otherwise.
+        let (low, high) = if long_mode {
+            (low, high)
+        } else {
+            (high << 32 | low, 0)
+        };
+        let mut count = 0;
+        if self.ipi_router.is_some() {
+            for apic_id in kvm::send_ipi_destinations(low, high, min as u32) {
+                // Vector and delivery mode from the guest, to a physical destination.
+                const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+                self.handle_icr_write((icr & 0x7ff) | ICR_LEVEL_ASSERT | (apic_id as u64) << 32);
+                count += 1;
+            }
+        } else {
+            warn!("KVM_HC_SEND_IPI ignored without an IPI router");
+        }
+        HypercallAbi::Kvm.set_result(&mut self.guest_regs, long_mode, count);
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
+    fn handle_kvm_msr_access(&mut self, write: bool, exit_info: &VmxExitInfo) -> AxResult {
+        let msr = self.regs().rcx as u32;
+        let value = self.read_edx_eax();
+        let (Some(kvm_pv), Some(mem)) = (self.kvm_pv.as_mut(), self.guest_memory.as_ref()) else {
+            return ax_err!(BadState, "KVM PV is not enabled");
+        };
+        if write {
+            trace!("guest KVM MSR {:#x} write: {:#x}", msr, value);
+            if let Err(err) = kvm_pv.write_msr(msr, value, mem.as_ref(), timer::current_tsc()) {
+                warn!(
+                    "Guest KVM MSR {:#x} write {:#x} causes #GP: {:?}",
+                    msr, value, err
+                );
+                self.queue_event(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+                return Ok(());
+            }
+        } else {
+            let value = kvm_pv.read_msr(msr)?;
+            self.write_edx_eax(value);
+        }
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
+    fn handle_hyperv_msr_access(&mut self, write: bool, exit_info: &VmxExitInfo) -> AxResult {
+        let msr = self.regs().rcx as u32;
+        let value = self.read_edx_eax();
+        let (Some(hv), Some(mem)) = (self.hyperv.as_mut(), self.guest_memory.as_ref()) else {
+            return ax_err!(BadState, "Hyper-V enlightenments are not enabled");
+        };
+        let now = timer::current_tsc();
+        if write {
+            trace!("guest Hyper-V MSR {:#x} write: {:#x}", msr, value);
+            if let Err(err) = hv.write_msr(msr, value, mem.as_ref(), now) {
+                warn!(
+                    "Guest Hyper-V MSR {:#x} write {:#x} causes #GP: {:?}",
+                    msr, value, err
+                );
+                self.queue_event(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+                return Ok(());
+            }
+        } else {
+            let value = hv.read_msr(msr, now)?;
+            self.write_edx_eax(value);
+        }
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
+    /// Raise #UD for VMFUNC with a disabled function or an invalid EPT view.
+    fn handle_vmfunc(&mut self) -> AxResult {
+        let regs = self.regs();
+        warn!(
+            "Guest VMFUNC {:#x} with EPT view {:#x} failed",
+            regs.rax as u32, regs.rcx as u32
+        );
+        self.queue_event(x86::irq::INVALID_OPCODE_VECTOR, None);
+        Ok(())
+    }
+
     fn handle_xsetbv(&mut self) -> AxResult {
         const XCR_XCR0: u64 = 0;
         const VM_EXIT_INSTR_LEN_XSETBV: u8 = 3;
@@ -1249,6 +3867,48 @@ impl<H: AxVCpuHal> VmxVcpu<H> {
     fn load_host_xstate(&mut self) {
         self.xstate.switch_to_host();
     }
+
+    /// Load guest debug registers if the guest may use them.
+    ///
+    /// Guest DR7 is loaded by VM entry, DR0-DR3 and DR6 are only switched if the
+    /// guest accesses debug registers directly, or has breakpoints enabled.
+    fn load_guest_debug_regs(&mut self) -> AxResult {
+        let dr7 = VmcsGuestNW::DR7.read()? as u64;
+        if self.debug_control.uses_hw_breakpoints() {
+            // Guest DR0-DR3 are replaced by the hardware breakpoints of the VMM.
+            self.host_debug_regs = Some((DebugRegs::save(), debug::read_dr7()));
+            debug::write_dr7(debug::DR7_FIXED_1);
+            DebugRegs {
+                dr: self.debug_control.breakpoint_addrs(),
+                dr6: self.guest_debug_regs.dr6,
+            }
+            .load();
+        } else if self.dr_passthrough || dr7 & debug::DR7_BREAKPOINT_ENABLE != 0 {
+            self.host_debug_regs = Some((DebugRegs::save(), debug::read_dr7()));
+            debug::write_dr7(debug::DR7_FIXED_1);
+            self.guest_debug_regs.load();
+        }
+        Ok(())
+    }
+
+    /// Restore host debug registers if guest debug registers have been loaded.
+    ///
+    /// DR7 has been saved into the VMCS and cleared by VM exit. MOV DR is
+    /// intercepted again, until the guest accesses debug registers next time.
+    fn load_host_debug_regs(&mut self) -> AxResult {
+        if let Some((host_regs, host_dr7)) = self.host_debug_regs.take() {
+            if !self.debug_control.uses_hw_breakpoints() {
+                self.guest_debug_regs = DebugRegs::save();
+            }
+            host_regs.load();
+            debug::write_dr7(host_dr7);
+        }
+        if self.dr_passthrough {
+            self.dr_passthrough = false;
+            self.set_mov_dr_exiting(true)?;
+        }
+        Ok(())
+    }
 }
 
 impl<H: AxVCpuHal> Drop for VmxVcpu<H> {
@@ -1311,6 +3971,8 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
 
     fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
         self.ept_root = Some(ept_root);
+        // The EPT root of a running vCPU is switched on the next VM entry.
+        self.eptp_update_pending = true;
         Ok(())
     }
 
@@ -1319,6 +3981,32 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
     }
 
     fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+        self.halt_reason = None;
+        // A deferred hypercall blocks the vCPU, otherwise its return value is left as is.
+        if self.has_deferred_hypercall() {
+            return Ok(self.halt(VmxHaltReason::DeferredHypercall));
+        }
+        self.pending_hypercall = None;
+        self.pending_string_in = None;
+        if core::mem::take(&mut self.triple_fault) {
+            return Ok(AxVCpuExitReason::SystemDown);
+        }
+
+        self.handle_pending_init_sipi()?;
+
+        // Stay idle until an event can wake up the halted guest.
+        match self.activity_state()? {
+            VmxActivityState::Hlt => {
+                self.fire_timers(timer::current_tsc());
+                if !self.has_deliverable_event() {
+                    return Ok(self.halt(VmxHaltReason::Hlt));
+                }
+            }
+            // Only a SIPI sent by `send_sipi` wakes up the guest.
+            VmxActivityState::WaitForSipi => return Ok(self.halt(VmxHaltReason::WaitForSipi)),
+            _ => {}
+        }
+
         match self.inner_run() {
             Some(exit_info) => Ok(if exit_info.entry_failure {
                 AxVCpuExitReason::FailEntry {
@@ -1328,64 +4016,80 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
             } else {
                 match exit_info.exit_reason {
                     VmxExitReason::VMCALL => {
+                        let long_mode = self.get_cpu_mode() == VmCpuMode::Mode64;
+                        // VMCALL raises #UD if hypercalls are disabled, see `handle_vmcall`.
+                        let Some((nr, args)) =
+                            self.hypercall_abi.decode(&self.guest_regs, long_mode)
+                        else {
+                            self.queue_event(x86::irq::INVALID_OPCODE_VECTOR, None);
+                            return Ok(AxVCpuExitReason::Nothing);
+                        };
                         self.advance_rip(exit_info.exit_instruction_length as _)?;
-                        AxVCpuExitReason::Hypercall {
-                            nr: self.regs().rax,
-                            args: [
-                                self.regs().rdi,
-                                self.regs().rsi,
-                                self.regs().rdx,
-                                self.regs().rcx,
-                                self.regs().r8,
-                                self.regs().r9,
-                            ],
-                        }
+                        self.pending_hypercall = Some(PendingHypercall {
+                            abi: self.hypercall_abi,
+                            long_mode,
+                            deferred: false,
+                        });
+                        AxVCpuExitReason::Hypercall { nr, args }
                     }
                     VmxExitReason::IO_INSTRUCTION => {
                         let io_info = self.io_exit_info().unwrap();
+                        if io_info.is_string {
+                            return self.handle_string_io(&io_info, &exit_info);
+                        }
                         self.advance_rip(exit_info.exit_instruction_length as _)?;
 
                         let port = io_info.port;
 
-                        if io_info.is_repeat || io_info.is_string {
-                            warn!(
-                                "VMX unsupported IO-Exit: {:#x?} of {:#x?}",
-                                io_info, exit_info
-                            );
-                            warn!("VCpu {:#x?}", self);
-                            AxVCpuExitReason::Halt
+                        let width = match AccessWidth::try_from(io_info.access_size as usize) {
+                            Ok(width) => width,
+                            Err(_) => {
+                                warn!("VMX invalid IO-Exit: {:#x?} of {:#x?}", io_info, exit_info);
+                                warn!("VCpu {:#x?}", self);
+                                return Ok(self.halt(VmxHaltReason::UnsupportedExit));
+                            }
+                        };
+
+                        if io_info.is_in {
+                            AxVCpuExitReason::IoRead {
+                                port: Port(port),
+                                width,
+                            }
+                        } else if port == QEMU_EXIT_PORT
+                            && width == AccessWidth::Word
+                            && self.regs().rax == QEMU_EXIT_MAGIC
+                        {
+                            AxVCpuExitReason::SystemDown
                         } else {
-                            let width = match AccessWidth::try_from(io_info.access_size as usize) {
-                                Ok(width) => width,
-                                Err(_) => {
-                                    warn!(
-                                        "VMX invalid IO-Exit: {:#x?} of {:#x?}",
-                                        io_info, exit_info
-                                    );
-                                    warn!("VCpu {:#x?}", self);
-                                    return Ok(AxVCpuExitReason::Halt);
-                                }
-                            };
-
-                            if io_info.is_in {
-                                AxVCpuExitReason::IoRead {
-                                    port: Port(port),
-                                    width,
-                                }
-                            } else if port == QEMU_EXIT_PORT
-                                && width == AccessWidth::Word
-                                && self.regs().rax == QEMU_EXIT_MAGIC
-                            {
-                                AxVCpuExitReason::SystemDown
-                            } else {
-                                AxVCpuExitReason::IoWrite {
-                                    port: Port(port),
-                                    width,
-                                    data: self.regs().rax.get_bits(width.bits_range()),
-                                }
+                            AxVCpuExitReason::IoWrite {
+                                port: Port(port),
+                                width,
+                                data: self.regs().rax.get_bits(width.bits_range()),
                             }
                         }
                     }
+                    VmxExitReason::HLT => self.halt(VmxHaltReason::Hlt),
+                    VmxExitReason::EXCEPTION_NMI if self.shadow_fault.is_some() => {
+                        let fault = self.shadow_fault.take().unwrap();
+                        AxVCpuExitReason::NestedPageFault {
+                            addr: fault.fault_guest_paddr,
+                            access_flags: fault.access_flags,
+                        }
+                    }
+                    VmxExitReason::EXCEPTION_NMI | VmxExitReason::MONITOR_TRAP_FLAG
+                        if self.debug_event.is_some() || self.exception_exit.is_some() =>
+                    {
+                        // Retrieved by `take_debug_event` or `take_exception_exit`.
+                        AxVCpuExitReason::Nothing
+                    }
+                    VmxExitReason::PREEMPTION_TIMER => {
+                        // The quantum is used up, reported by `quantum_expired`.
+                        AxVCpuExitReason::Nothing
+                    }
+                    VmxExitReason::SPP_EVENT => {
+                        // Retrieved by `take_spp_event`.
+                        AxVCpuExitReason::Nothing
+                    }
                     VmxExitReason::EXTERNAL_INTERRUPT => {
                         let int_info = self.interrupt_exit_info()?;
                         assert!(int_info.valid);
@@ -1411,20 +4115,24 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
                     _ => {
                         warn!("VMX unsupported VM-Exit: {:#x?}", exit_info);
                         warn!("VCpu {:#x?}", self);
-                        AxVCpuExitReason::Halt
+                        self.halt(VmxHaltReason::UnsupportedExit)
                     }
                 }
             }),
+            None if core::mem::take(&mut self.triple_fault) => Ok(AxVCpuExitReason::SystemDown),
             None => Ok(AxVCpuExitReason::Nothing),
         }
     }
 
     fn bind(&mut self) -> AxResult {
-        self.bind_to_current_processor()
+        self.bind_to_current_processor()?;
+        self.check_migration();
+        self.update_kvm_pv(false)
     }
 
     fn unbind(&mut self) -> AxResult {
         self.launched = false;
+        self.update_kvm_pv(true)?;
         self.unbind_from_current_processor()
     }
 
@@ -1443,6 +4151,17 @@ impl<H: AxVCpuHal> AxArchVCpu for VmxVcpu<H> {
     }
 
     fn set_return_value(&mut self, val: usize) {
-        self.regs_mut().rax = val as u64;
+        if let Some(string_io) = self.pending_string_in.take() {
+            if let Err(err) = self.complete_string_in(&string_io, val as u64) {
+                warn!("Failed to complete string I/O {:#x?}: {:?}", string_io, err);
+            }
+        } else if self.has_deferred_hypercall() {
+            // Only `complete_deferred_hypercall` completes a deferred hypercall.
+            warn!("return value of a deferred hypercall ignored: {:#x}", val);
+        } else if self.pending_hypercall.is_some() {
+            self.complete_hypercall(val as u64).unwrap();
+        } else {
+            self.regs_mut().rax = val as u64;
+        }
     }
 }
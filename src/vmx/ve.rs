@@ -0,0 +1,206 @@
+use axaddrspace::{
+    AxMmHal, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, NestedPageFaultInfo, PhysFrame,
+};
+use axerrno::AxResult;
+use bit_field::BitField;
+
+use super::vmcs;
+
+/// Suppress #VE, bit 63 of EPT paging-structure entries. EPT violations caused by
+/// entries with this bit set cause VM exits even if #VE is enabled.
+pub const EPT_SUPPRESS_VE: u64 = 1 << 63;
+
+/// The number of entries in an EPT paging structure.
+const EPT_ENTRIES: usize = 512;
+/// Read, write and execute access, an EPT entry without any of them is not present.
+const EPT_ACCESS_MASK: u64 = 0x7;
+/// An EPT entry of level 2 or 3 maps a large page rather than a table.
+const EPT_LARGE_PAGE: u64 = 1 << 7;
+/// The physical address in an EPT entry.
+const EPT_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Set [`EPT_SUPPRESS_VE`] in the non-present entries of an EPT paging structure,
+/// so that EPT violations caused by them, e.g. by emulated MMIO, still cause VM
+/// exits with #VE enabled. Return the number of entries newly marked.
+pub fn suppress_ve_non_present(table: &mut [u64; EPT_ENTRIES]) -> usize {
+    let mut count = 0;
+    for entry in table.iter_mut() {
+        if *entry & EPT_ACCESS_MASK == 0 && *entry & EPT_SUPPRESS_VE == 0 {
+            *entry |= EPT_SUPPRESS_VE;
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Set [`EPT_SUPPRESS_VE`] in the non-present entries of all paging structures of
+/// the EPT at `root` with `levels` levels. Return the number of entries newly marked.
+pub fn suppress_ve_in_ept<H: AxMmHal>(root: HostPhysAddr, levels: usize) -> usize {
+    let table = unsafe { &mut *(H::phys_to_virt(root).as_usize() as *mut [u64; EPT_ENTRIES]) };
+    let mut count = suppress_ve_non_present(table);
+    if levels > 1 {
+        for &entry in table.iter() {
+            if entry & EPT_ACCESS_MASK != 0 && entry & EPT_LARGE_PAGE == 0 {
+                let next = HostPhysAddr::from((entry & EPT_ADDR_MASK) as usize);
+                count += suppress_ve_in_ept::<H>(next, levels - 1);
+            }
+        }
+    }
+    count
+}
+
+/// The processor sets the semaphore in the information area when it delivers a #VE,
+/// further EPT violations cause VM exits until the guest clears it.
+const VE_INFO_SEMAPHORE_SET: u32 = 0xffff_ffff;
+/// The size of the virtualization-exception information. (SDM Vol. 3C, Section 26.5.7.2)
+const VE_INFO_SIZE: usize = 40;
+
+/// Virtualization-exception information of the last EPT violation converted
+/// into #VE. (SDM Vol. 3C, Section 26.5.7.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VeInfo {
+    /// The basic exit reason, EPT violation (48).
+    pub exit_reason: u32,
+    /// Whether the semaphore is set: a #VE has been delivered, and the guest has
+    /// not cleared the semaphore to accept another one.
+    pub busy: bool,
+    /// The exit qualification of the EPT violation.
+    pub qualification: u64,
+    /// The guest linear address, if it is valid in the exit qualification.
+    pub guest_linear_addr: Option<GuestVirtAddr>,
+    /// The guest physical address.
+    pub guest_phys_addr: GuestPhysAddr,
+    /// The index of the EPT view in use.
+    pub eptp_index: u16,
+}
+
+impl VeInfo {
+    /// Decode the information area.
+    pub fn from_bytes(bytes: &[u8; VE_INFO_SIZE]) -> Self {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let qualification = u64_at(8);
+        Self {
+            exit_reason: u32_at(0),
+            busy: u32_at(4) == VE_INFO_SEMAPHORE_SET,
+            qualification,
+            guest_linear_addr: qualification
+                .get_bit(7)
+                .then(|| GuestVirtAddr::from(u64_at(16) as usize)),
+            guest_phys_addr: GuestPhysAddr::from(u64_at(24) as usize),
+            eptp_index: u16::from_le_bytes([bytes[32], bytes[33]]),
+        }
+    }
+
+    /// The faulting access and address.
+    pub fn fault_info(&self) -> NestedPageFaultInfo {
+        NestedPageFaultInfo {
+            access_flags: vmcs::ept_violation_access_flags(self.qualification),
+            fault_guest_paddr: self.guest_phys_addr,
+        }
+    }
+}
+
+/// The 4K virtualization-exception information area, written by the processor
+/// when it delivers a #VE to the guest.
+pub struct VeInfoArea<H: AxMmHal> {
+    frame: PhysFrame<H>,
+}
+
+impl<H: AxMmHal> VeInfoArea<H> {
+    /// Allocate a clear information area.
+    pub fn new() -> AxResult<Self> {
+        Ok(Self {
+            frame: PhysFrame::alloc_zero()?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frame.start_paddr()
+    }
+
+    /// Read the information.
+    pub fn read(&self) -> VeInfo {
+        VeInfo::from_bytes(unsafe { &*(self.frame.as_mut_ptr() as *const [u8; VE_INFO_SIZE]) })
+    }
+
+    /// Clear the information and the semaphore, so that another #VE can be delivered.
+    pub fn reset(&mut self) {
+        unsafe {
+            (self.frame.as_mut_ptr() as *mut [u8; VE_INFO_SIZE]).write_volatile([0; VE_INFO_SIZE])
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::memory::TestMmHal;
+    use page_table_entry::MappingFlags;
+
+    #[test]
+    fn test_suppress_ve() {
+        let tables: [PhysFrame<TestMmHal>; 3] =
+            core::array::from_fn(|_| PhysFrame::alloc_zero().unwrap());
+        let table = |i: usize| unsafe { &mut *(tables[i].as_mut_ptr() as *mut [u64; EPT_ENTRIES]) };
+        let paddr = |i: usize| tables[i].start_paddr().as_usize() as u64;
+        // A 3-level EPT: a table at 0, a 1G page at 1, a non-present MMIO entry at 2.
+        table(0)[0] = paddr(1) | 0x7;
+        table(0)[1] = 0x4000_0000 | EPT_LARGE_PAGE | 0x7;
+        table(1)[0] = paddr(2) | 0x7;
+        table(2)[0] = 0x1000 | 0x3;
+        table(2)[3] = 0x3000 | 0x7;
+
+        let marked = suppress_ve_in_ept::<TestMmHal>(tables[0].start_paddr(), 3);
+        assert_eq!(marked, 3 * EPT_ENTRIES - 2 - 1 - 2);
+        assert_eq!(table(0)[0], paddr(1) | 0x7);
+        assert_eq!(table(0)[1], 0x4000_0000 | EPT_LARGE_PAGE | 0x7);
+        assert_eq!(table(0)[2], EPT_SUPPRESS_VE);
+        assert_eq!(table(1)[1], EPT_SUPPRESS_VE);
+        assert_eq!(table(2)[0], 0x1000 | 0x3);
+        assert_eq!(table(2)[1], EPT_SUPPRESS_VE);
+        assert_eq!(table(2)[3], 0x3000 | 0x7);
+
+        // Entries are only marked once, and present entries are left as is.
+        assert_eq!(
+            suppress_ve_in_ept::<TestMmHal>(tables[0].start_paddr(), 3),
+            0
+        );
+        table(2)[3] = 0;
+        assert_eq!(suppress_ve_non_present(table(2)), 1);
+        assert_eq!(table(2)[3], EPT_SUPPRESS_VE);
+    }
+
+    #[test]
+    fn test_ve_info() {
+        let mut bytes = [0; VE_INFO_SIZE];
+        bytes[0..4].copy_from_slice(&48u32.to_le_bytes());
+        bytes[4..8].copy_from_slice(&VE_INFO_SEMAPHORE_SET.to_le_bytes());
+        // Write access with a valid guest linear address.
+        bytes[8..16].copy_from_slice(&(1u64 << 1 | 1 << 7).to_le_bytes());
+        bytes[16..24].copy_from_slice(&0xffff_8000_1234_5678u64.to_le_bytes());
+        bytes[24..32].copy_from_slice(&0x1234_5678u64.to_le_bytes());
+        bytes[32..34].copy_from_slice(&3u16.to_le_bytes());
+
+        let info = VeInfo::from_bytes(&bytes);
+        assert_eq!(info.exit_reason, 48);
+        assert!(info.busy);
+        assert_eq!(
+            info.guest_linear_addr,
+            Some(GuestVirtAddr::from(0xffff_8000_1234_5678))
+        );
+        assert_eq!(info.guest_phys_addr, GuestPhysAddr::from(0x1234_5678));
+        assert_eq!(info.eptp_index, 3);
+        assert_eq!(info.fault_info().access_flags, MappingFlags::WRITE);
+
+        // Not busy, and the guest linear address is not valid.
+        bytes[4..8].fill(0);
+        bytes[8] = 1 << 2;
+        let info = VeInfo::from_bytes(&bytes);
+        assert!(!info.busy);
+        assert_eq!(info.guest_linear_addr, None);
+        assert_eq!(info.fault_info().access_flags, MappingFlags::EXECUTE);
+    }
+}
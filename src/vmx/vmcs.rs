@@ -6,12 +6,18 @@
 use bit_field::BitField;
 use x86::bits64::vmx;
 
-use axaddrspace::{GuestPhysAddr, HostPhysAddr, NestedPageFaultInfo};
-use axerrno::{AxResult, ax_err};
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr, NestedPageFaultInfo};
+use axerrno::{AxResult, ax_err, ax_err_type};
 use page_table_entry::MappingFlags;
 
 use super::as_axerr;
-use super::definitions::{VmxExitReason, VmxInstructionError, VmxInterruptionType};
+use super::definitions::{
+    VmxActivityState, VmxExitReason, VmxInstructionError, VmxInterruptionType,
+};
+use super::realmode::Vm86Regs;
+use super::segment::{Segment, SegmentRegister};
+use super::spp::SppEvent;
+use super::structs::{EPTPointer, VmxEptVpidCap};
 use crate::msr::Msr;
 
 // HYGIENE: These macros are only used in this file, so we can use `as_axerr` directly.
@@ -217,6 +223,7 @@ pub enum VmcsControlNW {
 define_vmcs_fields_rw!(VmcsControlNW, usize);
 
 /// 16-Bit Guest-State Fields. (SDM Vol. 3D, Appendix B.1.2)
+#[derive(Clone, Copy, Debug)]
 pub enum VmcsGuest16 {
     /// Guest ES selector.
     ES_SELECTOR = 0x800,
@@ -533,6 +540,139 @@ impl VmxInterruptInfo {
     }
 }
 
+/// Information for VM exits due to exceptions. (SDM Vol. 3C, Section 28.2.1, 28.2.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionExit {
+    /// Vector of the exception.
+    pub vector: u8,
+    /// Hardware exception, software exception (INT3 or INTO), or privileged
+    /// software exception (INT1).
+    pub int_type: VmxInterruptionType,
+    /// For hardware exceptions that would have delivered an error code on the stack.
+    pub err_code: Option<u32>,
+    /// For #PF, the linear address that would have been loaded into CR2.
+    pub cr2: Option<u64>,
+    /// For #DB, the debug conditions that would have been reported in DR6.
+    pub dr6: Option<u64>,
+    /// For software exceptions, the length of the instruction that raised it.
+    pub instruction_length: u32,
+}
+
+impl ExceptionExit {
+    /// DR6 bits reported in the exit qualification: B0-B3, BD and BS.
+    const DR6_CONDITIONS: u64 = 0x600f;
+
+    /// Decode from the VM-exit interruption information, the VM-exit interruption
+    /// error code, the exit qualification and the VM-exit instruction length.
+    ///
+    /// Return `None` if the VM exit is not caused by an exception, e.g. by an NMI.
+    pub fn from_raw(
+        int_info: u32,
+        err_code: u32,
+        qualification: u64,
+        instruction_length: u32,
+    ) -> Option<Self> {
+        let int_type = VmxInterruptionType::try_from(int_info.get_bits(8..11) as u8).ok()?;
+        if !int_info.get_bit(31)
+            || !matches!(
+                int_type,
+                VmxInterruptionType::HardException
+                    | VmxInterruptionType::SoftException
+                    | VmxInterruptionType::PrivSoftException
+            )
+        {
+            return None;
+        }
+        let vector = int_info.get_bits(0..8) as u8;
+        Some(Self {
+            vector,
+            int_type,
+            err_code: int_info.get_bit(11).then_some(err_code),
+            cr2: (vector == x86::irq::PAGE_FAULT_VECTOR).then_some(qualification),
+            dr6: (vector == x86::irq::DEBUG_VECTOR).then_some(qualification & Self::DR6_CONDITIONS),
+            instruction_length,
+        })
+    }
+
+    /// How this exception is handled if it was raised while delivering the hardware
+    /// exception `first`. (SDM Vol. 3A, Section 6.15, Table 6-5)
+    pub fn merge_with(&self, first: u8) -> ExceptionMerge {
+        // Benign exceptions, contributory exceptions and page faults.
+        let class = |vector: u8| match vector {
+            0 | 10..=13 => 1,
+            x86::irq::PAGE_FAULT_VECTOR => 2,
+            _ => 0,
+        };
+        match (class(first), class(self.vector)) {
+            _ if first == x86::irq::DOUBLE_FAULT_VECTOR && class(self.vector) != 0 => {
+                ExceptionMerge::TripleFault
+            }
+            (1, 1) | (2, 1) | (2, 2) => ExceptionMerge::DoubleFault,
+            _ => ExceptionMerge::Serial,
+        }
+    }
+
+    /// The interruption information to reflect the exception into the guest.
+    pub fn interrupt_info(&self) -> VmxInterruptInfo {
+        VmxInterruptInfo {
+            vector: self.vector,
+            int_type: self.int_type,
+            err_code: self.err_code,
+            valid: true,
+        }
+    }
+}
+
+/// How an exception raised while delivering another exception is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionMerge {
+    /// The exceptions are handled serially, the new one is delivered.
+    Serial,
+    /// A double fault (#DF) is delivered instead.
+    DoubleFault,
+    /// The processor shuts down.
+    TripleFault,
+}
+
+/// Information for VM exits due to EPT violations. (SDM Vol. 3C, Section 28.2.1, Table 28-7)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptViolation {
+    /// The access causing the EPT violation: data read, data write or instruction fetch.
+    pub access_flags: MappingFlags,
+    /// The access rights of the guest-physical address in the EPT. Execute is
+    /// supervisor-mode execute with mode-based execute control.
+    pub permissions: MappingFlags,
+    /// User-mode linear addresses can be executed, with mode-based execute control.
+    pub user_executable: bool,
+    /// The guest-physical address of the access.
+    pub guest_phys_addr: GuestPhysAddr,
+    /// The guest linear address, if the access has one.
+    pub guest_linear_addr: Option<GuestVirtAddr>,
+    /// The access is to the translation of the guest linear address, rather than
+    /// to a guest paging structure.
+    pub translated_access: bool,
+    /// The EPT violation happened while executing IRET, which unblocked NMIs.
+    pub nmi_unblocking: bool,
+}
+
+impl EptViolation {
+    /// Decode from the exit qualification, the guest-physical address and the guest
+    /// linear address.
+    pub fn from_raw(qualification: u64, guest_phys_addr: u64, guest_linear_addr: u64) -> Self {
+        let linear_addr_valid = qualification.get_bit(7);
+        Self {
+            access_flags: ept_violation_access_flags(qualification),
+            permissions: ept_violation_access_flags(qualification >> 3),
+            user_executable: qualification.get_bit(6),
+            guest_phys_addr: GuestPhysAddr::from(guest_phys_addr as usize),
+            guest_linear_addr: linear_addr_valid
+                .then(|| GuestVirtAddr::from(guest_linear_addr as usize)),
+            translated_access: linear_addr_valid && qualification.get_bit(8),
+            nmi_unblocking: qualification.get_bit(12),
+        }
+    }
+}
+
 /// Exit Qualification for I/O Instructions. (SDM Vol. 3C, Section 27.2.1, Table 27-5)
 #[derive(Debug)]
 pub struct VmxIoExitInfo {
@@ -548,6 +688,42 @@ pub struct VmxIoExitInfo {
     pub port: u16,
 }
 
+/// VM-exit instruction information for INS and OUTS. (SDM Vol. 3C, Section 28.2.5, Table 28-8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxStringIoInfo {
+    /// The address size of the instruction in bytes: 2, 4 or 8.
+    pub address_size: u8,
+    /// The segment of the memory operand: ES for INS, DS or the segment override
+    /// prefix for OUTS.
+    pub segment: SegmentRegister,
+}
+
+impl VmxStringIoInfo {
+    /// Decode from the VM-exit instruction information of INS if `is_in`, or OUTS.
+    pub fn from_raw(info: u32, is_in: bool) -> Option<Self> {
+        let address_size = match info.get_bits(7..10) {
+            0 => 2,
+            1 => 4,
+            2 => 8,
+            _ => return None,
+        };
+        let segment = if is_in {
+            SegmentRegister::Es
+        } else {
+            SegmentRegister::from_index(info.get_bits(15..18) as u8)?
+        };
+        Some(Self {
+            address_size,
+            segment,
+        })
+    }
+
+    /// The mask of the address size, applied to rSI, rDI and rCX.
+    pub fn address_mask(&self) -> u64 {
+        u64::MAX >> (64 - self.address_size as u32 * 8)
+    }
+}
+
 /// Exit Qualification for Control Register Accesses. (SDM Vol. 3C, Section 28.2.1, Table 28-5)
 #[derive(Debug)]
 pub struct CrAccessInfo {
@@ -578,7 +754,23 @@ pub struct CrAccessInfo {
     /// [31:16]
     /// For LMSW, the LMSW source data
     /// For CLTS and MOV CR, cleared to 0
-    pub lmsw_source_data: u8,
+    pub lmsw_source_data: u16,
+}
+
+/// Exit Qualification for MOV DR. (SDM Vol. 3C, Section 28.2.1, Table 28-4)
+#[derive(Debug)]
+pub struct DrAccessInfo {
+    /// [2:0]
+    /// Number of debug register
+    pub dr_number: u8,
+    /// [4]
+    /// Direction of access:
+    ///     0 = MOV to DR
+    ///     1 = MOV from DR
+    pub is_read: bool,
+    /// [11:8]
+    /// General-purpose register, encoded as in [`CrAccessInfo::gpr`]
+    pub gpr: u8,
 }
 
 /// Type of APIC-access, used in Exit Qualification for APIC Accesses. (SDM Vol. 3C, Section 28.2.2, Table 28-6)
@@ -680,12 +872,19 @@ pub fn set_control(
     Ok(())
 }
 
-pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> AxResult {
-    use super::instructions::{InvEptType, invept};
-    let eptp = super::structs::EPTPointer::from_table_phys(pml4_paddr).bits();
-    VmcsControl64::EPTP.write(eptp)?;
-    unsafe { invept(InvEptType::SingleContext, eptp).map_err(as_axerr)? };
-    Ok(())
+pub fn set_ept_pointer(eptp: EPTPointer) -> AxResult {
+    VmcsControl64::EPTP.write(eptp.bits())?;
+    invalidate_ept(eptp.bits())
+}
+
+/// Invalidate mappings derived from the EPT of `eptp`, with the INVEPT type
+/// supported by the processor.
+pub fn invalidate_ept(eptp: u64) -> AxResult {
+    use super::instructions::invept;
+    let inv_type = VmxEptVpidCap::read()
+        .ept_flush_type()
+        .ok_or_else(|| ax_err_type!(Unsupported, "INVEPT is not supported"))?;
+    unsafe { invept(inv_type, eptp).map_err(as_axerr) }
 }
 
 pub fn instruction_error() -> VmxInstructionError {
@@ -724,6 +923,53 @@ pub fn interrupt_exit_info() -> AxResult<VmxInterruptInfo> {
     })
 }
 
+/// The event being delivered through the guest IDT when the VM exit occurred, if any.
+/// (SDM Vol. 3C, Section 28.2.4)
+pub fn idt_vectoring_info() -> AxResult<Option<VmxInterruptInfo>> {
+    let info = VmcsReadOnly32::IDT_VECTORING_INFO.read()?;
+    if !info.get_bit(31) {
+        return Ok(None);
+    }
+    Ok(Some(VmxInterruptInfo {
+        vector: info.get_bits(0..8) as u8,
+        int_type: VmxInterruptionType::try_from(info.get_bits(8..11) as u8).unwrap(),
+        err_code: if info.get_bit(11) {
+            Some(VmcsReadOnly32::IDT_VECTORING_ERR_CODE.read()?)
+        } else {
+            None
+        },
+        valid: true,
+    }))
+}
+
+pub fn exception_exit_info() -> AxResult<Option<ExceptionExit>> {
+    Ok(ExceptionExit::from_raw(
+        VmcsReadOnly32::VMEXIT_INTERRUPTION_INFO.read()?,
+        VmcsReadOnly32::VMEXIT_INTERRUPTION_ERR_CODE.read()?,
+        VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? as u64,
+        VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?,
+    ))
+}
+
+/// Inject an event on the next VM entry, `instruction_length` is only used by
+/// software interrupts and exceptions.
+pub fn inject_interrupt_info(int_info: &VmxInterruptInfo, instruction_length: u32) -> AxResult {
+    if let Some(err_code) = int_info.err_code {
+        VmcsControl32::VMENTRY_EXCEPTION_ERR_CODE.write(err_code)?;
+    }
+    if int_info.int_type.is_soft() {
+        VmcsControl32::VMENTRY_INSTRUCTION_LEN.write(instruction_length)?;
+    }
+    VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD.write(int_info.bits())
+}
+
+/// Whether an event has been injected for the next VM entry.
+pub fn has_injected_event() -> AxResult<bool> {
+    Ok(VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD
+        .read()?
+        .get_bit(31))
+}
+
 pub fn inject_event(vector: u8, err_code: Option<u32>) -> AxResult {
     // SDM Vol. 3C, Section 24.8.3
     let err_code = if VmxInterruptionType::vector_has_error_code(vector) {
@@ -743,6 +989,25 @@ pub fn inject_event(vector: u8, err_code: Option<u32>) -> AxResult {
     Ok(())
 }
 
+pub fn activity_state() -> AxResult<VmxActivityState> {
+    let state = VmcsGuest32::ACTIVITY_STATE.read()?;
+    VmxActivityState::try_from(state).map_err(|_| {
+        ax_err_type!(
+            BadState,
+            format_args!("invalid guest activity state {}", state)
+        )
+    })
+}
+
+pub fn set_activity_state(state: VmxActivityState) -> AxResult {
+    VmcsGuest32::ACTIVITY_STATE.write(state as u32)
+}
+
+/// The vector of the SIPI that caused a VM exit. (SDM Vol. 3C, Section 28.2.1, Table 28-1)
+pub fn sipi_vector() -> AxResult<u8> {
+    Ok(VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?.get_bits(0..8) as u8)
+}
+
 pub fn io_exit_info() -> AxResult<VmxIoExitInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-5
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
@@ -755,10 +1020,112 @@ pub fn io_exit_info() -> AxResult<VmxIoExitInfo> {
     })
 }
 
+pub fn string_io_info(is_in: bool) -> AxResult<VmxStringIoInfo> {
+    VmxStringIoInfo::from_raw(VmcsReadOnly32::VMEXIT_INSTRUCTION_INFO.read()?, is_in)
+        .ok_or_else(|| ax_err_type!(BadState, "invalid instruction information of string I/O"))
+}
+
 pub fn ept_violation_info() -> AxResult<NestedPageFaultInfo> {
-    // SDM Vol. 3C, Section 27.2.1, Table 27-7
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
     let fault_guest_paddr = VmcsReadOnly64::GUEST_PHYSICAL_ADDR.read()? as usize;
+    Ok(NestedPageFaultInfo {
+        access_flags: ept_violation_access_flags(qualification as u64),
+        fault_guest_paddr: GuestPhysAddr::from(fault_guest_paddr),
+    })
+}
+
+pub fn ept_violation() -> AxResult<EptViolation> {
+    Ok(EptViolation::from_raw(
+        VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? as u64,
+        VmcsReadOnly64::GUEST_PHYSICAL_ADDR.read()?,
+        VmcsReadOnlyNW::GUEST_LINEAR_ADDR.read()? as u64,
+    ))
+}
+
+pub fn spp_event() -> AxResult<SppEvent> {
+    Ok(SppEvent::from_raw(
+        VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? as u64,
+        VmcsReadOnly64::GUEST_PHYSICAL_ADDR.read()?,
+    ))
+}
+
+/// The linear address of INVLPG that caused a VM exit.
+pub fn invlpg_address() -> AxResult<usize> {
+    VmcsReadOnlyNW::EXIT_QUALIFICATION.read()
+}
+
+/// Selector, base, limit and access rights fields of guest ES, CS, SS, DS, FS,
+/// GS and TR.
+const SEGMENT_FIELDS: [(VmcsGuest16, VmcsGuestNW, VmcsGuest32, VmcsGuest32); 7] = {
+    use VmcsGuest16::*;
+    use VmcsGuest32::*;
+    use VmcsGuestNW::*;
+    [
+        (ES_SELECTOR, ES_BASE, ES_LIMIT, ES_ACCESS_RIGHTS),
+        (CS_SELECTOR, CS_BASE, CS_LIMIT, CS_ACCESS_RIGHTS),
+        (SS_SELECTOR, SS_BASE, SS_LIMIT, SS_ACCESS_RIGHTS),
+        (DS_SELECTOR, DS_BASE, DS_LIMIT, DS_ACCESS_RIGHTS),
+        (FS_SELECTOR, FS_BASE, FS_LIMIT, FS_ACCESS_RIGHTS),
+        (GS_SELECTOR, GS_BASE, GS_LIMIT, GS_ACCESS_RIGHTS),
+        (TR_SELECTOR, TR_BASE, TR_LIMIT, TR_ACCESS_RIGHTS),
+    ]
+};
+
+const TR_FIELDS_INDEX: usize = 6;
+
+fn read_segment(index: usize) -> AxResult<Segment> {
+    let (selector, base, limit, access_rights) = SEGMENT_FIELDS[index];
+    Ok(Segment {
+        selector: selector.read()?,
+        base: base.read()? as u64,
+        limit: limit.read()?,
+        access_rights: access_rights.read()?,
+    })
+}
+
+/// The guest segment register `seg`.
+pub fn guest_segment(seg: SegmentRegister) -> AxResult<Segment> {
+    read_segment(seg as usize)
+}
+
+/// The guest registers changed by virtual-8086 mode.
+pub fn vm86_regs() -> AxResult<Vm86Regs> {
+    let mut segs = [Segment::default(); 6];
+    for (index, seg) in segs.iter_mut().enumerate() {
+        *seg = read_segment(index)?;
+    }
+    Ok(Vm86Regs {
+        segs,
+        tr: read_segment(TR_FIELDS_INDEX)?,
+        rip: VmcsGuestNW::RIP.read()? as u64,
+        rsp: VmcsGuestNW::RSP.read()? as u64,
+        rflags: VmcsGuestNW::RFLAGS.read()? as u64,
+    })
+}
+
+/// Write the guest registers changed by virtual-8086 mode.
+pub fn set_vm86_regs(regs: &Vm86Regs) -> AxResult {
+    let segs = regs.segs.iter().chain([&regs.tr]);
+    for (seg, (selector, base, limit, access_rights)) in segs.zip(SEGMENT_FIELDS) {
+        selector.write(seg.selector)?;
+        base.write(seg.base as usize)?;
+        limit.write(seg.limit)?;
+        access_rights.write(seg.access_rights)?;
+    }
+    VmcsGuestNW::RIP.write(regs.rip as usize)?;
+    VmcsGuestNW::RSP.write(regs.rsp as usize)?;
+    VmcsGuestNW::RFLAGS.write(regs.rflags as usize)
+}
+
+/// Whether the secondary processor-based VM-execution controls `bits` can be set.
+pub fn secondary_control_allowed(bits: u32) -> bool {
+    let allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+    allowed1 & bits == bits
+}
+
+/// The access causing an EPT violation, from its exit qualification.
+pub fn ept_violation_access_flags(qualification: u64) -> MappingFlags {
+    // SDM Vol. 3C, Section 27.2.1, Table 27-7
     let mut access_flags = MappingFlags::empty();
     if qualification.get_bit(0) {
         access_flags |= MappingFlags::READ;
@@ -769,44 +1136,54 @@ pub fn ept_violation_info() -> AxResult<NestedPageFaultInfo> {
     if qualification.get_bit(2) {
         access_flags |= MappingFlags::EXECUTE;
     }
-    Ok(NestedPageFaultInfo {
-        access_flags,
-        fault_guest_paddr: GuestPhysAddr::from(fault_guest_paddr),
-    })
+    access_flags
 }
 
-pub fn update_efer() -> AxResult {
+/// Write guest IA32_EFER on a change of the paging mode, and keep the guest
+/// state consistent with EFER.LMA for VM entries. (SDM Vol. 3C, Section 27.3.1)
+///
+/// - The "IA-32e mode guest" VM-entry control follows EFER.LMA.
+/// - In IA-32e mode, TR must refer to a 64-bit busy TSS. A 16-bit busy TSS left
+///   by a legacy guest is converted.
+/// - Out of IA-32e mode, CS.L is meaningless and cleared.
+pub fn update_efer(efer: u64) -> AxResult {
+    use controls::EntryControls as EntryCtrl;
     use x86_64::registers::control::EferFlags;
 
-    let efer = VmcsGuest64::IA32_EFER.read()?;
-    let mut guest_efer = EferFlags::from_bits_truncate(efer);
-
-    if guest_efer.contains(EferFlags::LONG_MODE_ENABLE)
-        && guest_efer.contains(EferFlags::LONG_MODE_ACTIVE)
-    {
-        // debug!("Guest IA32_EFER LONG_MODE_ACTIVE is set, just return");
-        return Ok(());
-    }
-
-    guest_efer.set(EferFlags::LONG_MODE_ACTIVE, true);
-
-    // debug!(
-    //     "Guest IA32_EFER from {:?} update to {:?}",
-    //     EferFlags::from_bits_truncate(efer),
-    //     guest_efer
-    // );
+    const TSS_TYPE_16BIT_BUSY: u32 = 0b0011;
+    const TSS_TYPE_64BIT_BUSY: u32 = 0b1011;
+    const CS_ACCESS_RIGHTS_L: usize = 13;
 
-    VmcsGuest64::IA32_EFER.write(guest_efer.bits())?;
+    VmcsGuest64::IA32_EFER.write(efer)?;
 
-    use controls::EntryControls as EntryCtrl;
+    let ia32e = EferFlags::from_bits_truncate(efer).contains(EferFlags::LONG_MODE_ACTIVE);
+    let entry_ctrl = EntryCtrl::IA32E_MODE_GUEST.bits();
+    let (set, clear) = if ia32e {
+        (entry_ctrl, 0)
+    } else {
+        (0, entry_ctrl)
+    };
     set_control(
         VmcsControl32::VMENTRY_CONTROLS,
         Msr::IA32_VMX_TRUE_ENTRY_CTLS,
         VmcsControl32::VMENTRY_CONTROLS.read()?,
-        (EntryCtrl::IA32E_MODE_GUEST).bits(),
-        0,
+        set,
+        clear,
     )?;
 
+    if ia32e {
+        let mut tr_access_rights = VmcsGuest32::TR_ACCESS_RIGHTS.read()?;
+        if tr_access_rights.get_bits(0..4) == TSS_TYPE_16BIT_BUSY {
+            tr_access_rights.set_bits(0..4, TSS_TYPE_64BIT_BUSY);
+            VmcsGuest32::TR_ACCESS_RIGHTS.write(tr_access_rights)?;
+        }
+    } else {
+        let mut cs_access_rights = VmcsGuest32::CS_ACCESS_RIGHTS.read()?;
+        if cs_access_rights.get_bit(CS_ACCESS_RIGHTS_L) {
+            cs_access_rights.set_bit(CS_ACCESS_RIGHTS_L, false);
+            VmcsGuest32::CS_ACCESS_RIGHTS.write(cs_access_rights)?;
+        }
+    }
     Ok(())
 }
 
@@ -819,7 +1196,16 @@ pub fn cr_access_info() -> AxResult<CrAccessInfo> {
         access_type: qualification.get_bits(4..6) as u8,
         lmsw_op_type: qualification.get_bits(6..7) as u8,
         gpr: qualification.get_bits(8..12) as u8,
-        lmsw_source_data: qualification.get_bits(16..32) as u8,
+        lmsw_source_data: qualification.get_bits(16..32) as u16,
+    })
+}
+
+pub fn dr_access_info() -> AxResult<DrAccessInfo> {
+    let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
+    Ok(DrAccessInfo {
+        dr_number: qualification.get_bits(0..3) as u8,
+        is_read: qualification.get_bit(4),
+        gpr: qualification.get_bits(8..12) as u8,
     })
 }
 
@@ -833,3 +1219,111 @@ pub fn apic_access_exit_info() -> AxResult<ApicAccessExitInfo> {
         non_event_delivery_asynchronous: qualification.get_bit(16),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VALID: u32 = 1 << 31;
+    const HAS_ERR_CODE: u32 = 1 << 11;
+
+    #[test]
+    fn test_ept_violation() {
+        // Instruction fetch from a user-mode executable page, with a linear address.
+        let info = EptViolation::from_raw(
+            1 << 2 | 1 << 3 | 1 << 6 | 1 << 7 | 1 << 8,
+            0x8000,
+            0x40_0000,
+        );
+        assert_eq!(info.access_flags, MappingFlags::EXECUTE);
+        assert_eq!(info.permissions, MappingFlags::READ);
+        assert!(info.user_executable);
+        assert_eq!(info.guest_phys_addr, GuestPhysAddr::from(0x8000));
+        assert_eq!(info.guest_linear_addr, Some(GuestVirtAddr::from(0x40_0000)));
+        assert!(info.translated_access);
+        assert!(!info.nmi_unblocking);
+
+        // Write to a read-only page without a linear address.
+        let info = EptViolation::from_raw(1 << 1 | 1 << 3 | 1 << 5 | 1 << 12, 0x9000, 0xdead);
+        assert_eq!(info.access_flags, MappingFlags::WRITE);
+        assert_eq!(info.permissions, MappingFlags::READ | MappingFlags::EXECUTE);
+        assert!(!info.user_executable);
+        assert_eq!(info.guest_linear_addr, None);
+        assert!(!info.translated_access);
+        assert!(info.nmi_unblocking);
+    }
+
+    #[test]
+    fn test_exception_exit() {
+        // #PF with an error code, CR2 is in the exit qualification.
+        let pf = ExceptionExit::from_raw(VALID | HAS_ERR_CODE | 3 << 8 | 14, 0x6, 0xdead_b000, 3)
+            .unwrap();
+        assert_eq!(pf.vector, 14);
+        assert_eq!(pf.int_type, VmxInterruptionType::HardException);
+        assert_eq!(pf.err_code, Some(0x6));
+        assert_eq!(pf.cr2, Some(0xdead_b000));
+        assert_eq!(pf.dr6, None);
+        assert_eq!(
+            pf.interrupt_info().bits(),
+            VALID | HAS_ERR_CODE | 3 << 8 | 14
+        );
+
+        // #DB, only the debug conditions are kept.
+        let db = ExceptionExit::from_raw(VALID | 3 << 8 | 1, 0, 0x1_4002, 0).unwrap();
+        assert_eq!(db.err_code, None);
+        assert_eq!(db.cr2, None);
+        assert_eq!(db.dr6, Some(0x4002));
+
+        // #BP raised by INT3 is a software exception.
+        let bp = ExceptionExit::from_raw(VALID | 6 << 8 | 3, 0, 0, 1).unwrap();
+        assert_eq!(bp.int_type, VmxInterruptionType::SoftException);
+        assert!(bp.interrupt_info().int_type.is_soft());
+        assert_eq!(bp.instruction_length, 1);
+    }
+
+    #[test]
+    fn test_not_exception_exit() {
+        // NMI and invalid information.
+        assert_eq!(ExceptionExit::from_raw(VALID | 2 << 8 | 2, 0, 0, 0), None);
+        assert_eq!(ExceptionExit::from_raw(3 << 8 | 6, 0, 0, 0), None);
+    }
+
+    #[test]
+    fn test_string_io_info() {
+        // OUTSB with a 32-bit address size and an FS override.
+        let outs = VmxStringIoInfo::from_raw(1 << 7 | 4 << 15, false).unwrap();
+        assert_eq!(outs.address_size, 4);
+        assert_eq!(outs.segment, SegmentRegister::Fs);
+        assert_eq!(outs.address_mask(), 0xffff_ffff);
+        // INS always writes through ES, the segment field is undefined.
+        let ins = VmxStringIoInfo::from_raw(3 << 15, true).unwrap();
+        assert_eq!(ins.address_size, 2);
+        assert_eq!(ins.segment, SegmentRegister::Es);
+        assert_eq!(ins.address_mask(), 0xffff);
+        let ins = VmxStringIoInfo::from_raw(2 << 7, true).unwrap();
+        assert_eq!(ins.address_mask(), u64::MAX);
+        // Reserved address size and segment register.
+        assert_eq!(VmxStringIoInfo::from_raw(3 << 7, true), None);
+        assert_eq!(VmxStringIoInfo::from_raw(6 << 15, false), None);
+    }
+
+    #[test]
+    fn test_exception_merge() {
+        let exception = |vector: u32| ExceptionExit::from_raw(VALID | 3 << 8 | vector, 0, 0, 0);
+        let gp = exception(13).unwrap();
+        let pf = exception(14).unwrap();
+        let ud = exception(6).unwrap();
+        // Contributory or page fault after a contributory exception or a page fault.
+        assert_eq!(gp.merge_with(11), ExceptionMerge::DoubleFault);
+        assert_eq!(gp.merge_with(14), ExceptionMerge::DoubleFault);
+        assert_eq!(pf.merge_with(14), ExceptionMerge::DoubleFault);
+        // Page fault after a contributory exception, and benign exceptions.
+        assert_eq!(pf.merge_with(13), ExceptionMerge::Serial);
+        assert_eq!(gp.merge_with(1), ExceptionMerge::Serial);
+        assert_eq!(ud.merge_with(14), ExceptionMerge::Serial);
+        // Anything but a benign exception after a double fault.
+        assert_eq!(pf.merge_with(8), ExceptionMerge::TripleFault);
+        assert_eq!(gp.merge_with(8), ExceptionMerge::TripleFault);
+        assert_eq!(ud.merge_with(8), ExceptionMerge::Serial);
+    }
+}
@@ -0,0 +1,166 @@
+use alloc::vec::Vec;
+
+use axaddrspace::{AxMmHal, HostPhysAddr, PhysFrame};
+use axerrno::{AxResult, ax_err};
+
+use super::structs::EptpConfig;
+use super::vmcs::controls::SecondaryControls;
+use crate::msr::Msr;
+
+/// The number of EPTPs in the EPTP list. (SDM Vol. 3C, Section 26.5.6.3)
+pub const EPTP_LIST_ENTRIES: usize = 512;
+/// The EPT view of the EPT root set by `set_ept_root`.
+pub const PRIMARY_EPT_VIEW: u16 = 0;
+
+/// The address of the EPT root in an EPTP.
+const EPTP_ROOT_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// EPTP switching, VM function 0, in the VM-function controls.
+pub const VMFUNC_EPTP_SWITCHING: u64 = 1 << 0;
+
+/// The EPT root referenced by `eptp`.
+pub fn eptp_root(eptp: u64) -> HostPhysAddr {
+    HostPhysAddr::from((eptp & EPTP_ROOT_MASK) as usize)
+}
+
+/// Whether the processor supports EPTP switching with VMFUNC.
+pub fn eptp_switching_supported() -> bool {
+    let allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+    allowed1 & SecondaryControls::ENABLE_VM_FUNCTIONS.bits() != 0
+        && Msr::IA32_VMX_VMFUNC.read() & VMFUNC_EPTP_SWITCHING != 0
+}
+
+/// The 4K EPTP list, the EPT views the guest can switch between with VMFUNC.
+/// An invalid EPTP of 0 marks an unused entry.
+pub struct EptpList<H: AxMmHal> {
+    frame: PhysFrame<H>,
+}
+
+impl<H: AxMmHal> EptpList<H> {
+    /// Allocate an empty list.
+    pub fn new() -> AxResult<Self> {
+        Ok(Self {
+            frame: PhysFrame::alloc_zero()?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frame.start_paddr()
+    }
+
+    fn entries(&self) -> &[u64; EPTP_LIST_ENTRIES] {
+        unsafe { &*(self.frame.as_mut_ptr() as *const [u64; EPTP_LIST_ENTRIES]) }
+    }
+
+    fn entries_mut(&mut self) -> &mut [u64; EPTP_LIST_ENTRIES] {
+        unsafe { &mut *(self.frame.as_mut_ptr() as *mut [u64; EPTP_LIST_ENTRIES]) }
+    }
+
+    /// The EPTP of view `index`, if it is used.
+    pub fn get(&self, index: u16) -> Option<u64> {
+        self.entries()
+            .get(index as usize)
+            .copied()
+            .filter(|&eptp| eptp != 0)
+    }
+
+    /// Set or clear the EPTP of view `index`, which must be less than
+    /// [`EPTP_LIST_ENTRIES`].
+    pub fn set(&mut self, index: u16, eptp: Option<u64>) -> AxResult {
+        match self.entries_mut().get_mut(index as usize) {
+            Some(entry) => {
+                *entry = eptp.unwrap_or(0);
+                Ok(())
+            }
+            None => ax_err!(InvalidInput, "invalid EPT view index"),
+        }
+    }
+
+    /// The EPTPs of the used views.
+    pub fn eptps(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries().iter().copied().filter(|&eptp| eptp != 0)
+    }
+
+    /// Rebuild the EPTPs of the used views with `config`, keeping their EPT roots.
+    ///
+    /// Return the EPTPs that have changed.
+    pub fn rebuild(&mut self, config: &EptpConfig) -> Vec<u64> {
+        let mut changed = Vec::new();
+        for entry in self.entries_mut().iter_mut().filter(|entry| **entry != 0) {
+            let eptp = config.eptp(eptp_root(*entry)).bits();
+            if eptp != *entry {
+                *entry = eptp;
+                changed.push(eptp);
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmx::EptMemoryType;
+    use crate::vmx::memory::TestMmHal;
+
+    #[test]
+    fn test_eptp_list() {
+        let mut list = EptpList::<TestMmHal>::new().unwrap();
+        assert_eq!(list.get(PRIMARY_EPT_VIEW), None);
+        assert_eq!(list.eptps().count(), 0);
+
+        list.set(PRIMARY_EPT_VIEW, Some(0x1000_005e)).unwrap();
+        list.set(3, Some(0x2000_005e)).unwrap();
+        list.set(511, Some(0x3000_005e)).unwrap();
+        assert_eq!(list.get(3), Some(0x2000_005e));
+        assert_eq!(list.get(2), None);
+        assert_eq!(
+            list.eptps().collect::<Vec<_>>(),
+            [0x1000_005e, 0x2000_005e, 0x3000_005e]
+        );
+
+        // Removed views are unused.
+        list.set(3, None).unwrap();
+        assert_eq!(list.get(3), None);
+        assert_eq!(list.eptps().count(), 2);
+    }
+
+    #[test]
+    fn test_invalid_view_index() {
+        let mut list = EptpList::<TestMmHal>::new().unwrap();
+        assert!(
+            list.set(EPTP_LIST_ENTRIES as u16, Some(0x1000_005e))
+                .is_err()
+        );
+        assert!(list.set(u16::MAX, None).is_err());
+        assert_eq!(list.get(EPTP_LIST_ENTRIES as u16), None);
+        assert_eq!(list.eptps().count(), 0);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut list = EptpList::<TestMmHal>::new().unwrap();
+        let config = EptpConfig::default();
+        let primary = config.eptp(HostPhysAddr::from(0x1000_0000)).bits();
+        let alternate = config.eptp(HostPhysAddr::from(0x2000_0000)).bits();
+        list.set(PRIMARY_EPT_VIEW, Some(primary)).unwrap();
+        list.set(7, Some(alternate)).unwrap();
+        assert!(list.rebuild(&config).is_empty());
+
+        // All used views follow the new configuration, and keep their EPT roots.
+        let config = EptpConfig {
+            memory_type: EptMemoryType::Uncacheable,
+            accessed_dirty: false,
+            ..config
+        };
+        let changed = list.rebuild(&config);
+        let expected = [
+            config.eptp(HostPhysAddr::from(0x1000_0000)).bits(),
+            config.eptp(HostPhysAddr::from(0x2000_0000)).bits(),
+        ];
+        assert_eq!(changed, expected);
+        assert_eq!(list.get(PRIMARY_EPT_VIEW), Some(expected[0]));
+        assert_eq!(list.get(7), Some(expected[1]));
+        assert_eq!(list.get(1), None);
+    }
+}
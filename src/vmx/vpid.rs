@@ -0,0 +1,113 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of VPIDs, VPID 0 is used by the host.
+const VPID_COUNT: usize = 1 << 16;
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// A lock-free allocator of virtual-processor identifiers (VPIDs).
+pub struct VpidAllocator {
+    bitmap: [AtomicU64; VPID_COUNT / WORD_BITS],
+}
+
+impl VpidAllocator {
+    /// Create an allocator with all VPIDs except 0 free.
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const FREE: AtomicU64 = AtomicU64::new(0);
+        let mut bitmap = [FREE; VPID_COUNT / WORD_BITS];
+        // VPID 0 is reserved for the host.
+        bitmap[0] = AtomicU64::new(1);
+        Self { bitmap }
+    }
+
+    /// Allocate a VPID, or return `None` if all of them are in use.
+    pub fn alloc(&self) -> Option<u16> {
+        for (index, word) in self.bitmap.iter().enumerate() {
+            let mut bits = word.load(Ordering::Relaxed);
+            while bits != u64::MAX {
+                let bit = bits.trailing_ones() as usize;
+                match word.compare_exchange_weak(
+                    bits,
+                    bits | 1 << bit,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some((index * WORD_BITS + bit) as u16),
+                    Err(current) => bits = current,
+                }
+            }
+        }
+        None
+    }
+
+    /// Free a VPID returned by [`alloc`](Self::alloc).
+    pub fn free(&self, vpid: u16) {
+        let (index, bit) = (vpid as usize / WORD_BITS, vpid as usize % WORD_BITS);
+        debug_assert_ne!(vpid, 0, "VPID 0 is reserved for the host");
+        let old = self.bitmap[index].fetch_and(!(1 << bit), Ordering::Release);
+        debug_assert!(old & 1 << bit != 0, "VPID {vpid} is not allocated");
+    }
+}
+
+/// VPIDs shared by the vCPUs on all physical CPUs, so that a vCPU keeps its VPID
+/// when it migrates.
+static VPID_ALLOCATOR: VpidAllocator = VpidAllocator::new();
+
+/// A VPID owned by a vCPU, freed when dropped.
+#[derive(Debug)]
+pub struct Vpid(u16);
+
+impl Vpid {
+    /// Allocate a VPID from the global allocator.
+    pub fn alloc() -> Option<Self> {
+        VPID_ALLOCATOR.alloc().map(Self)
+    }
+
+    /// The VPID value.
+    pub fn get(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Drop for Vpid {
+    fn drop(&mut self) {
+        VPID_ALLOCATOR.free(self.0);
+    }
+}
+
+/// An identifier of the current physical CPU, its x2APIC ID or initial APIC ID.
+pub fn current_cpu_id() -> u32 {
+    let cpuid = raw_cpuid::CpuId::new();
+    if let Some(level) = cpuid
+        .get_extended_topology_info()
+        .and_then(|mut topology| topology.next())
+    {
+        return level.x2apic_id();
+    }
+    cpuid
+        .get_feature_info()
+        .map_or(0, |info| info.initial_local_apic_id() as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alloc_and_free() {
+        let allocator = VpidAllocator::new();
+        assert_eq!(allocator.alloc(), Some(1));
+        assert_eq!(allocator.alloc(), Some(2));
+        allocator.free(1);
+        assert_eq!(allocator.alloc(), Some(1));
+        assert_eq!(allocator.alloc(), Some(3));
+
+        // VPIDs across words, until all of them are used.
+        for vpid in 4..VPID_COUNT {
+            assert_eq!(allocator.alloc(), Some(vpid as u16));
+        }
+        assert_eq!(allocator.alloc(), None);
+        allocator.free(0x1234);
+        assert_eq!(allocator.alloc(), Some(0x1234));
+    }
+}